@@ -0,0 +1,306 @@
+//! Rotating snapshots of the save file, and restoring them.
+//!
+//! Snapshots live in a `.backups` directory next to the save file, named for when they were
+//! taken. A restore is reconciled onto the current document as a new change, so it syncs to
+//! every peer like any other edit instead of being undone by the next merge.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use automerge::AutoCommit;
+use autosurgeon::hydrate;
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, Utc};
+use tokio::sync::oneshot::channel as oneshot_channel;
+use tracing::*;
+
+use crate::backends::{
+    McastConfig, TodoCommand, TodoState, connect_or_setup, multicast::TodoEvent, workspace,
+};
+
+pub const DEFAULT_BACKUP_COUNT: usize = 48;
+pub const DEFAULT_BACKUP_MAX_AGE_DAYS: u32 = 7;
+
+/// Saves closer together than this share the newest snapshot
+const BACKUP_INTERVAL: TimeDelta = TimeDelta::hours(1);
+
+const NAME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+const SNAPSHOT_EXTENSION: &str = "save";
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum BackupCommand {
+    /// List the snapshots of the workspace's save file, newest first
+    List,
+    /// Restore a snapshot, by name or path, as a new change that syncs to every peer
+    Restore { snapshot: String },
+}
+
+/// How many snapshots to keep, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub count: usize,
+    pub max_age: TimeDelta,
+}
+
+impl From<&McastConfig> for Retention {
+    fn from(config: &McastConfig) -> Self {
+        Self {
+            count: config.backup_count,
+            max_age: TimeDelta::days(config.backup_max_age_days.into()),
+        }
+    }
+}
+
+pub struct Snapshot {
+    pub name: String,
+    pub path: PathBuf,
+    pub taken: DateTime<Utc>,
+}
+
+pub fn dir(save_path: &Path) -> PathBuf {
+    save_path.with_extension("backups")
+}
+
+/// Every snapshot of the save file, newest first
+pub fn list(save_path: &Path) -> Result<Vec<Snapshot>> {
+    let dir = dir(save_path);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("could not read {}", dir.display())),
+    };
+
+    let mut snapshots: Vec<Snapshot> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != SNAPSHOT_EXTENSION {
+                return None;
+            }
+            let name = path.file_stem()?.to_str()?.to_string();
+            let taken = NaiveDateTime::parse_from_str(&name, NAME_FORMAT)
+                .ok()?
+                .and_utc();
+            Some(Snapshot { name, path, taken })
+        })
+        .collect();
+
+    snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.taken));
+    Ok(snapshots)
+}
+
+/// Copies the save file into a new snapshot if the newest is over [`BACKUP_INTERVAL`] old,
+/// then deletes whatever `retention` no longer covers
+pub fn snapshot(save_path: &Path, retention: Retention) -> Result<()> {
+    if retention.count == 0 {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    if list(save_path)?
+        .first()
+        .is_some_and(|newest| now - newest.taken < BACKUP_INTERVAL)
+    {
+        return Ok(());
+    }
+
+    take(save_path, retention, now)
+}
+
+/// Copies the save file into a new snapshot however recently the last was taken, e.g. before
+/// a compaction replaces it
+pub fn snapshot_now(save_path: &Path, retention: Retention) -> Result<()> {
+    if retention.count == 0 || !save_path.exists() {
+        return Ok(());
+    }
+    take(save_path, retention, Utc::now())
+}
+
+fn take(save_path: &Path, retention: Retention, now: DateTime<Utc>) -> Result<()> {
+    let dir = dir(save_path);
+    std::fs::create_dir_all(&dir).with_context(|| format!("could not create {}", dir.display()))?;
+
+    let name = now.format(NAME_FORMAT).to_string();
+    let temp = dir.join(format!("{name}.tmp"));
+    std::fs::copy(save_path, &temp)
+        .with_context(|| format!("could not copy {}", save_path.display()))?;
+    std::fs::rename(&temp, dir.join(format!("{name}.{SNAPSHOT_EXTENSION}")))?;
+    debug!("Took snapshot {name}");
+
+    prune(save_path, retention, now)
+}
+
+fn prune(save_path: &Path, retention: Retention, now: DateTime<Utc>) -> Result<()> {
+    for (idx, snapshot) in list(save_path)?.into_iter().enumerate() {
+        if idx >= retention.count || now - snapshot.taken > retention.max_age {
+            debug!("Removing snapshot {}", snapshot.name);
+            std::fs::remove_file(&snapshot.path)?;
+        }
+    }
+    Ok(())
+}
+
+fn load_state(path: &Path) -> Result<TodoState> {
+    let data = std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    Ok(hydrate(&AutoCommit::load(&data)?)?)
+}
+
+/// A snapshot given by name, or the path to any save file
+fn resolve(save_path: &Path, snapshot: &str) -> Result<PathBuf> {
+    let by_name = dir(save_path).join(format!("{snapshot}.{SNAPSHOT_EXTENSION}"));
+    if by_name.exists() {
+        return Ok(by_name);
+    }
+
+    let by_path = PathBuf::from(snapshot);
+    if by_path.is_file() {
+        return Ok(by_path);
+    }
+
+    Err(anyhow!(
+        "no snapshot called {snapshot}, see `todo-mcp backup list`"
+    ))
+}
+
+pub async fn run_backup(command: BackupCommand, config: McastConfig) -> Result<()> {
+    let save_path = workspace::save_path(&config.autosave_path, &config.workspace);
+
+    match command {
+        BackupCommand::List => {
+            let snapshots = list(&save_path)?;
+            if snapshots.is_empty() {
+                println!("No snapshots of workspace {} yet", config.workspace);
+            }
+
+            for snapshot in snapshots {
+                let summary = match load_state(&snapshot.path) {
+                    Ok(state) => format!(
+                        "{} lists, {} items",
+                        state.lists.len(),
+                        state
+                            .lists
+                            .iter()
+                            .map(|list| list.all_items().len())
+                            .sum::<usize>()
+                    ),
+                    Err(err) => format!("unreadable: {err}"),
+                };
+                println!(
+                    "{}  {}  {summary}",
+                    snapshot.name,
+                    snapshot
+                        .taken
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+        }
+        BackupCommand::Restore { snapshot } => {
+            let path = resolve(&save_path, &snapshot)?;
+            let state = load_state(&path)?;
+            let lists = state.lists.len();
+
+            let (tx, mut rx) = connect_or_setup(rand::random(), config);
+
+            // the restore goes on top of the current document, so wait until it's loaded
+            loop {
+                match rx.recv().await {
+                    Some(TodoEvent::StateUpdate(_)) => break,
+                    Some(
+                        TodoEvent::ConnectionStatus(_)
+                        | TodoEvent::Recovered(_)
+                        | TodoEvent::Document { .. },
+                    ) => {}
+                    None => return Err(anyhow!("sync stopped before the document loaded")),
+                }
+            }
+
+            tx.send(TodoCommand::Restore { state }).await?;
+
+            let (sender, saved) = oneshot_channel();
+            tx.send(TodoCommand::Shutdown { sender }).await?;
+            saved.await.ok();
+
+            println!("Restored {snapshot}, {lists} lists");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automerge::ActorId;
+    use autosurgeon::reconcile;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::ops;
+
+    #[test]
+    fn prune_keeps_the_newest_within_age() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-backup-{}", Uuid::new_v4()));
+        let save_path = dir.join("automerge.save");
+        std::fs::create_dir_all(super::dir(&save_path)).unwrap();
+
+        let now = Utc::now();
+        for hours in [1, 2, 3, 24 * 30] {
+            let name = (now - TimeDelta::hours(hours)).format(NAME_FORMAT);
+            std::fs::write(super::dir(&save_path).join(format!("{name}.save")), []).unwrap();
+        }
+
+        let retention = Retention {
+            count: 2,
+            max_age: TimeDelta::days(7),
+        };
+        prune(&save_path, retention, now).unwrap();
+
+        let kept: Vec<_> = list(&save_path).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept[0].taken > kept[1].taken);
+        assert!(now - kept[1].taken < TimeDelta::hours(3));
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn restore_survives_merging_with_peers() {
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, TodoState::default()).unwrap();
+
+        let list_id = Uuid::new_v4();
+        ops::apply(
+            &mut doc,
+            TodoCommand::AddList {
+                list_id,
+                title: "Groceries".into(),
+                metadata: HashMap::new(),
+            },
+        )
+        .unwrap();
+        let snapshot: TodoState = hydrate(&doc).unwrap();
+
+        ops::apply(&mut doc, TodoCommand::RemoveList { list_id }).unwrap();
+        let mut peer = doc.fork().with_actor(ActorId::random());
+
+        assert!(
+            ops::apply(
+                &mut doc,
+                TodoCommand::Restore {
+                    state: snapshot.clone()
+                }
+            )
+            .unwrap()
+        );
+
+        // the peer still has the list removed, merging must not undo the restore
+        doc.merge(&mut peer).unwrap();
+        peer.merge(&mut doc).unwrap();
+        for doc in [&doc, &peer] {
+            let state: TodoState = hydrate(doc).unwrap();
+            assert_eq!(state, snapshot);
+        }
+    }
+}
@@ -0,0 +1,567 @@
+//! Compacting a workspace's document down to its current state, and moving peers onto it.
+//!
+//! Every edit, down to each keystroke in the GUI, is a change the document keeps forever.
+//! Compacting rewrites the document as the shared genesis plus one change holding the lists
+//! as they are, and counts up the document's epoch. Sites only sync with peers on the same
+//! epoch, so a peer still holding the old history can't merge it back in; it moves onto the
+//! compaction instead once it hears of it.
+//!
+//! The compaction change is built the same way everywhere, from the same actor and without
+//! a timestamp, so any site holding the history it was built from rebuilds it byte for byte
+//! and only needs to carry over its own edits since.
+
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use automerge::{
+    ActorId, AutoCommit, ChangeHash, ObjId, ObjType, ROOT, ReadDoc, ScalarValue, Value,
+    transaction::{CommitOptions, Transactable},
+};
+use autosurgeon::{hydrate, reconcile};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use tokio::sync::oneshot::channel as oneshot_channel;
+
+use crate::backends::{
+    McastConfig, TodoCommand, TodoState, backup, connect_or_setup, history,
+    multicast::{TodoEvent, new_document},
+    workspace,
+};
+
+/// Root keys holding the epoch, alongside the lists
+const EPOCH_KEY: &str = "epoch";
+const BASE_KEY: &str = "compacted_from";
+
+/// How long `todo-mcp compact` waits for the compacted document to be saved
+const SAVE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Which compaction of the document a site is on.
+///
+/// Later compactions order after earlier ones, and two made concurrently from different
+/// histories are ordered by their base so every site agrees which one wins.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Epoch {
+    /// How many times the document has been compacted
+    pub number: u64,
+    /// The heads of the history the last compaction was built from, empty before the first
+    pub base: Vec<[u8; 32]>,
+}
+
+impl Epoch {
+    fn base_hashes(&self) -> Vec<ChangeHash> {
+        self.base.iter().copied().map(ChangeHash).collect()
+    }
+}
+
+/// The epoch the document is on
+pub fn epoch(doc: &AutoCommit) -> Epoch {
+    let number = match doc.get(ROOT, EPOCH_KEY) {
+        Ok(Some((Value::Scalar(value), _))) => value.to_u64().unwrap_or_default(),
+        _ => 0,
+    };
+    let base = match doc.get(ROOT, BASE_KEY) {
+        Ok(Some((Value::Scalar(value), _))) => value
+            .to_bytes()
+            .unwrap_or_default()
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+            .collect(),
+        _ => Vec::new(),
+    };
+    Epoch { number, base }
+}
+
+/// The actor of compaction `number`, the same on every site
+fn actor(number: u64) -> ActorId {
+    let mut bytes = [0; 16];
+    bytes[8..].copy_from_slice(&number.to_be_bytes());
+    ActorId::from(bytes)
+}
+
+/// Whether a change was made by compacting rather than by anyone editing
+pub fn is_compaction(actor_id: &ActorId) -> bool {
+    let bytes = actor_id.to_bytes();
+    bytes.len() == 16 && bytes[..8] == [0; 8] && bytes[8..] != [0; 8]
+}
+
+/// The next epoch after the document's current one, built from everything it holds
+pub fn next(doc: &mut AutoCommit) -> Epoch {
+    Epoch {
+        number: epoch(doc).number + 1,
+        base: doc.get_heads().into_iter().map(|hash| hash.0).collect(),
+    }
+}
+
+/// Builds the document `epoch` compacts `doc` to, which must hold the epoch's base
+pub fn compact(doc: &mut AutoCommit, epoch: &Epoch) -> Result<AutoCommit> {
+    let source = doc.fork_at(&epoch.base_hashes())?;
+
+    let mut compacted = new_document()?.with_actor(actor(epoch.number));
+    copy_map(&source, &ROOT, &mut compacted, &ROOT)?;
+    compacted.put(ROOT, EPOCH_KEY, epoch.number)?;
+    compacted.put(ROOT, BASE_KEY, ScalarValue::Bytes(epoch.base.concat()))?;
+    compacted.commit_with(CommitOptions::default().with_time(0));
+
+    Ok(compacted.with_actor(ActorId::random()))
+}
+
+/// Copies what a map holds into another, in key order so every site makes the same change.
+///
+/// Objects already in `to` are copied into rather than replaced, which keeps the genesis
+/// `lists` list shared with documents that haven't been compacted.
+fn copy_map(
+    from: &AutoCommit,
+    from_obj: &ObjId,
+    to: &mut AutoCommit,
+    to_obj: &ObjId,
+) -> Result<()> {
+    for key in from.keys(from_obj) {
+        if *from_obj == ROOT && (key == EPOCH_KEY || key == BASE_KEY) {
+            continue;
+        }
+        let Some((value, id)) = from.get(from_obj, key.as_str())? else {
+            continue;
+        };
+        match value {
+            Value::Scalar(scalar) => to.put(to_obj, key.as_str(), scalar.into_owned())?,
+            Value::Object(obj_type) => {
+                let existing = match to.get(to_obj, key.as_str())? {
+                    Some((Value::Object(existing_type), existing)) if existing_type == obj_type => {
+                        Some(existing)
+                    }
+                    _ => None,
+                };
+                let target = match existing {
+                    Some(existing) => existing,
+                    None => to.put_object(to_obj, key.as_str(), obj_type)?,
+                };
+                copy_object(from, &id, obj_type, to, &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn copy_object(
+    from: &AutoCommit,
+    from_obj: &ObjId,
+    obj_type: ObjType,
+    to: &mut AutoCommit,
+    to_obj: &ObjId,
+) -> Result<()> {
+    match obj_type {
+        ObjType::Map | ObjType::Table => copy_map(from, from_obj, to, to_obj),
+        ObjType::Text => {
+            to.splice_text(to_obj, 0, 0, &from.text(from_obj)?)?;
+            Ok(())
+        }
+        ObjType::List => {
+            for idx in 0..from.length(from_obj) {
+                let Some((value, id)) = from.get(from_obj, idx)? else {
+                    continue;
+                };
+                match value {
+                    Value::Scalar(scalar) => to.insert(to_obj, idx, scalar.into_owned())?,
+                    Value::Object(obj_type) => {
+                        let target = to.insert_object(to_obj, idx, obj_type)?;
+                        copy_object(from, &id, obj_type, to, &target)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The lists a site had when it moved onto a compaction it couldn't rebuild, to put back on
+/// top of the compaction once that has synced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unsynced {
+    /// As of the part of our history the compaction is known to hold
+    base: TodoState,
+    ours: TodoState,
+}
+
+impl Unsynced {
+    /// Our lists merged onto `theirs`, ours winning wherever we'd changed something since the
+    /// base and theirs everywhere else
+    fn merge_onto(&self, theirs: &TodoState) -> Result<TodoState> {
+        let merged = merge_json(
+            Some(&serde_json::to_value(&self.base)?),
+            &serde_json::to_value(&self.ours)?,
+            &serde_json::to_value(theirs)?,
+        );
+        Ok(serde_json::from_value(merged)?)
+    }
+}
+
+/// Three-way merges one side's value onto the other's.
+///
+/// Objects merge key by key, arrays of objects with an `id` item by item and arrays of strings,
+/// an item's tag names, name by name. So an item or tag we added or edited is kept alongside
+/// those only they have. Anything else we changed is ours.
+fn merge_json(base: Option<&Json>, ours: &Json, theirs: &Json) -> Json {
+    if base == Some(ours) || ours == theirs {
+        return theirs.clone();
+    }
+    match (ours, theirs) {
+        (Json::Object(ours), Json::Object(theirs)) => {
+            let base = base.and_then(Json::as_object);
+            let in_base = |key: &str| base.and_then(|base| base.get(key));
+            let mut merged = serde_json::Map::new();
+            for (key, value) in theirs {
+                match ours.get(key) {
+                    Some(ours) => {
+                        merged.insert(key.clone(), merge_json(in_base(key), ours, value));
+                    }
+                    // we removed it
+                    None if in_base(key).is_some() => {}
+                    None => {
+                        merged.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            for (key, value) in ours {
+                // they removed it unless we changed it since
+                if !theirs.contains_key(key) && in_base(key) != Some(value) {
+                    merged.insert(key.clone(), value.clone());
+                }
+            }
+            Json::Object(merged)
+        }
+        (Json::Array(ours), Json::Array(theirs))
+            if ours
+                .iter()
+                .chain(theirs)
+                .all(|value| value.get("id").is_some()) =>
+        {
+            let find = |values: &[Json], id: &Json| {
+                values
+                    .iter()
+                    .find(|value| value.get("id") == Some(id))
+                    .cloned()
+            };
+            let base = base.and_then(Json::as_array).cloned().unwrap_or_default();
+            let mut merged = Vec::new();
+            for value in theirs {
+                let id = &value["id"];
+                let in_base = find(&base, id);
+                match find(ours, id) {
+                    Some(ours) => merged.push(merge_json(in_base.as_ref(), &ours, value)),
+                    None if in_base.is_some() => {}
+                    None => merged.push(value.clone()),
+                }
+            }
+            for value in ours {
+                let id = &value["id"];
+                if find(theirs, id).is_none() && find(&base, id).as_ref() != Some(value) {
+                    merged.push(value.clone());
+                }
+            }
+            Json::Array(merged)
+        }
+        (Json::Array(ours), Json::Array(theirs))
+            if ours.iter().chain(theirs).all(Json::is_string) =>
+        {
+            let base = base.and_then(Json::as_array).cloned().unwrap_or_default();
+            // theirs, less those we removed, then those we added
+            let mut merged: Vec<Json> = theirs
+                .iter()
+                .filter(|name| ours.contains(name) || !base.contains(name))
+                .cloned()
+                .collect();
+            merged.extend(
+                ours.iter()
+                    .filter(|name| !theirs.contains(name) && !base.contains(name))
+                    .cloned(),
+            );
+            Json::Array(merged)
+        }
+        _ => ours.clone(),
+    }
+}
+
+/// Moves `doc` onto a later compaction, made by another site.
+///
+/// When `doc` holds the compaction's base it's rebuilt locally, and whatever `doc` has that
+/// the compaction doesn't, our own edits and any others we'd seen, goes on top as a new change.
+/// Otherwise `doc` goes back to the genesis for the sync to fill in, and the lists it had are
+/// returned to [`restore`] once it has.
+pub fn migrate(doc: &mut AutoCommit, epoch: &Epoch) -> Result<Option<Unsynced>> {
+    let base = epoch.base_hashes();
+    let held: Vec<ChangeHash> = base
+        .iter()
+        .copied()
+        .filter(|hash| doc.get_change_by_hash(hash).is_some())
+        .collect();
+    if held.len() < base.len() {
+        let ours: TodoState = hydrate(doc)?;
+        // a change we hold of the base holds everything it depends on, the compaction does too
+        let base = if held.is_empty() {
+            TodoState::default()
+        } else {
+            hydrate(&doc.fork_at(&held)?)?
+        };
+        *doc = new_document()?;
+        return Ok(Some(Unsynced { base, ours }));
+    }
+
+    let current: TodoState = hydrate(doc)?;
+    let mut compacted = compact(doc, epoch)?;
+    if hydrate::<_, TodoState>(&compacted)? != current {
+        reconcile(&mut compacted, &current)?;
+        compacted.commit_with(history::commit_options());
+    }
+
+    *doc = compacted;
+    Ok(None)
+}
+
+/// Puts what a site had before [`migrate`] couldn't rebuild `epoch` back onto `doc`, once the
+/// compaction has synced. It goes on as an edit made alongside any others since the
+/// compaction, so they merge as usual. Returns false while `doc` doesn't hold it yet.
+pub fn restore(doc: &mut AutoCommit, epoch: &Epoch, unsynced: &Unsynced) -> Result<bool> {
+    if self::epoch(doc) != *epoch {
+        return Ok(false);
+    }
+    let Some(compaction) = doc
+        .get_changes(&[])
+        .into_iter()
+        .find(|change| change.actor_id() == &actor(epoch.number))
+        .map(|change| change.hash())
+    else {
+        return Ok(false);
+    };
+
+    let mut fork = doc.fork_at(&[compaction])?;
+    let theirs: TodoState = hydrate(&fork)?;
+    let merged = unsynced.merge_onto(&theirs)?;
+    if merged != theirs {
+        reconcile(&mut fork, &merged)?;
+        fork.commit_with(history::commit_options());
+        doc.merge(&mut fork)?;
+    }
+    Ok(true)
+}
+
+/// How big a workspace's save file has grown
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics {
+    /// Size of the save file in bytes, 0 if there isn't one
+    pub save_size: u64,
+    /// Changes in the document's history
+    pub changes: usize,
+    pub epoch: u64,
+    pub lists: usize,
+    pub items: usize,
+    /// Snapshots of the save file kept
+    pub snapshots: usize,
+}
+
+impl Diagnostics {
+    pub fn read(save_path: &Path) -> Result<Self> {
+        let save_size = match std::fs::metadata(save_path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        let mut doc = history::load_path(save_path)?;
+        let state: TodoState = hydrate(&doc).unwrap_or_default();
+
+        Ok(Self {
+            save_size,
+            changes: doc.get_changes(&[]).len(),
+            epoch: epoch(&doc).number,
+            lists: state.lists.len(),
+            items: state.lists.iter().map(|list| list.all_items().len()).sum(),
+            snapshots: backup::list(save_path)?.len(),
+        })
+    }
+}
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes, {} changes, epoch {}, {} lists, {} items, {} snapshots",
+            self.save_size, self.changes, self.epoch, self.lists, self.items, self.snapshots
+        )
+    }
+}
+
+pub fn run_diagnostics(config: &McastConfig) -> Result<()> {
+    let save_path = workspace::save_path(&config.autosave_path, &config.workspace);
+    let diagnostics = Diagnostics::read(&save_path)?;
+
+    println!("Workspace:  {}", config.workspace);
+    println!("Save file:  {}", save_path.display());
+    println!("Size:       {} bytes", diagnostics.save_size);
+    println!("Changes:    {}", diagnostics.changes);
+    println!("Epoch:      {}", diagnostics.epoch);
+    println!(
+        "Lists:      {} lists, {} items",
+        diagnostics.lists, diagnostics.items
+    );
+    println!("Snapshots:  {}", diagnostics.snapshots);
+
+    Ok(())
+}
+
+pub async fn run_compact(config: McastConfig) -> Result<()> {
+    let save_path = workspace::save_path(&config.autosave_path, &config.workspace);
+    let before = Diagnostics::read(&save_path)?;
+    println!("Before: {before}");
+
+    let (tx, mut rx) = connect_or_setup(rand::random(), config);
+
+    // compact what's been loaded, not an empty document
+    loop {
+        match rx.recv().await {
+            Some(TodoEvent::StateUpdate(_)) => break,
+            Some(
+                TodoEvent::ConnectionStatus(_)
+                | TodoEvent::Recovered(_)
+                | TodoEvent::Document { .. },
+            ) => {}
+            None => return Err(anyhow!("sync stopped before the document loaded")),
+        }
+    }
+
+    tx.send(TodoCommand::Compact).await?;
+
+    let (sender, saved) = oneshot_channel();
+    tx.send(TodoCommand::Shutdown { sender }).await?;
+    saved.await.ok();
+
+    // a daemon saves in its own time
+    let after = tokio::time::timeout(SAVE_TIMEOUT, async {
+        loop {
+            let after = Diagnostics::read(&save_path)?;
+            if after.epoch > before.epoch {
+                return Ok::<_, anyhow::Error>(after);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("the compacted document wasn't saved in time"))??;
+    println!("After:  {after}");
+    println!("The previous save file is kept as a snapshot, see `todo-mcp backup list`");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::ops;
+
+    fn add_list(doc: &mut AutoCommit, title: &str) -> Uuid {
+        let list_id = Uuid::new_v4();
+        ops::apply(
+            doc,
+            TodoCommand::AddList {
+                list_id,
+                title: title.into(),
+                metadata: HashMap::from([
+                    ("a".to_string(), "1".to_string()),
+                    ("b".to_string(), "2".to_string()),
+                    ("c".to_string(), "3".to_string()),
+                ]),
+            },
+        )
+        .unwrap();
+        doc.commit();
+        list_id
+    }
+
+    #[test]
+    fn every_site_builds_the_same_compaction() {
+        let mut doc = new_document().unwrap();
+        for title in ["Groceries", "Chores", "Work"] {
+            add_list(&mut doc, title);
+        }
+        let mut peer = doc.fork().with_actor(ActorId::random());
+
+        let epoch = next(&mut doc);
+        let mut compacted = compact(&mut doc, &epoch).unwrap();
+        assert_eq!(super::epoch(&compacted), epoch);
+        assert_eq!(compacted.get_changes(&[]).len(), 2);
+        assert_eq!(
+            hydrate::<_, TodoState>(&compacted).unwrap(),
+            hydrate::<_, TodoState>(&doc).unwrap()
+        );
+
+        // the peer edits concurrently, then hears of the compaction
+        let concurrent = add_list(&mut peer, "Garden");
+        assert_eq!(migrate(&mut peer, &epoch).unwrap(), None);
+        assert_eq!(
+            peer.get_changes(&[])[1].hash(),
+            compacted.get_changes(&[])[1].hash()
+        );
+
+        // so merging shares the compaction, and keeps the peer's edit
+        compacted.merge(&mut peer).unwrap();
+        let state: TodoState = hydrate(&compacted).unwrap();
+        assert_eq!(state.lists.len(), 4);
+        assert!(state.list(concurrent).is_some());
+    }
+
+    #[test]
+    fn migrating_without_the_base_keeps_unsynced_edits() {
+        let mut doc = new_document().unwrap();
+        let groceries = add_list(&mut doc, "Groceries");
+        let mut behind = doc.fork().with_actor(ActorId::random());
+        // one the site that's behind never hears of
+        let chores = add_list(&mut doc, "Chores");
+
+        // it edits while offline
+        let garden = add_list(&mut behind, "Garden");
+        ops::apply(
+            &mut behind,
+            TodoCommand::RenameList {
+                list_id: groceries,
+                title: "Food".into(),
+            },
+        )
+        .unwrap();
+        behind.commit();
+
+        let epoch = next(&mut doc);
+        let mut compacted = compact(&mut doc, &epoch).unwrap();
+        let work = add_list(&mut compacted, "Work");
+
+        let unsynced = migrate(&mut behind, &epoch).unwrap().unwrap();
+        assert!(!restore(&mut behind, &epoch, &unsynced).unwrap());
+
+        behind.merge(&mut compacted).unwrap();
+        assert_eq!(super::epoch(&behind), epoch);
+        assert!(restore(&mut behind, &epoch, &unsynced).unwrap());
+
+        // and it all merges with what's been done since the compaction
+        compacted.merge(&mut behind).unwrap();
+        for doc in [&behind, &compacted] {
+            let state: TodoState = hydrate(doc).unwrap();
+            assert_eq!(state.lists.len(), 4);
+            assert_eq!(state.list(groceries).unwrap().title, "Food");
+            for list_id in [chores, garden, work] {
+                assert!(state.list(list_id).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn unsynced_tags_merge_by_name() {
+        let tags = |names: &[&str]| serde_json::json!(names);
+        let merged = merge_json(
+            Some(&tags(&["bug", "ui"])),
+            &tags(&["bug", "urgent"]),
+            &tags(&["bug", "ui", "login"]),
+        );
+        assert_eq!(merged, tags(&["bug", "login", "urgent"]));
+    }
+}
@@ -0,0 +1,138 @@
+use std::{convert::Infallible, fmt, path::Path, str::FromStr};
+
+use anyhow::{Context, anyhow};
+use ring::{
+    aead::{self, Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    hkdf::{self, HKDF_SHA256},
+};
+
+/// What encryption adds to every datagram, the nonce up front and the tag at the end
+pub const OVERHEAD: usize = NONCE_LEN + aead::MAX_TAG_LEN;
+
+const KEY_SALT: &[u8] = b"todo-mcp";
+const KEY_INFO: &[u8] = b"multicast sync v1";
+
+/// A pre-shared key as given on the command line or environment, kept out of `Debug` output
+#[derive(Clone, PartialEq, Eq)]
+pub struct Psk(String);
+
+impl FromStr for Psk {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Psk(s.to_string()))
+    }
+}
+
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Psk(..)")
+    }
+}
+
+impl Psk {
+    /// Reads the key from a file, surrounding whitespace is ignored so a trailing newline doesn't matter
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read key file {}", path.display()))?;
+
+        Ok(Psk(contents.trim().to_string()))
+    }
+}
+
+/// Seals and opens datagrams with ChaCha20-Poly1305, using a key derived from the [`Psk`]
+pub struct Cipher {
+    key: LessSafeKey,
+}
+
+impl Cipher {
+    pub fn new(psk: &Psk) -> anyhow::Result<Self> {
+        if psk.0.is_empty() {
+            return Err(anyhow!("pre-shared key is empty"));
+        }
+
+        let prk = hkdf::Salt::new(HKDF_SHA256, KEY_SALT).extract(psk.0.as_bytes());
+        let okm = prk
+            .expand(&[KEY_INFO], &CHACHA20_POLY1305)
+            .map_err(|_| anyhow!("could not derive key"))?;
+
+        Ok(Self {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+        })
+    }
+
+    /// Encrypts the datagram, returning the nonce, ciphertext and tag to put on the wire
+    pub fn seal(&self, datagram: &[u8]) -> Vec<u8> {
+        // Random nonces are fine at the rate we send, there's no shared counter between sites to use instead
+        let nonce: [u8; NONCE_LEN] = rand::random();
+
+        let mut in_out = datagram.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .expect("datagrams are well under the ChaCha20-Poly1305 size limit");
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + in_out.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.append(&mut in_out);
+
+        sealed
+    }
+
+    /// Decrypts a datagram in place, returning `None` if it wasn't sealed with our key or was tampered with
+    pub fn open<'a>(&self, datagram: &'a mut [u8]) -> Option<&'a [u8]> {
+        if datagram.len() < OVERHEAD {
+            return None;
+        }
+
+        let (nonce, in_out) = datagram.split_at_mut(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+
+        self.key
+            .open_in_place(nonce, Aad::empty(), in_out)
+            .ok()
+            .map(|plain| &*plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed(psk: &str) -> Cipher {
+        Cipher::new(&psk.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sealed_datagrams_open_with_the_same_key() {
+        let cipher = keyed("correct horse");
+        let mut sealed = cipher.seal(b"a datagram");
+        assert_eq!(sealed.len(), b"a datagram".len() + OVERHEAD);
+        assert_eq!(cipher.open(&mut sealed), Some(&b"a datagram"[..]));
+
+        // the nonce is fresh each time
+        assert_ne!(cipher.seal(b"a datagram"), cipher.seal(b"a datagram"));
+        assert!(Cipher::new(&"".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn tampered_short_and_foreign_datagrams_dont_open() {
+        let cipher = keyed("correct horse");
+        let sealed = cipher.seal(b"a datagram");
+
+        let mut flipped = sealed.clone();
+        flipped[NONCE_LEN + 2] ^= 0x01;
+        assert_eq!(cipher.open(&mut flipped), None);
+
+        let mut truncated = sealed[..NONCE_LEN - 1].to_vec();
+        assert_eq!(cipher.open(&mut truncated), None);
+        let mut no_tag = sealed[..sealed.len() - 1].to_vec();
+        assert_eq!(cipher.open(&mut no_tag), None);
+
+        let mut foreign = keyed("battery staple").seal(b"a datagram");
+        assert_eq!(cipher.open(&mut foreign), None);
+    }
+}
@@ -0,0 +1,460 @@
+//! A headless site that owns a workspace's document and network, for everything else to share.
+//!
+//! The daemon listens on a Unix socket next to the workspace's save file. Each line on the
+//! socket is a JSON [`TodoCommand`] from a client or [`TodoEvent`] from the daemon. Clients
+//! shut down by disconnecting, the daemon keeps syncing and saving until it's stopped.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    signal::unix::{SignalKind, signal},
+    sync::{
+        broadcast::{
+            Receiver as BroadcastReceiver, channel as broadcast_channel, error::RecvError,
+        },
+        mpsc::{Receiver as TokioReceiver, Sender as TokioSender, channel as tokio_channel},
+        oneshot::channel as oneshot_channel,
+    },
+};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use tracing::*;
+
+use crate::backends::{
+    multicast::{TodoCommand, TodoEvent, TodoState, setup},
+    proto::McastConfig,
+    workspace,
+};
+
+/// Longest line either side accepts, a whole state update fits comfortably
+const MAX_LINE: usize = 64 * 1024 * 1024;
+
+/// How long a client waits for the daemon to come back before running the site itself
+const RECONNECT_WAIT: Duration = Duration::from_secs(2);
+
+/// Where the daemon for the configured workspace listens, next to its save file
+pub fn socket_path(config: &McastConfig) -> PathBuf {
+    workspace::save_path(&config.autosave_path, &config.workspace).with_extension("sock")
+}
+
+/// The last state and status the site reported, sent to clients as soon as they connect
+#[derive(Default)]
+struct Latest {
+    state: Option<TodoState>,
+    status: Option<String>,
+}
+
+/// Runs the site for the configured workspace until interrupted or terminated
+#[instrument(skip_all, fields(workspace = %config.workspace))]
+pub async fn run_daemon(config: McastConfig) -> Result<()> {
+    let path = socket_path(&config);
+
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(anyhow!("a daemon is already running on {}", path.display()));
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create {}", parent.display()))?;
+    }
+
+    // Left behind by a daemon that didn't get to clean up
+    match std::fs::remove_file(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    info!("Daemon listening on {}", path.display());
+
+    let (commands, mut events) = setup(rand::random(), config);
+    let (events_tx, _) = broadcast_channel(128);
+    let latest = Arc::new(Mutex::new(Latest::default()));
+    // numbered from 1, commands sent to a site directly come from client 0
+    let mut clients = 0;
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                clients += 1;
+                let client = clients;
+                let (commands, events, latest) = (commands.clone(), events_tx.subscribe(), latest.clone());
+                tokio::spawn(async move {
+                    match serve_client(stream, client, &commands, events, latest).await {
+                        Ok(()) => debug!("Daemon client {client} disconnected"),
+                        Err(err) => debug!("Daemon client {client} dropped: {err:?}"),
+                    }
+                    commands.send(TodoCommand::ClientGone { client }).await.ok();
+                });
+            }
+            event = events.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                {
+                    let mut latest = latest.lock().unwrap();
+                    match &event {
+                        TodoEvent::StateUpdate(state) => latest.state = Some(state.clone()),
+                        TodoEvent::ConnectionStatus(status) => latest.status = Some(status.clone()),
+                        // a one-off as the daemon starts, not worth repeating to later clients
+                        TodoEvent::Recovered(_) => {}
+                        // an answer for one client, see `serve_client`
+                        TodoEvent::Document { .. } => {}
+                    }
+                }
+                // nobody connected is fine, they get the latest state when they do
+                events_tx.send(event).ok();
+            }
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+
+    info!("Daemon shutting down");
+    let (sender, saved) = oneshot_channel();
+    if commands
+        .send(TodoCommand::Shutdown { sender })
+        .await
+        .is_ok()
+    {
+        saved.await.ok();
+    }
+    std::fs::remove_file(&path).ok();
+
+    Ok(())
+}
+
+/// Passes commands from one client to the site, and every event back, until it disconnects
+async fn serve_client(
+    stream: UnixStream,
+    client: u64,
+    commands: &TokioSender<TodoCommand>,
+    mut events: BroadcastReceiver<TodoEvent>,
+    latest: Arc<Mutex<Latest>>,
+) -> Result<()> {
+    let (read, write) = stream.into_split();
+    let mut lines = FramedRead::new(read, LinesCodec::new_with_max_length(MAX_LINE));
+    let mut writer = FramedWrite::new(write, LinesCodec::new());
+
+    let catch_up = |latest: &Mutex<Latest>| {
+        let latest = latest.lock().unwrap();
+        let status = latest.status.clone().map(TodoEvent::ConnectionStatus);
+        let state = latest.state.clone().map(TodoEvent::StateUpdate);
+        status.into_iter().chain(state).collect::<Vec<_>>()
+    };
+
+    for event in catch_up(&latest) {
+        writer.send(serde_json::to_string(&event)?).await?;
+    }
+
+    loop {
+        tokio::select! {
+            line = lines.next() => {
+                let Some(line) = line else {
+                    return Ok(());
+                };
+                match serde_json::from_str(&line?) {
+                    Ok(command) => {
+                        let command = Box::new(command);
+                        commands.send(TodoCommand::FromClient { client, command }).await?
+                    }
+                    Err(err) => warn!("Ignoring command the daemon couldn't parse: {err}"),
+                }
+            }
+            event = events.recv() => {
+                match event {
+                    // only the client that asked for the document gets it
+                    Ok(TodoEvent::Document { client: to, .. }) if to != client => {}
+                    Ok(event) => writer.send(serde_json::to_string(&event)?).await?,
+                    Err(RecvError::Lagged(skipped)) => {
+                        // the latest state covers whatever was skipped
+                        debug!("Daemon client lagged, skipped {skipped} events");
+                        for event in catch_up(&latest) {
+                            writer.send(serde_json::to_string(&event)?).await?;
+                        }
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the workspace's daemon, `None` if there isn't one running.
+///
+/// Should the daemon go away, the client moves on to the next one to start, or runs the site in
+/// this process if none does, so the channels keep working either way.
+pub fn connect(
+    config: &McastConfig,
+) -> Option<(TokioSender<TodoCommand>, TokioReceiver<TodoEvent>)> {
+    let stream = open(config)?;
+
+    let (command_tx, command_rx) = tokio_channel(128);
+    let (event_tx, event_rx) = tokio_channel(128);
+    tokio::spawn(run_client(stream, config.clone(), command_rx, event_tx));
+
+    Some((command_tx, event_rx))
+}
+
+/// A connection to the workspace's daemon, `None` if nothing is listening
+fn open(config: &McastConfig) -> Option<UnixStream> {
+    let path = socket_path(config);
+    let stream = std::os::unix::net::UnixStream::connect(&path).ok()?;
+
+    match stream
+        .set_nonblocking(true)
+        .and_then(|()| UnixStream::from_std(stream))
+    {
+        Ok(stream) => {
+            info!("Connected to daemon on {}", path.display());
+            Some(stream)
+        }
+        Err(err) => {
+            warn!("Could not use daemon socket {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// Passes commands to the daemon and events back, reconnecting if it goes away and running
+/// the site here if it doesn't come back
+async fn run_client(
+    mut stream: UnixStream,
+    config: McastConfig,
+    mut commands: TokioReceiver<TodoCommand>,
+    events: TokioSender<TodoEvent>,
+) {
+    loop {
+        match serve_daemon(stream, &mut commands, &events).await {
+            Ok(()) => return,
+            Err(err) => {
+                error!("Lost connection to daemon: {err:?}");
+                events
+                    .send(TodoEvent::ConnectionStatus(
+                        "Lost connection to daemon".into(),
+                    ))
+                    .await
+                    .ok();
+            }
+        }
+
+        // a daemon being restarted takes a moment to listen again
+        let reconnected = tokio::time::timeout(RECONNECT_WAIT, async {
+            loop {
+                if let Some(stream) = open(&config) {
+                    return stream;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        match reconnected.await {
+            Ok(reconnected) => stream = reconnected,
+            Err(_) => break,
+        }
+    }
+
+    warn!("No daemon came back, running the site in this process");
+    let (site_commands, mut site_events) = setup(rand::random(), config);
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    return;
+                };
+                if site_commands.send(command).await.is_err() {
+                    return;
+                }
+            }
+            event = site_events.recv() => {
+                let Some(event) = event else {
+                    return;
+                };
+                if events.send(event).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Passes commands to one daemon and events back, until either side is done or it goes away
+async fn serve_daemon(
+    stream: UnixStream,
+    commands: &mut TokioReceiver<TodoCommand>,
+    events: &TokioSender<TodoEvent>,
+) -> Result<()> {
+    let (read, write) = stream.into_split();
+    let mut lines = FramedRead::new(read, LinesCodec::new_with_max_length(MAX_LINE));
+    let mut writer = FramedWrite::new(write, LinesCodec::new());
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                match command {
+                    Some(TodoCommand::Shutdown { sender }) => {
+                        // the daemon saves on its own, so we're done once our commands are out,
+                        // or it's already gone and there's nothing left to send
+                        SinkExt::<String>::close(&mut writer).await.ok();
+                        sender.send(()).ok();
+                        return Ok(());
+                    }
+                    Some(command) => writer.send(serde_json::to_string(&command)?).await?,
+                    None => return Ok(()),
+                }
+            }
+            line = lines.next() => {
+                let Some(line) = line else {
+                    return Err(anyhow!("daemon closed the connection"));
+                };
+                // nobody is listening any more
+                if events.send(serde_json::from_str(&line?)?).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use uuid::Uuid;
+
+    use autosurgeon::hydrate;
+
+    use super::*;
+    use crate::backends::{
+        history,
+        test_util::{free_port, wait_for},
+    };
+
+    #[tokio::test]
+    async fn clients_share_the_daemons_site() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-daemon-{}", Uuid::new_v4()));
+        let config = McastConfig {
+            autosave_path: dir.join("automerge.save"),
+            port: free_port(),
+            ..Default::default()
+        };
+
+        assert!(connect(&config).is_none());
+
+        let daemon = tokio::spawn(run_daemon(config.clone()));
+        let (tx_a, _events_a) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(client) = connect(&config) {
+                    return client;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("daemon did not start");
+        let (tx_b, mut events_b) = connect(&config).unwrap();
+
+        let list_id = Uuid::new_v4();
+        tx_a.send(TodoCommand::AddList {
+            list_id,
+            title: "Shared".into(),
+            metadata: HashMap::new(),
+        })
+        .await
+        .unwrap();
+
+        wait_for(&mut events_b, |state| state.list(list_id).is_some()).await;
+
+        // each client only undoes its own edits
+        let other_id = Uuid::new_v4();
+        tx_b.send(TodoCommand::Undo).await.unwrap();
+        tx_b.send(TodoCommand::AddList {
+            list_id: other_id,
+            title: "Mine".into(),
+            metadata: HashMap::new(),
+        })
+        .await
+        .unwrap();
+        let state = wait_for(&mut events_b, |state| state.list(other_id).is_some()).await;
+        assert!(state.list(list_id).is_some());
+        tx_a.send(TodoCommand::Undo).await.unwrap();
+        let state = wait_for(&mut events_b, |state| state.list(list_id).is_none()).await;
+        assert!(state.list(other_id).is_some());
+
+        // history reads the daemon's document as it is, saved or not
+        let doc = history::fetch(&tx_b, &mut events_b).await.unwrap();
+        assert_eq!(hydrate::<_, TodoState>(&doc).unwrap(), state);
+
+        // a client shutting down only disconnects it
+        let (sender, done) = oneshot_channel();
+        tx_a.send(TodoCommand::Shutdown { sender }).await.unwrap();
+        done.await.unwrap();
+        assert!(!daemon.is_finished());
+
+        let (_tx_c, mut events_c) = connect(&config).unwrap();
+        wait_for(&mut events_c, |state| state.list(other_id).is_some()).await;
+
+        daemon.abort();
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn clients_outlive_the_daemon() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-daemon-{}", Uuid::new_v4()));
+        let config = McastConfig {
+            autosave_path: dir.join("automerge.save"),
+            port: free_port(),
+            ..Default::default()
+        };
+
+        let daemon = tokio::spawn(run_daemon(config.clone()));
+        let (tx, mut events) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(client) = connect(&config) {
+                    return client;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("daemon did not start");
+
+        daemon.abort();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(TodoEvent::ConnectionStatus(status)) = events.recv().await
+                    && status == "Lost connection to daemon"
+                {
+                    return;
+                }
+            }
+        })
+        .await
+        .expect("the client never noticed the daemon going");
+
+        // nothing is listening any more, so the client runs the site itself
+        let list_id = Uuid::new_v4();
+        tx.send(TodoCommand::AddList {
+            list_id,
+            title: "Still here".into(),
+            metadata: HashMap::new(),
+        })
+        .await
+        .unwrap();
+        wait_for(&mut events, |state| state.list(list_id).is_some()).await;
+
+        let (sender, saved) = oneshot_channel();
+        tx.send(TodoCommand::Shutdown { sender }).await.unwrap();
+        saved.await.unwrap();
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
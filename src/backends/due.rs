@@ -0,0 +1,237 @@
+//! Due dates on items: how they're stored, parsed from what people type, and which are coming up.
+//!
+//! A due date is stored on its item as an Automerge timestamp, so every site agrees on the
+//! instant regardless of its time zone. Dates given without a time are due at the end of that
+//! day, local time.
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use uuid::Uuid;
+
+use crate::backends::{TodoItem, TodoState};
+
+/// How far ahead `get_due_todos` and the upcoming views look unless told otherwise
+pub const DEFAULT_UPCOMING_DAYS: u32 = 7;
+
+/// Stores an optional time, such as a due date, as a timestamp in milliseconds, a missing or
+/// null value being none
+pub mod crdt {
+    use autosurgeon::{Hydrate, HydrateError, Prop, ReadDoc, Reconciler};
+    use chrono::{DateTime, Utc};
+
+    struct Millis(i64);
+
+    impl Hydrate for Millis {
+        fn hydrate_timestamp(t: i64) -> Result<Self, HydrateError> {
+            Ok(Self(t))
+        }
+
+        fn hydrate_int(i: i64) -> Result<Self, HydrateError> {
+            Ok(Self(i))
+        }
+    }
+
+    pub fn hydrate<D: ReadDoc>(
+        doc: &D,
+        obj: &automerge::ObjId,
+        prop: Prop<'_>,
+    ) -> Result<Option<DateTime<Utc>>, HydrateError> {
+        // items from before due dates have no key at all
+        if doc.get(obj, &prop)?.is_none() {
+            return Ok(None);
+        }
+        let millis: Option<Millis> = Hydrate::hydrate(doc, obj, prop)?;
+        Ok(millis.and_then(|Millis(millis)| DateTime::from_timestamp_millis(millis)))
+    }
+
+    pub fn reconcile<R: Reconciler>(
+        due: &Option<DateTime<Utc>>,
+        mut reconciler: R,
+    ) -> Result<(), R::Error> {
+        match due {
+            Some(due) => reconciler.timestamp(due.timestamp_millis()),
+            None => reconciler.none(),
+        }
+    }
+}
+
+/// Reads a due date as people write it, relative to `now` where it needs to be.
+///
+/// Accepts RFC 3339, `YYYY-MM-DD HH:MM` (or with a `T`, as browsers send it), a bare
+/// `YYYY-MM-DD`, `today` and `tomorrow`. Times without a zone are local.
+pub fn parse(input: &str, now: DateTime<Local>) -> Result<DateTime<Utc>> {
+    let input = input.trim();
+
+    if let Ok(due) = DateTime::parse_from_rfc3339(input) {
+        return Ok(due.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"] {
+        if let Ok(due) = NaiveDateTime::parse_from_str(input, format) {
+            return local(due);
+        }
+    }
+
+    let date = match input.to_lowercase().as_str() {
+        "today" => now.date_naive(),
+        "tomorrow" => now.date_naive() + TimeDelta::days(1),
+        _ => NaiveDate::parse_from_str(input, "%Y-%m-%d").map_err(|_| {
+            anyhow!("can't read \"{input}\" as a due date, try YYYY-MM-DD or YYYY-MM-DD HH:MM")
+        })?,
+    };
+    local(date.and_time(end_of_day()))
+}
+
+/// Reads an optional due date, an empty one meaning none
+pub fn parse_optional(input: &str, now: DateTime<Local>) -> Result<Option<DateTime<Utc>>> {
+    if input.trim().is_empty() {
+        return Ok(None);
+    }
+    parse(input, now).map(Some)
+}
+
+/// The due date in local time, leaving out the time for dates due at the end of the day
+pub fn format(due: DateTime<Utc>) -> String {
+    let due = due.with_timezone(&Local);
+    if due.time() == end_of_day() {
+        due.format("%Y-%m-%d").to_string()
+    } else {
+        due.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
+
+/// Whether `item` is still open past its due date
+pub fn is_overdue(item: &TodoItem, now: DateTime<Utc>) -> bool {
+    !item.completed && item.due.is_some_and(|due| due < now)
+}
+
+/// Whether something due at `due` is within `days` of `now`, or already past it if asked for
+pub fn is_upcoming(
+    due: DateTime<Utc>,
+    now: DateTime<Utc>,
+    days: u32,
+    include_overdue: bool,
+) -> bool {
+    due <= now + TimeDelta::days(days.into()) && (include_overdue || due >= now)
+}
+
+/// An open item with a due date, and the list it's on
+#[derive(Debug, Clone, PartialEq)]
+pub struct DueItem {
+    pub list_id: Uuid,
+    pub list_title: String,
+    pub item: TodoItem,
+}
+
+/// Open items and subtasks due within `days` of `now` across every list, soonest first, along
+/// with the overdue ones if asked for
+pub fn upcoming(
+    state: &TodoState,
+    now: DateTime<Utc>,
+    days: u32,
+    include_overdue: bool,
+) -> Vec<DueItem> {
+    let mut due: Vec<DueItem> = state
+        .lists
+        .iter()
+        .flat_map(|list| {
+            list.all_items()
+                .into_iter()
+                .map(|(_, item)| item)
+                .filter(|item| !item.completed)
+                .filter(|item| {
+                    item.due
+                        .is_some_and(|due| is_upcoming(due, now, days, include_overdue))
+                })
+                .map(|item| DueItem {
+                    list_id: list.id,
+                    list_title: list.title.clone(),
+                    item: item.clone(),
+                })
+        })
+        .collect();
+    due.sort_by_key(|due| due.item.due);
+    due
+}
+
+fn end_of_day() -> NaiveTime {
+    NaiveTime::from_hms_opt(23, 59, 59).expect("valid time")
+}
+
+fn local(due: NaiveDateTime) -> Result<DateTime<Utc>> {
+    Local
+        .from_local_datetime(&due)
+        .earliest()
+        .map(|due| due.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("{due} doesn't exist in the local time zone"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automerge::AutoCommit;
+    use autosurgeon::{hydrate, reconcile};
+
+    use super::*;
+    use crate::backends::TodoList;
+
+    #[test]
+    fn parses_what_people_type() {
+        let now = Local.with_ymd_and_hms(2026, 3, 9, 10, 0, 0).unwrap();
+        let day = |due: DateTime<Utc>| due.with_timezone(&Local).format("%Y-%m-%d").to_string();
+
+        assert_eq!(day(parse("today", now).unwrap()), "2026-03-09");
+        assert_eq!(day(parse("Tomorrow", now).unwrap()), "2026-03-10");
+        assert_eq!(format(parse("2026-04-01", now).unwrap()), "2026-04-01");
+        assert_eq!(
+            format(parse("2026-04-01 09:30", now).unwrap()),
+            "2026-04-01 09:30"
+        );
+        assert_eq!(
+            parse("2026-04-01T09:30", now).unwrap(),
+            parse("2026-04-01 09:30", now).unwrap()
+        );
+        assert_eq!(
+            parse("2026-04-01T09:30:00Z", now).unwrap(),
+            Utc.with_ymd_and_hms(2026, 4, 1, 9, 30, 0).unwrap()
+        );
+        assert!(parse("next week", now).is_err());
+        assert_eq!(parse_optional(" ", now).unwrap(), None);
+    }
+
+    #[test]
+    fn due_dates_round_trip_and_sort() {
+        let now = Utc.with_ymd_and_hms(2026, 3, 9, 12, 0, 0).unwrap();
+        let item = |text: &str, due: Option<DateTime<Utc>>| TodoItem {
+            due,
+            ..TodoItem::new(text, HashMap::new())
+        };
+        let state = TodoState {
+            lists: vec![TodoList {
+                items: vec![
+                    item("next month", Some(now + TimeDelta::days(30))),
+                    item("friday", Some(now + TimeDelta::days(4))),
+                    item("yesterday", Some(now - TimeDelta::days(1))),
+                    item("whenever", None),
+                ],
+                ..TodoList::new("chores")
+            }],
+            ..Default::default()
+        };
+
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &state).unwrap();
+        let hydrated: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(hydrated, state);
+
+        let texts = |due: Vec<DueItem>| -> Vec<String> {
+            due.into_iter().map(|due| due.item.text).collect()
+        };
+        assert_eq!(
+            texts(upcoming(&hydrated, now, 7, true)),
+            ["yesterday", "friday"]
+        );
+        assert_eq!(texts(upcoming(&hydrated, now, 7, false)), ["friday"]);
+        assert!(is_overdue(&hydrated.lists[0].items[2], now));
+    }
+}
@@ -0,0 +1,420 @@
+//! The change history of a workspace's document, and its state at any point in it.
+//!
+//! History is read from the running site's document, asked for through its command channel so
+//! edits it hasn't saved yet are there too. `todo-mcp history` reads the save file unless a
+//! daemon is running. Each change is summarised by comparing the lists just before it with just
+//! after, and only the changes asked for are.
+
+use std::{path::Path, sync::LazyLock};
+
+use anyhow::{Result, anyhow};
+use automerge::{AutoCommit, Change, ChangeHash, transaction::CommitOptions};
+use autosurgeon::hydrate;
+use chrono::{DateTime, Local, Utc};
+use tokio::sync::mpsc::{Receiver as TokioReceiver, Sender as TokioSender};
+
+use crate::backends::{
+    McastConfig, TodoCommand, TodoItem, TodoList, TodoState, compaction, daemon, due,
+    multicast::TodoEvent, storage, workspace,
+};
+
+/// Changes `todo-mcp history` shows unless told otherwise
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/// Who made our changes, recorded as the message of each one
+static AUTHOR: LazyLock<String> = LazyLock::new(|| {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".into());
+    format!("{user}@{}", gethostname::gethostname().to_string_lossy())
+});
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct HistoryArgs {
+    /// How many of the most recent changes to show
+    #[arg(long, default_value_t = DEFAULT_HISTORY_LIMIT)]
+    pub limit: usize,
+    /// Show the lists as they were right after this change, by hash or unambiguous prefix
+    #[arg(long)]
+    pub at: Option<String>,
+}
+
+/// One change to the document
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub hash: ChangeHash,
+    /// The actor that made the change, every site and process has its own
+    pub actor: String,
+    /// `user@host` of whoever made the change, if their site recorded it
+    pub author: Option<String>,
+    /// When the change was made, if its site recorded it
+    pub time: Option<DateTime<Utc>>,
+    /// What the change did, e.g. `completed "milk" in "Groceries"`
+    pub summary: String,
+}
+
+/// Options to commit a local change with, so history can say who made it and when
+pub fn commit_options() -> CommitOptions {
+    CommitOptions::default()
+        .with_message(AUTHOR.as_str())
+        .with_time(Utc::now().timestamp())
+}
+
+/// The workspace's document as last saved, empty if it hasn't been
+pub fn load(config: &McastConfig) -> Result<AutoCommit> {
+    load_path(&workspace::save_path(
+        &config.autosave_path,
+        &config.workspace,
+    ))
+}
+
+/// The document in the save file at `path`, empty if there isn't one
+pub fn load_path(path: &Path) -> Result<AutoCommit> {
+    match storage::load(path)? {
+        Some(data) => Ok(AutoCommit::load(&data)?),
+        None => Ok(AutoCommit::new()),
+    }
+}
+
+/// Asks the site behind `tx` for its document, waiting among its `events` for the answer
+pub async fn fetch(
+    tx: &TokioSender<TodoCommand>,
+    events: &mut TokioReceiver<TodoEvent>,
+) -> Result<AutoCommit> {
+    tx.send(TodoCommand::GetDocument).await?;
+    loop {
+        match events.recv().await {
+            Some(TodoEvent::Document { data, .. }) => return Ok(AutoCommit::load(&data)?),
+            Some(_) => {}
+            None => return Err(anyhow!("sync stopped before sending its document")),
+        }
+    }
+}
+
+/// Every change in the document, newest first
+pub fn changes(doc: &mut AutoCommit) -> Vec<Change> {
+    let mut changes = doc.get_changes(&[]);
+    changes.reverse();
+    changes
+}
+
+/// Every change in the document, newest first, each summarised only once it's taken
+pub fn entries(doc: &mut AutoCommit) -> impl Iterator<Item = Result<HistoryEntry>> + '_ {
+    changes(doc)
+        .into_iter()
+        .map(move |change| entry(doc, &change))
+}
+
+/// Summarises a change by comparing the lists just before it with just after
+pub fn entry(doc: &mut AutoCommit, change: &Change) -> Result<HistoryEntry> {
+    let before = state_at(doc, change.deps())?;
+    let after = state_at(doc, &[change.hash()])?;
+    let summary = summarize(&before, &after);
+
+    let actor = change.actor_id().to_hex_string();
+    Ok(HistoryEntry {
+        hash: change.hash(),
+        actor: actor[..actor.len().min(8)].to_string(),
+        author: change.message().cloned(),
+        time: match change.timestamp() {
+            0 => None,
+            secs => DateTime::from_timestamp(secs, 0),
+        },
+        summary: if compaction::is_compaction(change.actor_id()) {
+            "compacted the history".into()
+        } else if summary.is_empty() {
+            "no visible changes".into()
+        } else {
+            summary.join(", ")
+        },
+    })
+}
+
+/// The change a hash, or an unambiguous prefix of one, refers to
+pub fn resolve(doc: &mut AutoCommit, hash: &str) -> Result<ChangeHash> {
+    let hash = hash.trim().to_lowercase();
+    if hash.is_empty() {
+        return Err(anyhow!("no change hash given"));
+    }
+    let mut matches = doc
+        .get_changes(&[])
+        .into_iter()
+        .map(|change| change.hash())
+        .filter(|candidate| candidate.to_string().starts_with(&hash));
+
+    match (matches.next(), matches.next()) {
+        (Some(found), None) => Ok(found),
+        (Some(_), _) => Err(anyhow!("{hash} matches more than one change")),
+        (None, _) => Err(anyhow!("no change matches {hash}")),
+    }
+}
+
+/// The lists as they were at `heads`, empty before the first change created them
+pub fn state_at(doc: &mut AutoCommit, heads: &[ChangeHash]) -> Result<TodoState> {
+    Ok(hydrate(&doc.fork_at(heads)?).unwrap_or_default())
+}
+
+/// Describes what changed between two states, in the order a reader would scan for it
+fn summarize(before: &TodoState, after: &TodoState) -> Vec<String> {
+    let mut summary = Vec::new();
+
+    for list in &after.lists {
+        let Some(old) = before.list(list.id) else {
+            summary.push(format!("added list \"{}\"", list.title));
+            continue;
+        };
+        if old.title != list.title {
+            summary.push(format!(
+                "renamed list \"{}\" to \"{}\"",
+                old.title, list.title
+            ));
+        }
+        if old.sort != list.sort {
+            summary.push(format!(
+                "sorted \"{}\" by {}",
+                list.title,
+                list.sort.label()
+            ));
+        }
+        summarize_items(old, list, &mut summary);
+    }
+
+    for list in &before.lists {
+        if after.list(list.id).is_none() {
+            summary.push(format!("removed list \"{}\"", list.title));
+        }
+    }
+
+    // tags registered by being used aren't worth a mention of their own
+    for (name, tag) in &after.tags {
+        if before.tags.get(name).is_some_and(|old| old != tag) {
+            summary.push(format!("coloured #{name} {}", tag.label()));
+        }
+    }
+
+    summary
+}
+
+fn summarize_items(before: &TodoList, after: &TodoList, summary: &mut Vec<String>) {
+    let title = &after.title;
+
+    for (parent, item) in with_parents(&after.items, None) {
+        let Some(old) = before.item(item.id) else {
+            match parent {
+                Some(parent) => summary.push(format!(
+                    "added \"{}\" under \"{}\" in \"{title}\"",
+                    item.text, parent.text
+                )),
+                None => summary.push(format!("added \"{}\" to \"{title}\"", item.text)),
+            }
+            continue;
+        };
+        if old.text != item.text {
+            summary.push(format!(
+                "renamed \"{}\" to \"{}\" in \"{title}\"",
+                old.text, item.text
+            ));
+        }
+        if old.completed != item.completed {
+            let verb = if item.completed {
+                "completed"
+            } else {
+                "reopened"
+            };
+            summary.push(format!("{verb} \"{}\" in \"{title}\"", item.text));
+        }
+        if old.priority != item.priority {
+            summary.push(format!(
+                "set the priority of \"{}\" in \"{title}\" to {}",
+                item.text,
+                item.priority.label()
+            ));
+        }
+        if old.tags != item.tags {
+            let change = if item.tags.is_empty() {
+                format!("removed the tags of \"{}\" in \"{title}\"", item.text)
+            } else {
+                let tags: Vec<String> = item.tags.iter().map(|tag| format!("#{tag}")).collect();
+                format!("tagged \"{}\" in \"{title}\" {}", item.text, tags.join(" "))
+            };
+            summary.push(change);
+        }
+        if old.notes != item.notes {
+            let verb = if item.notes.is_empty() {
+                "removed"
+            } else {
+                "edited"
+            };
+            summary.push(format!(
+                "{verb} the notes of \"{}\" in \"{title}\"",
+                item.text
+            ));
+        }
+        if old.due != item.due {
+            let change = match item.due {
+                Some(at) => format!(
+                    "made \"{}\" in \"{title}\" due {}",
+                    item.text,
+                    due::format(at)
+                ),
+                None => format!("cleared the due date of \"{}\" in \"{title}\"", item.text),
+            };
+            summary.push(change);
+        }
+    }
+
+    for (_, item) in before.all_items() {
+        if after.item(item.id).is_none() {
+            summary.push(format!("removed \"{}\" from \"{title}\"", item.text));
+        }
+    }
+}
+
+/// Every item in `items` and their subtasks, with the item each is a subtask of
+fn with_parents<'a>(
+    items: &'a [TodoItem],
+    parent: Option<&'a TodoItem>,
+) -> Vec<(Option<&'a TodoItem>, &'a TodoItem)> {
+    items
+        .iter()
+        .flat_map(|item| {
+            std::iter::once((parent, item)).chain(with_parents(&item.children, Some(item)))
+        })
+        .collect()
+}
+
+/// One line for `entry`, as `todo-mcp history` and the TUI show it
+pub fn format_entry(entry: &HistoryEntry) -> String {
+    let time = entry.time.map_or_else(
+        || "unknown time".to_string(),
+        |time| {
+            time.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        },
+    );
+    let hash = entry.hash.to_string();
+    format!(
+        "{}  {time}  {} ({})  {}",
+        &hash[..8],
+        entry.author.as_deref().unwrap_or("unknown"),
+        entry.actor,
+        entry.summary
+    )
+}
+
+pub async fn run_history(args: HistoryArgs, config: McastConfig) -> Result<()> {
+    let mut doc = match daemon::connect(&config) {
+        Some((tx, mut events)) => fetch(&tx, &mut events).await?,
+        None => load(&config)?,
+    };
+
+    if let Some(at) = args.at {
+        let hash = resolve(&mut doc, &at)?;
+        let state = state_at(&mut doc, &[hash])?;
+        println!("As of {hash}:");
+        for list in state.lists {
+            println!("{}", list.title);
+            for (depth, item) in list.all_items() {
+                let check = if item.completed { "x" } else { " " };
+                println!("{}[{check}] {}", "  ".repeat(depth + 1), item.text);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut entries = entries(&mut doc).take(args.limit).peekable();
+    if entries.peek().is_none() {
+        println!("No history in workspace {} yet", config.workspace);
+    }
+    for entry in entries {
+        println!("{}", format_entry(&entry?));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automerge::ActorId;
+    use autosurgeon::reconcile;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::{TodoCommand, ops, sort::Priority};
+
+    fn apply(doc: &mut AutoCommit, command: TodoCommand) {
+        ops::apply(doc, command).unwrap();
+        doc.commit_with(commit_options());
+    }
+
+    #[test]
+    fn entries_say_who_did_what() {
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, TodoState::default()).unwrap();
+        doc.commit();
+        let mut doc = doc.with_actor(ActorId::random());
+
+        let (list_id, item_id) = (Uuid::new_v4(), Uuid::new_v4());
+        apply(
+            &mut doc,
+            TodoCommand::AddList {
+                list_id,
+                title: "Groceries".into(),
+                metadata: HashMap::new(),
+            },
+        );
+        apply(
+            &mut doc,
+            TodoCommand::AddTodo {
+                list_id,
+                item_id,
+                text: "milk".into(),
+                metadata: HashMap::new(),
+                due: None,
+                priority: Priority::None,
+                parent_id: None,
+                tags: Vec::new(),
+            },
+        );
+        apply(&mut doc, TodoCommand::ToggleTodo { list_id, item_id });
+        let completed = doc.get_heads();
+        apply(&mut doc, TodoCommand::RemoveTodo { list_id, item_id });
+
+        let mut entries = entries(&mut doc).collect::<Result<Vec<_>>>().unwrap();
+        entries.reverse();
+        let summaries: Vec<_> = entries.iter().map(|entry| entry.summary.as_str()).collect();
+        assert_eq!(
+            summaries,
+            [
+                "no visible changes",
+                "added list \"Groceries\"",
+                "added \"milk\" to \"Groceries\"",
+                "completed \"milk\" in \"Groceries\"",
+                "removed \"milk\" from \"Groceries\"",
+            ]
+        );
+
+        let removal = entries.last().unwrap();
+        assert_eq!(removal.author.as_deref(), Some(AUTHOR.as_str()));
+        assert!(removal.time.is_some());
+
+        // the item is still there, completed, as of the change before
+        let hash = resolve(&mut doc, &completed[0].to_string()[..10]).unwrap();
+        let state = state_at(&mut doc, &[hash]).unwrap();
+        assert!(
+            state
+                .list(list_id)
+                .unwrap()
+                .item(item_id)
+                .unwrap()
+                .completed
+        );
+        assert_eq!(
+            resolve(&mut doc, " ").unwrap_err().to_string(),
+            "no change hash given"
+        );
+    }
+}
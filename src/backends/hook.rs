@@ -6,8 +6,11 @@ use tokio_util::codec::{FramedRead, LinesCodec};
 use tracing::{debug, warn};
 
 use crate::backends::{
-    multicast::{self, TodoEvent, TodoItem},
-    TodoCommand, TodoList, TodoState,
+    connect_or_setup,
+    multicast::{TodoEvent, TodoItem},
+    sort::Priority,
+    tags,
+    McastConfig, TodoCommand, TodoList, TodoState,
 };
 
 use serde::{Deserialize, Serialize};
@@ -94,11 +97,7 @@ fn guess_task_id(list: &TodoList, session_id: &str) -> String {
     let count = list
         .items
         .iter()
-        .filter(|i| {
-            i.metadata
-                .get("session_id")
-                .map_or(false, |s| s == session_id)
-        })
+        .filter(|i| i.metadata.get("session_id").is_some_and(|s| s == session_id))
         .count();
     (count + 1).to_string()
 }
@@ -109,11 +108,7 @@ fn find_list(todo_state: &TodoState, session_id: &str, list_name: &str) -> Optio
     todo_state
         .lists
         .iter()
-        .position(|l| {
-            l.metadata
-                .get("session_id")
-                .map_or(false, |s| s == session_id)
-        })
+        .position(|l| l.metadata.get("session_id").is_some_and(|s| s == session_id))
         .or_else(|| todo_state.lists.iter().position(|l| l.title == list_name))
 }
 
@@ -132,17 +127,19 @@ async fn ensure_list(
     let mut metadata = HashMap::new();
     metadata.insert("session_id".into(), session_id.into());
 
+    let list = TodoList {
+        metadata,
+        ..TodoList::new(list_name)
+    };
+
     tx.send(TodoCommand::AddList {
-        title: list_name.into(),
-        metadata: metadata.clone(),
+        list_id: list.id,
+        title: list.title.clone(),
+        metadata: list.metadata.clone(),
     })
     .await?;
 
-    todo_state.lists.push(TodoList {
-        title: list_name.into(),
-        items: vec![],
-        metadata,
-    });
+    todo_state.lists.push(list);
 
     Ok(todo_state.lists.len() - 1)
 }
@@ -155,7 +152,7 @@ async fn shutdown(tx: &tokio::sync::mpsc::Sender<TodoCommand>) -> anyhow::Result
     Ok(())
 }
 
-pub async fn run_hook() -> anyhow::Result<()> {
+pub async fn run_hook(config: McastConfig) -> anyhow::Result<()> {
     let stdin = io::stdin();
     let mut reader = FramedRead::new(stdin, LinesCodec::new());
     let line = reader.next().await.transpose()?.unwrap_or_default();
@@ -174,7 +171,7 @@ pub async fn run_hook() -> anyhow::Result<()> {
 
     let site_id = rand::random();
 
-    let (tx, mut rx) = multicast::setup(site_id);
+    let (tx, mut rx) = connect_or_setup(site_id, config);
 
     // receive at least one state change
     let mut todo_state = loop {
@@ -199,18 +196,25 @@ pub async fn run_hook() -> anyhow::Result<()> {
                 .unwrap_or_else(|| guess_task_id(&todo_state.lists[list_idx], &hook.session_id));
             metadata.insert("task_id".into(), task_id);
 
+            let (text, tags) = tags::extract(&subject);
+            let item = TodoItem {
+                tags,
+                ..TodoItem::new(text, metadata)
+            };
+
             tx.send(TodoCommand::AddTodo {
-                list_index: list_idx,
-                text: subject.clone(),
-                metadata: metadata.clone(),
+                list_id: todo_state.lists[list_idx].id,
+                item_id: item.id,
+                text: item.text.clone(),
+                metadata: item.metadata.clone(),
+                due: None,
+                priority: Priority::None,
+                parent_id: None,
+                tags: item.tags.clone(),
             })
             .await?;
 
-            todo_state.lists[list_idx].items.push(TodoItem {
-                text: subject,
-                completed: false,
-                metadata,
-            });
+            todo_state.lists[list_idx].items.push(item);
 
             shutdown(&tx).await?;
         }
@@ -235,23 +239,25 @@ pub async fn run_hook() -> anyhow::Result<()> {
             let mut item_idx = todo_state.lists[list_idx]
                 .items
                 .iter()
-                .position(|i| i.metadata.get("task_id").map_or(false, |t| t == &task_id));
+                .position(|i| i.metadata.get("task_id").is_some_and(|t| t == &task_id));
 
             // Fallback: read the Claude Code todos file and match by subject
-            if item_idx.is_none() {
-                if let Some(subject) = read_claude_todo_subject(&hook.session_id, &task_id) {
-                    debug!("task_id={task_id} not in metadata, falling back to subject match: {subject}");
-                    if let Some(idx) = todo_state.lists[list_idx]
-                        .items
-                        .iter()
-                        .position(|i| i.text == subject)
-                    {
-                        // Backfill the task_id metadata for future lookups
-                        todo_state.lists[list_idx].items[idx]
-                            .metadata
-                            .insert("task_id".into(), task_id.clone());
-                        item_idx = Some(idx);
-                    }
+            if item_idx.is_none()
+                && let Some(subject) = read_claude_todo_subject(&hook.session_id, &task_id)
+            {
+                debug!("task_id={task_id} not in metadata, falling back to subject match: {subject}");
+                // items are stored with the subject's #tags taken out
+                let (text, _) = tags::extract(&subject);
+                if let Some(idx) = todo_state.lists[list_idx]
+                    .items
+                    .iter()
+                    .position(|i| i.text == text)
+                {
+                    // Backfill the task_id metadata for future lookups
+                    todo_state.lists[list_idx].items[idx]
+                        .metadata
+                        .insert("task_id".into(), task_id.clone());
+                    item_idx = Some(idx);
                 }
             }
 
@@ -261,21 +267,24 @@ pub async fn run_hook() -> anyhow::Result<()> {
                 return Ok(());
             };
 
+            let list = &todo_state.lists[list_idx];
+            let item = &list.items[item_idx];
+
             match status.as_deref() {
                 Some("completed") => {
-                    if !todo_state.lists[list_idx].items[item_idx].completed {
+                    if !item.completed {
                         tx.send(TodoCommand::ToggleTodo {
-                            list_index: list_idx,
-                            item_index: item_idx,
+                            list_id: list.id,
+                            item_id: item.id,
                         })
                         .await?;
                     }
                 }
                 Some("pending") => {
-                    if todo_state.lists[list_idx].items[item_idx].completed {
+                    if item.completed {
                         tx.send(TodoCommand::ToggleTodo {
-                            list_index: list_idx,
-                            item_index: item_idx,
+                            list_id: list.id,
+                            item_id: item.id,
                         })
                         .await?;
                     }
@@ -5,12 +5,20 @@ use rmcp::{
     transport::stdio,
     ErrorData as McpError, Json, ServiceExt,
 };
+use automerge::AutoCommit;
+use chrono::{DateTime, Local, Utc};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
-use tokio::sync::mpsc::Sender;
-use tracing::debug;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::{debug, warn};
+use uuid::Uuid;
 
+use crate::backends::history::{self, HistoryEntry, DEFAULT_HISTORY_LIMIT};
 use crate::backends::multicast::{self, TodoEvent, TodoCommand, TodoState};
+use crate::backends::sort::{self, Priority, SortMode};
+use crate::backends::tags::{self, Tag};
+use crate::backends::{connect_or_setup, due, workspace, McastConfig};
 
 // Parameter structs for MCP tools
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
@@ -20,41 +28,98 @@ pub struct AddListParams {
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct RemoveListParams {
-    pub list_index: u32,
+    pub list_id: Uuid,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct RenameListParams {
-    pub list_index: u32,
+    pub list_id: Uuid,
     pub title: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct AddTodoParams {
-    pub list_index: u32,
+    pub list_id: Uuid,
     pub text: String,
+    /// When it's due, as RFC 3339, YYYY-MM-DD HH:MM or YYYY-MM-DD (end of that day) in local time
+    pub due: Option<String>,
+    /// None if not given
+    pub priority: Option<Priority>,
+    /// Add the item as a subtask of this item in the same list, at any depth
+    pub parent_id: Option<Uuid>,
+    /// Tags without the #, #tags written in the text are taken out of it and added too
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct SetTagsParams {
+    pub list_id: Uuid,
+    pub item_id: Uuid,
+    /// Replaces the item's tags, empty to remove them all
+    pub tags: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct SetNotesParams {
+    pub list_id: Uuid,
+    pub item_id: Uuid,
+    /// Replaces the item's notes, empty to remove them
+    pub notes: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct SetTagColorParams {
+    pub tag: String,
+    /// As #rrggbb or one of red, orange, yellow, green, teal, blue, purple and pink
+    pub color: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct SetPriorityParams {
+    pub list_id: Uuid,
+    pub item_id: Uuid,
+    pub priority: Priority,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct ToggleTodoParams {
-    pub list_index: u32,
-    pub item_index: u32,
+    pub list_id: Uuid,
+    pub item_id: Uuid,
+    /// When completing an item with subtasks, complete them too, true if not given
+    pub with_subtasks: Option<bool>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct SetDueDateParams {
+    pub list_id: Uuid,
+    pub item_id: Uuid,
+    /// As in add_todo, leave out to clear the due date
+    pub due: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct RemoveTodoParams {
-    pub list_index: u32,
-    pub item_index: u32,
+    pub list_id: Uuid,
+    pub item_id: Uuid,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct ClearCompletedParams {
-    pub list_index: u32,
+    pub list_id: Uuid,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct GetListParams {
-    pub list_index: Option<u32>,
+    pub list_id: Option<Uuid>,
+    /// Only the items with this tag, and the items they're subtasks of, leaving out lists
+    /// without any
+    pub tag: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct SwitchWorkspaceParams {
+    /// Name of the workspace, it is created if it doesn't exist yet
+    pub workspace: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
@@ -65,29 +130,133 @@ pub struct NameSessionParams {
     pub name: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct GetHistoryParams {
+    /// How many of the most recent matching changes to return, 50 if not given
+    pub limit: Option<usize>,
+    /// Only changes whose summary contains this text, e.g. an item's text to find who removed it
+    pub contains: Option<String>,
+    /// Also return the lists as they were right after this change, by hash or unambiguous prefix
+    pub at: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct GetDueTodosParams {
+    /// How many days ahead to look, 7 if not given
+    pub within_days: Option<u32>,
+    /// Also return open items already past their due date, true if not given
+    pub include_overdue: Option<bool>,
+}
+
 // Response types
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct TodoListsResponse {
     pub lists: Vec<TodoListResponse>,
+    /// Every tag in the workspace, by name
+    pub tags: Vec<TagResponse>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct TagResponse {
+    pub name: String,
+    /// As #rrggbb
+    pub color: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct TodoListResponse {
-    pub index: u32,
+    pub id: Uuid,
     pub title: String,
+    /// How the items below are ordered
+    pub sort: SortMode,
     pub items: Vec<TodoItemResponse>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
 pub struct TodoItemResponse {
-    pub index: u32,
+    pub id: Uuid,
     pub text: String,
     pub completed: bool,
+    /// RFC 3339, if it has a due date
+    pub due: Option<String>,
+    pub priority: Priority,
+    pub tags: Vec<String>,
+    /// Empty if it has none
+    pub notes: String,
+    /// Completed subtasks out of how many, if it has any
+    pub progress: Option<String>,
+    /// In the list's sort order
+    pub subtasks: Vec<TodoItemResponse>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct DueTodosResponse {
+    /// Soonest first
+    pub todos: Vec<DueTodoResponse>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct DueTodoResponse {
+    pub list_id: Uuid,
+    pub list_title: String,
+    pub item_id: Uuid,
+    pub text: String,
+    /// RFC 3339
+    pub due: String,
+    pub overdue: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct WorkspacesResponse {
+    pub current: String,
+    pub workspaces: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct HistoryResponse {
+    /// Newest first
+    pub changes: Vec<HistoryEntryResponse>,
+    /// The lists as of the change given in `at`
+    pub state: Option<TodoListsResponse>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct HistoryEntryResponse {
+    pub hash: String,
+    /// `user@host` of whoever made the change, if known
+    pub author: Option<String>,
+    /// Short id of the site process that made the change
+    pub actor: String,
+    /// RFC 3339, if known
+    pub time: Option<String>,
+    pub summary: String,
+}
+
+impl From<HistoryEntry> for HistoryEntryResponse {
+    fn from(entry: HistoryEntry) -> Self {
+        HistoryEntryResponse {
+            hash: entry.hash.to_string(),
+            author: entry.author,
+            actor: entry.actor,
+            time: entry.time.map(|time| time.to_rfc3339()),
+            summary: entry.summary,
+        }
+    }
+}
+
+/// The workspace we're connected to and the channel into its sync task
+struct Session {
+    config: McastConfig,
+    tx: Sender<TodoCommand>,
+    /// The site's answers to `GetDocument`, locked by whoever is waiting for one
+    documents: Arc<tokio::sync::Mutex<Receiver<Vec<u8>>>>,
 }
 
 pub struct TodoMcp {
     todo_state: Arc<RwLock<TodoState>>,
-    tx: Sender<TodoCommand>,
+    session: RwLock<Session>,
+    /// Bumped on every workspace switch, so updates from the old workspace are ignored
+    generation: Arc<AtomicU64>,
     tool_router: ToolRouter<Self>,
 }
 
@@ -97,77 +266,176 @@ impl From<&TodoState> for TodoListsResponse {
             lists: value
                 .lists
                 .iter()
-                .enumerate()
-                .map(|(list_index, list)| TodoListResponse {
-                    index: list_index as u32,
+                .map(|list| TodoListResponse {
+                    id: list.id,
                     title: list.title.clone(),
-                    items: list
-                        .items
-                        .iter()
-                        .enumerate()
-                        .map(|(item_index, item)| TodoItemResponse {
-                            index: item_index as u32,
-                            text: item.text.clone(),
-                            completed: item.completed,
-                        })
-                        .collect(),
+                    sort: list.sort,
+                    items: item_responses(&list.items, list.sort),
+                })
+                .collect(),
+            tags: tags::all(value)
+                .into_iter()
+                .map(|name| TagResponse {
+                    color: tags::color(value, &name).color,
+                    name,
                 })
                 .collect(),
         }
     }
 }
 
-pub async fn run_mcp() -> anyhow::Result<()> {
-    let todo_mcp = TodoMcp::new().serve(stdio()).await?;
+fn item_responses(items: &[multicast::TodoItem], mode: SortMode) -> Vec<TodoItemResponse> {
+    sort::sorted_items(items, mode)
+        .into_iter()
+        .map(|item| TodoItemResponse {
+            id: item.id,
+            text: item.text.clone(),
+            completed: item.completed,
+            due: item.due.map(|due| due.to_rfc3339()),
+            priority: item.priority,
+            tags: item.tags.clone(),
+            notes: item.notes.clone(),
+            progress: (!item.children.is_empty()).then(|| {
+                let (completed, total) = item.progress();
+                format!("{completed}/{total}")
+            }),
+            subtasks: item_responses(&item.children, mode),
+        })
+        .collect()
+}
 
-    todo_mcp.waiting().await?;
+/// Reads the tags given to a tool, along with those written into `text`, returning the text
+/// without them
+fn parse_tags(text: &str, given: &[String]) -> Result<(String, Vec<String>), McpError> {
+    let (text, mut found) = tags::extract(text);
+    for tag in given {
+        let tag =
+            tags::parse(tag).map_err(|err| McpError::invalid_params(err.to_string(), None))?;
+        if !found.contains(&tag) {
+            found.push(tag);
+        }
+    }
+    Ok((text, found))
+}
 
-    Ok(())
+/// Reads a due date given to a tool, an absent or empty one meaning none
+fn parse_due(due: Option<&str>) -> Result<Option<DateTime<Utc>>, McpError> {
+    due::parse_optional(due.unwrap_or_default(), Local::now())
+        .map_err(|err| McpError::invalid_params(err.to_string(), None))
 }
 
-#[tool_router]
-impl TodoMcp {
-    pub fn new() -> Self {
-        let todo_state = Arc::new(RwLock::new(TodoState::default()));
+pub async fn run_mcp(config: McastConfig) -> anyhow::Result<()> {
+    let todo_mcp = TodoMcp::new(config).serve(stdio()).await?;
 
-        let bg_state = todo_state.clone();
-        let site_id = rand::random();
+    todo_mcp.waiting().await?;
 
-        let (tx, mut recv) = multicast::setup(site_id);
+    Ok(())
+}
 
-        tokio::spawn(async move {
-            while let Some(change) = recv.recv().await {
-                if let TodoEvent::StateUpdate(new_state) = change {
+/// Starts syncing the workspace in `config`, keeping `todo_state` up to date until the generation moves on
+fn connect(
+    config: McastConfig,
+    todo_state: Arc<RwLock<TodoState>>,
+    generation: Arc<AtomicU64>,
+) -> Session {
+    let connected_generation = generation.load(Ordering::SeqCst);
+    let site_id = rand::random();
+
+    let (tx, mut recv) = connect_or_setup(site_id, config.clone());
+    let (documents_tx, documents) = channel(1);
+
+    tokio::spawn(async move {
+        while let Some(change) = recv.recv().await {
+            if generation.load(Ordering::SeqCst) != connected_generation {
+                break;
+            }
+            match change {
+                TodoEvent::StateUpdate(new_state) => {
                     debug!("New update received");
-                    let mut cur_state = bg_state.write().unwrap();
+                    let mut cur_state = todo_state.write().unwrap();
                     *cur_state = new_state;
                 }
+                TodoEvent::Recovered(recovery) => warn!("{recovery}"),
+                TodoEvent::ConnectionStatus(_) => {}
+                TodoEvent::Document { data, .. } => {
+                    // nobody is waiting if the call that asked for it was cancelled
+                    documents_tx.try_send(data).ok();
+                }
             }
-        });
+        }
+    });
+
+    Session {
+        config,
+        tx,
+        documents: Arc::new(tokio::sync::Mutex::new(documents)),
+    }
+}
+
+#[tool_router]
+impl TodoMcp {
+    pub fn new(config: McastConfig) -> Self {
+        let todo_state = Arc::new(RwLock::new(TodoState::default()));
+        let generation = Arc::new(AtomicU64::new(0));
+
+        let session = connect(config, todo_state.clone(), generation.clone());
 
         Self {
             todo_state,
-            tx,
+            session: RwLock::new(session),
+            generation,
             tool_router: Self::tool_router(),
         }
     }
 
-    #[tool(description = "Get all todo lists, or a specific list by index")]
+    fn tx(&self) -> Sender<TodoCommand> {
+        self.session.read().unwrap().tx.clone()
+    }
+
+    /// The site's document as it is now, edits it hasn't saved yet included
+    async fn document(&self) -> anyhow::Result<AutoCommit> {
+        let (tx, documents) = {
+            let session = self.session.read().unwrap();
+            (session.tx.clone(), session.documents.clone())
+        };
+        let mut documents = documents.lock().await;
+        // an answer to a call that was cancelled before it came
+        while documents.try_recv().is_ok() {}
+
+        tx.send(TodoCommand::GetDocument).await?;
+        let data = documents
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("sync stopped before sending its document"))?;
+        Ok(AutoCommit::load(&data)?)
+    }
+
+    #[tool(
+        description = "Get all todo lists, or a specific list by id, with each list's items in its sort order. Give a tag to get only the items with it across every list"
+    )]
     async fn get_todos(
         &self,
         Parameters(params): Parameters<GetListParams>,
     ) -> Result<Json<TodoListsResponse>, McpError> {
         let state = self.todo_state.read().unwrap();
-        let response: TodoListsResponse = (&*state).into();
+        let response: TodoListsResponse = match params.tag.as_deref() {
+            Some(tag) => {
+                let tag = tags::parse(tag)
+                    .map_err(|err| McpError::invalid_params(err.to_string(), None))?;
+                (&tags::filter_lists(&state, &tag)).into()
+            }
+            None => (&*state).into(),
+        };
 
         // If a specific list is requested, filter to just that one
-        if let Some(list_index) = params.list_index {
+        if let Some(list_id) = params.list_id {
             let filtered = TodoListsResponse {
                 lists: response
                     .lists
                     .into_iter()
-                    .filter(|l| l.index == list_index)
+                    .filter(|l| l.id == list_id)
                     .collect(),
+                tags: response.tags,
             };
             return Ok(Json(filtered));
         }
@@ -180,43 +448,41 @@ impl TodoMcp {
         &self,
         Parameters(params): Parameters<AddListParams>,
     ) -> Result<CallToolResult, McpError> {
-        {
-            let mut state = self.todo_state.write().unwrap();
-            state
-                .lists
-                .push(multicast::TodoList::new(params.title.clone()));
-        }
+        let list = multicast::TodoList::new(params.title);
+        let list_id = list.id;
 
-        self.tx
+        self.tx()
             .send(TodoCommand::AddList {
-                title: params.title,
+                list_id,
+                title: list.title.clone(),
                 metadata: HashMap::new(),
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
-        Ok(CallToolResult::success(vec![]))
+        self.todo_state.write().unwrap().lists.push(list);
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Created list with id {list_id}"
+        ))]))
     }
 
-    #[tool(description = "Remove a todo list by index")]
+    #[tool(description = "Remove a todo list by id")]
     async fn remove_list(
         &self,
         Parameters(params): Parameters<RemoveListParams>,
     ) -> Result<CallToolResult, McpError> {
         {
             let mut state = self.todo_state.write().unwrap();
-            let list_index = params.list_index as usize;
-            if list_index < state.lists.len() {
-                state.lists.remove(list_index);
-            }
+            state.lists.retain(|list| list.id != params.list_id);
         }
 
-        self.tx
+        self.tx()
             .send(TodoCommand::RemoveList {
-                list_index: params.list_index as usize,
+                list_id: params.list_id,
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![]))
     }
@@ -228,104 +494,300 @@ impl TodoMcp {
     ) -> Result<CallToolResult, McpError> {
         {
             let mut state = self.todo_state.write().unwrap();
-            let list_index = params.list_index as usize;
-            if list_index < state.lists.len() {
-                state.lists[list_index].title = params.title.clone();
+            if let Some(list) = state.list_mut(params.list_id) {
+                list.title = params.title.clone();
             }
         }
 
-        self.tx
+        self.tx()
             .send(TodoCommand::RenameList {
-                list_index: params.list_index as usize,
+                list_id: params.list_id,
                 title: params.title,
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![]))
     }
 
-    #[tool(description = "Add a new todo item to a specific list")]
+    #[tool(
+        description = "Add a new todo item to a specific list, or as a subtask of another item with parent_id. #tags in the text, as in \"Fix login #bug\", are taken out of it and added as tags"
+    )]
     async fn add_todo(
         &self,
         Parameters(params): Parameters<AddTodoParams>,
     ) -> Result<CallToolResult, McpError> {
+        let due = parse_due(params.due.as_deref())?;
+        let priority = params.priority.unwrap_or_default();
+        let (text, tags) = parse_tags(&params.text, params.tags.as_deref().unwrap_or_default())?;
+        let item = multicast::TodoItem {
+            due,
+            priority,
+            tags,
+            ..multicast::TodoItem::new(text, HashMap::new())
+        };
+        let item_id = item.id;
+
+        self.tx()
+            .send(TodoCommand::AddTodo {
+                list_id: params.list_id,
+                item_id,
+                text: item.text.clone(),
+                metadata: HashMap::new(),
+                due,
+                priority,
+                parent_id: params.parent_id,
+                tags: item.tags.clone(),
+            })
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
         {
             let mut state = self.todo_state.write().unwrap();
-            let list_index = params.list_index as usize;
-            if list_index < state.lists.len() {
-                state.lists[list_index].items.push(multicast::TodoItem {
-                    text: params.text.clone(),
-                    completed: false,
-                    metadata: HashMap::new(),
-                });
+            if let Some(list) = state.list_mut(params.list_id) {
+                match params.parent_id {
+                    Some(parent_id) => {
+                        if let Some(parent) = list.item_mut(parent_id) {
+                            parent.children.push(item);
+                        }
+                    }
+                    None => list.items.push(item),
+                }
             }
         }
 
-        self.tx
-            .send(TodoCommand::AddTodo {
-                list_index: params.list_index as usize,
-                text: params.text,
-                metadata: HashMap::new(),
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Created item with id {item_id}"
+        ))]))
+    }
+
+    #[tool(
+        description = "Toggle a todo item as either completed or incomplete, completing an item completes its subtasks too unless with_subtasks is false"
+    )]
+    async fn toggle_todo(
+        &self,
+        Parameters(params): Parameters<ToggleTodoParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let command = {
+            let mut state = self.todo_state.write().unwrap();
+            match state
+                .list_mut(params.list_id)
+                .and_then(|list| list.item_mut(params.item_id))
+            {
+                Some(item)
+                    if !item.completed
+                        && !item.children.is_empty()
+                        && params.with_subtasks.unwrap_or(true) =>
+                {
+                    item.complete_all();
+                    TodoCommand::CompleteTodo {
+                        list_id: params.list_id,
+                        item_id: params.item_id,
+                    }
+                }
+                item => {
+                    if let Some(item) = item {
+                        item.completed = !item.completed;
+                    }
+                    TodoCommand::ToggleTodo {
+                        list_id: params.list_id,
+                        item_id: params.item_id,
+                    }
+                }
+            }
+        };
+
+        self.tx()
+            .send(command)
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![]))
+    }
+
+    #[tool(description = "Set when a todo item is due, or clear its due date")]
+    async fn set_due_date(
+        &self,
+        Parameters(params): Parameters<SetDueDateParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let due = parse_due(params.due.as_deref())?;
+        {
+            let mut state = self.todo_state.write().unwrap();
+            if let Some(item) = state
+                .list_mut(params.list_id)
+                .and_then(|list| list.item_mut(params.item_id))
+            {
+                item.due = due;
+            }
+        }
+
+        self.tx()
+            .send(TodoCommand::SetDue {
+                list_id: params.list_id,
+                item_id: params.item_id,
+                due,
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![]))
     }
 
-    #[tool(description = "Toggle a todo item as either completed or incomplete")]
-    async fn toggle_todo(
+    #[tool(description = "Set a todo item's priority, from none up to urgent")]
+    async fn set_priority(
         &self,
-        Parameters(params): Parameters<ToggleTodoParams>,
+        Parameters(params): Parameters<SetPriorityParams>,
     ) -> Result<CallToolResult, McpError> {
         {
             let mut state = self.todo_state.write().unwrap();
-            let list_index = params.list_index as usize;
-            let item_index = params.item_index as usize;
-            if list_index < state.lists.len() {
-                let list = &mut state.lists[list_index];
-                if item_index < list.items.len() {
-                    list.items[item_index].completed = !list.items[item_index].completed;
-                }
+            if let Some(item) = state
+                .list_mut(params.list_id)
+                .and_then(|list| list.item_mut(params.item_id))
+            {
+                item.priority = params.priority;
             }
         }
 
-        self.tx
-            .send(TodoCommand::ToggleTodo {
-                list_index: params.list_index as usize,
-                item_index: params.item_index as usize,
+        self.tx()
+            .send(TodoCommand::SetPriority {
+                list_id: params.list_id,
+                item_id: params.item_id,
+                priority: params.priority,
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![]))
     }
 
-    #[tool(description = "Remove a specific todo item from a list")]
+    #[tool(description = "Replace a todo item's tags, new tags are added to the workspace with a colour")]
+    async fn set_tags(
+        &self,
+        Parameters(params): Parameters<SetTagsParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let (_, tags) = parse_tags("", &params.tags)?;
+        {
+            let mut state = self.todo_state.write().unwrap();
+            if let Some(item) = state
+                .list_mut(params.list_id)
+                .and_then(|list| list.item_mut(params.item_id))
+            {
+                item.tags = tags.clone();
+            }
+        }
+
+        self.tx()
+            .send(TodoCommand::SetTags {
+                list_id: params.list_id,
+                item_id: params.item_id,
+                tags,
+            })
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![]))
+    }
+
+    #[tool(description = "Replace a todo item's notes, merged character by character with edits made elsewhere")]
+    async fn set_notes(
+        &self,
+        Parameters(params): Parameters<SetNotesParams>,
+    ) -> Result<CallToolResult, McpError> {
+        {
+            let mut state = self.todo_state.write().unwrap();
+            if let Some(item) = state
+                .list_mut(params.list_id)
+                .and_then(|list| list.item_mut(params.item_id))
+            {
+                item.notes = params.notes.clone();
+            }
+        }
+
+        self.tx()
+            .send(TodoCommand::SetNotes {
+                list_id: params.list_id,
+                item_id: params.item_id,
+                notes: params.notes,
+            })
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![]))
+    }
+
+    #[tool(description = "Set the colour a tag is shown in on every device")]
+    async fn set_tag_color(
+        &self,
+        Parameters(params): Parameters<SetTagColorParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let tag = tags::parse(&params.tag)
+            .map_err(|err| McpError::invalid_params(err.to_string(), None))?;
+        let color = tags::parse_color(&params.color)
+            .map_err(|err| McpError::invalid_params(err.to_string(), None))?;
+        {
+            let mut state = self.todo_state.write().unwrap();
+            state.tags.insert(tag.clone(), Tag { color: color.clone() });
+        }
+
+        self.tx()
+            .send(TodoCommand::SetTagColor { tag, color })
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![]))
+    }
+
+    #[tool(
+        description = "Get the open todo items due soon across every list, soonest first, e.g. what is due this week. Overdue items are included unless include_overdue is false"
+    )]
+    async fn get_due_todos(
+        &self,
+        Parameters(params): Parameters<GetDueTodosParams>,
+    ) -> Result<Json<DueTodosResponse>, McpError> {
+        let now = Utc::now();
+        let state = self.todo_state.read().unwrap();
+        let todos = due::upcoming(
+            &state,
+            now,
+            params.within_days.unwrap_or(due::DEFAULT_UPCOMING_DAYS),
+            params.include_overdue.unwrap_or(true),
+        )
+        .into_iter()
+        .filter_map(|upcoming| {
+            let at = upcoming.item.due?;
+            Some(DueTodoResponse {
+                list_id: upcoming.list_id,
+                list_title: upcoming.list_title,
+                item_id: upcoming.item.id,
+                text: upcoming.item.text,
+                due: at.to_rfc3339(),
+                overdue: at < now,
+            })
+        })
+        .collect();
+
+        Ok(Json(DueTodosResponse { todos }))
+    }
+
+    #[tool(description = "Remove a specific todo item from a list, along with its subtasks")]
     async fn remove_todo(
         &self,
         Parameters(params): Parameters<RemoveTodoParams>,
     ) -> Result<CallToolResult, McpError> {
         {
             let mut state = self.todo_state.write().unwrap();
-            let list_index = params.list_index as usize;
-            let item_index = params.item_index as usize;
-            if list_index < state.lists.len() {
-                let list = &mut state.lists[list_index];
-                if item_index < list.items.len() {
-                    list.items.remove(item_index);
-                }
+            if let Some(list) = state.list_mut(params.list_id) {
+                list.remove_item(params.item_id);
             }
         }
 
-        self.tx
+        self.tx()
             .send(TodoCommand::RemoveTodo {
-                list_index: params.list_index as usize,
-                item_index: params.item_index as usize,
+                list_id: params.list_id,
+                item_id: params.item_id,
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![]))
     }
@@ -337,22 +799,157 @@ impl TodoMcp {
     ) -> Result<CallToolResult, McpError> {
         {
             let mut state = self.todo_state.write().unwrap();
-            let list_index = params.list_index as usize;
-            if list_index < state.lists.len() {
-                state.lists[list_index].items.retain(|item| !item.completed);
+            if let Some(list) = state.list_mut(params.list_id) {
+                list.clear_completed();
             }
         }
 
-        self.tx
+        self.tx()
             .send(TodoCommand::ClearCompleted {
-                list_index: params.list_index as usize,
+                list_id: params.list_id,
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![]))
     }
 
+    #[tool(
+        description = "Undo the last edit made from this client, e.g. a list or item removed by mistake. Edits from other devices and apps are kept"
+    )]
+    async fn undo(&self) -> Result<CallToolResult, McpError> {
+        self.tx()
+            .send(TodoCommand::Undo)
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![Content::text(
+            "Undid the last edit made from this site, if there was one. Use get_todos to see the result",
+        )]))
+    }
+
+    #[tool(description = "Make the last edit undo took back again")]
+    async fn redo(&self) -> Result<CallToolResult, McpError> {
+        self.tx()
+            .send(TodoCommand::Redo)
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        Ok(CallToolResult::success(vec![Content::text(
+            "Redid the last undone edit, if there was one. Use get_todos to see the result",
+        )]))
+    }
+
+    #[tool(description = "List the workspaces saved on this machine, and the one currently open")]
+    async fn list_workspaces(&self) -> Result<Json<WorkspacesResponse>, McpError> {
+        let (current, mut workspaces) = {
+            let session = self.session.read().unwrap();
+            (
+                session.config.workspace.clone(),
+                workspace::list(&session.config.autosave_path),
+            )
+        };
+
+        if !workspaces.contains(&current) {
+            workspaces.push(current.clone());
+        }
+
+        Ok(Json(WorkspacesResponse {
+            current,
+            workspaces,
+        }))
+    }
+
+    #[tool(
+        description = "Get the change history of the current workspace, newest first, with who made each change, when, and what it did. Use contains to find e.g. who removed an item, and at to see the lists as they were after a change"
+    )]
+    async fn get_history(
+        &self,
+        Parameters(params): Parameters<GetHistoryParams>,
+    ) -> Result<Json<HistoryResponse>, McpError> {
+        let mut doc = self
+            .document()
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+
+        let state = match params.at {
+            Some(at) => {
+                let hash = history::resolve(&mut doc, &at)
+                    .map_err(|err| McpError::invalid_params(err.to_string(), None))?;
+                let state = history::state_at(&mut doc, &[hash])
+                    .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+                Some((&state).into())
+            }
+            None => None,
+        };
+
+        // only as many changes are summarised as it takes to find enough that match
+        let contains = params.contains.map(|text| text.to_lowercase());
+        let limit = params.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+        let mut changes = Vec::new();
+        for entry in history::entries(&mut doc) {
+            if changes.len() == limit {
+                break;
+            }
+            let entry = entry.map_err(|err| McpError::internal_error(err.to_string(), None))?;
+            if contains
+                .as_ref()
+                .is_none_or(|text| entry.summary.to_lowercase().contains(text))
+            {
+                changes.push(entry.into());
+            }
+        }
+
+        Ok(Json(HistoryResponse { changes, state }))
+    }
+
+    #[tool(
+        description = "Switch to another workspace, each workspace has its own todo lists that are synced separately"
+    )]
+    async fn switch_workspace(
+        &self,
+        Parameters(params): Parameters<SwitchWorkspaceParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let name = workspace::parse_name(&params.workspace)
+            .map_err(|err| McpError::invalid_params(err, None))?;
+
+        let (mut config, old_tx) = {
+            let session = self.session.read().unwrap();
+            (session.config.clone(), session.tx.clone())
+        };
+
+        if config.workspace == name {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "Already in workspace {name}"
+            ))]));
+        }
+
+        // Let the old workspace save and say goodbye before we take over
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        old_tx
+            .send(TodoCommand::Shutdown {
+                sender: shutdown_tx,
+            })
+            .await
+            .ok();
+        shutdown_rx.await.ok();
+
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *self.todo_state.write().unwrap() = TodoState::default();
+
+        config.workspace = name.clone();
+        let session = connect(
+            config,
+            self.todo_state.clone(),
+            self.generation.clone(),
+        );
+        *self.session.write().unwrap() = session;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Switched to workspace {name}"
+        ))]))
+    }
+
     #[tool(
         description = "Rename a session's todo list by session_id. Use this after creating tasks to give the list a descriptive name."
     )]
@@ -360,16 +957,16 @@ impl TodoMcp {
         &self,
         Parameters(params): Parameters<NameSessionParams>,
     ) -> Result<CallToolResult, McpError> {
-        let list_index = {
+        let list_id = {
             let state = self.todo_state.read().unwrap();
-            state.lists.iter().position(|l| {
-                l.metadata
-                    .get("session_id")
-                    .map_or(false, |s| s == &params.session_id)
-            })
+            state
+                .lists
+                .iter()
+                .find(|l| l.metadata.get("session_id") == Some(&params.session_id))
+                .map(|l| l.id)
         };
 
-        let Some(list_index) = list_index else {
+        let Some(list_id) = list_id else {
             return Ok(CallToolResult::success(vec![Content::text(format!(
                 "No list found for session_id: {}",
                 params.session_id
@@ -380,18 +977,18 @@ impl TodoMcp {
 
         {
             let mut state = self.todo_state.write().unwrap();
-            if list_index < state.lists.len() {
-                state.lists[list_index].title = new_title.clone();
+            if let Some(list) = state.list_mut(list_id) {
+                list.title = new_title.clone();
             }
         }
 
-        self.tx
+        self.tx()
             .send(TodoCommand::RenameList {
-                list_index,
+                list_id,
                 title: new_title.clone(),
             })
             .await
-            .expect("always sends");
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![Content::text(format!(
             "List renamed to \"{}\"",
@@ -406,7 +1003,7 @@ impl rmcp::ServerHandler for TodoMcp {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             instructions: Some(
-                "Manages multiple todo lists with items. Supports creating lists, adding/toggling/removing items, and syncing state across devices. Use name_session to rename a session's list by session_id after creating tasks.".into(),
+                "Manages multiple todo lists with items. Supports creating lists, adding/toggling/removing items, and syncing state across devices. Lists live in workspaces, use list_workspaces and switch_workspace to move between them. Use name_session to rename a session's list by session_id after creating tasks. Use get_history to find out who changed what and when, and undo to take back an edit made by mistake. Items can have subtasks, due dates, priorities, tags and notes, use get_due_todos to see what is due soon and get_todos with a tag to find tagged items across lists.".into(),
             ),
             capabilities: ServerCapabilities::builder().enable_tools().build(),
             ..Default::default()
@@ -1,6 +1,42 @@
+pub mod backup;
+pub mod compaction;
+pub mod crypto;
+#[cfg(unix)]
+pub mod daemon;
+pub mod due;
 pub mod mcp;
+pub mod history;
 pub mod hook;
 pub mod multicast;
+pub mod notes;
+pub mod ops;
+pub mod peers;
 pub mod proto;
+pub mod relay;
+pub mod sort;
+pub mod storage;
+pub mod tags;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod transport;
+pub mod workspace;
 
 pub use multicast::{setup, TodoCommand, TodoItem, TodoList, TodoState};
+pub use proto::McastConfig;
+
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use multicast::TodoEvent;
+
+/// Connects to the workspace's daemon if one is running, otherwise runs the site in this process
+pub fn connect_or_setup(
+    site_id: u32,
+    config: McastConfig,
+) -> (Sender<TodoCommand>, Receiver<TodoEvent>) {
+    #[cfg(unix)]
+    if let Some(channels) = daemon::connect(&config) {
+        return channels;
+    }
+
+    setup(site_id, config)
+}
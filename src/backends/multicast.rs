@@ -1,15 +1,22 @@
-use automerge::AutoCommit;
+use automerge::{
+    ActorId, AutoCommit, ChangeHash, ObjType, ROOT, sync,
+    sync::SyncDoc,
+    transaction::{CommitOptions, Transactable},
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use uuid::Uuid;
 
 use anyhow::Context;
-use futures::TryStreamExt;
+use futures::{StreamExt, stream::FuturesUnordered};
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinSet;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use tracing::*;
 
@@ -19,123 +26,451 @@ use tokio::sync::mpsc::{
 
 use tokio::sync::oneshot::{Sender as OneshotSender, channel as oneshot_channel};
 
+use tokio::sync::broadcast::{
+    Receiver as BroadcastReceiver, Sender as BroadcastSender, channel as broadcast_channel,
+    error::RecvError,
+};
+
 use tokio::sync::{Notify, RwLock};
 
-use autosurgeon::{Hydrate, Reconcile, hydrate, reconcile};
+use autosurgeon::{Hydrate, Reconcile, hydrate};
 use std::sync::Arc;
 
-use crate::backends::proto::{McastReceiver, McastSender, ProtoMessage};
-
-#[cfg(target_os = "android")]
-const STORAGE_LOCATION: &str = "/data/data/dev.cetra.todomcp/files/automerge.save";
-
-#[cfg(not(target_os = "android"))]
-const STORAGE_LOCATION: &str = "~/.local/share/todo_mcp/automerge.save";
+use crate::backends::backup::{self, Retention};
+use crate::backends::compaction::{self, Epoch, Unsynced};
+use crate::backends::history;
+use crate::backends::ops::{self, assign_missing_ids};
+use crate::backends::proto::{McastConfig, ProtoMessage};
+use crate::backends::sort::{Priority, SortMode};
+use crate::backends::storage::{self, Saved};
+use crate::backends::tags::Tag;
+use crate::backends::transport::{self, Transport, run_transport};
+use crate::backends::workspace;
 
 #[derive(Debug, Clone, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
 pub struct TodoItem {
+    /// Stable identifier, used to address this item instead of its position
+    #[key]
+    #[serde(default = "Uuid::new_v4")]
+    #[autosurgeon(missing = "Uuid::nil")]
+    pub id: Uuid,
+    #[autosurgeon(with = "crate::backends::notes::crdt")]
     pub text: String,
     pub completed: bool,
     #[serde(default)]
     #[autosurgeon(missing = "Default::default")]
     pub metadata: HashMap<String, String>,
+    /// When the item is due, if ever
+    #[serde(default)]
+    #[autosurgeon(with = "crate::backends::due::crdt")]
+    pub due: Option<DateTime<Utc>>,
+    #[serde(default)]
+    #[autosurgeon(missing = "Default::default")]
+    pub priority: Priority,
+    /// When the item was added, unknown for items from before this was kept
+    #[serde(default)]
+    #[autosurgeon(with = "crate::backends::due::crdt")]
+    pub created: Option<DateTime<Utc>>,
+    /// Subtasks, which can have subtasks of their own
+    #[serde(default)]
+    #[autosurgeon(missing = "Default::default")]
+    pub children: Vec<TodoItem>,
+    /// Tag names, see [`tags`](crate::backends::tags)
+    #[serde(default)]
+    #[autosurgeon(with = "crate::backends::tags::crdt")]
+    pub tags: Vec<String>,
+    /// Longer, multi-line notes, empty if there are none
+    #[serde(default)]
+    #[autosurgeon(with = "crate::backends::notes::crdt")]
+    pub notes: String,
+}
+
+impl TodoItem {
+    pub fn new(text: impl Into<String>, metadata: HashMap<String, String>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            text: text.into(),
+            completed: false,
+            metadata,
+            due: None,
+            priority: Priority::None,
+            // to the millisecond, as precise as the document stores it
+            created: Some(Utc::now().trunc_subsecs(3)),
+            children: Vec::new(),
+            tags: Vec::new(),
+            notes: String::new(),
+        }
+    }
+
+    /// How many of the item's direct subtasks are completed, out of how many
+    pub fn progress(&self) -> (usize, usize) {
+        let completed = self.children.iter().filter(|c| c.completed).count();
+        (completed, self.children.len())
+    }
+
+    /// Marks the item and all of its subtasks completed
+    pub fn complete_all(&mut self) {
+        self.completed = true;
+        for child in &mut self.children {
+            child.complete_all();
+        }
+    }
+}
+
+/// Finds an item among `items` or, at any depth, their subtasks
+fn find_item(items: &[TodoItem], id: Uuid) -> Option<&TodoItem> {
+    items.iter().find_map(|item| {
+        (item.id == id)
+            .then_some(item)
+            .or_else(|| find_item(&item.children, id))
+    })
+}
+
+fn find_item_mut(items: &mut [TodoItem], id: Uuid) -> Option<&mut TodoItem> {
+    for item in items {
+        if item.id == id {
+            return Some(item);
+        }
+        if let Some(found) = find_item_mut(&mut item.children, id) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+/// Appends every item in `items` and, after each, its subtasks to `out`, with their depth
+fn flatten<'a>(items: &'a [TodoItem], depth: usize, out: &mut Vec<(usize, &'a TodoItem)>) {
+    for item in items {
+        out.push((depth, item));
+        flatten(&item.children, depth + 1, out);
+    }
 }
 
 #[derive(Debug, Clone, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
 pub struct TodoList {
+    /// Stable identifier, used to address this list instead of its position
+    #[key]
+    #[serde(default = "Uuid::new_v4")]
+    #[autosurgeon(missing = "Uuid::nil")]
+    pub id: Uuid,
     pub title: String,
     pub items: Vec<TodoItem>,
     #[serde(default)]
     #[autosurgeon(missing = "Default::default")]
     pub metadata: HashMap<String, String>,
+    /// How the items are shown, they're stored in the order they were added
+    #[serde(default)]
+    #[autosurgeon(missing = "Default::default")]
+    pub sort: SortMode,
 }
 
 impl TodoList {
     pub fn new(title: impl Into<String>) -> Self {
         Self {
+            id: Uuid::new_v4(),
             title: title.into(),
             items: Vec::new(),
             metadata: HashMap::new(),
+            sort: SortMode::Manual,
+        }
+    }
+
+    /// The item with the given id, at any depth
+    pub fn item(&self, id: Uuid) -> Option<&TodoItem> {
+        find_item(&self.items, id)
+    }
+
+    pub fn item_mut(&mut self, id: Uuid) -> Option<&mut TodoItem> {
+        find_item_mut(&mut self.items, id)
+    }
+
+    /// Removes the item with the given id, and its subtasks with it, wherever it is
+    pub fn remove_item(&mut self, id: Uuid) {
+        fn remove(items: &mut Vec<TodoItem>, id: Uuid) {
+            items.retain(|item| item.id != id);
+            for item in items {
+                remove(&mut item.children, id);
+            }
+        }
+        remove(&mut self.items, id);
+    }
+
+    /// Removes the completed items, and the completed subtasks of the rest
+    pub fn clear_completed(&mut self) {
+        fn clear(items: &mut Vec<TodoItem>) {
+            items.retain(|item| !item.completed);
+            for item in items {
+                clear(&mut item.children);
+            }
         }
+        clear(&mut self.items);
+    }
+
+    /// Every item in stored order, each followed by its subtasks, with how deep it's nested
+    pub fn all_items(&self) -> Vec<(usize, &TodoItem)> {
+        let mut out = Vec::new();
+        flatten(&self.items, 0, &mut out);
+        out
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TodoEvent {
     StateUpdate(TodoState),
     ConnectionStatus(String),
+    /// The save file wouldn't load and was replaced by what could be salvaged from it
+    Recovered(Recovery),
+    /// The site's document, saved, in answer to the `GetDocument` of `client`
+    Document {
+        client: u64,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recovery {
+    /// Where the unreadable save file was moved to
+    pub moved_to: PathBuf,
+    /// Changes salvaged from it, anything else comes back when peers resync
+    pub changes: usize,
+}
+
+impl std::fmt::Display for Recovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Save file was corrupt and moved to {}, ",
+            self.moved_to.display()
+        )?;
+        if self.changes == 0 {
+            write!(f, "nothing could be salvaged, waiting for peers to resync")
+        } else {
+            write!(
+                f,
+                "salvaged {} changes, peers will resync the rest",
+                self.changes
+            )
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Reconcile, Hydrate, PartialEq, Serialize, Deserialize)]
 pub struct TodoState {
     pub lists: Vec<TodoList>,
+    /// The workspace's tags and their colours, by name
+    #[serde(default)]
+    #[autosurgeon(missing = "Default::default")]
+    pub tags: BTreeMap<String, Tag>,
 }
 
-#[derive(Debug)]
+impl TodoState {
+    pub fn list(&self, id: Uuid) -> Option<&TodoList> {
+        self.lists.iter().find(|list| list.id == id)
+    }
+
+    pub fn list_mut(&mut self, id: Uuid) -> Option<&mut TodoList> {
+        self.lists.iter_mut().find(|list| list.id == id)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum TodoCommand {
     // List operations
     AddList {
+        list_id: Uuid,
         title: String,
         metadata: HashMap<String, String>,
     },
     RemoveList {
-        list_index: usize,
+        list_id: Uuid,
     },
     RenameList {
-        list_index: usize,
+        list_id: Uuid,
         title: String,
     },
+    /// Changes how the list's items are shown on every site
+    SetSort {
+        list_id: Uuid,
+        sort: SortMode,
+    },
 
-    // Item operations (now require list_index)
+    // Item operations
     AddTodo {
-        list_index: usize,
+        list_id: Uuid,
+        item_id: Uuid,
         text: String,
         metadata: HashMap<String, String>,
+        #[serde(default)]
+        due: Option<DateTime<Utc>>,
+        #[serde(default)]
+        priority: Priority,
+        /// The item to add this as a subtask of, or `None` for the top of the list
+        #[serde(default)]
+        parent_id: Option<Uuid>,
+        #[serde(default)]
+        tags: Vec<String>,
     },
+    /// Changes an item's text, as the characters that changed so concurrent edits merge
     RenameTodo {
-        list_index: usize,
-        item_index: usize,
+        list_id: Uuid,
+        item_id: Uuid,
         text: String,
     },
+    /// Changes an item's notes, as `RenameTodo` does its text
+    SetNotes {
+        list_id: Uuid,
+        item_id: Uuid,
+        notes: String,
+    },
     ToggleTodo {
-        list_index: usize,
-        item_index: usize,
+        list_id: Uuid,
+        item_id: Uuid,
+    },
+    /// Completes an item along with all of its subtasks
+    CompleteTodo {
+        list_id: Uuid,
+        item_id: Uuid,
+    },
+    /// Sets or, given `None`, clears an item's due date
+    SetDue {
+        list_id: Uuid,
+        item_id: Uuid,
+        due: Option<DateTime<Utc>>,
+    },
+    SetPriority {
+        list_id: Uuid,
+        item_id: Uuid,
+        priority: Priority,
+    },
+    /// Replaces an item's tags, registering any the workspace hasn't seen yet
+    SetTags {
+        list_id: Uuid,
+        item_id: Uuid,
+        tags: Vec<String>,
     },
     RemoveTodo {
-        list_index: usize,
-        item_index: usize,
+        list_id: Uuid,
+        item_id: Uuid,
     },
     ClearCompleted {
-        list_index: usize,
+        list_id: Uuid,
+    },
+
+    // Tag operations
+    /// Sets a tag's colour, registering it if it's new
+    SetTagColor {
+        tag: String,
+        color: String,
+    },
+
+    // Undo operations
+    /// Takes back this site's last edit
+    Undo,
+    /// Makes the last edit `Undo` took back again
+    Redo,
+    /// Puts a removed list back at `index`, how its removal is undone
+    InsertList {
+        index: usize,
+        list: TodoList,
+    },
+    /// Puts a removed item back at `index` among its parent's subtasks, or the list's items,
+    /// how its removal is undone
+    InsertTodo {
+        list_id: Uuid,
+        #[serde(default)]
+        parent_id: Option<Uuid>,
+        index: usize,
+        item: TodoItem,
+    },
+    /// Sets whether an item is completed, how toggling or completing it is undone
+    SetCompleted {
+        list_id: Uuid,
+        item_id: Uuid,
+        completed: bool,
+    },
+
+    // Document operations
+    /// Makes the document match `state`, e.g. a backup, as an ordinary change
+    Restore {
+        state: TodoState,
+    },
+    /// Rewrites the document as its current state, dropping its history
+    Compact,
+    /// Asks for the document as it is now, answered with [`TodoEvent::Document`]
+    GetDocument,
+
+    // Daemon operations
+    /// A command from one of the daemon's clients, which each have their own undo history
+    #[serde(skip)]
+    FromClient {
+        client: u64,
+        command: Box<TodoCommand>,
+    },
+    /// A daemon client disconnected, and its undo history goes with it
+    #[serde(skip)]
+    ClientGone {
+        client: u64,
     },
 
     // Sync operations
+    /// Not serialized, daemon clients shut down by disconnecting
+    #[serde(skip)]
     Shutdown {
         sender: OneshotSender<()>,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SyncMessage {
     DeltaChange(Vec<u8>),
-    State(Vec<u8>),
-    RequestState(u32),
-    Announce(Vec<u8>),
-    Alive,
+    /// An encoded Automerge sync protocol message, addressed to a single site
+    Sync {
+        to: u32,
+        message: Vec<u8>,
+    },
+    /// Asks a site for changes we've received dependents of but not the changes themselves
+    RequestChanges {
+        to: u32,
+        hashes: Vec<[u8; 32]>,
+    },
+    /// Concatenated raw changes, sent in reply to a `RequestChanges`
+    Changes {
+        to: u32,
+        changes: Vec<u8>,
+    },
+    Announce,
+    /// Heartbeat, carrying the sender's current document heads and the compaction they're on
+    Alive {
+        heads: Vec<[u8; 32]>,
+        epoch: Epoch,
+    },
     Shutdown,
 }
 
-pub fn setup(site_id: u32) -> (TokioSender<TodoCommand>, TokioReceiver<TodoEvent>) {
+pub fn setup(
+    site_id: u32,
+    config: McastConfig,
+) -> (TokioSender<TodoCommand>, TokioReceiver<TodoEvent>) {
+    let transports = transport::from_config(site_id, &config);
+    setup_with_transports(site_id, config, transports)
+}
+
+/// Like [`setup`], syncing over the given transports instead of the ones in the config
+pub fn setup_with_transports(
+    site_id: u32,
+    config: McastConfig,
+    transports: Vec<Box<dyn Transport>>,
+) -> (TokioSender<TodoCommand>, TokioReceiver<TodoEvent>) {
     // a few channels to setup
 
     // change coming in from one of our clients
     let (change_tx, change_rx) = tokio_channel(128);
 
-    // messages received from multicast
+    // events for our clients
     let (message_tx, message_rx) = tokio_channel(128);
 
     tokio::spawn(async move {
-        if let Err(err) = async_inner(site_id, message_tx, change_rx).await {
+        if let Err(err) = async_inner(site_id, config, transports, message_tx, change_rx).await {
             error!("Error with async task:{err:?}");
         };
     });
@@ -146,46 +481,266 @@ pub fn setup(site_id: u32) -> (TokioSender<TodoCommand>, TokioReceiver<TodoEvent
 pub struct SiteState {
     commit: AutoCommit,
     alive: BTreeMap<u32, Instant>,
+    /// Automerge sync protocol state for each site we're exchanging changes with
+    peers: HashMap<u32, sync::State>,
+    /// The site we last asked for missing changes, and when
+    gap_request: Option<(u32, Instant)>,
+    /// The undo history of each client, see [`TodoCommand::FromClient`]
+    undo: HashMap<u64, UndoStacks>,
+    /// The compaction we're on. Ahead of the document's own while it syncs one it couldn't
+    /// rebuild, and it isn't saved until then
+    epoch: Epoch,
+    /// The compaction each site last told us it's on, we only sync with those on ours
+    peer_epochs: HashMap<u32, Epoch>,
+    /// What we had before moving onto a compaction we couldn't rebuild, put back once it syncs
+    unsynced: Option<Unsynced>,
 }
 
+/// How to take back a client's recent edits, and make those it undid again
+#[derive(Default)]
+struct UndoStacks {
+    /// How to take back each of the client's recent edits, the last one on top
+    undo: VecDeque<Vec<TodoCommand>>,
+    /// How to make each edit that's been undone again, the last one undone on top
+    redo: VecDeque<Vec<TodoCommand>>,
+    /// The item and field the last edit typed into, more typing there is undone along with it
+    typing: Option<(Uuid, &'static str)>,
+}
+
+/// The client commands sent straight to the site come from, rather than through the daemon
+const LOCAL_CLIENT: u64 = 0;
+
 const ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// How long to wait for a `Changes` reply before asking for missing changes again
+const GAP_RETRY: Duration = Duration::from_secs(2);
+
+/// How often we send our heads to every site
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long transports get to send our `Shutdown` once the first has, before we stop waiting
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// How many edits can be undone
+const UNDO_LIMIT: usize = 100;
+
+/// Actor for the initial change of every new document.
+///
+/// Each site starts from an identical genesis change so that the root `lists`
+/// object is shared, rather than each fresh document creating its own and one
+/// of them winning the conflict when they first sync.
+const GENESIS_ACTOR: [u8; 16] = [0; 16];
+
+/// The hash of the genesis change, which every build must make byte for byte the same
+#[cfg(test)]
+const GENESIS_HASH: &str = "7da2ebe561b3066501711a2f8dd54e1de5e5c19cd14bc4c51d31408d6faf8841";
+
+pub fn new_document() -> Result<AutoCommit> {
+    let mut autocommit = AutoCommit::new().with_actor(ActorId::from(GENESIS_ACTOR));
+    // built by hand rather than reconciled from `TodoState`, so adding a field to it can't
+    // change the genesis, other root objects are created when they're first used
+    autocommit.put_object(ROOT, "lists", ObjType::List)?;
+    autocommit.commit_with(CommitOptions::default().with_time(0));
+    Ok(autocommit.with_actor(ActorId::random()))
+}
+
+/// Loads the save file, or salvages what it can from it and moves it aside if it won't load
+fn open(path: &Path) -> Result<(AutoCommit, Option<Recovery>)> {
+    let Some(data) = storage::load(path)? else {
+        debug!("creating new file");
+        return Ok((new_document()?, None));
+    };
+
+    let err = match AutoCommit::load(&data) {
+        Ok(mut autocommit) => {
+            assign_missing_ids(&mut autocommit)?;
+            return Ok((autocommit, None));
+        }
+        Err(err) => err,
+    };
+    error!("Could not load save file {}: {err}", path.display());
+
+    let Some(moved_to) = storage::quarantine(path, &data)? else {
+        // another process saved over it in the meantime
+        return open(path);
+    };
+    warn!("Moved unreadable save file to {}", moved_to.display());
+
+    // salvaged changes sit on the shared genesis, so peers can sync the rest into it
+    let mut autocommit = new_document()?;
+    let changes = storage::salvage(&data, &mut autocommit);
+    assign_missing_ids(&mut autocommit)?;
+    storage::save(path, &mut autocommit)?;
+
+    Ok((autocommit, Some(Recovery { moved_to, changes })))
+}
+
 impl SiteState {
     async fn new(file_location: PathBuf, change_tx: TokioSender<TodoEvent>) -> Result<Self> {
-        let file_data = {
-            tokio::task::spawn_blocking(move || -> std::io::Result<Option<Vec<u8>>> {
-                match std::fs::File::open(&file_location) {
-                    Ok(file) => {
-                        let mut data = Vec::new();
-                        std::io::Read::read_to_end(&mut &file, &mut data)?;
-                        Ok(Some(data))
-                    }
-                    Err(_) => Ok(None),
-                }
-            })
-            .await?
-        }?;
+        let (commit, recovery) =
+            tokio::task::spawn_blocking(move || open(&file_location)).await??;
 
-        let commit = if let Some(file) = file_data {
-            let autocommit = AutoCommit::load(&file)?;
-            let state = hydrate(&autocommit)?;
-            change_tx.send(TodoEvent::StateUpdate(state)).await?;
-            autocommit
-        } else {
-            debug!("creating new file");
-            let mut autocommit = AutoCommit::new();
-            let state: TodoState = TodoState::default();
-            reconcile(&mut autocommit, state.clone())?;
-            change_tx.send(TodoEvent::StateUpdate(state)).await?;
-            autocommit
-        };
+        change_tx
+            .send(TodoEvent::StateUpdate(hydrate(&commit)?))
+            .await?;
+        if let Some(recovery) = recovery {
+            change_tx.send(TodoEvent::Recovered(recovery)).await?;
+        }
 
         Ok(Self {
+            epoch: compaction::epoch(&commit),
             commit,
             alive: BTreeMap::new(),
+            peers: HashMap::new(),
+            gap_request: None,
+            undo: HashMap::new(),
+            peer_epochs: HashMap::new(),
+            unsynced: None,
         })
     }
 
+    /// Compacts the document down to its current state, peers move onto it when they hear
+    fn compact(&mut self) -> Result<bool> {
+        let epoch = compaction::next(&mut self.commit);
+        self.commit = compaction::compact(&mut self.commit, &epoch)?;
+        info!("Compacted the document, now on epoch {}", epoch.number);
+        self.enter(epoch);
+        Ok(true)
+    }
+
+    /// Moves onto a later compaction another site made, returning a status to show for it
+    fn migrate(&mut self, epoch: Epoch) -> Result<String> {
+        self.unsynced = compaction::migrate(&mut self.commit, &epoch)?;
+        let status = if self.unsynced.is_none() {
+            format!("Moved onto compacted history, epoch {}", epoch.number)
+        } else {
+            format!(
+                "Resyncing compacted history, epoch {}, unsynced edits are put back once it has",
+                epoch.number
+            )
+        };
+        info!("{status}");
+        self.enter(epoch);
+        Ok(status)
+    }
+
+    /// Puts back what we had before a compaction we couldn't rebuild, once it has synced
+    fn restore_unsynced(&mut self) -> Result<()> {
+        if let Some(unsynced) = &self.unsynced
+            && compaction::restore(&mut self.commit, &self.epoch, unsynced)?
+        {
+            info!("Put unsynced edits back onto epoch {}", self.epoch.number);
+            self.unsynced = None;
+        }
+        Ok(())
+    }
+
+    /// Loads changes a site sent us, see [`Self::request_missing`] for any they depend on
+    fn load_changes(&mut self, changes: &[u8]) -> Result<()> {
+        self.commit.load_incremental(changes)?;
+        self.restore_unsynced()
+    }
+
+    /// Starts over on `epoch` with the document it now holds, every sync session included
+    fn enter(&mut self, epoch: Epoch) {
+        // the new history goes to peers through the sync, never as a delta they'd merge blindly
+        self.commit.save_incremental();
+        self.peers.clear();
+        self.gap_request = None;
+        self.epoch = epoch;
+    }
+
+    /// Whether a site is on the same compaction as us, so we can exchange changes with it
+    fn in_epoch(&self, peer: u32) -> bool {
+        self.peer_epochs.get(&peer) == Some(&self.epoch)
+    }
+
+    /// Our heartbeat, with our heads and the compaction they're on
+    fn alive(&mut self) -> SyncMessage {
+        SyncMessage::Alive {
+            heads: self
+                .commit
+                .get_heads()
+                .into_iter()
+                .map(|hash| hash.0)
+                .collect(),
+            epoch: self.epoch.clone(),
+        }
+    }
+
+    /// Applies a client's edit, remembering how to take it back
+    fn edit(&mut self, client: u64, command: TodoCommand) -> Result<bool> {
+        let typing = typing_into(&command);
+        let stacks = self.undo.entry(client).or_default();
+        // typing on in the same field is taken back along with the edit that started it
+        let inverse = match typing.is_some() && stacks.typing == typing {
+            true => None,
+            false => Some(ops::inverse(&self.commit, &command)?),
+        };
+        if !ops::apply(&mut self.commit, command)? {
+            return Ok(false);
+        }
+
+        if let Some(inverse) = inverse {
+            if stacks.undo.len() == UNDO_LIMIT {
+                stacks.undo.pop_front();
+            }
+            stacks.undo.push_back(inverse);
+        }
+        stacks.typing = typing;
+        stacks.redo.clear();
+        Ok(true)
+    }
+
+    fn undo(&mut self, client: u64) -> Result<bool> {
+        let Some(commands) = self.stacks(client).undo.pop_back() else {
+            return Ok(false);
+        };
+        let (changed, inverse) = self.apply_all(commands)?;
+        if changed {
+            self.stacks(client).redo.push_back(inverse);
+        }
+        Ok(changed)
+    }
+
+    fn redo(&mut self, client: u64) -> Result<bool> {
+        let Some(commands) = self.stacks(client).redo.pop_back() else {
+            return Ok(false);
+        };
+        let (changed, inverse) = self.apply_all(commands)?;
+        if changed {
+            self.stacks(client).undo.push_back(inverse);
+        }
+        Ok(changed)
+    }
+
+    /// The client's undo history, done typing as far as it's concerned
+    fn stacks(&mut self, client: u64) -> &mut UndoStacks {
+        let stacks = self.undo.entry(client).or_default();
+        stacks.typing = None;
+        stacks
+    }
+
+    /// Applies compensating commands as a new change, returning whether any applied and how to
+    /// take them back in turn.
+    ///
+    /// Whatever other sites have done since is left alone, a command whose list or item has
+    /// since been removed just doesn't apply.
+    fn apply_all(&mut self, commands: Vec<TodoCommand>) -> Result<(bool, Vec<TodoCommand>)> {
+        let mut changed = false;
+        let mut inverse = Vec::new();
+        for command in commands {
+            let mut take_back = ops::inverse(&self.commit, &command)?;
+            if ops::apply(&mut self.commit, command)? {
+                changed = true;
+                // the last applied is the first to take back
+                take_back.append(&mut inverse);
+                inverse = take_back;
+            }
+        }
+        Ok((changed, inverse))
+    }
+
     async fn update_aliveness(
         &mut self,
         tx: &TokioSender<TodoEvent>,
@@ -197,6 +752,12 @@ impl SiteState {
             self.alive.insert(id, Instant::now());
         }
         self.alive.retain(|_, time| time.elapsed() < ALIVE_TIMEOUT);
+        // a site that comes back will announce itself and start a new session
+        let alive = &self.alive;
+        self.peers
+            .retain(|id, _| alive.contains_key(id) || Some(*id) == incoming_site_id);
+        self.peer_epochs
+            .retain(|id, _| alive.contains_key(id) || Some(*id) == incoming_site_id);
 
         let alive_count_after = self.alive.len();
 
@@ -216,22 +777,87 @@ impl SiteState {
         Ok(())
     }
 
-    async fn merge(&mut self, val: Vec<u8>) -> Result<()> {
-        let mut other = AutoCommit::load(&val)?;
+    /// Starts a new sync session with a site, returning the first message to send it
+    fn start_sync(&mut self, peer: u32) -> Option<SyncMessage> {
+        self.peers.insert(peer, sync::State::new());
+        self.generate_sync(peer)
+    }
 
-        let current_value: TodoState = hydrate(&self.commit)?;
+    /// Generates the next sync message for a site, if it is missing anything we have
+    fn generate_sync(&mut self, peer: u32) -> Option<SyncMessage> {
+        let state = self.peers.entry(peer).or_default();
+        self.commit
+            .sync()
+            .generate_sync_message(state)
+            .map(|message| SyncMessage::Sync {
+                to: peer,
+                message: message.encode(),
+            })
+    }
 
-        // If this is a newly initialised instance
-        if current_value.lists.is_empty() {
-            debug!("Pristine state, replacing ours with theirs");
-            let new = other.with_actor(self.commit.get_actor().clone());
+    /// Applies a sync message from a site, returning whether our document changed
+    fn receive_sync(&mut self, peer: u32, message: &[u8]) -> Result<bool> {
+        let message = sync::Message::decode(message)?;
+        let heads_before = self.commit.get_heads();
 
-            self.commit = new;
-        } else {
-            self.commit.merge(&mut other)?;
+        let state = self.peers.entry(peer).or_default();
+        self.commit.sync().receive_sync_message(state, message)?;
+        self.restore_unsynced()?;
+
+        Ok(self.commit.get_heads() != heads_before)
+    }
+
+    /// Asks `from` for any changes we hold dependents of but are missing ourselves.
+    ///
+    /// `load_incremental` queues changes whose dependencies haven't arrived yet, which
+    /// happens whenever a `DeltaChange` datagram is lost. Requests to the same site
+    /// are rate limited by [`GAP_RETRY`] so we don't ask again while a reply is in flight.
+    fn request_missing(&mut self, from: u32) -> Option<SyncMessage> {
+        let missing = self.commit.get_missing_deps(&[]);
+
+        if missing.is_empty() {
+            self.gap_request = None;
+            return None;
         }
 
-        Ok(())
+        if let Some((site, at)) = self.gap_request
+            && site == from
+            && at.elapsed() < GAP_RETRY
+        {
+            return None;
+        }
+
+        debug!(
+            "Missing {} changes, requesting from Site:{}",
+            missing.len(),
+            from
+        );
+        self.gap_request = Some((from, Instant::now()));
+
+        Some(SyncMessage::RequestChanges {
+            to: from,
+            hashes: missing.into_iter().map(|hash| hash.0).collect(),
+        })
+    }
+
+    /// Whether a site has advertised heads that include changes we don't have
+    fn has_unknown_heads(&mut self, heads: &[[u8; 32]]) -> bool {
+        heads
+            .iter()
+            .any(|hash| self.commit.get_change_by_hash(&ChangeHash(*hash)).is_none())
+    }
+
+    /// Collects the requested changes we have, for replying to a `RequestChanges`
+    fn changes_for(&mut self, to: u32, hashes: &[[u8; 32]]) -> Option<SyncMessage> {
+        let mut changes = Vec::new();
+
+        for hash in hashes {
+            if let Some(mut change) = self.commit.get_change_by_hash(&ChangeHash(*hash)) {
+                changes.extend_from_slice(&change.bytes());
+            }
+        }
+
+        (!changes.is_empty()).then_some(SyncMessage::Changes { to, changes })
     }
 
     async fn shutdown_site(
@@ -240,6 +866,8 @@ impl SiteState {
         incoming_site_id: u32,
     ) -> Result<()> {
         self.alive.remove(&incoming_site_id);
+        self.peers.remove(&incoming_site_id);
+        self.peer_epochs.remove(&incoming_site_id);
         tx.send(TodoEvent::ConnectionStatus(format!(
             "Site Disconnected, Connections: {}",
             self.alive.len()
@@ -266,27 +894,28 @@ type Site = Arc<RwLock<SiteState>>;
 /// ==[change_rx]====[change_tx]==========================================
 /// |  async_inner                                                        |
 /// |                                                                     |
-/// |  +----------------+                         +--------------------+  |
-/// |  | write_notify   |---[m_write_tx]--------->| write_to_multicast |---->UDP
-/// |  | Applies local  |                    +--->| + Alive every 1s   |  |
-/// |  | edits to CRDT  |                    |    +--------------------+  |
-/// |  +-------+--------+                    |    (restarted by select!)  |
-/// |          |                             |                            |
-/// |          |--[file_write_tx]--+         |                            |
-/// |                              v         |                            |
-/// |                      +----------------+|                            |
-/// |                      | save_to_file   ||                            |
-/// |                      | _task          ||----> disk                  |
-/// |                      +----------------+|                            |
-/// |                              ^         |                            |
-/// |          +--[file_write_tx]--+         |                            |
-/// |          |                             |                            |
-/// |  +-------+-----------+---[m_write_tx]--+                            |
-/// |  | read_notify       |                      +--------------------+  |
-/// |  | Merges remote     |<--[multi_write_rx]---| read_from_multicast|<----UDP
-/// |  | CRDT changes      |                      +--------------------+  |
-/// |  | Sends State/Req   |                      (restarted by select!)  |
-/// |  | directly to mcast |                                              |
+/// |  +----------------+            +---------+  +--------------------+  |
+/// |  | write_notify   |-[m_write]->| fan_out |=>| run_transport      |  |
+/// |  | Applies local  |       +--->|         |  | one per Transport: |<--->UDP
+/// |  | edits to CRDT  |       |    +---------+  | multicast, peers,  |<--->TCP
+/// |  +-------+--------+       |  [outbound_tx]  | in-memory channel  |  |
+/// |          |    heartbeat --+ Alive/heads 1s  +---------+----------+  |
+/// |          |                                            |             |
+/// |          |--[file_write_tx]--+                        |             |
+/// |                              v                        |             |
+/// |                      +----------------+               |             |
+/// |                      | save_to_file   |               |             |
+/// |                      | _task          |----> disk     |             |
+/// |                      +----------------+               |             |
+/// |                              ^                        |             |
+/// |          +--[file_write_tx]--+                        |             |
+/// |          |                                            |             |
+/// |  +-------+-----------+---[m_write_tx]--> fan_out      |             |
+/// |  | read_notify       |                                |             |
+/// |  | Merges remote     |<--[multi_write_rx]-------------+             |
+/// |  | CRDT changes      |                                              |
+/// |  | Replies to Sync   |                                              |
+/// |  | through m_write   |                                              |
 /// |  |                   |                                              |
 /// |  | +---------------+ |                                              |
 /// |  | | aliveness sub | |                                              |
@@ -295,9 +924,9 @@ type Site = Arc<RwLock<SiteState>>;
 /// |  +-------------------+                                              |
 /// |                                                                     |
 /// |  +-------------------+                                              |
-/// |  | network_watcher   |--[Arc<Notify>]--> select! loop restarts      |
-/// |  | OS interface      |                   read_from_multicast &      |
-/// |  | monitor           |                   write_to_multicast         |
+/// |  | network_watcher   |--[Arc<Notify>]--> every run_transport        |
+/// |  | OS interface      |                   reconnects its transport   |
+/// |  | monitor           |                                              |
 /// |  +-------------------+                                              |
 /// |                                                                     |
 /// =====================================================================
@@ -305,6 +934,7 @@ type Site = Arc<RwLock<SiteState>>;
 /// Channels (created in async_inner):
 ///   file_write_tx/rx  : mpsc<OneshotSender<()>>(8) - trigger file save + ack
 ///   m_write_tx/rx     : mpsc<SyncMessage>(8)       - outbound messages to network
+///   outbound_tx       : broadcast<SyncMessage>     - fan_out's copy of them for each transport
 ///   multi_write_tx/rx : mpsc<ProtoMessage>(8)      - inbound messages from network
 ///   network_notify    : Arc<Notify>                - signals network interface changes
 ///
@@ -317,29 +947,33 @@ type Site = Arc<RwLock<SiteState>>;
 ///   2. network_watcher   - monitors OS network interfaces, fires Notify
 ///   3. write_notify      - processes local TodoCommands, mutates CRDT, sends Messages
 ///   4. read_notify       - processes remote Messages, merges CRDT, emits TodoEvents
-///                          sends State/RequestState directly to m_write_tx
+///                          sends per-site Sync protocol messages directly to m_write_tx
 ///      +- aliveness sub  - prunes stale sites every 1s, updates AliveConnections count
+///   5. fan_out           - copies m_write_rx into outbound_tx
+///   6. heartbeat         - sends our heads and epoch every 1s
 ///
-/// Restart loop (select!):
-///   read_from_multicast & write_to_multicast are NOT in the JoinSet.
-///   They are polled in a select! loop and restarted (with 10s backoff
-///   or immediately on network change) if either future completes/errors.
+/// Transports (polled by async_inner, not in the JoinSet):
+///   Each is driven by run_transport, which sends Announce on connect and
+///   reconnects it (with 10s backoff or immediately on network change)
+///   whenever it fails, independently of the others. They only finish once
+///   they've sent our Shutdown, then async_inner gives the rest a moment to
+///   send theirs, waits for the final save and returns, dropping the
+///   JoinSet tasks with it.
 /// ```
-#[instrument(skip(change_tx, change_rx))]
+#[instrument(skip(transports, change_tx, change_rx))]
 pub async fn async_inner(
     site_id: u32,
+    config: McastConfig,
+    transports: Vec<Box<dyn Transport>>,
     change_tx: TokioSender<TodoEvent>,
     change_rx: TokioReceiver<TodoCommand>,
 ) -> Result<()> {
-    let file_location: PathBuf = shellexpand::tilde(
-        &std::env::var("TODOMCP_AUTOSAVE_PATH").unwrap_or_else(|_| STORAGE_LOCATION.to_owned()),
-    )
-    .to_string()
-    .into();
+    let file_location = workspace::save_path(&config.autosave_path, &config.workspace);
 
     let (file_write_tx, file_write_rx) = tokio_channel::<OneshotSender<()>>(128);
-    let (m_write_tx, mut m_write_rx) = tokio_channel::<SyncMessage>(128);
+    let (m_write_tx, m_write_rx) = tokio_channel::<SyncMessage>(128);
     let (multi_write_tx, multi_write_rx) = tokio_channel::<ProtoMessage<SyncMessage>>(128);
+    let (outbound_tx, mut shutdown_rx) = broadcast_channel::<SyncMessage>(128);
 
     let network_notify = Arc::new(Notify::new());
 
@@ -351,17 +985,24 @@ pub async fn async_inner(
 
     join_set.spawn(save_to_file_task(
         file_location,
+        Retention::from(&config),
         site.clone(),
+        change_tx.clone(),
         file_write_rx,
     ));
 
     join_set.spawn(network_watcher(network_notify.clone()));
 
+    join_set.spawn(fan_out(m_write_rx, outbound_tx.clone()));
+
+    join_set.spawn(heartbeat(site.clone(), m_write_tx.clone()));
+
     let m_write_tx_read = m_write_tx.clone();
 
     join_set.spawn(write_notify(
         site.clone(),
         change_rx,
+        change_tx.clone(),
         m_write_tx,
         file_write_tx.clone(),
     ));
@@ -375,49 +1016,48 @@ pub async fn async_inner(
         file_write_tx.clone(),
     ));
 
-    let mut read_task = read_from_multicast(multi_write_tx.clone());
-    let mut write_task = write_to_multicast(site_id, site.clone(), &mut m_write_rx);
-
-    loop {
-        tokio::select! {
-            _ = network_notify.notified() => {
-                debug!("network watcher notified");
-                change_tx.send(TodoEvent::ConnectionStatus("Network status changed".into())).await?;
-            }
-            result = read_task => {
-                // the write task has finished with the receiver, we need to reinitialize
-                if let Err(err) = result {
-                    change_tx.send(TodoEvent::ConnectionStatus("Error reading from network, trying reconnect in 10s".into())).await?;
-                    error!("Error reading from multicast sleeping 10s and trying again, {err:?}");
-                }
-            }
-            result = write_task => {
+    let mut running: FuturesUnordered<_> = transports
+        .into_iter()
+        .map(|transport| {
+            run_transport(
+                transport,
+                multi_write_tx.clone(),
+                outbound_tx.subscribe(),
+                network_notify.clone(),
+                change_tx.clone(),
+            )
+        })
+        .collect();
 
-                // the write task has finished with the receiver, we need to reinitialize
-                if let Err(err) = result {
-                    change_tx.send(TodoEvent::ConnectionStatus("Error writing to network, trying reconnect in 10s".into())).await?;
-                    error!("Error writing to multicast sleeping 10s and trying again, {err:?}");
-                }
-            }
-        }
+    // The transports reconnect themselves, so they only finish at shutdown
+    tokio::select! {
+        _ = shutdown_requested(&mut shutdown_rx) => {}
+        _ = async { while running.next().await.is_some() {} } => {}
+    }
 
-        let sleep = tokio::time::sleep(std::time::Duration::from_secs(10));
+    tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while running.next().await.is_some() {}
+    })
+    .await
+    .ok();
+
+    // Saves are handled in order so once this one is done the shutdown save is too,
+    // and the tasks can be dropped
+    let (saved_tx, saved_rx) = oneshot_channel();
+    if file_write_tx.send(saved_tx).await.is_ok() {
+        saved_rx.await.ok();
+    }
+    debug!("Shut down, stopping tasks");
+    Ok(())
+}
 
-        tokio::select! {
-            _ = sleep => {
-                debug!("sleep finished, trying again");
-            },
-            _ = network_notify.notified() => {
-                debug!("network watcher notified, trying again");
-            }
+/// Waits for our `Shutdown` to be sent, which is the last thing a site sends
+async fn shutdown_requested(rx: &mut BroadcastReceiver<SyncMessage>) {
+    loop {
+        match rx.recv().await {
+            Ok(SyncMessage::Shutdown) | Err(RecvError::Closed) => return,
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
         }
-
-        change_tx
-            .send(TodoEvent::ConnectionStatus("Reconnecting".into()))
-            .await?;
-
-        read_task = read_from_multicast(multi_write_tx.clone());
-        write_task = write_to_multicast(site_id, site.clone(), &mut m_write_rx);
     }
 }
 
@@ -441,33 +1081,84 @@ async fn network_watcher(notify: Arc<Notify>) -> Result<()> {
     }
 }
 
-/// Saves the state to the file at the given location
+/// Saves the state to the file at the given location, snapshotting it as `retention` allows
+/// and before replacing it with a compaction
 async fn save_to_file_task(
     path_buf: PathBuf,
+    retention: Retention,
     site: Site,
+    change_tx: TokioSender<TodoEvent>,
     mut rx: TokioReceiver<OneshotSender<()>>,
 ) -> Result<()> {
-    if let Some(parent) = path_buf.parent() {
-        tokio::fs::create_dir_all(parent).await.with_context(|| {
-            format!(
-                "Could not create parent dir for writing: {}",
-                parent.display()
-            )
-        })?;
-    }
+    let mut saved_epoch = site.read().await.epoch.clone();
 
     while let Some(sender) = rx.recv().await {
-        debug!("Save State Called");
-        let state = {
+        loop {
+            debug!("Save State Called");
             // we don't want to stuff up the save_incremental stuff so we save a clone
-            site.read().await.commit.clone().save()
-        };
-        debug!("Grabbed State");
+            let (mut doc, epoch) = {
+                let slock = site.read().await;
+                (slock.commit.clone(), slock.epoch.clone())
+            };
+            debug!("Grabbed State");
+
+            if compaction::epoch(&doc) != epoch {
+                // saving now would put the old history back, it's still in the file
+                debug!("Not saving until epoch {} has synced", epoch.number);
+                break;
+            }
 
-        tokio::fs::write(&path_buf, &state)
-            .await
+            let path = path_buf.clone();
+            let compacted = epoch > saved_epoch;
+            let (mut doc, saved) = tokio::task::spawn_blocking(move || {
+                if compacted && let Err(err) = backup::snapshot_now(&path, retention) {
+                    warn!(
+                        "Could not snapshot {} before compaction: {err:?}",
+                        path.display()
+                    );
+                }
+                let saved = storage::save(&path, &mut doc)?;
+                // a missed snapshot isn't worth failing the save over
+                if let Err(err) = backup::snapshot(&path, retention) {
+                    warn!("Could not snapshot {}: {err:?}", path.display());
+                }
+                Ok::<_, anyhow::Error>((doc, saved))
+            })
+            .await?
             .with_context(|| format!("could not save to path {}", path_buf.display()))?;
 
+            match saved {
+                Saved::Written { merged } => {
+                    let mut slock = site.write().await;
+                    // unless we've compacted since, when merging would put the old history back
+                    if merged && slock.epoch == epoch {
+                        // another process saved changes we hadn't seen, they're ours now too
+                        debug!("Merged changes from the save file");
+                        slock.commit.merge(&mut doc)?;
+                        let new_value: TodoState = hydrate(&slock.commit)?;
+                        change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
+                    }
+                    saved_epoch = epoch;
+                    break;
+                }
+                Saved::Superseded(mut on_disk) => {
+                    // another process sharing the file compacted it, move onto that and save again
+                    let mut slock = site.write().await;
+                    let on_disk_epoch = compaction::epoch(&on_disk);
+                    if on_disk_epoch > slock.epoch {
+                        let status = slock.migrate(on_disk_epoch.clone())?;
+                        change_tx.send(TodoEvent::ConnectionStatus(status)).await?;
+                    }
+                    if on_disk_epoch == slock.epoch {
+                        slock.commit.merge(&mut on_disk)?;
+                        slock.restore_unsynced()?;
+                        let new_value: TodoState = hydrate(&slock.commit)?;
+                        change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
+                    }
+                }
+            }
+        }
+
         debug!("Wrote State, notifying");
         // Notify the receiver that we have saved
         sender.send(()).ok();
@@ -475,6 +1166,7 @@ async fn save_to_file_task(
 
     Ok(())
 }
+
 // Reads packets from the multicast group and updates local state if necessary
 #[instrument(skip_all, fields(site_id = site_id))]
 pub async fn read_notify(
@@ -485,7 +1177,6 @@ pub async fn read_notify(
     m_write_tx: TokioSender<SyncMessage>,
     write_tx: TokioSender<OneshotSender<()>>,
 ) -> Result<()> {
-    let mut state_set = HashSet::new();
     let mut join_set = JoinSet::new();
 
     let bg_site = site.clone();
@@ -522,74 +1213,138 @@ pub async fn read_notify(
         match message {
             SyncMessage::DeltaChange(val) => {
                 debug!("Site:{} DeltaChange:{}", incoming_site_id, val.len());
-                // If we've seen this site before
-                if state_set.contains(&incoming_site_id) {
-                    let mut wrt = site.write().await;
-                    wrt.commit.load_incremental(&val)?;
+                let mut wrt = site.write().await;
+                if !wrt.in_epoch(incoming_site_id) {
+                    continue;
+                }
+                // If we're already syncing with this site
+                if wrt.peers.contains_key(&incoming_site_id) {
+                    wrt.load_changes(&val)?;
 
                     let new_value: TodoState = hydrate(&wrt.commit)?;
                     should_notify_save = true;
                     change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
-                } else {
-                    // request the full state
-                    m_write_tx
-                        .send(SyncMessage::RequestState(incoming_site_id))
-                        .await?;
+
+                    // the site that made this change has everything it depends on
+                    if let Some(request) = wrt.request_missing(incoming_site_id) {
+                        m_write_tx.send(request).await?;
+                    }
+                } else if let Some(reply) = wrt.start_sync(incoming_site_id) {
+                    // catch up on everything we're missing from them
+                    m_write_tx.send(reply).await?;
                 }
             }
+            SyncMessage::Announce => {
+                debug!("Announce from Site:{}", incoming_site_id);
+                let mut wrt = site.write().await;
 
-            message @ (SyncMessage::State(_) | SyncMessage::Announce(_)) => {
-                let is_announce = matches!(message, SyncMessage::Announce(_));
+                if !wrt.in_epoch(incoming_site_id) {
+                    // tell them which compaction we're on, we sync once we know theirs
+                    let alive = wrt.alive();
+                    m_write_tx.send(alive).await?;
+                } else if let Some(reply) = wrt.start_sync(incoming_site_id) {
+                    m_write_tx.send(reply).await?;
+                }
+            }
+            SyncMessage::Sync { to, message } => {
+                // sync messages are addressed to a single site
+                if to != site_id {
+                    continue;
+                }
+                debug!("Site:{} Sync:{}", incoming_site_id, message.len());
+                let mut wrt = site.write().await;
+                if !wrt.in_epoch(incoming_site_id) {
+                    continue;
+                }
 
-                let val = match message {
-                    SyncMessage::State(val) => val,
-                    SyncMessage::Announce(val) => val,
-                    _ => unreachable!(),
-                };
+                if wrt.receive_sync(incoming_site_id, &message)? {
+                    let new_value: TodoState = hydrate(&wrt.commit)?;
+                    change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
+                    should_notify_save = true;
+                }
 
-                if is_announce {
-                    debug!(
-                        "Announce from Site:{}, State:{}, sending our state",
-                        incoming_site_id,
-                        val.len()
-                    );
-                } else {
-                    debug!("Site:{} State:{}", incoming_site_id, val.len());
+                if let Some(reply) = wrt.generate_sync(incoming_site_id) {
+                    m_write_tx.send(reply).await?;
+                }
+            }
+            SyncMessage::RequestChanges { to, hashes } => {
+                if to != site_id {
+                    continue;
                 }
+                debug!("Site:{} RequestChanges:{}", incoming_site_id, hashes.len());
                 let mut wrt = site.write().await;
+                if !wrt.in_epoch(incoming_site_id) {
+                    continue;
+                }
 
-                wrt.merge(val).await?;
-
-                let new_value: TodoState = hydrate(&wrt.commit)?;
+                if let Some(reply) = wrt.changes_for(incoming_site_id, &hashes) {
+                    m_write_tx.send(reply).await?;
+                }
+            }
+            SyncMessage::Changes { to, changes } => {
+                if to != site_id {
+                    continue;
+                }
+                debug!("Site:{} Changes:{}", incoming_site_id, changes.len());
+                let mut wrt = site.write().await;
+                if !wrt.in_epoch(incoming_site_id) {
+                    continue;
+                }
 
-                change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
-                state_set.insert(incoming_site_id);
+                wrt.load_changes(&changes)?;
 
+                let new_value: TodoState = hydrate(&wrt.commit)?;
                 should_notify_save = true;
+                change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
 
-                if is_announce {
-                    m_write_tx
-                        .send(SyncMessage::State(wrt.commit.save()))
-                        .await?;
+                // the changes we just got may themselves depend on ones we don't have
+                if let Some(request) = wrt.request_missing(incoming_site_id) {
+                    m_write_tx.send(request).await?;
                 }
             }
-            SyncMessage::RequestState(requested_site_id) => {
-                debug!(
-                    "Site:{} RequestState:{}",
-                    incoming_site_id, requested_site_id
-                );
-                if requested_site_id == site_id {
-                    m_write_tx
-                        .send(SyncMessage::State(site.write().await.commit.save()))
-                        .await?;
-                }
-            }
-            SyncMessage::Alive => {
+            SyncMessage::Alive { heads, epoch } => {
                 debug!("Alive from Site:{}", incoming_site_id);
                 let mut wrt = site.write().await;
 
                 wrt.update_aliveness(&change_tx, Some(incoming_site_id))
                     .await?;
+
+                let known = wrt.peer_epochs.insert(incoming_site_id, epoch.clone());
+
+                match epoch.cmp(&wrt.epoch) {
+                    // they move onto ours once they hear our heartbeat
+                    Ordering::Less => {}
+                    Ordering::Greater => {
+                        let status = wrt.migrate(epoch)?;
+                        let new_value: TodoState = hydrate(&wrt.commit)?;
+                        change_tx.send(TodoEvent::StateUpdate(new_value)).await?;
+                        change_tx.send(TodoEvent::ConnectionStatus(status)).await?;
+                        should_notify_save = true;
+
+                        if let Some(reply) = wrt.start_sync(incoming_site_id) {
+                            m_write_tx.send(reply).await?;
+                        }
+                    }
+                    Ordering::Equal => {
+                        // We've just learned they're on our compaction, or they have something
+                        // we don't and we've diverged. Whichever side is behind notices this
+                        // from the other's heartbeat and starts a sync
+                        if known.as_ref() != Some(&epoch) || wrt.has_unknown_heads(&heads) {
+                            debug!("Site:{} has unknown heads, starting sync", incoming_site_id);
+                            if let Some(reply) = wrt.start_sync(incoming_site_id) {
+                                m_write_tx.send(reply).await?;
+                            }
+                        }
+
+                        // retry if the site we asked for missing changes never answered
+                        if let Some((gap_site, _)) = wrt.gap_request
+                            && gap_site == incoming_site_id
+                            && let Some(request) = wrt.request_missing(incoming_site_id)
+                        {
+                            m_write_tx.send(request).await?;
+                        }
+                    }
+                }
             }
             SyncMessage::Shutdown => {
                 debug!("Shutdown from Site:{}", incoming_site_id);
@@ -606,155 +1361,73 @@ pub async fn read_notify(
     return Ok(());
 }
 
-// Reads packets from the multicast group and updates local state if necessary
-#[instrument(skip_all)]
-pub async fn read_from_multicast(
-    multi_write_tx: TokioSender<ProtoMessage<SyncMessage>>,
-) -> Result<()> {
-    let mut receiver = McastReceiver::<SyncMessage>::new()?;
-
-    while let Some(message) = receiver.try_next().await? {
-        multi_write_tx.send(message).await?;
+/// The item and text field a command types into, if it's a text edit
+fn typing_into(command: &TodoCommand) -> Option<(Uuid, &'static str)> {
+    match command {
+        TodoCommand::RenameTodo { item_id, .. } => Some((*item_id, "text")),
+        TodoCommand::SetNotes { item_id, .. } => Some((*item_id, "notes")),
+        _ => None,
     }
-
-    Ok(())
 }
 
 #[instrument(skip_all)]
 pub async fn write_notify(
     site: Site,
     mut change_rx: TokioReceiver<TodoCommand>,
+    event_tx: TokioSender<TodoEvent>,
     change_tx: TokioSender<SyncMessage>,
     write_tx: TokioSender<OneshotSender<()>>,
 ) -> Result<()> {
     while let Some(change) = change_rx.recv().await {
+        let (client, change) = match change {
+            TodoCommand::FromClient { client, command } => (client, *command),
+            change => (LOCAL_CLIENT, change),
+        };
         let mut slock = site.write().await;
         let mut should_notify_save = false;
 
-        let mut current_state: TodoState = hydrate(&slock.commit)?;
-
-        let to_send = match change {
-            // List operations
-            TodoCommand::AddList { title, metadata } => {
-                current_state.lists.push(TodoList {
-                    title,
-                    items: vec![],
-                    metadata,
-                });
-                reconcile(&mut slock.commit, current_state)?;
-
-                should_notify_save = true;
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
-            }
-            TodoCommand::RemoveList { list_index } => {
-                if list_index < current_state.lists.len() {
-                    current_state.lists.remove(list_index);
-
-                    reconcile(&mut slock.commit, current_state)?;
-
-                    should_notify_save = true;
-                }
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
-            }
-            TodoCommand::RenameList { list_index, title } => {
-                if list_index < current_state.lists.len() {
-                    current_state.lists[list_index].title = title;
-
-                    reconcile(&mut slock.commit, current_state)?;
-
-                    should_notify_save = true;
-                }
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
+        match change {
+            TodoCommand::ClientGone { client } => {
+                slock.undo.remove(&client);
+                continue;
             }
-
-            // Item operations
-            TodoCommand::AddTodo {
-                list_index,
-                text,
-                metadata,
-            } => {
-                if list_index < current_state.lists.len() {
-                    current_state.lists[list_index].items.push(TodoItem {
-                        text,
-                        completed: false,
-                        metadata,
-                    });
-
-                    reconcile(&mut slock.commit, current_state)?;
-
-                    should_notify_save = true;
-                }
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
-            }
-            TodoCommand::RenameTodo {
-                list_index,
-                item_index,
-                text,
-            } => {
-                if list_index < current_state.lists.len() {
-                    if let Some(item) = current_state.lists[list_index].items.get_mut(item_index) {
-                        item.text = text;
-                    }
-
-                    reconcile(&mut slock.commit, current_state)?;
-
-                    should_notify_save = true;
-                }
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
+            TodoCommand::GetDocument => {
+                let data = slock.commit.save();
+                event_tx.send(TodoEvent::Document { client, data }).await?;
+                continue;
             }
-            TodoCommand::ToggleTodo {
-                list_index,
-                item_index,
-            } => {
-                if list_index < current_state.lists.len() {
-                    let list = &mut current_state.lists[list_index];
-                    if item_index < list.items.len() {
-                        list.items[item_index].completed = !list.items[item_index].completed;
-
-                        reconcile(&mut slock.commit, current_state)?;
+            _ => {}
+        }
 
-                        should_notify_save = true;
-                    }
-                }
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
+        let to_send = match change {
+            TodoCommand::Shutdown { sender } => {
+                write_tx.send(sender).await.ok();
+                SyncMessage::Shutdown
             }
-            TodoCommand::RemoveTodo {
-                list_index,
-                item_index,
-            } => {
-                if list_index < current_state.lists.len() {
-                    let list = &mut current_state.lists[list_index];
-                    if item_index < list.items.len() {
-                        list.items.remove(item_index);
-
-                        reconcile(&mut slock.commit, current_state)?;
-
-                        should_notify_save = true;
-                    }
-                }
-                SyncMessage::DeltaChange(slock.commit.save_incremental())
+            // peers hear of the compaction from our heartbeat, and sync it from us
+            TodoCommand::Compact => {
+                should_notify_save = slock.compact()?;
+                slock.alive()
             }
-            TodoCommand::ClearCompleted { list_index } => {
-                if list_index < current_state.lists.len() {
-                    current_state.lists[list_index]
-                        .items
-                        .retain(|item| !item.completed);
-
-                    reconcile(&mut slock.commit, current_state)?;
-
-                    should_notify_save = true;
+            command => {
+                should_notify_save = match command {
+                    TodoCommand::Undo => slock.undo(client)?,
+                    TodoCommand::Redo => slock.redo(client)?,
+                    command => slock.edit(client, command)?,
+                };
+                if should_notify_save {
+                    slock.commit.commit_with(history::commit_options());
                 }
                 SyncMessage::DeltaChange(slock.commit.save_incremental())
             }
-
-            TodoCommand::Shutdown { sender } => {
-                write_tx.send(sender).await.ok();
-                SyncMessage::Shutdown
-            }
         };
 
         if should_notify_save {
             write_tx.try_send(oneshot_channel().0).ok();
+
+            // clients sharing this site through the daemon only learn of each other's edits here
+            let new_value: TodoState = hydrate(&slock.commit)?;
+            event_tx.send(TodoEvent::StateUpdate(new_value)).await?;
         }
 
         change_tx.send(to_send).await?;
@@ -762,45 +1435,217 @@ pub async fn write_notify(
     Ok(())
 }
 
-#[instrument(skip_all, fields(site_id = site_id))]
-pub async fn write_to_multicast(
-    site_id: u32,
-    site: Site,
-    recv: &mut TokioReceiver<SyncMessage>,
+/// Copies every outgoing message to each transport, multicast and peers
+async fn fan_out(
+    mut m_write_rx: TokioReceiver<SyncMessage>,
+    outbound_tx: BroadcastSender<SyncMessage>,
 ) -> Result<()> {
-    let mut mcast_sender = McastSender::new(site_id)?;
+    while let Some(message) = m_write_rx.recv().await {
+        // only fails while every transport is restarting, heartbeats catch them up after
+        outbound_tx.send(message).ok();
+    }
+    Ok(())
+}
 
-    let mut announce_interval = tokio::time::interval(Duration::from_secs(1));
-
-    debug!("sending announce");
-    // send the initial announce message
-    mcast_sender
-        .send(SyncMessage::Announce(
-            site.read().await.commit.clone().save(),
-        ))
-        .await?;
+/// Sends our heads every [`HEARTBEAT_INTERVAL`], so sites know we're alive and when they've diverged
+async fn heartbeat(site: Site, m_write_tx: TokioSender<SyncMessage>) -> Result<()> {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
 
     loop {
-        tokio::select! {
-            _ = announce_interval.tick() => {
-                mcast_sender.send(SyncMessage::Alive).await?;
-            }
+        interval.tick().await;
+        let alive = site.write().await.alive();
+        m_write_tx.send(alive).await?;
+    }
+}
 
-            message = recv.recv() => {
-                if let Some(message) = message {
-                    match message {
-                        SyncMessage::Shutdown => {
-                            mcast_sender.send(message).await?;
-                            return Ok(());
-                        }
-                        other => {
-                            mcast_sender.send(other).await?;
-                        }
-                    }
-                } else {
-                    return Ok(())
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn corrupt_save_is_moved_aside_and_salvaged() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-recover-{}", Uuid::new_v4()));
+        let path = dir.join("automerge.save");
+
+        let mut doc = new_document().unwrap();
+        let heads = doc.get_heads();
+        let list_id = Uuid::new_v4();
+        ops::apply(
+            &mut doc,
+            TodoCommand::AddList {
+                list_id,
+                title: "Kept".into(),
+                metadata: HashMap::new(),
+            },
+        )
+        .unwrap();
+
+        // a good change after a truncated document
+        let mut data = doc.save();
+        data.truncate(data.len() / 2);
+        data.extend(doc.save_after(&heads));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, &data).unwrap();
+
+        let (doc, recovery) = open(&path).unwrap();
+        let recovery = recovery.expect("save file should not have loaded");
+        assert_eq!(recovery.changes, 1);
+        assert_eq!(std::fs::read(&recovery.moved_to).unwrap(), data);
+
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert!(state.list(list_id).is_some());
+
+        // what was salvaged is saved in its place, so it loads next time
+        let (_, recovery) = open(&path).unwrap();
+        assert!(recovery.is_none());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn genesis_never_changes() {
+        let mut doc = new_document().unwrap();
+        let genesis = doc.get_changes(&[]);
+        assert_eq!(genesis.len(), 1);
+        assert_eq!(genesis[0].hash().to_string(), GENESIS_HASH);
+        assert_eq!(hydrate::<_, TodoState>(&doc).unwrap(), TodoState::default());
+    }
+
+    fn site() -> SiteState {
+        SiteState {
+            commit: new_document().unwrap(),
+            alive: BTreeMap::new(),
+            peers: HashMap::new(),
+            gap_request: None,
+            undo: HashMap::new(),
+            epoch: Epoch::default(),
+            peer_epochs: HashMap::new(),
+            unsynced: None,
+        }
+    }
+
+    #[test]
+    fn undo_and_redo_local_edits() {
+        let mut site = site();
+        let lists = |site: &SiteState| hydrate::<_, TodoState>(&site.commit).unwrap().lists;
+
+        let list_id = Uuid::new_v4();
+        site.edit(
+            LOCAL_CLIENT,
+            TodoCommand::AddList {
+                list_id,
+                title: "Groceries".into(),
+                metadata: HashMap::new(),
+            },
+        )
+        .unwrap();
+        let item_id = Uuid::new_v4();
+        site.edit(
+            LOCAL_CLIENT,
+            TodoCommand::AddTodo {
+                list_id,
+                item_id,
+                text: "milk".into(),
+                metadata: HashMap::new(),
+                due: None,
+                priority: Priority::None,
+                parent_id: None,
+                tags: Vec::new(),
+            },
+        )
+        .unwrap();
+        site.edit(LOCAL_CLIENT, TodoCommand::RemoveList { list_id })
+            .unwrap();
+        assert!(lists(&site).is_empty());
+
+        assert!(site.undo(LOCAL_CLIENT).unwrap());
+        assert_eq!(lists(&site)[0].items[0].text, "milk");
+
+        assert!(site.redo(LOCAL_CLIENT).unwrap());
+        assert!(lists(&site).is_empty());
+        assert!(!site.redo(LOCAL_CLIENT).unwrap());
+
+        assert!(site.undo(LOCAL_CLIENT).unwrap());
+        assert!(site.undo(LOCAL_CLIENT).unwrap());
+        assert!(lists(&site)[0].items.is_empty());
+
+        // a new edit can't be followed by redoing what was undone before it
+        site.edit(
+            LOCAL_CLIENT,
+            TodoCommand::RenameList {
+                list_id,
+                title: "Shopping".into(),
+            },
+        )
+        .unwrap();
+        assert!(!site.redo(LOCAL_CLIENT).unwrap());
+        assert!(site.undo(LOCAL_CLIENT).unwrap());
+        assert_eq!(lists(&site)[0].title, "Groceries");
+    }
+
+    #[test]
+    fn typing_undoes_together_and_clients_undo_their_own() {
+        let mut site = site();
+        let lists = |site: &SiteState| hydrate::<_, TodoState>(&site.commit).unwrap().lists;
+        let (list_id, item_id) = (Uuid::new_v4(), Uuid::new_v4());
+        site.edit(
+            1,
+            TodoCommand::AddList {
+                list_id,
+                title: "Groceries".into(),
+                metadata: HashMap::new(),
+            },
+        )
+        .unwrap();
+        site.edit(
+            1,
+            TodoCommand::AddTodo {
+                list_id,
+                item_id,
+                text: "milk".into(),
+                metadata: HashMap::new(),
+                due: None,
+                priority: Priority::None,
+                parent_id: None,
+                tags: Vec::new(),
+            },
+        )
+        .unwrap();
+        for text in ["oat", "oat milk"] {
+            let text = text.into();
+            let rename = TodoCommand::RenameTodo {
+                list_id,
+                item_id,
+                text,
+            };
+            site.edit(1, rename).unwrap();
+        }
+        for notes in ["semi", "semi skimmed"] {
+            let notes = notes.into();
+            let set = TodoCommand::SetNotes {
+                list_id,
+                item_id,
+                notes,
+            };
+            site.edit(1, set).unwrap();
         }
+        let title = "Shopping".into();
+        site.edit(2, TodoCommand::RenameList { list_id, title })
+            .unwrap();
+
+        assert!(site.undo(1).unwrap());
+        assert_eq!(lists(&site)[0].items[0].notes, "");
+        assert_eq!(lists(&site)[0].items[0].text, "oat milk");
+        assert!(site.undo(1).unwrap());
+        assert_eq!(lists(&site)[0].items[0].text, "milk");
+        assert_eq!(lists(&site)[0].title, "Shopping");
+
+        assert!(site.undo(2).unwrap());
+        assert_eq!(lists(&site)[0].title, "Groceries");
+        assert!(!site.undo(2).unwrap());
+        assert!(site.undo(1).unwrap());
+        assert!(lists(&site)[0].items.is_empty());
     }
 }
@@ -0,0 +1,48 @@
+//! Notes on items, and the collaborative text they and item text are stored as.
+//!
+//! Both are Automerge text objects rather than plain strings, and edits to them are applied as
+//! the characters that changed, so two sites editing the same item at once both keep their
+//! edits. Items from before this have their text as a plain string, which is read as it is and
+//! turned into a text object the next time it's edited.
+
+/// Stores a string as Automerge text, reading plain strings and missing values too, the latter
+/// as empty
+pub mod crdt {
+    use autosurgeon::{
+        Hydrate, HydrateError, Prop, ReadDoc, Reconciler, reconcile::TextReconciler,
+    };
+
+    struct Plain(String);
+
+    impl Hydrate for Plain {
+        fn hydrate_string(s: &'_ str) -> Result<Self, HydrateError> {
+            Ok(Self(s.to_string()))
+        }
+
+        fn hydrate_text<D: ReadDoc>(doc: &D, obj: &automerge::ObjId) -> Result<Self, HydrateError> {
+            Ok(Self(doc.text(obj)?))
+        }
+    }
+
+    pub fn hydrate<D: ReadDoc>(
+        doc: &D,
+        obj: &automerge::ObjId,
+        prop: Prop<'_>,
+    ) -> Result<String, HydrateError> {
+        // items from before notes have no key at all
+        if doc.get(obj, &prop)?.is_none() {
+            return Ok(String::new());
+        }
+        let Plain(text) = Hydrate::hydrate(doc, obj, prop)?;
+        Ok(text)
+    }
+
+    pub fn reconcile<R: Reconciler>(text: &str, mut reconciler: R) -> Result<(), R::Error> {
+        reconciler.text()?.update(text)
+    }
+}
+
+/// The first line of the notes, to show where there's no room for all of them
+pub fn preview(notes: &str) -> Option<&str> {
+    notes.lines().map(str::trim).find(|line| !line.is_empty())
+}
@@ -0,0 +1,1305 @@
+//! Targeted Automerge operations for applying a [`TodoCommand`] to a document.
+//!
+//! Rather than hydrating the whole [`TodoState`](super::TodoState) and reconciling
+//! it back, each command only touches the objects it is about: lists and items
+//! are found by id, inserted or deleted at their current position, and fields are
+//! put on their own map key. Concurrent edits from other sites therefore merge
+//! with the intent of each edit instead of being diffed by position.
+
+use anyhow::Result;
+use automerge::{
+    AutoCommit, ObjId, ObjType, Prop, ROOT, ReadDoc, ScalarValue, Value, transaction::Transactable,
+};
+use autosurgeon::{Hydrate, hydrate, hydrate_prop, reconcile, reconcile_insert, reconcile_prop};
+use tracing::*;
+use uuid::Uuid;
+
+use crate::backends::multicast::{TodoCommand, TodoItem, TodoList, TodoState};
+use crate::backends::tags::{self, Tag};
+
+/// Applies a command to the document, returning whether anything changed
+pub fn apply(doc: &mut AutoCommit, command: TodoCommand) -> Result<bool> {
+    match command {
+        // List operations
+        TodoCommand::AddList {
+            list_id,
+            title,
+            metadata,
+        } => {
+            let lists = lists(doc)?;
+            let len = doc.length(&lists);
+            reconcile_insert(
+                doc,
+                lists,
+                len,
+                TodoList {
+                    id: list_id,
+                    metadata,
+                    ..TodoList::new(title)
+                },
+            )?;
+            Ok(true)
+        }
+        TodoCommand::RemoveList { list_id } => {
+            let lists = lists(doc)?;
+            let Some((idx, _)) = find(doc, &lists, list_id)? else {
+                return Ok(false);
+            };
+            doc.delete(&lists, idx)?;
+            Ok(true)
+        }
+        TodoCommand::RenameList { list_id, title } => {
+            let Some(list) = find_list(doc, list_id)? else {
+                return Ok(false);
+            };
+            doc.put(&list, "title", title)?;
+            Ok(true)
+        }
+        TodoCommand::SetSort { list_id, sort } => {
+            let Some(list) = find_list(doc, list_id)? else {
+                return Ok(false);
+            };
+            reconcile_prop(doc, &list, "sort", sort)?;
+            Ok(true)
+        }
+
+        // Item operations
+        TodoCommand::AddTodo {
+            list_id,
+            item_id,
+            text,
+            metadata,
+            due,
+            priority,
+            parent_id,
+            tags,
+        } => {
+            let Some(items) = find_children(doc, list_id, parent_id)? else {
+                return Ok(false);
+            };
+            let len = doc.length(&items);
+            register_tags(doc, &tags)?;
+            reconcile_insert(
+                doc,
+                items,
+                len,
+                TodoItem {
+                    id: item_id,
+                    due,
+                    priority,
+                    tags,
+                    ..TodoItem::new(text, metadata)
+                },
+            )?;
+            Ok(true)
+        }
+        TodoCommand::RenameTodo {
+            list_id,
+            item_id,
+            text,
+        } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            update_text(doc, &item, "text", &text)
+        }
+        TodoCommand::SetNotes {
+            list_id,
+            item_id,
+            notes,
+        } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            update_text(doc, &item, "notes", &notes)
+        }
+        TodoCommand::ToggleTodo { list_id, item_id } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            let completed = is_completed(doc, &item)?;
+            doc.put(&item, "completed", !completed)?;
+            Ok(true)
+        }
+        TodoCommand::CompleteTodo { list_id, item_id } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            complete(doc, &item)
+        }
+        TodoCommand::SetDue {
+            list_id,
+            item_id,
+            due,
+        } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            let due = match due {
+                Some(due) => ScalarValue::Timestamp(due.timestamp_millis()),
+                None => ScalarValue::Null,
+            };
+            doc.put(&item, "due", due)?;
+            Ok(true)
+        }
+        TodoCommand::SetPriority {
+            list_id,
+            item_id,
+            priority,
+        } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            reconcile_prop(doc, &item, "priority", priority)?;
+            Ok(true)
+        }
+        TodoCommand::SetTags {
+            list_id,
+            item_id,
+            tags,
+        } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            register_tags(doc, &tags)?;
+            reconcile_prop(doc, &item, "tags", tags::crdt::Keys(&tags))?;
+            Ok(true)
+        }
+        TodoCommand::RemoveTodo { list_id, item_id } => {
+            let Some(items) = find_items(doc, list_id)? else {
+                return Ok(false);
+            };
+            let Some((seq, idx, _)) = find_nested(doc, &items, item_id)? else {
+                return Ok(false);
+            };
+            doc.delete(&seq, idx)?;
+            Ok(true)
+        }
+        TodoCommand::ClearCompleted { list_id } => {
+            let Some(items) = find_items(doc, list_id)? else {
+                return Ok(false);
+            };
+            clear_completed(doc, &items)
+        }
+
+        // Tag operations
+        TodoCommand::SetTagColor { tag, color } => {
+            let registry = tag_registry(doc)?;
+            reconcile_prop(doc, &registry, tag.as_str(), Tag { color })?;
+            Ok(true)
+        }
+
+        // Undo operations
+        // the site keeps the undo stack, and applies what it holds as the commands below
+        TodoCommand::Undo | TodoCommand::Redo => Ok(false),
+        TodoCommand::InsertList { index, list } => {
+            let lists = lists(doc)?;
+            if find(doc, &lists, list.id)?.is_some() {
+                return Ok(false);
+            }
+            let index = index.min(doc.length(&lists));
+            reconcile_insert(doc, lists, index, list)?;
+            Ok(true)
+        }
+        TodoCommand::InsertTodo {
+            list_id,
+            parent_id,
+            index,
+            item,
+        } => {
+            if find_item(doc, list_id, item.id)?.is_some() {
+                return Ok(false);
+            }
+            let Some(items) = find_children(doc, list_id, parent_id)? else {
+                return Ok(false);
+            };
+            let index = index.min(doc.length(&items));
+            reconcile_insert(doc, items, index, item)?;
+            Ok(true)
+        }
+        TodoCommand::SetCompleted {
+            list_id,
+            item_id,
+            completed,
+        } => {
+            let Some(item) = find_item(doc, list_id, item_id)? else {
+                return Ok(false);
+            };
+            if is_completed(doc, &item)? == completed {
+                return Ok(false);
+            }
+            doc.put(&item, "completed", completed)?;
+            Ok(true)
+        }
+
+        // Document operations
+        TodoCommand::Restore { state } => {
+            // reconciling diffs against the current document, so the restore lands on top of
+            // every change since instead of rewinding history that peers would merge back in
+            if hydrate::<_, TodoState>(doc)? == state {
+                return Ok(false);
+            }
+            reconcile(doc, &state)?;
+            Ok(true)
+        }
+        // replaces the document rather than changing it, so the site does it
+        TodoCommand::Compact => Ok(false),
+        // the site answers it, there's nothing to change
+        TodoCommand::GetDocument => Ok(false),
+
+        // the site unwraps these, keeping each daemon client's undo history apart
+        TodoCommand::FromClient { .. } | TodoCommand::ClientGone { .. } => Ok(false),
+
+        TodoCommand::Shutdown { .. } => Ok(false),
+    }
+}
+
+/// The commands that take back `command`, read from the document before it's applied.
+///
+/// Removed lists and items are put back whole, where they were removed from. Commands that
+/// wouldn't change anything have nothing to take back.
+pub fn inverse(doc: &AutoCommit, command: &TodoCommand) -> Result<Vec<TodoCommand>> {
+    // only what the command touches is read, rather than hydrating the whole state for every edit
+    let lists = match doc.get(ROOT, "lists")? {
+        Some((Value::Object(ObjType::List), lists)) => Some(lists),
+        _ => None,
+    };
+    let find_list = |list_id: Uuid| -> Result<Option<(usize, ObjId)>> {
+        match &lists {
+            Some(lists) => find(doc, lists, list_id),
+            None => Ok(None),
+        }
+    };
+    let find_item =
+        |list_id: Uuid, item_id: Uuid| -> Result<Option<(Option<Uuid>, usize, TodoItem)>> {
+            let Some((_, list)) = find_list(list_id)? else {
+                return Ok(None);
+            };
+            let Some((_, items)) = doc.get(&list, "items")? else {
+                return Ok(None);
+            };
+            let Some((seq, index, _)) = find_nested(doc, &items, item_id)? else {
+                return Ok(None);
+            };
+            Ok(Some((
+                parent_id(doc, &seq)?,
+                index,
+                hydrate_prop(doc, &seq, index)?,
+            )))
+        };
+
+    Ok(match command {
+        // List operations
+        TodoCommand::AddList { list_id, .. } => vec![TodoCommand::RemoveList { list_id: *list_id }],
+        TodoCommand::RemoveList { list_id } => match (&lists, find_list(*list_id)?) {
+            (Some(lists), Some((index, _))) => vec![TodoCommand::InsertList {
+                index,
+                list: hydrate_prop(doc, lists, index)?,
+            }],
+            _ => Vec::new(),
+        },
+        TodoCommand::RenameList { list_id, .. } => match find_list(*list_id)? {
+            Some((_, list)) => vec![TodoCommand::RenameList {
+                list_id: *list_id,
+                title: hydrate_prop(doc, &list, "title")?,
+            }],
+            None => Vec::new(),
+        },
+        TodoCommand::SetSort { list_id, .. } => match find_list(*list_id)? {
+            Some((_, list)) => vec![TodoCommand::SetSort {
+                list_id: *list_id,
+                sort: read_field(doc, &list, "sort")?.unwrap_or_default(),
+            }],
+            None => Vec::new(),
+        },
+
+        // Item operations
+        TodoCommand::AddTodo {
+            list_id, item_id, ..
+        } => vec![TodoCommand::RemoveTodo {
+            list_id: *list_id,
+            item_id: *item_id,
+        }],
+        TodoCommand::RenameTodo {
+            list_id, item_id, ..
+        } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::RenameTodo {
+                list_id: *list_id,
+                item_id: *item_id,
+                text: item.text,
+            })
+            .into_iter()
+            .collect(),
+        TodoCommand::SetNotes {
+            list_id, item_id, ..
+        } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::SetNotes {
+                list_id: *list_id,
+                item_id: *item_id,
+                notes: item.notes,
+            })
+            .into_iter()
+            .collect(),
+        // sets it back rather than toggling again, which a concurrent toggle would turn around
+        TodoCommand::ToggleTodo { list_id, item_id } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::SetCompleted {
+                list_id: *list_id,
+                item_id: *item_id,
+                completed: item.completed,
+            })
+            .into_iter()
+            .collect(),
+        // reopens just the ones it completed
+        TodoCommand::CompleteTodo { list_id, item_id } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| open_ids(&item))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item_id| TodoCommand::SetCompleted {
+                list_id: *list_id,
+                item_id,
+                completed: false,
+            })
+            .collect(),
+        TodoCommand::SetDue {
+            list_id, item_id, ..
+        } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::SetDue {
+                list_id: *list_id,
+                item_id: *item_id,
+                due: item.due,
+            })
+            .into_iter()
+            .collect(),
+        TodoCommand::SetPriority {
+            list_id, item_id, ..
+        } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::SetPriority {
+                list_id: *list_id,
+                item_id: *item_id,
+                priority: item.priority,
+            })
+            .into_iter()
+            .collect(),
+        TodoCommand::SetTags {
+            list_id, item_id, ..
+        } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::SetTags {
+                list_id: *list_id,
+                item_id: *item_id,
+                tags: item.tags,
+            })
+            .into_iter()
+            .collect(),
+        TodoCommand::RemoveTodo { list_id, item_id } => find_item(*list_id, *item_id)?
+            .map(|(parent_id, index, item)| TodoCommand::InsertTodo {
+                list_id: *list_id,
+                parent_id,
+                index,
+                item,
+            })
+            .into_iter()
+            .collect(),
+        // in order, so each goes back in front of the ones after it
+        TodoCommand::ClearCompleted { list_id } => {
+            let mut completed = Vec::new();
+            if let Some((_, list)) = find_list(*list_id)?
+                && let Some((_, items)) = doc.get(&list, "items")?
+            {
+                find_completed(doc, &items, None, &mut completed)?;
+            }
+            completed
+                .into_iter()
+                .map(|(parent_id, index, item)| TodoCommand::InsertTodo {
+                    list_id: *list_id,
+                    parent_id,
+                    index,
+                    item,
+                })
+                .collect()
+        }
+
+        // Tag operations
+        // a tag that wasn't registered goes back to the colour it would have been given
+        TodoCommand::SetTagColor { tag, .. } => {
+            let registered = match doc.get(ROOT, "tags")? {
+                Some((Value::Object(ObjType::Map), registry)) => {
+                    read_field::<Tag>(doc, &registry, tag)?
+                }
+                _ => None,
+            };
+            vec![TodoCommand::SetTagColor {
+                tag: tag.clone(),
+                color: registered.unwrap_or_else(|| Tag::new(tag)).color,
+            }]
+        }
+
+        // Undo operations
+        TodoCommand::InsertList { list, .. } => vec![TodoCommand::RemoveList { list_id: list.id }],
+        TodoCommand::InsertTodo { list_id, item, .. } => vec![TodoCommand::RemoveTodo {
+            list_id: *list_id,
+            item_id: item.id,
+        }],
+        TodoCommand::SetCompleted {
+            list_id, item_id, ..
+        } => find_item(*list_id, *item_id)?
+            .map(|(_, _, item)| TodoCommand::SetCompleted {
+                list_id: *list_id,
+                item_id: *item_id,
+                completed: item.completed,
+            })
+            .into_iter()
+            .collect(),
+
+        // Document operations
+        // the one command that changes everything, so it's the one that reads everything
+        TodoCommand::Restore { .. } => vec![TodoCommand::Restore {
+            state: hydrate(doc)?,
+        }],
+        // the lists are the same before and after
+        TodoCommand::Compact | TodoCommand::GetDocument => Vec::new(),
+
+        TodoCommand::Undo
+        | TodoCommand::Redo
+        | TodoCommand::FromClient { .. }
+        | TodoCommand::ClientGone { .. }
+        | TodoCommand::Shutdown { .. } => Vec::new(),
+    })
+}
+
+/// The field of the map `obj` under `key`, or `None` if it hasn't been set
+fn read_field<T: Hydrate>(doc: &AutoCommit, obj: &ObjId, key: &str) -> Result<Option<T>> {
+    if doc.get(obj, key)?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(hydrate_prop(doc, obj, key)?))
+}
+
+/// The id of the item `seq` holds the subtasks of, or `None` if it's a list's own items
+fn parent_id(doc: &AutoCommit, seq: &ObjId) -> Result<Option<Uuid>> {
+    match doc.parents(seq)?.next() {
+        Some(parent) if parent.prop == Prop::Map("children".into()) => read_id(doc, &parent.obj),
+        _ => Ok(None),
+    }
+}
+
+/// The completed items `ClearCompleted` removes, leaving out the subtasks that go with them
+fn find_completed(
+    doc: &AutoCommit,
+    seq: &ObjId,
+    parent_id: Option<Uuid>,
+    out: &mut Vec<(Option<Uuid>, usize, TodoItem)>,
+) -> Result<()> {
+    for index in 0..doc.length(seq) {
+        let Some((_, item)) = doc.get(seq, index)? else {
+            continue;
+        };
+        if is_completed(doc, &item)? {
+            out.push((parent_id, index, hydrate_prop(doc, seq, index)?));
+        } else if let Some((Value::Object(ObjType::List), children)) = doc.get(&item, "children")? {
+            find_completed(doc, &children, read_id(doc, &item)?, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The item and those of its subtasks that are still open
+fn open_ids(item: &TodoItem) -> Vec<Uuid> {
+    let mut open: Vec<Uuid> = (!item.completed).then_some(item.id).into_iter().collect();
+    for child in &item.children {
+        open.extend(open_ids(child));
+    }
+    open
+}
+
+/// Gives every list and item without an `id` a fresh one.
+///
+/// Documents saved before ids were introduced only address things by position.
+/// The id is put directly on the existing objects rather than reconciled, so the
+/// list structure is left untouched and concurrent migrations resolve to one id.
+pub fn assign_missing_ids(doc: &mut AutoCommit) -> Result<()> {
+    let Some((_, lists)) = doc.get(ROOT, "lists")? else {
+        return Ok(());
+    };
+
+    for list_idx in 0..doc.length(&lists) {
+        let Some((_, list)) = doc.get(&lists, list_idx)? else {
+            continue;
+        };
+        ensure_id(doc, &list)?;
+
+        let Some((_, items)) = doc.get(&list, "items")? else {
+            continue;
+        };
+        for item_idx in 0..doc.length(&items) {
+            if let Some((_, item)) = doc.get(&items, item_idx)? {
+                ensure_id(doc, &item)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn ensure_id(doc: &mut AutoCommit, obj: &ObjId) -> Result<()> {
+    if doc.get(obj, "id")?.is_none() {
+        debug!("assigning id to {obj}");
+        doc.put(
+            obj,
+            "id",
+            ScalarValue::Bytes(Uuid::new_v4().as_bytes().to_vec()),
+        )?;
+    }
+    Ok(())
+}
+
+/// The root `lists` sequence, created if this document has never had one
+fn lists(doc: &mut AutoCommit) -> Result<ObjId> {
+    match doc.get(ROOT, "lists")? {
+        Some((Value::Object(ObjType::List), lists)) => Ok(lists),
+        _ => Ok(doc.put_object(ROOT, "lists", ObjType::List)?),
+    }
+}
+
+/// The root `tags` map, created if this document has never had one.
+///
+/// Two sites creating it at once conflict, and the tags only one of them registered go back
+/// to the colours they would have been given, which is what those are anyway.
+fn tag_registry(doc: &mut AutoCommit) -> Result<ObjId> {
+    match doc.get(ROOT, "tags")? {
+        Some((Value::Object(ObjType::Map), tags)) => Ok(tags),
+        _ => Ok(doc.put_object(ROOT, "tags", ObjType::Map)?),
+    }
+}
+
+/// Registers the tags the workspace hasn't seen yet, with the colours they're given
+fn register_tags(doc: &mut AutoCommit, tags: &[String]) -> Result<()> {
+    if tags.is_empty() {
+        return Ok(());
+    }
+    let registry = tag_registry(doc)?;
+    for tag in tags {
+        if doc.get(&registry, tag.as_str())?.is_none() {
+            reconcile_prop(doc, &registry, tag.as_str(), Tag::new(tag))?;
+        }
+    }
+    Ok(())
+}
+
+fn find_list(doc: &mut AutoCommit, list_id: Uuid) -> Result<Option<ObjId>> {
+    let lists = lists(doc)?;
+    Ok(find(doc, &lists, list_id)?.map(|(_, list)| list))
+}
+
+fn find_items(doc: &mut AutoCommit, list_id: Uuid) -> Result<Option<ObjId>> {
+    let Some(list) = find_list(doc, list_id)? else {
+        return Ok(None);
+    };
+    Ok(doc.get(&list, "items")?.map(|(_, items)| items))
+}
+
+/// The item with the given id, at any depth in the list
+fn find_item(doc: &mut AutoCommit, list_id: Uuid, item_id: Uuid) -> Result<Option<ObjId>> {
+    let Some(items) = find_items(doc, list_id)? else {
+        return Ok(None);
+    };
+    Ok(find_nested(doc, &items, item_id)?.map(|(_, _, item)| item))
+}
+
+/// The sequence to add items to, the list's own items or the subtasks of `parent_id`
+fn find_children(
+    doc: &mut AutoCommit,
+    list_id: Uuid,
+    parent_id: Option<Uuid>,
+) -> Result<Option<ObjId>> {
+    let Some(parent_id) = parent_id else {
+        return find_items(doc, list_id);
+    };
+    let Some(parent) = find_item(doc, list_id, parent_id)? else {
+        return Ok(None);
+    };
+    match doc.get(&parent, "children")? {
+        Some((Value::Object(ObjType::List), children)) => Ok(Some(children)),
+        // items from before subtasks don't have the sequence yet
+        _ => Ok(Some(doc.put_object(&parent, "children", ObjType::List)?)),
+    }
+}
+
+/// Finds the element of `seq`, or of its elements' subtasks, with the given id, returning the
+/// sequence it's in, its current index there and the item
+fn find_nested(doc: &AutoCommit, seq: &ObjId, id: Uuid) -> Result<Option<(ObjId, usize, ObjId)>> {
+    for idx in 0..doc.length(seq) {
+        let Some((_, item)) = doc.get(seq, idx)? else {
+            continue;
+        };
+        if read_id(doc, &item)? == Some(id) {
+            return Ok(Some((seq.clone(), idx, item)));
+        }
+        if let Some((Value::Object(ObjType::List), children)) = doc.get(&item, "children")?
+            && let Some(found) = find_nested(doc, &children, id)?
+        {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// Removes the completed items of `seq`, and the completed subtasks of the rest
+fn clear_completed(doc: &mut AutoCommit, seq: &ObjId) -> Result<bool> {
+    let mut changed = false;
+    // walk backwards so deletions don't shift the indexes still to visit
+    for idx in (0..doc.length(seq)).rev() {
+        let Some((_, item)) = doc.get(seq, idx)? else {
+            continue;
+        };
+        if is_completed(doc, &item)? {
+            doc.delete(seq, idx)?;
+            changed = true;
+        } else if let Some((Value::Object(ObjType::List), children)) = doc.get(&item, "children")? {
+            changed |= clear_completed(doc, &children)?;
+        }
+    }
+    Ok(changed)
+}
+
+/// Makes a text field of `obj` read `value`, splicing in just what changed so edits made
+/// concurrently on other sites merge with it. Plain strings from before text was collaborative
+/// are replaced with a text object first.
+fn update_text(doc: &mut AutoCommit, obj: &ObjId, prop: &str, value: &str) -> Result<bool> {
+    let text = match doc.get(obj, prop)? {
+        Some((Value::Object(ObjType::Text), text)) => text,
+        _ => doc.put_object(obj, prop, ObjType::Text)?,
+    };
+    if doc.text(&text)? == value {
+        return Ok(false);
+    }
+    doc.update_text(&text, value)?;
+    Ok(true)
+}
+
+/// Completes the item and all of its subtasks, returning whether any were still open
+fn complete(doc: &mut AutoCommit, item: &ObjId) -> Result<bool> {
+    let mut changed = false;
+    if !is_completed(doc, item)? {
+        doc.put(item, "completed", true)?;
+        changed = true;
+    }
+    if let Some((Value::Object(ObjType::List), children)) = doc.get(item, "children")? {
+        for idx in 0..doc.length(&children) {
+            if let Some((_, child)) = doc.get(&children, idx)? {
+                changed |= complete(doc, &child)?;
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Finds the element of `seq` with the given id, returning its current index and object
+fn find(doc: &AutoCommit, seq: &ObjId, id: Uuid) -> Result<Option<(usize, ObjId)>> {
+    for idx in 0..doc.length(seq) {
+        if let Some((_, obj)) = doc.get(seq, idx)?
+            && read_id(doc, &obj)? == Some(id)
+        {
+            return Ok(Some((idx, obj)));
+        }
+    }
+    Ok(None)
+}
+
+fn read_id(doc: &AutoCommit, obj: &ObjId) -> Result<Option<Uuid>> {
+    Ok(match doc.get(obj, "id")? {
+        Some((Value::Scalar(value), _)) => match value.as_ref() {
+            ScalarValue::Bytes(bytes) => Uuid::from_slice(bytes).ok(),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn is_completed(doc: &AutoCommit, item: &ObjId) -> Result<bool> {
+    Ok(matches!(
+        doc.get(item, "completed")?,
+        Some((Value::Scalar(value), _)) if matches!(value.as_ref(), ScalarValue::Boolean(true))
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automerge::ActorId;
+    use autosurgeon::{hydrate, reconcile};
+
+    use super::*;
+    use crate::backends::{
+        TodoState,
+        sort::{Priority, SortMode},
+    };
+
+    fn new_doc() -> AutoCommit {
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, TodoState::default()).unwrap();
+        doc
+    }
+
+    fn fork(doc: &mut AutoCommit) -> AutoCommit {
+        doc.fork().with_actor(ActorId::random())
+    }
+
+    fn add_list(doc: &mut AutoCommit, title: &str) -> Uuid {
+        let list_id = Uuid::new_v4();
+        apply(
+            doc,
+            TodoCommand::AddList {
+                list_id,
+                title: title.into(),
+                metadata: HashMap::new(),
+            },
+        )
+        .unwrap();
+        list_id
+    }
+
+    fn add_todo(doc: &mut AutoCommit, list_id: Uuid, text: &str) -> Uuid {
+        let item_id = Uuid::new_v4();
+        apply(
+            doc,
+            TodoCommand::AddTodo {
+                list_id,
+                item_id,
+                text: text.into(),
+                metadata: HashMap::new(),
+                due: None,
+                priority: Priority::None,
+                parent_id: None,
+                tags: Vec::new(),
+            },
+        )
+        .unwrap();
+        item_id
+    }
+
+    /// Merges both ways and checks the two docs converged, returning the result
+    fn converge(left: &mut AutoCommit, right: &mut AutoCommit) -> TodoState {
+        left.merge(right).unwrap();
+        right.merge(left).unwrap();
+
+        let left_state: TodoState = hydrate(left).unwrap();
+        let right_state: TodoState = hydrate(right).unwrap();
+        assert_eq!(left_state, right_state);
+
+        left_state
+    }
+
+    #[test]
+    fn remove_list_while_editing_another() {
+        let mut left = new_doc();
+        let first = add_list(&mut left, "first");
+        let second = add_list(&mut left, "second");
+        let item = add_todo(&mut left, second, "milk");
+        let mut right = fork(&mut left);
+
+        apply(&mut left, TodoCommand::RemoveList { list_id: first }).unwrap();
+        apply(
+            &mut right,
+            TodoCommand::ToggleTodo {
+                list_id: second,
+                item_id: item,
+            },
+        )
+        .unwrap();
+
+        let state = converge(&mut left, &mut right);
+        assert_eq!(state.lists.len(), 1);
+        assert_eq!(state.lists[0].id, second);
+        assert!(state.lists[0].item(item).unwrap().completed);
+    }
+
+    #[test]
+    fn remove_item_while_renaming_its_neighbour() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let eggs = add_todo(&mut left, list, "eggs");
+        let ham = add_todo(&mut left, list, "ham");
+        let mut right = fork(&mut left);
+
+        apply(
+            &mut left,
+            TodoCommand::RemoveTodo {
+                list_id: list,
+                item_id: eggs,
+            },
+        )
+        .unwrap();
+        apply(
+            &mut right,
+            TodoCommand::RenameTodo {
+                list_id: list,
+                item_id: ham,
+                text: "green ham".into(),
+            },
+        )
+        .unwrap();
+
+        let state = converge(&mut left, &mut right);
+        let items = &state.list(list).unwrap().items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, ham);
+        assert_eq!(items[0].text, "green ham");
+    }
+
+    #[test]
+    fn concurrent_adds_keep_both_items() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let mut right = fork(&mut left);
+
+        let from_left = add_todo(&mut left, list, "left");
+        let from_right = add_todo(&mut right, list, "right");
+
+        let state = converge(&mut left, &mut right);
+        let list = state.list(list).unwrap();
+        assert_eq!(list.items.len(), 2);
+        assert!(list.item(from_left).is_some());
+        assert!(list.item(from_right).is_some());
+    }
+
+    #[test]
+    fn clear_completed_while_adding() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let done = add_todo(&mut left, list, "done");
+        let open = add_todo(&mut left, list, "open");
+        apply(
+            &mut left,
+            TodoCommand::ToggleTodo {
+                list_id: list,
+                item_id: done,
+            },
+        )
+        .unwrap();
+        let mut right = fork(&mut left);
+
+        apply(&mut left, TodoCommand::ClearCompleted { list_id: list }).unwrap();
+        let added = add_todo(&mut right, list, "added");
+
+        let state = converge(&mut left, &mut right);
+        let ids: Vec<Uuid> = state
+            .list(list)
+            .unwrap()
+            .items
+            .iter()
+            .map(|i| i.id)
+            .collect();
+        assert_eq!(ids, vec![open, added]);
+    }
+
+    #[test]
+    fn rename_removed_list_stays_removed() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let mut right = fork(&mut left);
+
+        apply(&mut left, TodoCommand::RemoveList { list_id: list }).unwrap();
+        apply(
+            &mut right,
+            TodoCommand::RenameList {
+                list_id: list,
+                title: "renamed".into(),
+            },
+        )
+        .unwrap();
+
+        let state = converge(&mut left, &mut right);
+        assert!(state.lists.is_empty());
+    }
+
+    #[test]
+    fn legacy_documents_get_ids() {
+        let mut doc = AutoCommit::new();
+        let lists = doc.put_object(ROOT, "lists", ObjType::List).unwrap();
+        let list = doc.insert_object(&lists, 0, ObjType::Map).unwrap();
+        doc.put(&list, "title", "old").unwrap();
+        let items = doc.put_object(&list, "items", ObjType::List).unwrap();
+        let item = doc.insert_object(&items, 0, ObjType::Map).unwrap();
+        doc.put(&item, "text", "old item").unwrap();
+        doc.put(&item, "completed", false).unwrap();
+
+        assign_missing_ids(&mut doc).unwrap();
+
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert!(!state.lists[0].id.is_nil());
+        assert!(!state.lists[0].items[0].id.is_nil());
+        assert_eq!(state.lists[0].items[0].text, "old item");
+        assert_eq!(state.lists[0].items[0].notes, "");
+
+        // plain string text becomes collaborative text once edited
+        apply(
+            &mut doc,
+            TodoCommand::RenameTodo {
+                list_id: state.lists[0].id,
+                item_id: state.lists[0].items[0].id,
+                text: "old item, edited".into(),
+            },
+        )
+        .unwrap();
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(state.lists[0].items[0].text, "old item, edited");
+    }
+
+    #[test]
+    fn inverse_puts_removed_items_back_in_place() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let items: Vec<Uuid> = ["eggs", "ham", "milk"]
+            .into_iter()
+            .map(|text| add_todo(&mut left, list, text))
+            .collect();
+        for item_id in [items[0], items[2]] {
+            apply(
+                &mut left,
+                TodoCommand::ToggleTodo {
+                    list_id: list,
+                    item_id,
+                },
+            )
+            .unwrap();
+        }
+        let before: TodoState = hydrate(&left).unwrap();
+
+        let command = TodoCommand::ClearCompleted { list_id: list };
+        let inverse = inverse(&left, &command).unwrap();
+        apply(&mut left, command).unwrap();
+
+        // someone else edits what's left meanwhile
+        let mut right = fork(&mut left);
+        apply(
+            &mut right,
+            TodoCommand::RenameTodo {
+                list_id: list,
+                item_id: items[1],
+                text: "bacon".into(),
+            },
+        )
+        .unwrap();
+
+        for command in inverse {
+            assert!(apply(&mut left, command).unwrap());
+        }
+
+        let state = converge(&mut left, &mut right);
+        let restored = state.list(list).unwrap();
+        let ids: Vec<Uuid> = restored.items.iter().map(|item| item.id).collect();
+        assert_eq!(ids, items);
+        assert_eq!(restored.items[0], before.lists[0].items[0]);
+        assert_eq!(restored.items[1].text, "bacon");
+    }
+
+    #[test]
+    fn inverses_take_each_edit_back() {
+        let mut doc = new_doc();
+        let list = add_list(&mut doc, "list");
+        let item = add_todo(&mut doc, list, "eggs");
+        let before: TodoState = hydrate(&doc).unwrap();
+
+        let edits = [
+            TodoCommand::RenameList {
+                list_id: list,
+                title: "shopping".into(),
+            },
+            TodoCommand::SetSort {
+                list_id: list,
+                sort: SortMode::Priority,
+            },
+            TodoCommand::SetNotes {
+                list_id: list,
+                item_id: item,
+                notes: "free range".into(),
+            },
+            TodoCommand::SetPriority {
+                list_id: list,
+                item_id: item,
+                priority: Priority::High,
+            },
+            TodoCommand::SetTagColor {
+                tag: "food".into(),
+                color: "#123456".into(),
+            },
+            TodoCommand::RemoveList { list_id: list },
+        ];
+        let mut undo = Vec::new();
+        for command in edits {
+            undo.push(inverse(&doc, &command).unwrap());
+            assert!(apply(&mut doc, command).unwrap());
+        }
+        for commands in undo.into_iter().rev() {
+            for command in commands {
+                apply(&mut doc, command).unwrap();
+            }
+        }
+
+        let mut after: TodoState = hydrate(&doc).unwrap();
+        // the tag is registered now, with the colour it would have been given anyway
+        assert_eq!(after.tags.remove("food"), Some(Tag::new("food")));
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn undoing_a_toggle_sets_it_back_rather_than_toggling() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let item = add_todo(&mut left, list, "eggs");
+        let toggle = || TodoCommand::ToggleTodo {
+            list_id: list,
+            item_id: item,
+        };
+
+        let undo = inverse(&left, &toggle()).unwrap();
+        apply(&mut left, toggle()).unwrap();
+
+        // someone else reopens it before we undo
+        let mut right = fork(&mut left);
+        apply(&mut right, toggle()).unwrap();
+        left.merge(&mut right).unwrap();
+
+        for command in undo {
+            assert!(!apply(&mut left, command).unwrap());
+        }
+        let state = converge(&mut left, &mut right);
+        assert!(!state.list(list).unwrap().item(item).unwrap().completed);
+    }
+
+    #[test]
+    fn subtasks_complete_with_their_parent_and_undo() {
+        let mut doc = new_doc();
+        let list = add_list(&mut doc, "list");
+        let parent = add_todo(&mut doc, list, "move house");
+        let subtask = |doc: &mut AutoCommit, parent_id: Uuid, text: &str| {
+            let item_id = Uuid::new_v4();
+            apply(
+                doc,
+                TodoCommand::AddTodo {
+                    list_id: list,
+                    item_id,
+                    text: text.into(),
+                    metadata: HashMap::new(),
+                    due: None,
+                    priority: Priority::None,
+                    parent_id: Some(parent_id),
+                    tags: Vec::new(),
+                },
+            )
+            .unwrap();
+            item_id
+        };
+        let boxes = subtask(&mut doc, parent, "pack boxes");
+        let books = subtask(&mut doc, boxes, "pack books");
+        let van = subtask(&mut doc, parent, "book a van");
+        apply(
+            &mut doc,
+            TodoCommand::ToggleTodo {
+                list_id: list,
+                item_id: van,
+            },
+        )
+        .unwrap();
+
+        let state: TodoState = hydrate(&doc).unwrap();
+        let tree = state.list(list).unwrap();
+        assert_eq!(tree.items.len(), 1);
+        assert_eq!(tree.item(parent).unwrap().progress(), (1, 2));
+        assert_eq!(tree.item(boxes).unwrap().children[0].id, books);
+
+        let complete = TodoCommand::CompleteTodo {
+            list_id: list,
+            item_id: parent,
+        };
+        let reopen = inverse(&doc, &complete).unwrap();
+        apply(&mut doc, complete).unwrap();
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert!(
+            state
+                .list(list)
+                .unwrap()
+                .all_items()
+                .iter()
+                .all(|(_, i)| i.completed)
+        );
+
+        // undoing reopens only what completing the parent completed
+        for command in reopen {
+            apply(&mut doc, command).unwrap();
+        }
+        let state: TodoState = hydrate(&doc).unwrap();
+        let tree = state.list(list).unwrap();
+        assert!(!tree.item(parent).unwrap().completed);
+        assert!(!tree.item(books).unwrap().completed);
+        assert!(tree.item(van).unwrap().completed);
+
+        let before: TodoState = hydrate(&doc).unwrap();
+        let remove = TodoCommand::RemoveTodo {
+            list_id: list,
+            item_id: boxes,
+        };
+        let restore = inverse(&doc, &remove).unwrap();
+        apply(&mut doc, remove).unwrap();
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert!(state.list(list).unwrap().item(books).is_none());
+
+        for command in restore {
+            apply(&mut doc, command).unwrap();
+        }
+        let restored: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(restored, before);
+    }
+
+    #[test]
+    fn tags_are_registered_when_used() {
+        let mut doc = new_doc();
+        let list = add_list(&mut doc, "list");
+        let item = add_todo(&mut doc, list, "fix login");
+        let mut other = fork(&mut doc);
+
+        apply(
+            &mut doc,
+            TodoCommand::SetTags {
+                list_id: list,
+                item_id: item,
+                tags: vec!["bug".into(), "urgent".into()],
+            },
+        )
+        .unwrap();
+        let recolor = TodoCommand::SetTagColor {
+            tag: "bug".into(),
+            color: "#000000".into(),
+        };
+        let undo = inverse(&doc, &recolor).unwrap();
+        apply(&mut doc, recolor).unwrap();
+        // meanwhile another site tags it with one the first hasn't seen
+        apply(
+            &mut other,
+            TodoCommand::SetTags {
+                list_id: list,
+                item_id: item,
+                tags: vec!["login".into()],
+            },
+        )
+        .unwrap();
+
+        let state = converge(&mut doc, &mut other);
+        assert_eq!(state.tags["bug"].color, "#000000");
+        assert_eq!(state.tags["urgent"], Tag::new("urgent"));
+        assert_eq!(state.tags["login"], Tag::new("login"));
+
+        for command in undo {
+            apply(&mut doc, command).unwrap();
+        }
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(state.tags["bug"], Tag::new("bug"));
+    }
+
+    #[test]
+    fn concurrent_tag_changes_merge_by_name() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let item = add_todo(&mut left, list, "fix login");
+        let set_tags = |doc: &mut AutoCommit, tags: &[&str]| {
+            let tags = tags.iter().map(|tag| tag.to_string()).collect();
+            apply(
+                doc,
+                TodoCommand::SetTags {
+                    list_id: list,
+                    item_id: item,
+                    tags,
+                },
+            )
+            .unwrap();
+        };
+        set_tags(&mut left, &["bug", "ui"]);
+        let mut right = fork(&mut left);
+
+        // one site adds a tag while the other takes one away and adds another
+        set_tags(&mut left, &["bug", "ui", "urgent"]);
+        set_tags(&mut right, &["bug", "login"]);
+
+        let state = converge(&mut left, &mut right);
+        let tags = &state.list(list).unwrap().item(item).unwrap().tags;
+        assert_eq!(tags, &["bug", "login", "urgent"]);
+    }
+
+    #[test]
+    fn tags_saved_as_a_list_are_read_and_replaced() {
+        let mut doc = new_doc();
+        let list = add_list(&mut doc, "list");
+        let item_id = add_todo(&mut doc, list, "fix login");
+        let item = find_item(&mut doc, list, item_id).unwrap().unwrap();
+        let tags = doc.put_object(&item, "tags", ObjType::List).unwrap();
+        doc.insert(&tags, 0, "urgent").unwrap();
+        doc.insert(&tags, 1, "bug").unwrap();
+
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(state.lists[0].items[0].tags, ["urgent", "bug"]);
+
+        apply(
+            &mut doc,
+            TodoCommand::SetTags {
+                list_id: list,
+                item_id,
+                tags: vec!["bug".into()],
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            doc.get(&item, "tags").unwrap(),
+            Some((Value::Object(ObjType::Map), _))
+        ));
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(state.lists[0].items[0].tags, ["bug"]);
+    }
+
+    #[test]
+    fn concurrent_text_edits_merge() {
+        let mut left = new_doc();
+        let list = add_list(&mut left, "list");
+        let item = add_todo(&mut left, list, "buy milk");
+        let mut right = fork(&mut left);
+
+        let rename = |doc: &mut AutoCommit, text: &str| {
+            apply(
+                doc,
+                TodoCommand::RenameTodo {
+                    list_id: list,
+                    item_id: item,
+                    text: text.into(),
+                },
+            )
+            .unwrap()
+        };
+        let set_notes = |doc: &mut AutoCommit, notes: &str| {
+            apply(
+                doc,
+                TodoCommand::SetNotes {
+                    list_id: list,
+                    item_id: item,
+                    notes: notes.into(),
+                },
+            )
+            .unwrap()
+        };
+        assert!(rename(&mut left, "buy oat milk"));
+        assert!(rename(&mut right, "buy milk and eggs"));
+        assert!(!rename(&mut right, "buy milk and eggs"));
+        set_notes(&mut left, "the big carton");
+
+        let state = converge(&mut left, &mut right);
+        let merged = state.list(list).unwrap().item(item).unwrap();
+        assert_eq!(merged.text, "buy oat milk and eggs");
+        assert_eq!(merged.notes, "the big carton");
+
+        let mut right = fork(&mut left);
+        set_notes(&mut left, "the big carton\nsemi-skimmed");
+        set_notes(&mut right, "the big green carton");
+        let state = converge(&mut left, &mut right);
+        let merged = state.list(list).unwrap().item(item).unwrap();
+        assert_eq!(merged.notes, "the big green carton\nsemi-skimmed");
+    }
+}
@@ -0,0 +1,656 @@
+//! Syncing with a static list of peers, for networks that drop multicast
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Sink, SinkExt, StreamExt, future::BoxFuture};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{
+            Receiver as BroadcastReceiver, Sender as BroadcastSender, channel as broadcast_channel,
+            error::RecvError,
+        },
+        mpsc::{Sender as TokioSender, channel as tokio_channel},
+    },
+    task::{JoinHandle, JoinSet},
+};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite, LengthDelimitedCodec},
+    sync::CancellationToken,
+};
+use tracing::*;
+
+use crate::backends::{
+    crypto::Cipher,
+    multicast::SyncMessage,
+    proto::{McastConfig, McastReceiver, McastSender, ProtoMessage},
+    transport::{MessageSink, MessageStream, Transport, datagram_sink},
+    workspace,
+};
+
+/// How long to wait before dialing a peer again after its connection drops
+const PEER_RETRY: Duration = Duration::from_secs(5);
+
+/// Largest frame we accept from a peer, a whole document sync fits comfortably
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// namespace & site_id, u32 big endian, ahead of the encoded message
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// Grace period for peer connections to send our `Shutdown` before the transport closes
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// The configured peers over UDP, with the same datagrams as multicast sent to each address
+pub struct UdpPeers {
+    site_id: u32,
+    config: McastConfig,
+}
+
+impl UdpPeers {
+    pub fn new(site_id: u32, config: McastConfig) -> Self {
+        Self { site_id, config }
+    }
+}
+
+impl Transport for UdpPeers {
+    fn name(&self) -> &'static str {
+        "UDP peers"
+    }
+
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>> {
+        Box::pin(async move {
+            let receiver = McastReceiver::<SyncMessage>::unicast(&self.config)?;
+            let sender = McastSender::unicast(self.site_id, &self.config).await?;
+
+            Ok((receiver.boxed(), datagram_sink(sender)))
+        })
+    }
+}
+
+/// The configured peers over TCP, dialing each one and accepting connections on `--peer-listen`
+pub struct TcpPeers {
+    site_id: u32,
+    config: McastConfig,
+}
+
+impl TcpPeers {
+    pub fn new(site_id: u32, config: McastConfig) -> Self {
+        Self { site_id, config }
+    }
+}
+
+impl Transport for TcpPeers {
+    fn name(&self) -> &'static str {
+        "TCP peers"
+    }
+
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>> {
+        Box::pin(async move {
+            let codec = FrameCodec::new(self.site_id, &self.config)?;
+            let listener = match self.config.peer_listen {
+                Some(listen) => Some(TcpListener::bind(listen).await?),
+                None => None,
+            };
+
+            let (inbound_tx, inbound_rx) = tokio_channel(128);
+            let (outbound_tx, _) = broadcast_channel(128);
+
+            let task = tokio::spawn(run_tcp(
+                codec,
+                listener,
+                self.config.peers.clone(),
+                inbound_tx,
+                outbound_tx.clone(),
+            ));
+
+            let stream = futures::stream::unfold(inbound_rx, |mut rx| async move {
+                rx.recv().await.map(|message| (message, rx))
+            });
+
+            let sink = ConnectionsSink {
+                outbound: outbound_tx,
+                task,
+            };
+
+            Ok((stream.boxed(), Box::pin(sink) as MessageSink))
+        })
+    }
+}
+
+/// Hands messages to every open connection, closing once they've all sent our `Shutdown`
+struct ConnectionsSink {
+    outbound: BroadcastSender<SyncMessage>,
+    task: JoinHandle<()>,
+}
+
+impl Sink<SyncMessage> for ConnectionsSink {
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: SyncMessage) -> Result<()> {
+        // no connections yet is fine, each one announces itself when it opens
+        self.outbound.send(message).ok();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.task).poll(cx).map(|_| Ok(()))
+    }
+}
+
+impl Drop for ConnectionsSink {
+    fn drop(&mut self) {
+        // stops the listener and every connection when the transport is reconnected
+        self.task.abort();
+    }
+}
+
+/// Accepts and dials connections until `outbound` carries our `Shutdown`, then gives them
+/// [`SHUTDOWN_GRACE`] to pass it on. A failed listener ends the inbound stream with its error.
+async fn run_tcp(
+    codec: FrameCodec,
+    listener: Option<TcpListener>,
+    peers: Vec<String>,
+    inbound: TokioSender<Result<ProtoMessage<SyncMessage>>>,
+    outbound: BroadcastSender<SyncMessage>,
+) {
+    let mut shutdown = outbound.subscribe();
+    let mut connections = JoinSet::new();
+    let sites = Sites::default();
+
+    for peer in peers {
+        connections.spawn(dial(
+            peer,
+            codec.clone(),
+            sites.clone(),
+            inbound.clone(),
+            outbound.clone(),
+        ));
+    }
+
+    loop {
+        tokio::select! {
+            accepted = accept(listener.as_ref()) => {
+                match accepted {
+                    Ok((stream, addr)) => {
+                        debug!("Accepted peer connection from {addr}");
+                        let served = serve_connection(
+                            stream,
+                            false,
+                            codec.clone(),
+                            sites.clone(),
+                            inbound.clone(),
+                            outbound.subscribe(),
+                        );
+                        connections.spawn(async move { served.await.map(|_| ()) });
+                    }
+                    Err(err) => {
+                        inbound.send(Err(err.into())).await.ok();
+                        return;
+                    }
+                }
+            }
+            Some(result) = connections.join_next() => {
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => debug!("Peer connection closed: {err:?}"),
+                    Err(err) => debug!("Peer connection task failed: {err:?}"),
+                }
+            }
+            message = shutdown.recv() => {
+                if matches!(message, Ok(SyncMessage::Shutdown) | Err(RecvError::Closed)) {
+                    tokio::time::timeout(SHUTDOWN_GRACE, async {
+                        while connections.join_next().await.is_some() {}
+                    })
+                    .await
+                    .ok();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn accept(
+    listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Keeps a connection open to a configured peer, redialing whenever it drops
+async fn dial(
+    peer: String,
+    codec: FrameCodec,
+    sites: Sites,
+    inbound: TokioSender<Result<ProtoMessage<SyncMessage>>>,
+    outbound: BroadcastSender<SyncMessage>,
+) -> Result<()> {
+    loop {
+        match TcpStream::connect(&peer).await {
+            Ok(stream) => {
+                debug!("Connected to peer {peer}");
+                match serve_connection(
+                    stream,
+                    true,
+                    codec.clone(),
+                    sites.clone(),
+                    inbound.clone(),
+                    outbound.subscribe(),
+                )
+                .await
+                {
+                    Ok(Served::Shutdown) => return Ok(()),
+                    Ok(Served::Duplicate { kept }) => {
+                        debug!("Already connected to peer {peer}, redialing once that drops");
+                        kept.cancelled().await;
+                        continue;
+                    }
+                    Err(err) => debug!("Connection to peer {peer} dropped: {err:?}"),
+                }
+            }
+            Err(err) => debug!("Could not connect to peer {peer}: {err}"),
+        }
+
+        tokio::time::sleep(PEER_RETRY).await;
+    }
+}
+
+/// How a peer connection ended, other than by failing
+enum Served {
+    /// Our `Shutdown` was sent
+    Shutdown,
+    /// There's another connection to the same site, which has ended once `kept` is cancelled
+    Duplicate { kept: CancellationToken },
+}
+
+/// The connection kept to each site. Peers that list each other dial each other, and both
+/// sides keep the one dialed by the lower site id so every change is only sent once
+#[derive(Clone, Default)]
+struct Sites(Arc<Mutex<SitesInner>>);
+
+#[derive(Default)]
+struct SitesInner {
+    kept: HashMap<u32, Kept>,
+    next_id: u64,
+}
+
+struct Kept {
+    id: u64,
+    preferred: bool,
+    /// Cancelled when the connection should close, and once it has
+    token: CancellationToken,
+}
+
+/// A connection kept for a site, given back when it's dropped
+struct Claim {
+    sites: Sites,
+    site_id: u32,
+    id: u64,
+    token: CancellationToken,
+}
+
+impl Sites {
+    /// Keeps a connection to `site_id`, closing one kept already unless that one is preferred.
+    /// Hands back the kept connection's token instead if this one is the duplicate
+    fn claim(&self, site_id: u32, preferred: bool) -> Result<Claim, CancellationToken> {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(kept) = inner.kept.get(&site_id)
+            && (kept.preferred || !preferred)
+        {
+            return Err(kept.token.clone());
+        }
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let token = CancellationToken::new();
+        let replaced = inner.kept.insert(
+            site_id,
+            Kept {
+                id,
+                preferred,
+                token: token.clone(),
+            },
+        );
+        if let Some(replaced) = replaced {
+            replaced.token.cancel();
+        }
+
+        Ok(Claim {
+            sites: self.clone(),
+            site_id,
+            id,
+            token,
+        })
+    }
+
+    /// The token of the connection kept for `site_id`, already cancelled if there isn't one
+    fn kept(&self, site_id: u32) -> CancellationToken {
+        match self.0.lock().unwrap().kept.get(&site_id) {
+            Some(kept) => kept.token.clone(),
+            None => {
+                let token = CancellationToken::new();
+                token.cancel();
+                token
+            }
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut inner = self.sites.0.lock().unwrap();
+        if inner
+            .kept
+            .get(&self.site_id)
+            .is_some_and(|kept| kept.id == self.id)
+        {
+            inner.kept.remove(&self.site_id);
+        }
+        self.token.cancel();
+    }
+}
+
+/// Relays messages over one peer connection, until our `Shutdown` has been sent or it turns
+/// out to be a second connection to the same site
+async fn serve_connection(
+    stream: TcpStream,
+    dialed: bool,
+    codec: FrameCodec,
+    sites: Sites,
+    inbound: TokioSender<Result<ProtoMessage<SyncMessage>>>,
+    mut outbound: BroadcastReceiver<SyncMessage>,
+) -> Result<Served> {
+    stream.set_nodelay(true)?;
+    let (read, write) = stream.into_split();
+
+    let mut reader = FramedRead::new(read, length_delimited());
+    let mut writer = FramedWrite::new(write, length_delimited());
+
+    // The peer starts a sync with us on hearing this, like it would over multicast
+    writer.send(codec.encode(&SyncMessage::Announce)?).await?;
+
+    // which site this is only shows once it sends us something
+    let mut claim: Option<Claim> = None;
+
+    loop {
+        let superseded = claim.as_ref().map(|claim| claim.token.clone());
+        tokio::select! {
+            frame = reader.next() => {
+                let Some(frame) = frame else {
+                    return Err(anyhow!("peer closed the connection"));
+                };
+                if let Some(message) = codec.decode(frame?) {
+                    if claim.is_none() {
+                        let preferred = dialed == (codec.site_id < message.site_id);
+                        match sites.claim(message.site_id, preferred) {
+                            Ok(claimed) => claim = Some(claimed),
+                            Err(kept) => return Ok(Served::Duplicate { kept }),
+                        }
+                    }
+                    inbound.send(Ok(message)).await?;
+                }
+            }
+            () = async move {
+                match superseded {
+                    Some(token) => token.cancelled_owned().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let site_id = claim.as_ref().map_or(0, |claim| claim.site_id);
+                debug!("Closing a second connection to Site:{site_id}");
+                return Ok(Served::Duplicate { kept: sites.kept(site_id) });
+            }
+            message = outbound.recv() => {
+                match message {
+                    Ok(message) => {
+                        let shutdown = matches!(message, SyncMessage::Shutdown);
+                        writer.send(codec.encode(&message)?).await?;
+                        if shutdown {
+                            return Ok(Served::Shutdown);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Peer connection lagged, skipped {skipped} messages");
+                    }
+                    Err(RecvError::Closed) => return Ok(Served::Shutdown),
+                }
+            }
+        }
+    }
+}
+
+/// Frames on a TCP stream, shared with the relay
+pub fn length_delimited() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME)
+        .new_codec()
+}
+
+/// Turns messages into frames and back: the namespace and site id, then the bincode message,
+/// all sealed with the pre-shared key if there is one
+#[derive(Clone)]
+pub struct FrameCodec {
+    namespace: u32,
+    site_id: u32,
+    cipher: Option<std::sync::Arc<Cipher>>,
+}
+
+impl FrameCodec {
+    pub fn new(site_id: u32, config: &McastConfig) -> Result<Self> {
+        Ok(Self {
+            namespace: workspace::namespace(&config.workspace),
+            site_id,
+            cipher: config.cipher()?.map(std::sync::Arc::new),
+        })
+    }
+
+    /// The same codec for another workspace, which the relay needs as it serves all of them
+    pub fn with_namespace(self, namespace: u32) -> Self {
+        Self { namespace, ..self }
+    }
+
+    pub fn encode(&self, message: &SyncMessage) -> Result<Bytes> {
+        let mut frame = BytesMut::new();
+        frame.put_u32(self.namespace);
+        frame.put_u32(self.site_id);
+        frame.extend_from_slice(&bincode::serde::encode_to_vec(
+            message,
+            bincode::config::standard(),
+        )?);
+
+        Ok(match &self.cipher {
+            Some(cipher) => cipher.seal(&frame).into(),
+            None => frame.freeze(),
+        })
+    }
+
+    /// The namespace a frame is for, without decoding the message. `None` if it fails verification
+    pub fn namespace_of(&self, frame: &[u8]) -> Option<u32> {
+        let mut sealed;
+        let plain = match &self.cipher {
+            Some(cipher) => {
+                sealed = frame.to_vec();
+                cipher.open(&mut sealed)?
+            }
+            None => frame,
+        };
+
+        (plain.len() >= FRAME_HEADER_SIZE)
+            .then(|| u32::from_be_bytes(plain[..4].try_into().unwrap()))
+    }
+
+    pub fn decode(&self, mut frame: BytesMut) -> Option<ProtoMessage<SyncMessage>> {
+        let plain = match &self.cipher {
+            Some(cipher) => {
+                let Some(plain) = cipher.open(&mut frame) else {
+                    debug!("Dropping peer frame that failed verification");
+                    return None;
+                };
+                plain
+            }
+            None => &frame[..],
+        };
+
+        if plain.len() < FRAME_HEADER_SIZE {
+            debug!("Dropping peer frame with a bad header, len:{}", plain.len());
+            return None;
+        }
+
+        let field = |n: usize| u32::from_be_bytes(plain[n * 4..n * 4 + 4].try_into().unwrap());
+
+        if field(0) != self.namespace {
+            trace!("Ignoring peer frame from another workspace");
+            return None;
+        }
+
+        match bincode::serde::decode_from_slice(
+            &plain[FRAME_HEADER_SIZE..],
+            bincode::config::standard(),
+        ) {
+            Ok((message, _)) => Some(ProtoMessage {
+                site_id: field(1),
+                message,
+            }),
+            Err(err) => {
+                warn!("Could not decode peer frame from Site:{}: {err}", field(1));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::multicast::{TodoCommand, TodoState, setup};
+    use crate::backends::proto::PeerTransport;
+    use crate::backends::sort::Priority;
+    use crate::backends::test_util::{free_port, wait_for};
+
+    /// A site listening on `listen` with `peer` as its only peer, and a multicast port of its
+    /// own so the two sites can only find each other through the peer transport
+    fn site_config(
+        dir: &std::path::Path,
+        transport: PeerTransport,
+        listen: u16,
+        peer: u16,
+    ) -> McastConfig {
+        McastConfig {
+            autosave_path: dir.join(format!("{listen}.save")),
+            port: free_port(),
+            peers: vec![format!("127.0.0.1:{peer}")],
+            peer_transport: transport,
+            peer_listen: Some(SocketAddr::from(([127, 0, 0, 1], listen))),
+            ..Default::default()
+        }
+    }
+
+    async fn converges_over(transport: PeerTransport) {
+        let dir: PathBuf = std::env::temp_dir().join(format!("todo-mcp-peers-{}", Uuid::new_v4()));
+        let (port_a, port_b) = (free_port(), free_port());
+
+        let (tx_a, mut events_a) = setup(1, site_config(&dir, transport, port_a, port_b));
+        let (tx_b, mut events_b) = setup(2, site_config(&dir, transport, port_b, port_a));
+
+        let (groceries, milk, work) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        tx_a.send(TodoCommand::AddList {
+            list_id: groceries,
+            title: "Groceries".into(),
+            metadata: HashMap::new(),
+        })
+        .await
+        .unwrap();
+        tx_a.send(TodoCommand::AddTodo {
+            list_id: groceries,
+            item_id: milk,
+            text: "Milk".into(),
+            metadata: HashMap::new(),
+            due: None,
+            priority: Priority::None,
+            parent_id: None,
+            tags: Vec::new(),
+        })
+        .await
+        .unwrap();
+        tx_b.send(TodoCommand::AddList {
+            list_id: work,
+            title: "Work".into(),
+            metadata: HashMap::new(),
+        })
+        .await
+        .unwrap();
+
+        let converged = |state: &TodoState| {
+            state.list(work).is_some()
+                && state
+                    .list(groceries)
+                    .is_some_and(|list| list.item(milk).is_some())
+        };
+
+        let state_a = wait_for(&mut events_a, converged).await;
+        let state_b = wait_for(&mut events_b, converged).await;
+        assert_eq!(state_a.lists.len(), 2);
+        assert_eq!(state_b.lists.len(), 2);
+
+        for tx in [tx_a, tx_b] {
+            let (sender, saved) = tokio::sync::oneshot::channel();
+            tx.send(TodoCommand::Shutdown { sender }).await.unwrap();
+            saved.await.ok();
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn keeps_one_connection_to_each_site() {
+        let sites = Sites::default();
+
+        // the connection the other site dialed arrives first, then the one we dialed
+        let theirs = sites.claim(2, false).unwrap();
+        let ours = sites.claim(2, true).unwrap();
+        assert!(theirs.token.is_cancelled());
+        drop(theirs);
+
+        // and a later one isn't kept alongside it
+        let kept = sites.claim(2, false).err().unwrap();
+        assert!(!kept.is_cancelled());
+        assert!(sites.claim(3, false).is_ok());
+
+        drop(ours);
+        assert!(kept.is_cancelled());
+        assert!(sites.claim(2, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn converges_over_tcp() {
+        converges_over(PeerTransport::Tcp).await;
+    }
+
+    #[tokio::test]
+    async fn converges_over_udp() {
+        converges_over(PeerTransport::Udp).await;
+    }
+}
@@ -1,12 +1,15 @@
 use std::{
-    cmp,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    io,
     marker::PhantomData,
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::PathBuf,
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
+use clap::{ArgAction, Args, ValueEnum};
 use futures::{ready, Stream};
 use pin_project_lite::pin_project;
 use serde::{de::DeserializeOwned, Serialize};
@@ -14,43 +17,417 @@ use socket2::{Domain, Socket, Type};
 use tokio::{io::ReadBuf, net::UdpSocket};
 use tracing::*;
 
+use crate::backends::{
+    backup::{DEFAULT_BACKUP_COUNT, DEFAULT_BACKUP_MAX_AGE_DAYS},
+    crypto::{self, Cipher, Psk},
+    workspace::{self, DEFAULT_WORKSPACE, STORAGE_LOCATION},
+};
+
 const BUF_SIZE: usize = 1400;
 
+/// namespace, site_id, seq, num, idx, total len & checksum, all u32 big endian
+const HEADER_SIZE: usize = 28;
+
+/// How long an incomplete message is kept around waiting for its missing fragments
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many incomplete messages we track per site before dropping the oldest
+const MAX_IN_FLIGHT: usize = 16;
+
+/// The longest message we reassemble, a header claiming more is dropped
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// How many sites we reassemble messages for at once before dropping the quietest, as site ids
+/// come straight from the datagram
+const MAX_SITES: usize = 64;
+
+const DEFAULT_GROUP: Ipv4Addr = Ipv4Addr::new(239, 1, 1, 1);
+/// Link-local scope, the IPv6 equivalent of a TTL of 1
+const DEFAULT_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff12, 0, 0, 0, 0, 0, 0, 0x1111);
+const DEFAULT_PORT: u16 = 1111;
+const DEFAULT_TTL: u32 = 1;
+
+/// Which IP families the multicast sockets use
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum McastFamily {
+    #[default]
+    V4,
+    V6,
+    /// Send on both families, messages arriving on both are only delivered once
+    Dual,
+}
+
+impl McastFamily {
+    fn v4(self) -> bool {
+        matches!(self, McastFamily::V4 | McastFamily::Dual)
+    }
+
+    fn v6(self) -> bool {
+        matches!(self, McastFamily::V6 | McastFamily::Dual)
+    }
+}
+
+/// How configured peers are reached
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PeerTransport {
+    /// Length-prefixed frames over a connection to each peer
+    #[default]
+    Tcp,
+    /// The same fragmented datagrams as multicast, sent to each peer
+    Udp,
+}
+
+/// Where and how we sync with other sites
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct McastConfig {
+    /// Workspace to open, each has its own document, save file and sync namespace
+    #[arg(
+        long = "workspace",
+        env = "TODOMCP_WORKSPACE",
+        default_value = DEFAULT_WORKSPACE,
+        value_parser = workspace::parse_name,
+        global = true
+    )]
+    pub workspace: String,
+    /// Save file of the default workspace, other workspaces are saved in a `workspaces` directory next to it
+    #[arg(
+        long = "autosave-path",
+        env = "TODOMCP_AUTOSAVE_PATH",
+        default_value = STORAGE_LOCATION,
+        global = true
+    )]
+    pub autosave_path: PathBuf,
+    /// Number of save file snapshots to keep, 0 turns backups off
+    #[arg(
+        long = "backup-count",
+        env = "TODOMCP_BACKUP_COUNT",
+        default_value_t = DEFAULT_BACKUP_COUNT,
+        global = true
+    )]
+    pub backup_count: usize,
+    /// Snapshots older than this many days are deleted
+    #[arg(
+        long = "backup-max-age-days",
+        env = "TODOMCP_BACKUP_MAX_AGE_DAYS",
+        default_value_t = DEFAULT_BACKUP_MAX_AGE_DAYS,
+        global = true
+    )]
+    pub backup_max_age_days: u32,
+    /// IP family to sync over
+    #[arg(
+        long = "mcast-family",
+        env = "TODOMCP_MCAST_FAMILY",
+        value_enum,
+        default_value_t = McastFamily::V4,
+        global = true
+    )]
+    pub family: McastFamily,
+    /// Multicast group to sync on, use a different group to keep teams apart
+    #[arg(
+        long = "mcast-group",
+        env = "TODOMCP_MCAST_GROUP",
+        default_value_t = DEFAULT_GROUP,
+        global = true
+    )]
+    pub group: Ipv4Addr,
+    /// IPv6 multicast group, ff12:: groups are link-local and ff15:: site-local
+    #[arg(
+        long = "mcast-group-v6",
+        env = "TODOMCP_MCAST_GROUP_V6",
+        default_value_t = DEFAULT_GROUP_V6,
+        global = true
+    )]
+    pub group_v6: Ipv6Addr,
+    /// UDP port for the multicast group
+    #[arg(
+        long = "mcast-port",
+        env = "TODOMCP_MCAST_PORT",
+        default_value_t = DEFAULT_PORT,
+        global = true
+    )]
+    pub port: u16,
+    /// Multicast TTL (hop limit for IPv6), 1 keeps traffic on the local network
+    #[arg(
+        long = "mcast-ttl",
+        env = "TODOMCP_MCAST_TTL",
+        default_value_t = DEFAULT_TTL,
+        global = true
+    )]
+    pub ttl: u32,
+    /// Whether our own datagrams are looped back, needed for instances on the same host to see each other
+    #[arg(
+        long = "mcast-loopback",
+        env = "TODOMCP_MCAST_LOOPBACK",
+        default_value_t = true,
+        action = ArgAction::Set,
+        global = true
+    )]
+    pub loopback: bool,
+    /// Address of the local interface to sync over, e.g. the VPN's, instead of letting the OS choose
+    #[arg(
+        long = "mcast-interface",
+        env = "TODOMCP_MCAST_INTERFACE",
+        global = true
+    )]
+    pub interface: Option<Ipv4Addr>,
+    /// Index of the interface to use for IPv6, picked from the first non-loopback IPv6 interface if unset
+    #[arg(
+        long = "mcast-interface-index",
+        env = "TODOMCP_MCAST_INTERFACE_INDEX",
+        global = true
+    )]
+    pub interface_index: Option<u32>,
+    /// Pre-shared key that every datagram is encrypted and authenticated with, prefer the env var or a key file
+    #[arg(
+        long = "psk",
+        env = "TODOMCP_PSK",
+        hide_env_values = true,
+        global = true
+    )]
+    pub psk: Option<Psk>,
+    /// File to read the pre-shared key from, instead of `--psk`
+    #[arg(
+        long = "psk-file",
+        env = "TODOMCP_PSK_FILE",
+        conflicts_with = "psk",
+        global = true
+    )]
+    pub psk_file: Option<PathBuf>,
+    /// Peers to sync with directly as `host:port`, for networks that drop multicast
+    #[arg(
+        long = "peer",
+        env = "TODOMCP_PEERS",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub peers: Vec<String>,
+    /// Whether peers are reached over TCP or unicast UDP
+    #[arg(
+        long = "peer-transport",
+        env = "TODOMCP_PEER_TRANSPORT",
+        value_enum,
+        default_value_t = PeerTransport::Tcp,
+        global = true
+    )]
+    pub peer_transport: PeerTransport,
+    /// Address to accept peers on, peers can only reach us if this is set
+    #[arg(
+        long = "peer-listen",
+        env = "TODOMCP_PEER_LISTEN",
+        global = true
+    )]
+    pub peer_listen: Option<SocketAddr>,
+    /// Relay to sync through, `host:port` for TCP or `ws://host:port/` for WebSocket
+    #[arg(long = "relay", env = "TODOMCP_RELAY", global = true)]
+    pub relay: Option<String>,
+}
+
+impl Default for McastConfig {
+    fn default() -> Self {
+        Self {
+            workspace: DEFAULT_WORKSPACE.to_string(),
+            autosave_path: STORAGE_LOCATION.into(),
+            backup_count: DEFAULT_BACKUP_COUNT,
+            backup_max_age_days: DEFAULT_BACKUP_MAX_AGE_DAYS,
+            family: McastFamily::V4,
+            group: DEFAULT_GROUP,
+            group_v6: DEFAULT_GROUP_V6,
+            port: DEFAULT_PORT,
+            ttl: DEFAULT_TTL,
+            loopback: true,
+            interface: None,
+            interface_index: None,
+            psk: None,
+            psk_file: None,
+            peers: Vec::new(),
+            peer_transport: PeerTransport::Tcp,
+            peer_listen: None,
+            relay: None,
+        }
+    }
+}
+
+impl McastConfig {
+    fn interface(&self) -> Ipv4Addr {
+        self.interface.unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// The cipher for the configured key, if any, traffic is sent in the clear without one
+    pub fn cipher(&self) -> anyhow::Result<Option<Cipher>> {
+        let psk = match (&self.psk_file, &self.psk) {
+            (Some(path), _) => Psk::from_file(path)?,
+            (None, Some(psk)) => psk.clone(),
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Cipher::new(&psk)?))
+    }
+
+    /// The IPv6 interface index, link-local groups need one to be set so we don't leave it to the OS
+    fn interface_index(&self) -> u32 {
+        if let Some(index) = self.interface_index {
+            return index;
+        }
+
+        let interfaces = match netwatcher::list_interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(err) => {
+                warn!("Could not list interfaces, leaving the IPv6 interface to the OS: {err:?}");
+                return 0;
+            }
+        };
+
+        let index = interfaces
+            .values()
+            .filter(|interface| {
+                interface.ipv6_ips().next().is_some()
+                    && !interface.ipv6_ips().any(|ip| ip.is_loopback())
+            })
+            .map(|interface| interface.index)
+            .min()
+            .unwrap_or(0);
+
+        debug!("Using IPv6 interface index {index}");
+
+        index
+    }
+
+    fn recv_socket_v4(&self) -> anyhow::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        socket.join_multicast_v4(&self.group, &self.interface())?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        // Bind to the wildcard address, group traffic isn't addressed to the interface's own address
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port)).into())?;
+
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    fn recv_socket_v6(&self, index: u32) -> anyhow::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+        // Otherwise the IPv6 socket would also claim the port for IPv4 in dual mode
+        socket.set_only_v6(true)?;
+        socket.join_multicast_v6(&self.group_v6, index)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, self.port)).into())?;
+
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    fn send_socket_v4(&self) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        socket.set_multicast_ttl_v4(self.ttl)?;
+        socket.set_multicast_loop_v4(self.loopback)?;
+        if let Some(interface) = self.interface {
+            socket.set_multicast_if_v4(&interface)?;
+            socket.bind(&SocketAddr::from((interface, 0)).into())?;
+        }
+        socket.set_nonblocking(true)?;
+
+        let send_addr = SocketAddr::from((self.group, self.port));
+
+        Ok((UdpSocket::from_std(socket.into())?, send_addr))
+    }
+
+    fn send_socket_v6(&self, index: u32) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+        socket.set_only_v6(true)?;
+        socket.set_multicast_hops_v6(self.ttl)?;
+        socket.set_multicast_loop_v6(self.loopback)?;
+        socket.set_multicast_if_v6(index)?;
+        socket.set_nonblocking(true)?;
+
+        let send_addr = SocketAddrV6::new(self.group_v6, self.port, 0, index).into();
+
+        Ok((UdpSocket::from_std(socket.into())?, send_addr))
+    }
+}
+
 pin_project! {
     pub struct McastReceiver<D: DeserializeOwned> {
+        namespace: u32,
         site_partials: HashMap<u32, SitePartials>,
-        #[pin]
-        socket: UdpSocket,
-        buffer: [u8; BUF_SIZE + 16],
+        stats: ReassemblyStats,
+        // One per family, polled round robin
+        sockets: Vec<UdpSocket>,
+        next_socket: usize,
+        cipher: Option<Cipher>,
+        buffer: [u8; BUF_SIZE + HEADER_SIZE + crypto::OVERHEAD],
         _var: PhantomData<D>,
     }
 }
 
+#[derive(Clone)]
 pub struct ProtoMessage<D: DeserializeOwned> {
     pub site_id: u32,
     pub message: D,
 }
 
+/// Counters for what happened to the packets a [`McastReceiver`] has seen
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// Datagrams received
+    pub packets: u64,
+    /// Messages successfully reassembled and decoded
+    pub messages: u64,
+    /// Fragments we already had for that message, or messages already delivered over the other family
+    pub duplicates: u64,
+    /// Datagrams with a bad header, or that disagree with earlier fragments
+    pub malformed: u64,
+    /// Messages that failed the length/checksum check or could not be decoded
+    pub corrupt: u64,
+    /// Datagrams that failed decryption, either sent without our key or tampered with
+    pub unauthenticated: u64,
+    /// Incomplete messages dropped after [`REASSEMBLY_TIMEOUT`] or to make room
+    pub expired: u64,
+}
+
 impl<D: DeserializeOwned> McastReceiver<D> {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(config: &McastConfig) -> anyhow::Result<Self> {
         // This is our multicast listener
         // This does actually include *all* messages including from the originating site
-        let recv_socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
-        recv_socket.join_multicast_v4(&Ipv4Addr::new(239, 1, 1, 1), &Ipv4Addr::new(0, 0, 0, 0))?;
-        recv_socket.set_reuse_address(true)?;
-        recv_socket.set_reuse_port(true)?;
-        recv_socket.set_nonblocking(true)?;
-        recv_socket.bind(&"0.0.0.0:1111".parse::<SocketAddr>()?.into())?;
+        let mut sockets = Vec::new();
+        if config.family.v4() {
+            sockets.push(config.recv_socket_v4()?);
+        }
+        if config.family.v6() {
+            sockets.push(config.recv_socket_v6(config.interface_index())?);
+        }
+
+        Self::with_sockets(config, sockets)
+    }
 
-        let socket = UdpSocket::from_std(recv_socket.into())?;
+    /// Receives datagrams sent straight to `--peer-listen` by unicast UDP peers, nothing if it isn't set
+    pub fn unicast(config: &McastConfig) -> anyhow::Result<Self> {
+        let mut sockets = Vec::new();
+        if let Some(listen) = config.peer_listen {
+            let socket = std::net::UdpSocket::bind(listen)?;
+            socket.set_nonblocking(true)?;
+            sockets.push(UdpSocket::from_std(socket)?);
+        }
+
+        Self::with_sockets(config, sockets)
+    }
 
+    fn with_sockets(config: &McastConfig, sockets: Vec<UdpSocket>) -> anyhow::Result<Self> {
         Ok(McastReceiver {
+            namespace: workspace::namespace(&config.workspace),
             site_partials: HashMap::new(),
-            socket,
-            buffer: [0; BUF_SIZE + 16],
+            stats: ReassemblyStats::default(),
+            sockets,
+            next_socket: 0,
+            cipher: config.cipher()?,
+            buffer: [0; BUF_SIZE + HEADER_SIZE + crypto::OVERHEAD],
             _var: PhantomData,
         })
     }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
 }
 
 impl<D: DeserializeOwned> Stream for McastReceiver<D> {
@@ -59,176 +436,677 @@ impl<D: DeserializeOwned> Stream for McastReceiver<D> {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.project();
 
-        let mut read_buf = ReadBuf::new(&mut *this.buffer);
+        // Keep reading until a whole message is assembled or the sockets have nothing left,
+        // in which case `poll_recv` has registered the waker for us
+        loop {
+            let len = ready!(poll_recv_any(
+                this.sockets,
+                this.next_socket,
+                cx,
+                &mut *this.buffer
+            ))?;
+
+            this.stats.packets += 1;
 
-        ready!(this.socket.poll_recv(cx, &mut read_buf))?;
+            let filled_buffer = match this.cipher {
+                Some(cipher) => {
+                    let Some(plain) = cipher.open(&mut this.buffer[..len]) else {
+                        debug!("Dropping datagram that failed verification, len:{len}");
+                        this.stats.unauthenticated += 1;
+                        continue;
+                    };
+                    plain
+                }
+                None => &this.buffer[..len],
+            };
 
-        let filled_buffer = read_buf.filled();
+            let Some(header) = Header::parse(filled_buffer) else {
+                warn!(
+                    "Dropping datagram with a bad partial header, len:{}",
+                    filled_buffer.len()
+                );
+                this.stats.malformed += 1;
+                continue;
+            };
 
-        let amt = filled_buffer.len();
+            if header.namespace != *this.namespace {
+                trace!("Ignoring datagram from another workspace");
+                continue;
+            }
 
-        if amt < 16 {
-            warn!("Amount on the wire is below the partial header size!");
-            return Poll::Pending;
-        }
-        let incoming_site_id = u32::from_be_bytes(filled_buffer[0..4].try_into()?);
+            let now = Instant::now();
 
-        let site_partials = this.site_partials.entry(incoming_site_id).or_default();
+            // Throw away anything that has been waiting too long for its missing fragments
+            for partials in this.site_partials.values_mut() {
+                partials.expire(now, this.stats);
+            }
+            this.site_partials.retain(|_, partials| !partials.is_empty());
 
-        site_partials.fill_from_buffer(filled_buffer)?;
+            if !this.site_partials.contains_key(&header.site_id)
+                && this.site_partials.len() >= MAX_SITES
+            {
+                drop_quietest(this.site_partials, this.stats);
+            }
+            let site_partials = this.site_partials.entry(header.site_id).or_default();
 
-        if let Some(bytes) = site_partials.get_buffer() {
-            let (message, _) =
-                bincode::serde::decode_from_slice::<D, _>(&bytes, bincode::config::standard())?;
-            let message = ProtoMessage {
-                site_id: incoming_site_id,
-                message,
+            let Some(bytes) = site_partials.fill_from_buffer(
+                &header,
+                &filled_buffer[HEADER_SIZE..],
+                now,
+                this.stats,
+            ) else {
+                continue;
             };
 
-            Poll::Ready(Some(Ok(message)))
-        } else {
-            cx.waker().wake_by_ref();
-            Poll::Pending
+            match bincode::serde::decode_from_slice::<D, _>(&bytes, bincode::config::standard()) {
+                Ok((message, _)) => {
+                    this.stats.messages += 1;
+                    return Poll::Ready(Some(Ok(ProtoMessage {
+                        site_id: header.site_id,
+                        message,
+                    })));
+                }
+                Err(err) => {
+                    warn!(
+                        "Could not decode message Site:{}, Seq:{}: {err}",
+                        header.site_id, header.seq
+                    );
+                    this.stats.corrupt += 1;
+                }
+            }
         }
     }
 }
 
-struct SitePartials {
+/// Polls each socket in turn starting after the last one that was ready, so one family can't starve the other
+fn poll_recv_any(
+    sockets: &[UdpSocket],
+    next_socket: &mut usize,
+    cx: &mut Context<'_>,
+    buffer: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    for offset in 0..sockets.len() {
+        let idx = (*next_socket + offset) % sockets.len();
+        let mut read_buf = ReadBuf::new(buffer);
+
+        if let Poll::Ready(result) = sockets[idx].poll_recv(cx, &mut read_buf) {
+            *next_socket = (idx + 1) % sockets.len();
+            return Poll::Ready(result.map(|()| read_buf.filled().len()));
+        }
+    }
+
+    Poll::Pending
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    // Hash of the workspace name, so groups can be shared between workspaces
+    namespace: u32,
+    site_id: u32,
+    // The seq represents the current message number from the other end
+    // I.e, if this message is fragmented over multiple packets (which is usually the case, 1500 bytes lol),
     seq: u32,
+    // This is the total number of partials for this given seq
     num: u32,
-    partials: Vec<(u32, Vec<u8>)>,
+    // This is the current partial index
+    idx: u32,
+    // Length and crc32 of the whole encoded message, checked once reassembled
+    len: u32,
+    checksum: u32,
 }
 
-impl SitePartials {
-    fn fill_from_buffer(&mut self, buf: &[u8]) -> anyhow::Result<()> {
-        // The seq represents the current message number from the other end
-        // I.e, if this message is fragmented over multiple packets (which is usually the case, 1500 bytes lol),
-        let seq = u32::from_be_bytes(buf[4..8].try_into()?);
-        // This is the total number of partials for this given seq
-        let num = u32::from_be_bytes(buf[8..12].try_into()?);
-        // This is the current partial index
-        let idx = u32::from_be_bytes(buf[12..16].try_into()?);
+impl Header {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let field = |n: usize| u32::from_be_bytes(buf[n * 4..n * 4 + 4].try_into().unwrap());
 
-        trace!("Seq:{}, Num:{}, Idx:{}", seq, num, idx);
+        let header = Header {
+            namespace: field(0),
+            site_id: field(1),
+            seq: field(2),
+            num: field(3),
+            idx: field(4),
+            len: field(5),
+            checksum: field(6),
+        };
 
-        if seq != self.seq {
-            trace!("Resetting partials for site");
-            self.partials = vec![];
-            self.seq = seq;
-            self.num = num;
+        // fragments are always `BUF_SIZE` but for the last, so the count follows from the length
+        if header.len as usize > MAX_MESSAGE
+            || header.num as usize != (header.len as usize).div_ceil(BUF_SIZE)
+            || header.idx >= header.num
+        {
+            return None;
         }
 
-        // This is the actual partial data
-        let partial = buf[16..].to_vec();
+        Some(header)
+    }
 
-        trace!("Partial len:{}", partial.len());
+    /// How long the fragment's body must be, `BUF_SIZE` for all but the last
+    fn body_len(&self) -> usize {
+        let before = self.idx as usize * BUF_SIZE;
+        (self.len as usize - before).min(BUF_SIZE)
+    }
 
-        self.partials.push((idx, partial));
+    fn write(&self, buf: &mut Vec<u8>) {
+        for field in [
+            self.namespace,
+            self.site_id,
+            self.seq,
+            self.num,
+            self.idx,
+            self.len,
+            self.checksum,
+        ] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+    }
+}
 
-        Ok(())
+/// The fragments received so far for one message
+struct Partial {
+    num: u32,
+    len: u32,
+    checksum: u32,
+    started: Instant,
+    fragments: BTreeMap<u32, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct SitePartials {
+    in_flight: HashMap<u32, Partial>,
+    // Seqs recently delivered, so the copy arriving over the other family is dropped
+    completed: HashMap<u32, Instant>,
+}
+
+impl SitePartials {
+    fn is_empty(&self) -> bool {
+        self.in_flight.is_empty() && self.completed.is_empty()
     }
 
-    fn get_buffer(&mut self) -> Option<Vec<u8>> {
+    /// When we last started or delivered a message from the site
+    fn last_active(&self) -> Option<Instant> {
+        let started = self.in_flight.values().map(|partial| partial.started);
+        started.chain(self.completed.values().copied()).max()
+    }
+
+    /// Adds a fragment, returning the whole message once every fragment has arrived
+    fn fill_from_buffer(
+        &mut self,
+        header: &Header,
+        body: &[u8],
+        now: Instant,
+        stats: &mut ReassemblyStats,
+    ) -> Option<Vec<u8>> {
+        trace!(
+            "Seq:{}, Num:{}, Idx:{}, Partial len:{}",
+            header.seq,
+            header.num,
+            header.idx,
+            body.len()
+        );
+
+        if body.len() != header.body_len() {
+            warn!(
+                "Dropping fragment Seq:{}, Idx:{} with a body of {} bytes, expected {}",
+                header.seq,
+                header.idx,
+                body.len(),
+                header.body_len()
+            );
+            stats.malformed += 1;
+            return None;
+        }
+
+        if self.completed.contains_key(&header.seq) {
+            trace!("Already delivered Seq:{}", header.seq);
+            stats.duplicates += 1;
+            return None;
+        }
+
+        if !self.in_flight.contains_key(&header.seq) && self.in_flight.len() >= MAX_IN_FLIGHT {
+            self.drop_oldest(stats);
+        }
+
+        let partial = self.in_flight.entry(header.seq).or_insert_with(|| Partial {
+            num: header.num,
+            len: header.len,
+            checksum: header.checksum,
+            started: now,
+            fragments: BTreeMap::new(),
+        });
+
+        if (partial.num, partial.len, partial.checksum) != (header.num, header.len, header.checksum)
+        {
+            warn!(
+                "Fragment for Seq:{} disagrees with earlier fragments, dropping message",
+                header.seq
+            );
+            stats.malformed += 1;
+            self.in_flight.remove(&header.seq);
+            return None;
+        }
+
+        if partial.fragments.contains_key(&header.idx) {
+            trace!("Duplicate fragment Seq:{}, Idx:{}", header.seq, header.idx);
+            stats.duplicates += 1;
+            return None;
+        }
+
+        partial.fragments.insert(header.idx, body.to_vec());
+
         // We can't get the buffer yet if there is not enough data
-        if self.partials.len() != self.num as usize {
+        if partial.fragments.len() != partial.num as usize {
             return None;
         }
 
-        let mut partials = std::mem::take(&mut self.partials);
+        let partial = self.in_flight.remove(&header.seq)?;
 
-        partials.sort_by(|(left, _), (right, _)| left.cmp(right));
+        let bytes: Vec<u8> = partial.fragments.into_values().flatten().collect();
 
-        Some(
-            partials
-                .into_iter()
-                .map(|(_idx, buf)| buf)
-                .flatten()
-                .collect(),
-        )
+        if bytes.len() != partial.len as usize || crc32fast::hash(&bytes) != partial.checksum {
+            warn!(
+                "Reassembled Seq:{} failed validation, len:{} expected:{}",
+                header.seq,
+                bytes.len(),
+                partial.len
+            );
+            stats.corrupt += 1;
+            return None;
+        }
+
+        self.completed.insert(header.seq, now);
+
+        Some(bytes)
     }
-}
 
-impl Default for SitePartials {
-    fn default() -> Self {
-        Self {
-            seq: 0,
-            num: 0,
-            partials: vec![],
+    fn expire(&mut self, now: Instant, stats: &mut ReassemblyStats) {
+        self.in_flight.retain(|seq, partial| {
+            let keep = now.duration_since(partial.started) < REASSEMBLY_TIMEOUT;
+            if !keep {
+                debug!(
+                    "Expiring Seq:{}, received {}/{} fragments",
+                    seq,
+                    partial.fragments.len(),
+                    partial.num
+                );
+                stats.expired += 1;
+            }
+            keep
+        });
+        self.completed
+            .retain(|_, delivered| now.duration_since(*delivered) < REASSEMBLY_TIMEOUT);
+    }
+
+    fn drop_oldest(&mut self, stats: &mut ReassemblyStats) {
+        if let Some(oldest) = self
+            .in_flight
+            .iter()
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(seq, _)| *seq)
+        {
+            self.in_flight.remove(&oldest);
+            stats.expired += 1;
         }
     }
 }
 
+/// Drops the site we've heard from least recently, along with its incomplete messages
+fn drop_quietest(site_partials: &mut HashMap<u32, SitePartials>, stats: &mut ReassemblyStats) {
+    if let Some(quietest) = site_partials
+        .iter()
+        .min_by_key(|(_, partials)| partials.last_active())
+        .map(|(site_id, _)| *site_id)
+        && let Some(partials) = site_partials.remove(&quietest)
+    {
+        debug!("Dropping Site:{quietest} to make room for another");
+        stats.expired += partials.in_flight.len() as u64;
+    }
+}
+
 pub struct McastSender {
+    namespace: u32,
     site_id: u32,
     seq: u32,
-    socket: UdpSocket,
-    send_addr: SocketAddr,
+    // One per family, or per peer for unicast, every fragment goes out on all of them
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    cipher: Option<Cipher>,
 }
 
 impl McastSender {
-    pub fn new(site_id: u32) -> anyhow::Result<Self> {
-        let send_socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
-        send_socket.set_nonblocking(true)?;
+    pub fn new(site_id: u32, config: &McastConfig) -> anyhow::Result<Self> {
+        let mut sockets = Vec::new();
+        if config.family.v4() {
+            sockets.push(config.send_socket_v4()?);
+        }
+        if config.family.v6() {
+            sockets.push(config.send_socket_v6(config.interface_index())?);
+        }
 
-        let send_addr = "239.1.1.1:1111".parse::<SocketAddr>()?;
-        let socket = UdpSocket::from_std(send_socket.into())?;
+        Self::with_sockets(site_id, config, sockets)
+    }
+
+    /// Sends to each of the configured `--peer`s instead of a multicast group
+    pub async fn unicast(site_id: u32, config: &McastConfig) -> anyhow::Result<Self> {
+        let mut sockets = Vec::new();
+        for peer in &config.peers {
+            for addr in tokio::net::lookup_host(peer).await? {
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                sockets.push((UdpSocket::bind(local).await?, addr));
+            }
+        }
 
+        Self::with_sockets(site_id, config, sockets)
+    }
+
+    fn with_sockets(
+        site_id: u32,
+        config: &McastConfig,
+        sockets: Vec<(UdpSocket, SocketAddr)>,
+    ) -> anyhow::Result<Self> {
         Ok(Self {
+            namespace: workspace::namespace(&config.workspace),
             site_id,
-            seq: 1,
-            socket,
-            send_addr,
+            // Start somewhere random so a quick restart isn't mistaken for messages already delivered
+            seq: rand::random(),
+            sockets,
+            cipher: config.cipher()?,
         })
     }
 
     pub async fn send<D: Serialize>(&mut self, message: D) -> anyhow::Result<()> {
-        let mut val: Vec<u8> =
-            bincode::serde::encode_to_vec(&message, bincode::config::standard())?;
-
-        let mut to_send = val.len();
+        let val: Vec<u8> = bincode::serde::encode_to_vec(&message, bincode::config::standard())?;
 
-        trace!("Total len to send:{}", to_send);
+        trace!("Total len to send:{}", val.len());
 
-        let num = (to_send / BUF_SIZE) as u32 + 1;
+        let chunks: Vec<&[u8]> = val.chunks(BUF_SIZE).collect();
+        let num = chunks.len() as u32;
 
         trace!("Total number to send:{}", num);
 
-        let mut idx: u32 = 0;
+        let len = val.len() as u32;
+        let checksum = crc32fast::hash(&val);
 
-        while to_send > 0 {
-            let end = cmp::min(BUF_SIZE, val.len());
-            let new_val = val.split_off(end);
-
-            let mut body = val;
-
-            trace!("Body len:{}", body.len());
+        for (idx, body) in chunks.into_iter().enumerate() {
+            let header = Header {
+                namespace: self.namespace,
+                site_id: self.site_id,
+                seq: self.seq,
+                num,
+                idx: idx as u32,
+                len,
+                checksum,
+            };
 
-            let mut payload = Vec::new();
             trace!(
-                "Sending Site:{}, Seq:{}, Num:{} Idx:{}",
+                "Sending Site:{}, Seq:{}, Num:{} Idx:{}, Body len:{}",
                 self.site_id,
                 self.seq,
                 num,
-                idx
+                idx,
+                body.len()
             );
 
-            payload.append(&mut self.site_id.to_be_bytes().to_vec());
-            payload.append(&mut self.seq.to_be_bytes().to_vec());
-            payload.append(&mut num.to_be_bytes().to_vec());
-            payload.append(&mut idx.to_be_bytes().to_vec());
-            payload.append(&mut body);
+            let mut payload = Vec::with_capacity(HEADER_SIZE + body.len());
+            header.write(&mut payload);
+            payload.extend_from_slice(body);
 
-            val = new_val;
+            if let Some(cipher) = &self.cipher {
+                payload = cipher.seal(&payload);
+            }
 
-            self.socket.send_to(&payload, &self.send_addr).await?;
+            for (socket, send_addr) in &self.sockets {
+                socket.send_to(&payload, send_addr).await?;
+            }
+        }
 
-            to_send = to_send - end;
+        self.seq = self.seq.wrapping_add(1);
 
-            idx += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message that takes `num` fragments to send
+    fn message(num: usize) -> Vec<u8> {
+        (0..(num - 1) * BUF_SIZE + 100).map(|i| i as u8).collect()
+    }
+
+    /// The headers and bodies `message` is sent as
+    fn fragments(site_id: u32, seq: u32, message: &[u8]) -> Vec<(Header, Vec<u8>)> {
+        let chunks: Vec<&[u8]> = message.chunks(BUF_SIZE).collect();
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(idx, body)| {
+                let header = Header {
+                    namespace: 1,
+                    site_id,
+                    seq,
+                    num: chunks.len() as u32,
+                    idx: idx as u32,
+                    len: message.len() as u32,
+                    checksum: crc32fast::hash(message),
+                };
+                (header, body.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headers_round_trip_and_bad_ones_are_rejected() {
+        let (header, _) = fragments(7, 3, &message(2)).remove(1);
+        let mut buf = Vec::new();
+        header.write(&mut buf);
+        assert_eq!(Header::parse(&buf), Some(header));
+
+        assert_eq!(Header::parse(&buf[..HEADER_SIZE - 1]), None);
+        let idx_past_num = Header { idx: 3, ..header };
+        let mut buf = Vec::new();
+        idx_past_num.write(&mut buf);
+        assert_eq!(Header::parse(&buf), None);
+
+        // the fragment count has to match the length, which can't be more than we'd send
+        let too_many = Header {
+            num: u32::MAX,
+            ..header
+        };
+        let too_long = Header {
+            num: (MAX_MESSAGE + 1).div_ceil(BUF_SIZE) as u32,
+            len: MAX_MESSAGE as u32 + 1,
+            ..header
+        };
+        for bad in [too_many, too_long] {
+            let mut buf = Vec::new();
+            bad.write(&mut buf);
+            assert_eq!(Header::parse(&buf), None);
         }
+    }
 
-        self.seq += 1;
+    #[test]
+    fn drops_fragments_whose_body_doesnt_fit() {
+        let message = &message(2);
+        let sent = fragments(1, 0, message);
+        let mut partials = SitePartials::default();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
 
-        Ok(())
+        let (first, body) = &sent[0];
+        let mut longer = body.clone();
+        longer.push(0);
+        let (last, body) = &sent[1];
+        let shorter = &body[1..];
+        assert_eq!(
+            partials.fill_from_buffer(first, &longer, now, &mut stats),
+            None
+        );
+        assert_eq!(
+            partials.fill_from_buffer(last, shorter, now, &mut stats),
+            None
+        );
+        assert_eq!(stats.malformed, 2);
+        assert!(partials.in_flight.is_empty());
+
+        // the fragments as sent still make the message
+        let delivered: Vec<_> = sent
+            .iter()
+            .filter_map(|(header, body)| partials.fill_from_buffer(header, body, now, &mut stats))
+            .collect();
+        assert_eq!(delivered, std::slice::from_ref(message));
+    }
+
+    #[test]
+    fn reassembles_fragments_in_reverse_order() {
+        let message = &message(4);
+        let mut partials = SitePartials::default();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
+
+        let mut delivered = None;
+        for (header, body) in fragments(1, 0, message).into_iter().rev() {
+            assert_eq!(delivered, None);
+            delivered = partials.fill_from_buffer(&header, &body, now, &mut stats);
+        }
+        assert_eq!(delivered.as_deref(), Some(&message[..]));
+        assert_eq!(stats, ReassemblyStats::default());
+    }
+
+    #[test]
+    fn drops_duplicate_fragments_and_messages() {
+        let message = &message(3);
+        let sent = fragments(1, 0, message);
+        let mut partials = SitePartials::default();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
+
+        let (first, body) = &sent[0];
+        assert_eq!(
+            partials.fill_from_buffer(first, body, now, &mut stats),
+            None
+        );
+        assert_eq!(
+            partials.fill_from_buffer(first, body, now, &mut stats),
+            None
+        );
+        assert_eq!(stats.duplicates, 1);
+
+        let mut delivered = None;
+        for (header, body) in &sent[1..] {
+            delivered = partials.fill_from_buffer(header, body, now, &mut stats);
+        }
+        assert_eq!(delivered.as_deref(), Some(&message[..]));
+
+        // the whole message again, as it arrives over the other family
+        for (header, body) in &sent {
+            assert_eq!(
+                partials.fill_from_buffer(header, body, now, &mut stats),
+                None
+            );
+        }
+        assert_eq!(stats.duplicates, 1 + sent.len() as u64);
+    }
+
+    #[test]
+    fn partial_messages_expire() {
+        let sent = fragments(1, 0, &message(3));
+        let mut partials = SitePartials::default();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
+
+        let (header, body) = &sent[0];
+        partials.fill_from_buffer(header, body, now, &mut stats);
+        partials.expire(now + REASSEMBLY_TIMEOUT / 2, &mut stats);
+        assert!(!partials.is_empty());
+
+        partials.expire(now + REASSEMBLY_TIMEOUT, &mut stats);
+        assert!(partials.is_empty());
+        assert_eq!(stats.expired, 1);
+
+        // the rest arriving late starts over rather than completing it
+        for (header, body) in &sent[1..] {
+            let later = now + REASSEMBLY_TIMEOUT;
+            assert_eq!(
+                partials.fill_from_buffer(header, body, later, &mut stats),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn drops_the_oldest_once_too_many_are_in_flight() {
+        let mut partials = SitePartials::default();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
+
+        for seq in 0..=MAX_IN_FLIGHT as u32 {
+            let (header, body) = fragments(1, seq, &message(2)).remove(0);
+            let at = now + Duration::from_millis(seq.into());
+            partials.fill_from_buffer(&header, &body, at, &mut stats);
+        }
+        assert_eq!(partials.in_flight.len(), MAX_IN_FLIGHT);
+        assert!(!partials.in_flight.contains_key(&0));
+        assert_eq!(stats.expired, 1);
+    }
+
+    #[test]
+    fn rejects_corrupt_and_inconsistent_fragments() {
+        let message = &message(3);
+        let mut partials = SitePartials::default();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
+
+        let mut sent = fragments(1, 0, message);
+        sent[1].1[0] ^= 1;
+        let delivered: Vec<_> = sent
+            .iter()
+            .filter_map(|(header, body)| partials.fill_from_buffer(header, body, now, &mut stats))
+            .collect();
+        assert!(delivered.is_empty());
+        assert_eq!(stats.corrupt, 1);
+
+        // a fragment claiming a different checksum than the first drops the message
+        let sent = fragments(1, 1, message);
+        let (first, body) = &sent[0];
+        partials.fill_from_buffer(first, body, now, &mut stats);
+        let (second, body) = &sent[1];
+        let disagrees = Header {
+            checksum: second.checksum ^ 1,
+            ..*second
+        };
+        assert_eq!(
+            partials.fill_from_buffer(&disagrees, body, now, &mut stats),
+            None
+        );
+        assert_eq!(stats.malformed, 1);
+        assert!(partials.in_flight.is_empty());
+    }
+
+    #[test]
+    fn drops_the_quietest_site_to_make_room() {
+        let mut site_partials: HashMap<u32, SitePartials> = HashMap::new();
+        let mut stats = ReassemblyStats::default();
+        let now = Instant::now();
+
+        for site_id in 0..MAX_SITES as u32 {
+            let (header, body) = fragments(site_id, 0, &message(2)).remove(0);
+            let at = now + Duration::from_millis(site_id.into());
+            site_partials
+                .entry(site_id)
+                .or_default()
+                .fill_from_buffer(&header, &body, at, &mut stats);
+        }
+
+        drop_quietest(&mut site_partials, &mut stats);
+        assert_eq!(site_partials.len(), MAX_SITES - 1);
+        assert!(!site_partials.contains_key(&0));
+        assert_eq!(stats.expired, 1);
     }
 }
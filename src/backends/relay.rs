@@ -0,0 +1,562 @@
+//! A relay for syncing across networks multicast can't reach.
+//!
+//! Clients connect with `--relay` and the relay passes each frame on to every other client in
+//! the same workspace, without needing to understand it. With `--replica` it also keeps a copy
+//! of each workspace while clients are in it, so a client can catch up on changes made by
+//! others who have since gone offline.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, anyhow};
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, SinkExt, StreamExt, TryStreamExt, future::BoxFuture, stream::BoxStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Mutex,
+        broadcast::{Sender as BroadcastSender, channel as broadcast_channel, error::RecvError},
+        mpsc::Sender as TokioSender,
+        oneshot::channel as oneshot_channel,
+    },
+};
+use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::*;
+
+use crate::backends::{
+    multicast::{SyncMessage, TodoCommand, TodoEvent, setup_with_transports},
+    peers::{FrameCodec, length_delimited},
+    proto::McastConfig,
+    transport::{MessageSink, MessageStream, Transport},
+    workspace::DEFAULT_WORKSPACE,
+};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct RelayArgs {
+    /// Address to accept clients on
+    #[arg(long, env = "TODOMCP_RELAY_LISTEN", default_value = "0.0.0.0:1113")]
+    pub listen: SocketAddr,
+    /// Accept WebSocket connections instead of plain TCP
+    #[arg(long, env = "TODOMCP_RELAY_WEBSOCKET")]
+    pub websocket: bool,
+    /// Keep a replica of every workspace in this directory, so clients can catch up while the
+    /// others are offline, needs `--psk` so only clients with the key can open workspaces
+    #[arg(long, env = "TODOMCP_RELAY_REPLICA")]
+    pub replica: Option<PathBuf>,
+}
+
+type FrameStream = BoxStream<'static, Result<BytesMut>>;
+type FrameSink = Pin<Box<dyn Sink<Bytes, Error = anyhow::Error> + Send>>;
+
+/// Every frame sent in a workspace, tagged with the connection it came from so it isn't echoed
+type Room = BroadcastSender<(u64, Bytes)>;
+
+/// Commands for a room's replica, held so it runs until the last client leaves
+type Replica = Option<TokioSender<TodoCommand>>;
+
+/// How many workspaces the relay serves at once, each with a replica if `--replica` is set
+const MAX_ROOMS: usize = 256;
+
+/// A workspace with clients in it
+struct OpenRoom {
+    room: Room,
+    replica: Replica,
+    clients: usize,
+}
+
+/// Accepts clients until the listener fails.
+///
+/// Frames are checked against `--psk` if it's set, so clients using a key need the relay to
+/// have it too.
+#[instrument(skip_all, fields(listen = %args.listen))]
+pub async fn run_relay(args: RelayArgs, config: McastConfig) -> Result<()> {
+    // without a key anyone could make up workspaces, and have a replica save each of them
+    if args.replica.is_some() && config.cipher()?.is_none() {
+        return Err(anyhow!(
+            "--replica needs --psk, so only clients with the key open workspaces"
+        ));
+    }
+
+    let listener = TcpListener::bind(args.listen).await?;
+    info!("Relay listening on {}", listener.local_addr()?);
+
+    let relay = Arc::new(Relay {
+        codec: FrameCodec::new(0, &config)?,
+        replica: args.replica,
+        config,
+        rooms: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(0),
+    });
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let relay = relay.clone();
+        let websocket = args.websocket;
+
+        tokio::spawn(async move {
+            debug!("Accepted relay client {addr}");
+            let result = match websocket {
+                true => match tokio_tungstenite::accept_async(stream).await {
+                    Ok(stream) => relay.serve(websocket_frames(stream)).await,
+                    Err(err) => Err(err.into()),
+                },
+                false => match tcp_frames(stream) {
+                    Ok(frames) => relay.serve(frames).await,
+                    Err(err) => Err(err),
+                },
+            };
+
+            match result {
+                Ok(()) => debug!("Relay client {addr} disconnected"),
+                Err(err) => debug!("Relay client {addr} dropped: {err:?}"),
+            }
+        });
+    }
+}
+
+struct Relay {
+    codec: FrameCodec,
+    replica: Option<PathBuf>,
+    config: McastConfig,
+    rooms: Mutex<HashMap<u32, OpenRoom>>,
+    next_id: AtomicU64,
+}
+
+impl Relay {
+    /// Passes frames between one client and its workspace's room until either side hangs up
+    async fn serve(&self, (mut frames_in, frames_out): (FrameStream, FrameSink)) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        // The first frame, an announce, says which workspace the client is in
+        let Some(first) = frames_in.try_next().await? else {
+            return Ok(());
+        };
+        let Some(namespace) = self.codec.namespace_of(&first) else {
+            return Err(anyhow!("first frame failed verification"));
+        };
+
+        let room = self.join(namespace).await?;
+        let result = self
+            .relay_frames(id, namespace, &room, first, (frames_in, frames_out))
+            .await;
+        self.leave(namespace).await;
+        result
+    }
+
+    /// Passes the client's frames to the room and the room's frames to the client
+    async fn relay_frames(
+        &self,
+        id: u64,
+        namespace: u32,
+        room: &Room,
+        first: BytesMut,
+        (mut frames_in, mut frames_out): (FrameStream, FrameSink),
+    ) -> Result<()> {
+        let mut room_rx = room.subscribe();
+        room.send((id, first.freeze())).ok();
+
+        loop {
+            tokio::select! {
+                frame = frames_in.try_next() => {
+                    let Some(frame) = frame? else {
+                        return Ok(());
+                    };
+                    if self.codec.namespace_of(&frame) == Some(namespace) {
+                        room.send((id, frame.freeze())).ok();
+                    } else {
+                        debug!("Dropping relay frame that failed verification or is for another workspace");
+                    }
+                }
+                message = room_rx.recv() => {
+                    match message {
+                        Ok((from, frame)) if from != id => frames_out.send(frame).await?,
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            debug!("Relay client lagged, skipped {skipped} frames");
+                        }
+                        Err(RecvError::Closed) => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// The room for a workspace, opened along with its replica when the first client joins
+    async fn join(&self, namespace: u32) -> Result<Room> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(open) = rooms.get_mut(&namespace) {
+            open.clients += 1;
+            return Ok(open.room.clone());
+        }
+        if rooms.len() >= MAX_ROOMS {
+            return Err(anyhow!("already relaying {MAX_ROOMS} workspaces"));
+        }
+
+        let room = broadcast_channel(1024).0;
+        let replica = match &self.replica {
+            Some(dir) => Some(self.start_replica(namespace, dir, &room)?),
+            None => None,
+        };
+
+        let open = OpenRoom {
+            room: room.clone(),
+            replica,
+            clients: 1,
+        };
+        rooms.insert(namespace, open);
+        Ok(room)
+    }
+
+    /// Leaves a workspace's room, closing it and stopping its replica after the last client
+    async fn leave(&self, namespace: u32) {
+        let mut rooms = self.rooms.lock().await;
+        let Some(open) = rooms.get_mut(&namespace) else {
+            return;
+        };
+        open.clients -= 1;
+        if open.clients > 0 {
+            return;
+        }
+
+        debug!("Closing room for workspace {namespace:08x}");
+        let Some(OpenRoom {
+            replica: Some(commands),
+            ..
+        }) = rooms.remove(&namespace)
+        else {
+            return;
+        };
+
+        // the lock is held until the replica has saved, so a client joining again starts the
+        // next one from everything this one had
+        let (sender, saved) = oneshot_channel();
+        if commands
+            .send(TodoCommand::Shutdown { sender })
+            .await
+            .is_ok()
+        {
+            saved.await.ok();
+        }
+        info!("Stopped replica for workspace {namespace:08x}");
+    }
+
+    /// Runs a site in the room, saving to `<dir>/<namespace>.save`
+    fn start_replica(
+        &self,
+        namespace: u32,
+        dir: &std::path::Path,
+        room: &Room,
+    ) -> Result<TokioSender<TodoCommand>> {
+        let site_id = rand::random();
+        let config = McastConfig {
+            workspace: DEFAULT_WORKSPACE.into(),
+            autosave_path: dir.join(format!("{namespace:08x}.save")),
+            ..self.config.clone()
+        };
+
+        let transport = RoomTransport {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            codec: FrameCodec::new(site_id, &config)?.with_namespace(namespace),
+            room: room.clone(),
+        };
+
+        info!("Starting replica {site_id} for workspace {namespace:08x}");
+        let (commands, mut events) =
+            setup_with_transports(site_id, config, vec![Box::new(transport)]);
+
+        // nobody is watching the replica, but its events still need taking off the channel
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if let TodoEvent::ConnectionStatus(status) = event {
+                    debug!("Replica {namespace:08x}: {status}");
+                }
+            }
+        });
+
+        Ok(commands)
+    }
+}
+
+/// Connects a replica to its room, as if it were one more client
+struct RoomTransport {
+    id: u64,
+    codec: FrameCodec,
+    room: Room,
+}
+
+impl Transport for RoomTransport {
+    fn name(&self) -> &'static str {
+        "relay room"
+    }
+
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>> {
+        Box::pin(async move {
+            let (id, codec) = (self.id, self.codec.clone());
+            let stream = futures::stream::unfold(self.room.subscribe(), move |mut rx| {
+                let codec = codec.clone();
+                async move {
+                    loop {
+                        match rx.recv().await {
+                            Ok((from, frame)) if from != id => {
+                                if let Some(message) = codec.decode(BytesMut::from(&frame[..])) {
+                                    return Some((Ok(message), rx));
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(RecvError::Lagged(skipped)) => {
+                                debug!("Replica lagged, skipped {skipped} frames");
+                            }
+                            Err(RecvError::Closed) => return None,
+                        }
+                    }
+                }
+            });
+
+            let (codec, room) = (self.codec.clone(), self.room.clone());
+            let sink = futures::sink::unfold((), move |(), message: SyncMessage| {
+                let frame = codec.encode(&message);
+                let room = room.clone();
+                async move {
+                    room.send((id, frame?)).ok();
+                    Ok::<_, anyhow::Error>(())
+                }
+            });
+
+            Ok((stream.boxed(), Box::pin(sink) as MessageSink))
+        })
+    }
+}
+
+/// Syncs through the relay at `--relay`
+pub struct RelayClient {
+    site_id: u32,
+    config: McastConfig,
+}
+
+impl RelayClient {
+    pub fn new(site_id: u32, config: McastConfig) -> Self {
+        Self { site_id, config }
+    }
+}
+
+impl Transport for RelayClient {
+    fn name(&self) -> &'static str {
+        "relay"
+    }
+
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>> {
+        Box::pin(async move {
+            let Some(relay) = &self.config.relay else {
+                return Err(anyhow!("no relay configured"));
+            };
+
+            let (frames_in, frames_out) = if relay.starts_with("ws://") {
+                websocket_frames(tokio_tungstenite::connect_async(relay.as_str()).await?.0)
+            } else if relay.starts_with("wss://") {
+                return Err(anyhow!(
+                    "wss:// relays aren't supported, put the relay behind a proxy that serves ws://"
+                ));
+            } else {
+                tcp_frames(TcpStream::connect(relay.as_str()).await?)?
+            };
+            debug!("Connected to relay {relay}");
+
+            let codec = FrameCodec::new(self.site_id, &self.config)?;
+            let decoder = codec.clone();
+            let stream = frames_in
+                .try_filter_map(move |frame| futures::future::ready(Ok(decoder.decode(frame))));
+            let sink = frames_out
+                .with(move |message: SyncMessage| futures::future::ready(codec.encode(&message)));
+
+            Ok((stream.boxed(), Box::pin(sink) as MessageSink))
+        })
+    }
+}
+
+/// Length delimited frames, the same as between TCP peers
+fn tcp_frames(stream: TcpStream) -> Result<(FrameStream, FrameSink)> {
+    stream.set_nodelay(true)?;
+    let (read, write) = stream.into_split();
+
+    let frames_in = FramedRead::new(read, length_delimited()).map_err(anyhow::Error::from);
+    let frames_out = FramedWrite::new(write, length_delimited()).sink_map_err(anyhow::Error::from);
+
+    Ok((frames_in.boxed(), Box::pin(frames_out)))
+}
+
+/// One binary message per frame, anything else the other side sends is ignored
+fn websocket_frames<S>(stream: WebSocketStream<S>) -> (FrameStream, FrameSink)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (frames_out, frames_in) = stream.split();
+
+    let frames_in = frames_in
+        .map_err(anyhow::Error::from)
+        .try_filter_map(|message| async move {
+            Ok(match message {
+                Message::Binary(data) => Some(BytesMut::from(&data[..])),
+                _ => None,
+            })
+        });
+    let frames_out = frames_out
+        .sink_map_err(anyhow::Error::from)
+        .with(|frame: Bytes| async move { Ok::<_, anyhow::Error>(Message::Binary(frame)) });
+
+    (frames_in.boxed(), Box::pin(frames_out))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use tokio::sync::mpsc::Receiver as TokioReceiver;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::{
+        crypto::Psk,
+        history,
+        multicast::{TodoState, setup},
+        test_util::{free_port, wait_for},
+        workspace,
+    };
+
+    /// Starts a relay on a free port, returning the address clients should use
+    fn start_relay(websocket: bool, replica: Option<PathBuf>, psk: Option<Psk>) -> String {
+        let port = free_port();
+        let args = RelayArgs {
+            listen: SocketAddr::from(([127, 0, 0, 1], port)),
+            websocket,
+            replica,
+        };
+        let config = McastConfig {
+            psk,
+            ..Default::default()
+        };
+        tokio::spawn(run_relay(args, config));
+
+        match websocket {
+            true => format!("ws://127.0.0.1:{port}/"),
+            false => format!("127.0.0.1:{port}"),
+        }
+    }
+
+    /// A client with a multicast port of its own, so it can only sync through the relay
+    fn client(
+        dir: &std::path::Path,
+        site_id: u32,
+        relay: &str,
+        psk: Option<Psk>,
+    ) -> (TokioSender<TodoCommand>, TokioReceiver<TodoEvent>) {
+        setup(
+            site_id,
+            McastConfig {
+                autosave_path: dir.join(format!("{site_id}.save")),
+                port: free_port(),
+                relay: Some(relay.into()),
+                psk,
+                ..Default::default()
+            },
+        )
+    }
+
+    async fn add_list(tx: &TokioSender<TodoCommand>, list_id: Uuid) {
+        tx.send(TodoCommand::AddList {
+            list_id,
+            title: list_id.to_string(),
+            metadata: HashMap::new(),
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn shutdown(tx: TokioSender<TodoCommand>) {
+        let (sender, saved) = tokio::sync::oneshot::channel();
+        tx.send(TodoCommand::Shutdown { sender }).await.unwrap();
+        saved.await.ok();
+    }
+
+    async fn converges_through(websocket: bool) {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-relay-{}", Uuid::new_v4()));
+        let relay = start_relay(websocket, None, None);
+
+        let (tx_a, mut events_a) = client(&dir, 1, &relay, None);
+        let (tx_b, mut events_b) = client(&dir, 2, &relay, None);
+
+        let (list_a, list_b) = (Uuid::new_v4(), Uuid::new_v4());
+        add_list(&tx_a, list_a).await;
+        add_list(&tx_b, list_b).await;
+
+        let converged =
+            |state: &TodoState| state.list(list_a).is_some() && state.list(list_b).is_some();
+        wait_for(&mut events_a, converged).await;
+        wait_for(&mut events_b, converged).await;
+
+        shutdown(tx_a).await;
+        shutdown(tx_b).await;
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn converges_through_tcp_relay() {
+        converges_through(false).await;
+    }
+
+    #[tokio::test]
+    async fn converges_through_websocket_relay() {
+        converges_through(true).await;
+    }
+
+    #[tokio::test]
+    async fn replica_catches_up_offline_clients() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-relay-{}", Uuid::new_v4()));
+        let psk: Psk = "relay key".parse().unwrap();
+        let relay = start_relay(false, Some(dir.join("replica")), Some(psk.clone()));
+
+        let list_id = Uuid::new_v4();
+        let (tx_a, _events_a) = client(&dir, 1, &relay, Some(psk.clone()));
+        add_list(&tx_a, list_id).await;
+
+        // the replica saves the change before the only client goes offline
+        let namespace = workspace::namespace(DEFAULT_WORKSPACE);
+        let save = dir.join("replica").join(format!("{namespace:08x}.save"));
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while !history::load_path(&save)
+                .and_then(|doc| Ok(autosurgeon::hydrate::<_, TodoState>(&doc)?))
+                .is_ok_and(|state| state.list(list_id).is_some())
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the replica never saved the change");
+        shutdown(tx_a).await;
+
+        let (tx_b, mut events_b) = client(&dir, 2, &relay, Some(psk));
+        wait_for(&mut events_b, |state| state.list(list_id).is_some()).await;
+
+        shutdown(tx_b).await;
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn replica_needs_a_key() {
+        let args = RelayArgs {
+            listen: SocketAddr::from(([127, 0, 0, 1], free_port())),
+            websocket: false,
+            replica: Some(std::env::temp_dir()),
+        };
+        assert!(run_relay(args, McastConfig::default()).await.is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Item priorities, and the orders a list's items can be shown in.
+//!
+//! Items are stored in the order they were added, which is the manual order. A list's sort
+//! mode is stored on the list, so it's remembered and shared with every site, and only
+//! changes how its items are shown.
+
+use std::cmp::Reverse;
+
+use autosurgeon::{Hydrate, Reconcile};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::backends::{TodoItem, TodoList};
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Reconcile,
+    Hydrate,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    None,
+    Low,
+    Medium,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 5] = [
+        Priority::None,
+        Priority::Low,
+        Priority::Medium,
+        Priority::High,
+        Priority::Urgent,
+    ];
+
+    /// The next priority up, wrapping from urgent back to none
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|p| *p == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Priority::None => "none",
+            Priority::Low => "low",
+            Priority::Medium => "medium",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        }
+    }
+
+    pub fn parse(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.label() == label)
+    }
+}
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Reconcile,
+    Hydrate,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SortMode {
+    /// The order items were added in
+    #[default]
+    Manual,
+    /// Most urgent first
+    Priority,
+    /// Soonest due first, items without a due date last
+    Due,
+    /// Newest first
+    Created,
+}
+
+impl SortMode {
+    pub const ALL: [SortMode; 4] = [
+        SortMode::Manual,
+        SortMode::Priority,
+        SortMode::Due,
+        SortMode::Created,
+    ];
+
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|m| *m == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SortMode::Manual => "manual",
+            SortMode::Priority => "priority",
+            SortMode::Due => "due date",
+            SortMode::Created => "newest",
+        }
+    }
+
+    pub fn parse(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.label() == label)
+    }
+
+    /// Positions of `items` in the order this mode shows them, ties keeping their manual order
+    pub fn order<T>(self, items: &[T], key: impl Fn(&T) -> SortKey) -> Vec<usize> {
+        let keys: Vec<SortKey> = items.iter().map(key).collect();
+        let mut order: Vec<usize> = (0..items.len()).collect();
+        match self {
+            SortMode::Manual => {}
+            SortMode::Priority => order.sort_by_key(|idx| Reverse(keys[*idx].priority)),
+            SortMode::Due => order.sort_by_key(|idx| (keys[*idx].due.is_none(), keys[*idx].due)),
+            // items from before creation times were kept have none, and go last
+            SortMode::Created => order.sort_by_key(|idx| Reverse(keys[*idx].created)),
+        }
+        order
+    }
+}
+
+/// What an item is sorted by, whichever type it's shown from
+pub struct SortKey {
+    pub priority: Priority,
+    pub due: Option<DateTime<Utc>>,
+    pub created: Option<DateTime<Utc>>,
+}
+
+impl From<&TodoItem> for SortKey {
+    fn from(item: &TodoItem) -> Self {
+        Self {
+            priority: item.priority,
+            due: item.due,
+            created: item.created,
+        }
+    }
+}
+
+/// The list's items in the order its sort mode shows them
+pub fn sorted(list: &TodoList) -> Vec<&TodoItem> {
+    sorted_items(&list.items, list.sort)
+}
+
+/// `items` in the order `mode` shows them, subtasks being sorted among themselves
+pub fn sorted_items(items: &[TodoItem], mode: SortMode) -> Vec<&TodoItem> {
+    mode.order(items, |item| item.into())
+        .into_iter()
+        .map(|idx| &items[idx])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automerge::AutoCommit;
+    use autosurgeon::{hydrate, reconcile};
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::backends::TodoState;
+
+    #[test]
+    fn sorts_by_each_mode_and_round_trips() {
+        let now = Utc::now();
+        let item = |text: &str, priority, due: Option<i64>, age: i64| TodoItem {
+            priority,
+            due: due.map(|days| now + TimeDelta::days(days)),
+            created: Some(now - TimeDelta::hours(age)),
+            ..TodoItem::new(text, HashMap::new())
+        };
+        let mut list = TodoList {
+            items: vec![
+                item("a", Priority::Low, None, 2),
+                item("b", Priority::Urgent, Some(5), 4),
+                item("c", Priority::None, Some(1), 3),
+                item("d", Priority::Urgent, None, 1),
+            ],
+            ..TodoList::new("list")
+        };
+        let texts = |list: &TodoList| -> String {
+            sorted(list)
+                .into_iter()
+                .map(|item| item.text.as_str())
+                .collect()
+        };
+
+        assert_eq!(texts(&list), "abcd");
+        list.sort = SortMode::Priority;
+        assert_eq!(texts(&list), "bdac");
+        list.sort = SortMode::Due;
+        assert_eq!(texts(&list), "cbad");
+        list.sort = SortMode::Created;
+        assert_eq!(texts(&list), "dacb");
+
+        let state = TodoState {
+            lists: vec![list],
+            ..Default::default()
+        };
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &state).unwrap();
+        let hydrated: TodoState = hydrate(&doc).unwrap();
+        assert_eq!(hydrated.lists[0].sort, SortMode::Created);
+        assert_eq!(hydrated.lists[0].items[1].priority, Priority::Urgent);
+    }
+}
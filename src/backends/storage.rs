@@ -0,0 +1,261 @@
+//! Reading and writing save files safely when several processes share one.
+//!
+//! Every access holds an advisory lock on a `.lock` file next to the save file, shared for
+//! reads and exclusive for writes. Writes go to a temporary file that is synced and renamed
+//! over the save file, so a crash mid-write leaves the previous save intact.
+//!
+//! A save file that won't load is moved aside rather than overwritten, and whatever changes
+//! can still be read from it are salvaged.
+
+use std::{
+    cmp::Ordering,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use automerge::AutoCommit;
+use chrono::Utc;
+use fs2::FileExt;
+use tracing::*;
+
+use crate::backends::compaction;
+
+/// Held for as long as the save file is being read or replaced, unlocked when dropped
+struct SaveLock {
+    _file: File,
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    path.with_extension("lock")
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension("tmp")
+}
+
+/// Every Automerge chunk, document or change, starts with these bytes
+const CHUNK_MAGIC: [u8; 4] = [0x85, 0x6f, 0x4a, 0x83];
+
+fn lock(path: &Path, exclusive: bool) -> Result<SaveLock> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create {}", parent.display()))?;
+    }
+
+    let lock_path = lock_path(path);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("could not open lock file {}", lock_path.display()))?;
+
+    if exclusive {
+        FileExt::lock_exclusive(&file)?;
+    } else {
+        FileExt::lock_shared(&file)?;
+    }
+
+    Ok(SaveLock { _file: file })
+}
+
+fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+/// Replaces the file at `path` with `data`, without ever leaving it half written
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let temp = temp_path(path);
+
+    let mut file =
+        File::create(&temp).with_context(|| format!("could not create {}", temp.display()))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("could not move {} into place", temp.display()))?;
+
+    // the rename itself is only durable once the directory is synced
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// The contents of the save file, `None` if nothing has been saved yet
+pub fn load(path: &Path) -> Result<Option<Vec<u8>>> {
+    let _lock = lock(path, false)?;
+    read(path)
+}
+
+/// What [`save`] did with the save file
+pub enum Saved {
+    /// Written, `merged` if the file had changes the document didn't, they're in it now
+    Written { merged: bool },
+    /// Left alone, the file holds a later compaction the document should move onto first
+    Superseded(Box<AutoCommit>),
+}
+
+/// Saves the document, merging in whatever another process saved since we last looked.
+///
+/// A file from an earlier compaction is replaced without merging, so its history doesn't
+/// come back, and one from a later compaction is handed back instead of being overwritten.
+pub fn save(path: &Path, doc: &mut AutoCommit) -> Result<Saved> {
+    let _lock = lock(path, true)?;
+
+    let merged = match read(path)? {
+        Some(data) => match AutoCommit::load(&data) {
+            Ok(mut on_disk) => match compaction::epoch(&on_disk).cmp(&compaction::epoch(doc)) {
+                Ordering::Greater => return Ok(Saved::Superseded(Box::new(on_disk))),
+                Ordering::Less => false,
+                Ordering::Equal => !doc.merge(&mut on_disk)?.is_empty(),
+            },
+            Err(err) => {
+                warn!("Replacing unreadable save file {}: {err}", path.display());
+                false
+            }
+        },
+        None => false,
+    };
+
+    write_atomic(path, &doc.save())?;
+
+    Ok(Saved::Written { merged })
+}
+
+/// Moves a save file that wouldn't load out of the way, returning where it went.
+///
+/// `data` is what failed to load. `None` means the file no longer holds it, another process
+/// has saved over it since, so it's left alone to be loaded again.
+pub fn quarantine(path: &Path, data: &[u8]) -> Result<Option<PathBuf>> {
+    let _lock = lock(path, true)?;
+
+    if read(path)?.as_deref() != Some(data) {
+        return Ok(None);
+    }
+
+    let moved_to = path.with_extension(format!("{}.corrupt", Utc::now().format("%Y%m%dT%H%M%SZ")));
+    std::fs::rename(path, &moved_to)
+        .with_context(|| format!("could not move {} aside", path.display()))?;
+
+    Ok(Some(moved_to))
+}
+
+/// Loads whatever changes are still readable from a damaged save file into `doc`.
+///
+/// Each chunk carries its own checksum, so loading from the start of every chunk keeps the
+/// changes before and after a damaged one. Returns how many changes `doc` gained.
+pub fn salvage(data: &[u8], doc: &mut AutoCommit) -> usize {
+    let before = doc.get_changes(&[]).len();
+
+    for start in (0..data.len()).filter(|&idx| data[idx..].starts_with(&CHUNK_MAGIC)) {
+        if let Err(err) = doc.load_incremental(&data[start..]) {
+            debug!("Nothing to salvage at byte {start}: {err}");
+        }
+    }
+
+    doc.get_changes(&[]).len() - before
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automerge::ActorId;
+    use autosurgeon::{hydrate, reconcile};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::{TodoCommand, TodoState, ops};
+
+    fn add_list(doc: &mut AutoCommit) -> Uuid {
+        let list_id = Uuid::new_v4();
+        ops::apply(
+            doc,
+            TodoCommand::AddList {
+                list_id,
+                title: "List".into(),
+                metadata: HashMap::new(),
+            },
+        )
+        .unwrap();
+        list_id
+    }
+
+    #[test]
+    fn save_keeps_changes_from_other_writers() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-storage-{}", Uuid::new_v4()));
+        let path = dir.join("automerge.save");
+
+        let mut base = AutoCommit::new();
+        reconcile(&mut base, TodoState::default()).unwrap();
+        let mut a = base.fork().with_actor(ActorId::random());
+        let mut b = base.fork().with_actor(ActorId::random());
+
+        let list_a = add_list(&mut a);
+        let list_b = add_list(&mut b);
+
+        assert!(matches!(
+            save(&path, &mut a).unwrap(),
+            Saved::Written { merged: false }
+        ));
+        // b has never seen a's list, saving must not lose it
+        assert!(matches!(
+            save(&path, &mut b).unwrap(),
+            Saved::Written { merged: true }
+        ));
+        assert!(!temp_path(&path).exists());
+
+        let on_disk = AutoCommit::load(&load(&path).unwrap().unwrap()).unwrap();
+        let state: TodoState = hydrate(&on_disk).unwrap();
+        assert!(state.list(list_a).is_some());
+        assert!(state.list(list_b).is_some());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn salvage_keeps_chunks_either_side_of_damage() {
+        let mut base = AutoCommit::new();
+        reconcile(&mut base, TodoState::default()).unwrap();
+        let mut data = base.save();
+        let heads = base.get_heads();
+
+        let mut a = base.fork().with_actor(ActorId::random());
+        let lost = add_list(&mut a);
+        let mut damaged = a.save_after(&heads);
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        data.extend(damaged);
+
+        let mut b = base.fork().with_actor(ActorId::random());
+        let kept = add_list(&mut b);
+        data.extend(b.save_after(&heads));
+
+        assert!(AutoCommit::load(&data).is_err());
+
+        let mut doc = AutoCommit::new();
+        assert_eq!(salvage(&data, &mut doc), 2);
+        let state: TodoState = hydrate(&doc).unwrap();
+        assert!(state.list(lost).is_none());
+        assert!(state.list(kept).is_some());
+    }
+
+    #[test]
+    fn load_before_first_save_is_empty() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-storage-{}", Uuid::new_v4()));
+
+        assert!(load(&dir.join("automerge.save")).unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,293 @@
+//! Tags on items, and the workspace's registry of tag colours.
+//!
+//! Tags are stored on each item by name, lowercase and without the `#`, as the keys of a map so
+//! tags added and removed on different sites at once all merge. The registry lives
+//! in the document next to the lists, so a tag has the same colour on every site. A tag is
+//! registered the first time it's used, with a colour picked from its name.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Result, anyhow};
+use autosurgeon::{Hydrate, Reconcile};
+use serde::{Deserialize, Serialize};
+
+use crate::backends::{TodoItem, TodoList, TodoState};
+
+/// Stores an item's tag names as the keys of a map with a null value each, reading the list
+/// items from before that have too, and missing values as no tags
+pub mod crdt {
+    use autosurgeon::{
+        Hydrate, HydrateError, Prop, ReadDoc, Reconcile, Reconciler,
+        reconcile::{MapReconciler, NoKey},
+    };
+
+    struct Names(Vec<String>);
+
+    impl Hydrate for Names {
+        fn hydrate_map<D: ReadDoc>(doc: &D, obj: &automerge::ObjId) -> Result<Self, HydrateError> {
+            let names = doc.map_range(obj, ..).map(|item| item.key.into_owned());
+            Ok(Self(names.collect()))
+        }
+
+        fn hydrate_seq<D: ReadDoc>(doc: &D, obj: &automerge::ObjId) -> Result<Self, HydrateError> {
+            Ok(Self(Vec::hydrate_seq(doc, obj)?))
+        }
+    }
+
+    /// The value under each tag's key, there's nothing to it but being there
+    struct Present;
+
+    impl Reconcile for Present {
+        type Key<'a> = NoKey;
+
+        fn reconcile<R: Reconciler>(&self, mut reconciler: R) -> Result<(), R::Error> {
+            reconciler.none()
+        }
+    }
+
+    /// An item's tags to reconcile on their own, as `SetTags` does
+    pub struct Keys<'a>(pub &'a [String]);
+
+    impl Reconcile for Keys<'_> {
+        type Key<'a> = NoKey;
+
+        fn reconcile<R: Reconciler>(&self, reconciler: R) -> Result<(), R::Error> {
+            reconcile(self.0, reconciler)
+        }
+    }
+
+    pub fn hydrate<D: ReadDoc>(
+        doc: &D,
+        obj: &automerge::ObjId,
+        prop: Prop<'_>,
+    ) -> Result<Vec<String>, HydrateError> {
+        // items from before tags have no key at all
+        if doc.get(obj, &prop)?.is_none() {
+            return Ok(Vec::new());
+        }
+        let Names(names) = Hydrate::hydrate(doc, obj, prop)?;
+        Ok(names)
+    }
+
+    /// Removes the keys of tags that are gone and adds those that are new, leaving the rest
+    pub fn reconcile<R: Reconciler>(tags: &[String], mut reconciler: R) -> Result<(), R::Error> {
+        let mut map = reconciler.map()?;
+        let gone: Vec<String> = map
+            .entries()
+            .map(|(name, _)| name.into_owned())
+            .filter(|name| !tags.contains(name))
+            .collect();
+        for name in gone {
+            map.delete(name)?;
+        }
+        for name in tags {
+            if map.entry(name).is_none() {
+                map.put(name, Present)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The colours tags are given, by name
+pub const PALETTE: [(&str, &str); 8] = [
+    ("red", "#dc2626"),
+    ("orange", "#ea580c"),
+    ("yellow", "#ca8a04"),
+    ("green", "#16a34a"),
+    ("teal", "#0d9488"),
+    ("blue", "#2563eb"),
+    ("purple", "#9333ea"),
+    ("pink", "#db2777"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Reconcile, Hydrate, Serialize, Deserialize)]
+pub struct Tag {
+    /// As `#rrggbb`
+    pub color: String,
+}
+
+impl Tag {
+    /// The colour a tag gets when it's first used, the same on every site
+    pub fn new(name: &str) -> Self {
+        let hash = name.bytes().fold(0usize, |hash, b| {
+            hash.wrapping_mul(31).wrapping_add(b.into())
+        });
+        Self {
+            color: PALETTE[hash % PALETTE.len()].1.to_string(),
+        }
+    }
+
+    /// The colour's name in the palette, or `#rrggbb` if it's not one of them
+    pub fn label(&self) -> &str {
+        PALETTE
+            .iter()
+            .find(|(_, hex)| *hex == self.color)
+            .map_or(self.color.as_str(), |(name, _)| name)
+    }
+
+    /// The colour as red, green and blue
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        let hex = self.color.strip_prefix('#')?;
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some((channel(0)?, channel(2)?, channel(4)?))
+    }
+}
+
+/// Reads a tag as people write it, with or without its `#`
+pub fn parse(input: &str) -> Result<String> {
+    let name = input.trim().trim_start_matches('#').to_lowercase();
+    if name.is_empty() {
+        return Err(anyhow!("a tag needs a name"));
+    }
+    if let Some(ch) = name
+        .chars()
+        .find(|ch| !(ch.is_alphanumeric() || matches!(ch, '-' | '_' | '/')))
+    {
+        return Err(anyhow!("tags can't contain '{ch}'"));
+    }
+    Ok(name)
+}
+
+/// Reads a space or comma separated list of tags, dropping repeats
+pub fn parse_list(input: &str) -> Result<Vec<String>> {
+    let mut tags = Vec::new();
+    for word in input.split([' ', ',']).filter(|word| !word.is_empty()) {
+        let tag = parse(word)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+/// Reads a colour as a palette name or `#rrggbb`
+pub fn parse_color(input: &str) -> Result<String> {
+    let input = input.trim().to_lowercase();
+    if let Some((_, hex)) = PALETTE.iter().find(|(name, _)| *name == input) {
+        return Ok(hex.to_string());
+    }
+    let tag = Tag { color: input };
+    match tag.rgb() {
+        Some(_) => Ok(tag.color),
+        None => Err(anyhow!(
+            "can't read \"{}\" as a colour, try #rrggbb or one of {}",
+            tag.color,
+            PALETTE.map(|(name, _)| name).join(", ")
+        )),
+    }
+}
+
+/// Splits the `#tags` out of what was typed for a new item, as in `Fix login #bug #urgent`
+pub fn extract(text: &str) -> (String, Vec<String>) {
+    let mut tags = Vec::new();
+    let mut words = Vec::new();
+    for word in text.split_whitespace() {
+        match word.strip_prefix('#').map(parse) {
+            Some(Ok(tag)) => {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            _ => words.push(word),
+        }
+    }
+    (words.join(" "), tags)
+}
+
+/// Whether the item or any of its subtasks has `tag`
+pub fn matches(item: &TodoItem, tag: &str) -> bool {
+    item.tags.iter().any(|t| t == tag) || item.children.iter().any(|child| matches(child, tag))
+}
+
+/// The items that match `tag`, keeping only their subtasks that match too
+pub fn filter(items: &[TodoItem], tag: &str) -> Vec<TodoItem> {
+    items
+        .iter()
+        .filter(|item| matches(item, tag))
+        .map(|item| TodoItem {
+            children: filter(&item.children, tag),
+            ..item.clone()
+        })
+        .collect()
+}
+
+/// The lists with items matching `tag`, keeping only those items
+pub fn filter_lists(state: &TodoState, tag: &str) -> TodoState {
+    TodoState {
+        lists: state
+            .lists
+            .iter()
+            .filter_map(|list| {
+                let items = filter(&list.items, tag);
+                (!items.is_empty()).then(|| TodoList {
+                    items,
+                    ..list.clone()
+                })
+            })
+            .collect(),
+        tags: state.tags.clone(),
+    }
+}
+
+/// Every tag that's registered or on an item, by name
+pub fn all(state: &TodoState) -> BTreeSet<String> {
+    let mut tags: BTreeSet<String> = state.tags.keys().cloned().collect();
+    for list in &state.lists {
+        for (_, item) in list.all_items() {
+            tags.extend(item.tags.iter().cloned());
+        }
+    }
+    tags
+}
+
+/// The tag's registered colour, or the one it would be given
+pub fn color(state: &TodoState, name: &str) -> Tag {
+    state
+        .tags
+        .get(name)
+        .cloned()
+        .unwrap_or_else(|| Tag::new(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn extracts_and_filters_tags() {
+        let (text, tags) = extract("Fix login #bug #Urgent #bug on #1 the # page");
+        assert_eq!(text, "Fix login on the # page");
+        assert_eq!(tags, ["bug", "urgent", "1"]);
+        assert_eq!(parse_list("#a, b  c,a").unwrap(), ["a", "b", "c"]);
+        assert!(parse("no.dots").is_err());
+        assert_eq!(parse_color("Teal").unwrap(), "#0d9488");
+        assert!(parse_color("#12345").is_err());
+
+        let tagged = |text: &str, tags: &[&str], children| TodoItem {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            children,
+            ..TodoItem::new(text, HashMap::new())
+        };
+        let items = vec![
+            tagged(
+                "release",
+                &[],
+                vec![
+                    tagged("fix crash", &["bug"], vec![]),
+                    tagged("notes", &[], vec![]),
+                ],
+            ),
+            tagged("refactor", &["chore"], vec![]),
+        ];
+        let filtered = filter(&items, "bug");
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].children.len(), 1);
+        assert_eq!(filtered[0].children[0].text, "fix crash");
+    }
+}
@@ -0,0 +1,34 @@
+//! Helpers shared by the tests that run whole sites
+
+use std::time::Duration;
+
+use tokio::sync::mpsc::Receiver as TokioReceiver;
+
+use crate::backends::{TodoState, multicast::TodoEvent};
+
+/// A port nothing is listening on, for a site or relay to take
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Waits for a state update that's `done`, failing the test if none comes within 20 seconds
+pub async fn wait_for(
+    events: &mut TokioReceiver<TodoEvent>,
+    done: impl Fn(&TodoState) -> bool,
+) -> TodoState {
+    tokio::time::timeout(Duration::from_secs(20), async {
+        loop {
+            if let Some(TodoEvent::StateUpdate(state)) = events.recv().await
+                && done(&state)
+            {
+                return state;
+            }
+        }
+    })
+    .await
+    .expect("the state never got there")
+}
@@ -0,0 +1,420 @@
+//! The ways sync messages get between sites.
+//!
+//! `async_inner` doesn't care how a message travels, it hands every outgoing message to each
+//! [`Transport`] it was given and merges whatever they receive. Multicast, the peers and the
+//! relay are implementations, and [`ChannelHub`] connects sites in the same process.
+
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow};
+use futures::{Sink, SinkExt, StreamExt, TryStreamExt, future::BoxFuture, stream::BoxStream};
+use tokio::sync::{
+    Notify,
+    broadcast::{
+        Receiver as BroadcastReceiver, Sender as BroadcastSender, channel as broadcast_channel,
+        error::RecvError,
+    },
+    mpsc::Sender as TokioSender,
+};
+use tokio::time::Instant;
+use tracing::*;
+
+use crate::backends::{
+    multicast::{SyncMessage, TodoEvent},
+    peers::{TcpPeers, UdpPeers},
+    proto::{McastConfig, McastReceiver, McastSender, PeerTransport, ProtoMessage},
+    relay::RelayClient,
+};
+
+/// How long to wait before reconnecting a transport after it fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// How often the multicast stream logs its reassembly stats
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages from other sites, including our own when the transport echoes them back
+pub type MessageStream = BoxStream<'static, Result<ProtoMessage<SyncMessage>>>;
+
+/// Messages from us to every site the transport reaches
+pub type MessageSink = Pin<Box<dyn Sink<SyncMessage, Error = anyhow::Error> + Send>>;
+
+/// Something sync messages can be sent and received over
+pub trait Transport: Send + Sync {
+    /// Short name for connection status and logs
+    fn name(&self) -> &'static str;
+
+    /// Opens the transport, called again after a backoff whenever the stream fails or ends.
+    ///
+    /// Closing the sink must wait until everything sent on it is out, that's how our `Shutdown`
+    /// is known to have been delivered.
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>>;
+}
+
+/// Multicast, plus the peer transport if `--peer` or `--peer-listen` is set and the relay if
+/// `--relay` is
+pub fn from_config(site_id: u32, config: &McastConfig) -> Vec<Box<dyn Transport>> {
+    let mut transports: Vec<Box<dyn Transport>> = vec![Box::new(Multicast {
+        site_id,
+        config: config.clone(),
+    })];
+
+    if !config.peers.is_empty() || config.peer_listen.is_some() {
+        transports.push(match config.peer_transport {
+            PeerTransport::Udp => Box::new(UdpPeers::new(site_id, config.clone())),
+            PeerTransport::Tcp => Box::new(TcpPeers::new(site_id, config.clone())),
+        });
+    }
+
+    if config.relay.is_some() {
+        transports.push(Box::new(RelayClient::new(site_id, config.clone())));
+    }
+
+    transports
+}
+
+/// Runs a transport until our `Shutdown` has gone out over it.
+///
+/// Reconnects after [`RECONNECT_DELAY`], or sooner on a network change, whenever it fails.
+/// A network change while connected reconnects it too, sockets may be bound to an interface
+/// that has gone away.
+#[instrument(skip_all, fields(transport = transport.name()))]
+pub async fn run_transport(
+    transport: Box<dyn Transport>,
+    inbound: TokioSender<ProtoMessage<SyncMessage>>,
+    mut outbound: BroadcastReceiver<SyncMessage>,
+    network_notify: Arc<Notify>,
+    change_tx: TokioSender<TodoEvent>,
+) {
+    let name = transport.name();
+
+    loop {
+        tokio::select! {
+            result = run_connection(&*transport, &inbound, &mut outbound) => {
+                match result {
+                    Ok(()) => return,
+                    Err(err) => {
+                        change_tx.send(TodoEvent::ConnectionStatus(format!("Error syncing over {name}, trying reconnect in 10s"))).await.ok();
+                        error!("Error syncing over {name} sleeping 10s and trying again, {err:?}");
+                    }
+                }
+            }
+            _ = network_notify.notified() => {
+                debug!("network watcher notified");
+                change_tx.send(TodoEvent::ConnectionStatus("Network status changed".into())).await.ok();
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {
+                debug!("sleep finished, trying again");
+            },
+            _ = network_notify.notified() => {
+                debug!("network watcher notified, trying again");
+            }
+        }
+
+        change_tx
+            .send(TodoEvent::ConnectionStatus("Reconnecting".into()))
+            .await
+            .ok();
+    }
+}
+
+/// Connects the transport and passes messages through it, returning once our `Shutdown` is out
+async fn run_connection(
+    transport: &dyn Transport,
+    inbound: &TokioSender<ProtoMessage<SyncMessage>>,
+    outbound: &mut BroadcastReceiver<SyncMessage>,
+) -> Result<()> {
+    let (mut stream, mut sink) = transport.connect().await?;
+
+    debug!("sending announce");
+    // send the initial announce message, peers respond by starting a sync session
+    sink.send(SyncMessage::Announce).await?;
+
+    loop {
+        tokio::select! {
+            message = stream.try_next() => {
+                let Some(message) = message? else {
+                    return Err(anyhow!("{} stream ended", transport.name()));
+                };
+                inbound.send(message).await?;
+            }
+            message = outbound.recv() => {
+                match message {
+                    Ok(SyncMessage::Shutdown) => {
+                        sink.send(SyncMessage::Shutdown).await?;
+                        sink.close().await?;
+                        return Ok(());
+                    }
+                    Ok(message) => sink.send(message).await?,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("{} sender lagged, skipped {skipped} messages", transport.name());
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Sends each message with `sender` before taking the next
+pub fn datagram_sink(sender: McastSender) -> MessageSink {
+    Box::pin(futures::sink::unfold(
+        sender,
+        |mut sender, message: SyncMessage| async move {
+            sender.send(message).await?;
+            Ok::<_, anyhow::Error>(sender)
+        },
+    ))
+}
+
+/// The multicast group from the config, over IPv4, IPv6 or both
+pub struct Multicast {
+    site_id: u32,
+    config: McastConfig,
+}
+
+impl Transport for Multicast {
+    fn name(&self) -> &'static str {
+        "multicast"
+    }
+
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>> {
+        Box::pin(async move {
+            let receiver = McastReceiver::<SyncMessage>::new(&self.config)?;
+            let sender = McastSender::new(self.site_id, &self.config)?;
+
+            Ok((logging_stats(receiver), datagram_sink(sender)))
+        })
+    }
+}
+
+/// Reads messages from the receiver, logging its reassembly stats every [`STATS_INTERVAL`]
+fn logging_stats(receiver: McastReceiver<SyncMessage>) -> MessageStream {
+    futures::stream::unfold(
+        (receiver, Instant::now()),
+        |(mut receiver, mut logged)| async move {
+            let message = receiver.try_next().await.transpose()?;
+
+            if logged.elapsed() >= STATS_INTERVAL {
+                let stats = receiver.stats();
+                debug!(
+                    "Reassembly stats: packets:{} messages:{} duplicates:{} malformed:{} corrupt:{} unauthenticated:{} expired:{}",
+                    stats.packets,
+                    stats.messages,
+                    stats.duplicates,
+                    stats.malformed,
+                    stats.corrupt,
+                    stats.unauthenticated,
+                    stats.expired
+                );
+                logged = Instant::now();
+            }
+
+            Some((message, (receiver, logged)))
+        },
+    )
+    .boxed()
+}
+
+/// An in-memory network, every site connected to the same hub hears every message.
+///
+/// Lets several sites run in one process, without sockets, which is what the sync tests use.
+#[derive(Clone)]
+pub struct ChannelHub {
+    tx: BroadcastSender<ProtoMessage<SyncMessage>>,
+}
+
+impl Default for ChannelHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelHub {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast_channel(1024).0,
+        }
+    }
+
+    /// A transport connecting `site_id` to the hub
+    pub fn transport(&self, site_id: u32) -> ChannelTransport {
+        ChannelTransport {
+            site_id,
+            hub: self.clone(),
+        }
+    }
+}
+
+pub struct ChannelTransport {
+    site_id: u32,
+    hub: ChannelHub,
+}
+
+impl Transport for ChannelTransport {
+    fn name(&self) -> &'static str {
+        "channel"
+    }
+
+    fn connect(&self) -> BoxFuture<'_, Result<(MessageStream, MessageSink)>> {
+        Box::pin(async move {
+            let stream = futures::stream::unfold(self.hub.tx.subscribe(), |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(message) => return Some((Ok(message), rx)),
+                        Err(RecvError::Lagged(skipped)) => {
+                            debug!("Channel transport lagged, skipped {skipped} messages");
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            });
+
+            let (site_id, tx) = (self.site_id, self.hub.tx.clone());
+            let sink = futures::sink::unfold((), move |(), message: SyncMessage| {
+                // nobody else on the hub yet is fine, the same as an empty multicast group
+                tx.send(ProtoMessage { site_id, message }).ok();
+                async { Ok::<_, anyhow::Error>(()) }
+            });
+
+            Ok((stream.boxed(), Box::pin(sink) as MessageSink))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::sync::mpsc::{Receiver as TokioReceiver, Sender as TokioSender};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backends::{
+        compaction,
+        multicast::{TodoCommand, setup_with_transports},
+        test_util::wait_for,
+    };
+
+    fn site(
+        hub: &ChannelHub,
+        dir: &std::path::Path,
+        site_id: u32,
+    ) -> (TokioSender<TodoCommand>, TokioReceiver<TodoEvent>) {
+        let config = McastConfig {
+            autosave_path: dir.join(format!("{site_id}.save")),
+            ..Default::default()
+        };
+
+        setup_with_transports(site_id, config, vec![Box::new(hub.transport(site_id))])
+    }
+
+    #[tokio::test]
+    async fn sites_on_a_hub_converge() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-hub-{}", Uuid::new_v4()));
+        let hub = ChannelHub::new();
+
+        let mut sites: Vec<_> = (1..=3).map(|site_id| site(&hub, &dir, site_id)).collect();
+
+        let lists: Vec<Uuid> = (0..sites.len()).map(|_| Uuid::new_v4()).collect();
+        for ((tx, _), list_id) in sites.iter().zip(&lists) {
+            tx.send(TodoCommand::AddList {
+                list_id: *list_id,
+                title: list_id.to_string(),
+                metadata: HashMap::new(),
+            })
+            .await
+            .unwrap();
+        }
+
+        for (_, events) in sites.iter_mut() {
+            let state = wait_for(events, |state| {
+                lists.iter().all(|id| state.list(*id).is_some())
+            })
+            .await;
+            assert_eq!(state.lists.len(), lists.len());
+        }
+
+        // A late joiner catches up on everything from its announce
+        let (late_tx, mut late_events) = site(&hub, &dir, 4);
+        wait_for(&mut late_events, |state| {
+            lists.iter().all(|id| state.list(*id).is_some())
+        })
+        .await;
+
+        sites.push((late_tx, late_events));
+        for (tx, _) in sites {
+            let (sender, saved) = tokio::sync::oneshot::channel();
+            tx.send(TodoCommand::Shutdown { sender }).await.unwrap();
+            saved.await.ok();
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn compaction_reaches_every_site() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-compact-{}", Uuid::new_v4()));
+        let hub = ChannelHub::new();
+
+        let mut sites: Vec<_> = (1..=2).map(|site_id| site(&hub, &dir, site_id)).collect();
+
+        let mut lists = Vec::new();
+        for (tx, _) in &sites {
+            for _ in 0..5 {
+                let list_id = Uuid::new_v4();
+                lists.push(list_id);
+                tx.send(TodoCommand::AddList {
+                    list_id,
+                    title: list_id.to_string(),
+                    metadata: HashMap::new(),
+                })
+                .await
+                .unwrap();
+            }
+        }
+        for (_, events) in sites.iter_mut() {
+            wait_for(events, |state| {
+                lists.iter().all(|id| state.list(*id).is_some())
+            })
+            .await;
+        }
+
+        sites[0].0.send(TodoCommand::Compact).await.unwrap();
+
+        // an edit after the compaction still reaches the other site, without duplicating lists
+        let late = Uuid::new_v4();
+        lists.push(late);
+        sites[1]
+            .0
+            .send(TodoCommand::AddList {
+                list_id: late,
+                title: "late".into(),
+                metadata: HashMap::new(),
+            })
+            .await
+            .unwrap();
+        for (_, events) in sites.iter_mut() {
+            let state = wait_for(events, |state| {
+                state.list(late).is_some() && state.lists.len() == lists.len()
+            })
+            .await;
+            assert!(lists.iter().all(|id| state.list(*id).is_some()));
+        }
+
+        for (tx, _) in sites {
+            let (sender, saved) = tokio::sync::oneshot::channel();
+            tx.send(TodoCommand::Shutdown { sender }).await.unwrap();
+            saved.await.ok();
+        }
+        for site_id in 1..=2 {
+            let diagnostics =
+                compaction::Diagnostics::read(&dir.join(format!("{site_id}.save"))).unwrap();
+            assert_eq!(diagnostics.epoch, 1);
+            assert_eq!(diagnostics.lists, lists.len());
+            assert!(diagnostics.changes < 10, "{diagnostics}");
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,140 @@
+//! Named workspaces, each with its own Automerge document, save file and sync namespace
+
+use std::path::{Path, PathBuf};
+
+use tracing::*;
+
+pub const DEFAULT_WORKSPACE: &str = "default";
+
+#[cfg(target_os = "android")]
+pub const STORAGE_LOCATION: &str = "/data/data/dev.cetra.todomcp/files/automerge.save";
+
+#[cfg(not(target_os = "android"))]
+pub const STORAGE_LOCATION: &str = "~/.local/share/todo_mcp/automerge.save";
+
+/// Directory next to the default save file holding every other workspace's save file
+const WORKSPACES_DIR: &str = "workspaces";
+
+const SAVE_EXTENSION: &str = "save";
+
+/// Checks a workspace name is usable as a file name, used as the clap value parser too
+pub fn parse_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.len() > 64 {
+        return Err("workspace names must be between 1 and 64 characters".into());
+    }
+
+    if !name
+        .chars()
+        .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+    {
+        return Err("workspace names may only contain letters, digits, '-' and '_'".into());
+    }
+
+    Ok(name.to_string())
+}
+
+/// Expands `~` in the configured autosave path, the default workspace's save file
+fn default_save_path(autosave_path: &Path) -> PathBuf {
+    shellexpand::tilde(&autosave_path.to_string_lossy())
+        .to_string()
+        .into()
+}
+
+fn workspaces_dir(autosave_path: &Path) -> PathBuf {
+    default_save_path(autosave_path).with_file_name(WORKSPACES_DIR)
+}
+
+/// The default workspace keeps the location it had before workspaces existed
+pub fn save_path(autosave_path: &Path, workspace: &str) -> PathBuf {
+    if workspace == DEFAULT_WORKSPACE {
+        return default_save_path(autosave_path);
+    }
+
+    workspaces_dir(autosave_path).join(format!("{workspace}.{SAVE_EXTENSION}"))
+}
+
+/// Tags every datagram so sites only merge messages from their own workspace
+pub fn namespace(workspace: &str) -> u32 {
+    crc32fast::hash(workspace.as_bytes())
+}
+
+/// Every workspace that has been saved on this machine, the default one first
+pub fn list(autosave_path: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+
+    match std::fs::read_dir(workspaces_dir(autosave_path)) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == SAVE_EXTENSION)
+                    && let Some(name) = path.file_stem().and_then(|stem| stem.to_str())
+                    && name != DEFAULT_WORKSPACE
+                    && parse_name(name).is_ok()
+                {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => warn!("Could not read workspaces directory: {err}"),
+    }
+
+    names.sort();
+    names.insert(0, DEFAULT_WORKSPACE.to_string());
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn names_must_be_usable_as_file_names() {
+        assert_eq!(parse_name("work-2_b").unwrap(), "work-2_b");
+        for name in ["", "..", "a/b", "a\\b", "a.b", &"x".repeat(65)] {
+            assert!(parse_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn default_workspace_keeps_the_legacy_save_file() {
+        let autosave = Path::new("/data/todo/automerge.save");
+        assert_eq!(save_path(autosave, DEFAULT_WORKSPACE), autosave);
+        assert_eq!(
+            save_path(autosave, "work"),
+            Path::new("/data/todo/workspaces/work.save")
+        );
+    }
+
+    #[test]
+    fn namespaces_are_stable_and_differ() {
+        assert_eq!(namespace("work"), namespace("work"));
+        assert_eq!(namespace("work"), crc32fast::hash(b"work"));
+        assert_ne!(namespace("work"), namespace("home"));
+        assert_ne!(namespace("work"), namespace(DEFAULT_WORKSPACE));
+    }
+
+    #[test]
+    fn lists_saved_workspaces() {
+        let dir = std::env::temp_dir().join(format!("todo-mcp-workspaces-{}", Uuid::new_v4()));
+        let autosave = dir.join("automerge.save");
+        assert_eq!(list(&autosave), [DEFAULT_WORKSPACE]);
+
+        for name in ["work", "home"] {
+            let path = save_path(&autosave, name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        // neither of these is a workspace
+        let workspaces = workspaces_dir(&autosave);
+        std::fs::write(workspaces.join("notes.txt"), b"").unwrap();
+        std::fs::write(workspaces.join("not.valid.save"), b"").unwrap();
+
+        assert_eq!(list(&autosave), [DEFAULT_WORKSPACE, "home", "work"]);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
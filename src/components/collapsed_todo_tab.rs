@@ -23,12 +23,12 @@ pub fn CollapsedTodoTab(
 
     let completed = todo
         .read()
-        .items
-        .iter()
+        .all_items()
+        .into_iter()
         .filter(|item| item.completed)
         .count();
 
-    let total = todo.read().items.len();
+    let total = todo.read().all_items().len();
 
     rsx! {
         div {
@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 
+use chrono::Utc;
 use dioxus::prelude::*;
+use uuid::Uuid;
 
 use crate::{
-    backends::TodoCommand,
+    backends::{
+        sort::{Priority, SortMode},
+        TodoCommand,
+    },
     components::{
-        TodoItem, TodoList, TodoListStoreExt, TodoState, TodoStateStoreImplExt,
+        TodoItem, TodoList, TodoListStoreExt, TodoState, TodoStateStoreImplExt, TAG_FILTER,
     },
 };
 
@@ -17,7 +22,7 @@ pub struct ExpandedTodoTabProps {
     todo: Store<TodoList>,
     state: Store<TodoState>,
     on_collapse: Callback<()>,
-    on_remove: Callback<usize>,
+    on_remove: Callback<Uuid>,
 }
 
 #[component]
@@ -30,10 +35,22 @@ pub fn ExpandedTodoTab(
         on_remove,
     }: ExpandedTodoTabProps,
 ) -> Element {
+    let list_id = todo.read().id;
     let mut title = todo.title();
     let mut items = todo.items();
+    let mut sort = todo.sort();
     let mut focus_new_item = use_signal(|| false);
 
+    // Shown in the list's sort order, stored in the order they were added
+    let mut order = {
+        let list = todo.read();
+        list.sort.order(&list.items, |item| item.into())
+    };
+    if let Some(tag) = TAG_FILTER.read().as_deref() {
+        order.retain(|idx| todo.read().items[*idx].has_tag(tag));
+    }
+    let rows: Vec<_> = items.iter().collect();
+
     rsx! {
         div {
             class: "relative rounded-t-3xl last:rounded-b-3xl -mt-8 first:mt-0 px-2 pt-4 pb-10 last:pb-2 hover:shadow-[0_0_15px_0_rgba(0,0,0,0.2)] transition-all duration-300 ease-out cursor-pointer",
@@ -65,21 +82,43 @@ pub fn ExpandedTodoTab(
                 input {
                     class: "text-xl w-full font-bold text-gray-900",
                     value: "{title}",
+                    onkeydown: move |evt| evt.stop_propagation(),
                     oninput: move |evt| {
                         title.set(evt.value());
                         state.send_update(TodoCommand::RenameList {
-                            list_index: idx,
+                            list_id,
                             title: evt.value(),
                         });
                     },
                 }
 
+                select {
+                    class: "text-sm bg-transparent text-gray-700 cursor-pointer",
+                    onclick: move |evt| evt.stop_propagation(),
+                    onchange: move |evt| {
+                        if let Some(mode) = SortMode::parse(&evt.value()) {
+                            sort.set(mode);
+                            state.send_update(TodoCommand::SetSort {
+                                list_id,
+                                sort: mode,
+                            });
+                        }
+                    },
+                    for mode in SortMode::ALL {
+                        option {
+                            value: mode.label(),
+                            selected: mode == sort(),
+                            "Sort: {mode.label()}"
+                        }
+                    }
+                }
+
                 // Delete button
                 button {
                     class: "p-2 cursor-pointer rounded-full bg-white/50 transition-colors duration-200",
                     onclick: move |evt| {
                         evt.stop_propagation();
-                        on_remove(idx);
+                        on_remove(list_id);
                     },
 
                     svg {
@@ -98,22 +137,23 @@ pub fn ExpandedTodoTab(
             }
 
             div { class: "mt-4 space-y-2 animate-fade-in",
-                for (item_idx, todo_item) in items.iter().enumerate() {
+                for item_idx in order {
                     {
+                        let todo_item = rows[item_idx];
                         let is_last = item_idx == items.len() - 1;
                         let should_focus = is_last && *focus_new_item.read();
                         rsx! {
                             TodoItemRow {
-                                list_idx: idx,
-                                item_idx: item_idx,
+                                list_id,
                                 todo: todo_item,
+                                sort: sort(),
                                 state: state,
                                 autofocus: should_focus,
-                                on_remove: move |item_idx| {
-                                    items.remove(item_idx);
+                                on_remove: move |item_id| {
+                                    items.write().retain(|item: &TodoItem| item.id != item_id);
                                     state.send_update(TodoCommand::RemoveTodo {
-                                        list_index: idx,
-                                        item_index: item_idx,
+                                        list_id,
+                                        item_id,
                                     });
                                 },
                                 on_focused: move |_| {
@@ -128,14 +168,27 @@ pub fn ExpandedTodoTab(
                 button {
                     class: "cursor-pointer mt-2 w-full p-2 bg-white/30 rounded-2xl text-gray-700 hover:bg-white/50 transition-colors duration-200 flex items-center justify-center gap-2",
                     onclick: move |_evt| {
+                        let item_id = Uuid::new_v4();
                         items.write().push(TodoItem {
+                            id: item_id,
                             text: "".into(),
                             completed: false,
+                            due: None,
+                            priority: Priority::None,
+                            created: Some(Utc::now()),
+                            children: vec![],
+                            tags: vec![],
+                            notes: String::new(),
                         });
                         state.send_update(TodoCommand::AddTodo {
-                            list_index: idx,
+                            list_id,
+                            item_id,
                             text: "".into(),
                             metadata: HashMap::new(),
+                            due: None,
+                            priority: Priority::None,
+                            parent_id: None,
+                            tags: vec![],
                         });
                         focus_new_item.set(true);
                     },
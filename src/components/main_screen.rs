@@ -1,38 +1,157 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
+use chrono::Utc;
 use dioxus::prelude::*;
+use uuid::Uuid;
 
 use crate::{
-    backends::TodoCommand,
-    components::{CONNECTION_STATE, TODOS, TodoList, TodoState, TodoStateStoreImplExt},
+    backends::{due, sort::SortMode, McastConfig, TodoCommand},
+    components::{
+        tag_color, CONNECTION_STATE, NOTICE, TAGS, TAG_FILTER, TODOS, TodoList, TodoState,
+        TodoStateStoreImplExt,
+    },
 };
 
 use super::TodoTab;
 
 #[component]
 pub fn MainScreen() -> Element {
-    let state = use_store(|| TodoState::new());
+    // desktop passes in the config parsed from the command line
+    let config = try_consume_context::<McastConfig>().unwrap_or_default();
+    let state = use_store(|| TodoState::new(config));
 
     let mut todos = TODOS.resolve();
 
     let connection_state = CONNECTION_STATE.read();
+    let notice = NOTICE.read().clone();
+
+    let filter = TAG_FILTER.read().clone();
+    let shown = move |tags: &[String]| filter.as_ref().is_none_or(|tag| tags.contains(tag));
+
+    // Every tag in the workspace, to filter by
+    let mut all_tags: BTreeSet<String> = TAGS.read().keys().cloned().collect();
+    for list in TODOS.read().iter() {
+        for item in list.all_items() {
+            all_tags.extend(item.tags.iter().cloned());
+        }
+    }
+
+    // Open items and subtasks due this week or overdue, across every list
+    let now = Utc::now();
+    let mut upcoming = Vec::new();
+    for list in TODOS.read().iter() {
+        for item in list.all_items().into_iter().filter(|item| !item.completed && shown(&item.tags)) {
+            if let Some(at) = item.due.filter(|at| due::is_upcoming(*at, now, due::DEFAULT_UPCOMING_DAYS, true)) {
+                upcoming.push((at, item.text.clone(), list.title.clone()));
+            }
+        }
+    }
+    upcoming.sort_by_key(|(at, _, _)| *at);
 
     rsx! {
-        div { class: "flex flex-col min-h-screen",
+        div {
+            class: "flex flex-col min-h-screen",
+            tabindex: "0",
+            // Ctrl-Z undoes this site's last edit, text fields keep their own undo
+            onkeydown: move |evt| {
+                let modifiers = evt.modifiers();
+                if !(modifiers.ctrl() || modifiers.meta()) {
+                    return;
+                }
+                let command = match evt.key() {
+                    Key::Character(key) if key.eq_ignore_ascii_case("z") && modifiers.shift() => {
+                        TodoCommand::Redo
+                    }
+                    Key::Character(key) if key.eq_ignore_ascii_case("z") => TodoCommand::Undo,
+                    Key::Character(key) if key.eq_ignore_ascii_case("y") => TodoCommand::Redo,
+                    _ => return,
+                };
+                evt.prevent_default();
+                state.send_update(command);
+            },
+        if let Some(notice) = notice {
+            div { class: "px-2 pt-2",
+                div { class: "p-4 rounded-2xl bg-white/80 text-gray-900 text-sm flex items-center justify-between gap-2",
+                    span { "{notice}" }
+                    button {
+                        class: "cursor-pointer px-2 font-medium",
+                        onclick: move |_evt| {
+                            *NOTICE.write() = None;
+                        },
+                        "Dismiss"
+                    }
+                }
+            }
+        }
+        if !upcoming.is_empty() {
+            div { class: "px-2 pt-2",
+                div { class: "p-4 rounded-2xl bg-white/50 text-gray-900 text-sm space-y-2",
+                    div { class: "font-bold", "Upcoming" }
+                    for (at, text, list_title) in upcoming {
+                        div { class: "flex items-center justify-between gap-2",
+                            span {
+                                class: "whitespace-nowrap font-medium",
+                                style: if at < now { "color: #dc2626" } else { "" },
+                                if at < now { "Overdue {due::format(at)}" } else { "{due::format(at)}" }
+                            }
+                            span { class: "w-full truncate", "{text}" }
+                            span { class: "whitespace-nowrap text-gray-500", "{list_title}" }
+                        }
+                    }
+                }
+            }
+        }
+        if !all_tags.is_empty() {
+            div { class: "px-2 pt-2",
+                div {
+                    class: "p-4 rounded-2xl bg-white/50 text-gray-900 text-sm flex items-center gap-2",
+                    style: "flex-wrap: wrap",
+                    span { class: "font-bold", "Tags" }
+                    button {
+                        class: "cursor-pointer px-2 rounded-full",
+                        style: if TAG_FILTER.read().is_none() { "font-weight: 600" } else { "" },
+                        onclick: move |_evt| {
+                            *TAG_FILTER.write() = None;
+                        },
+                        "All"
+                    }
+                    for tag in all_tags {
+                        button {
+                            class: "cursor-pointer px-2 rounded-full text-white whitespace-nowrap",
+                            style: if TAG_FILTER.read().as_ref() == Some(&tag) {
+                                format!("background-color: {}; font-weight: 600; outline: 2px solid #111827", tag_color(&tag))
+                            } else {
+                                format!("background-color: {}", tag_color(&tag))
+                            },
+                            onclick: {
+                                let tag = tag.clone();
+                                move |_evt| {
+                                    *TAG_FILTER.write() = Some(tag.clone());
+                                }
+                            },
+                            "#{tag}"
+                        }
+                    }
+                }
+            }
+        }
         div { class: "p-2",
             div {
+                // only the lists with items tagged as filtered by
                 for (idx , todo) in TODOS.resolve().iter().enumerate() {
-                    TodoTab {
-                        idx,
-                        todo,
-                        state,
-                        on_remove: move |idx| {
-                            todos.write().remove(idx);
-                            state
-                                .send_update(TodoCommand::RemoveList {
-                                    list_index: idx,
-                                });
-                        },
+                    if TAG_FILTER.read().is_none() || todo.read().all_items().iter().any(|item| shown(&item.tags)) {
+                        TodoTab {
+                            idx,
+                            todo,
+                            state,
+                            on_remove: move |list_id| {
+                                todos.write().retain(|list: &TodoList| list.id != list_id);
+                                state
+                                    .send_update(TodoCommand::RemoveList {
+                                        list_id,
+                                    });
+                            },
+                        }
                     }
                 }
             }
@@ -43,15 +162,19 @@ pub fn MainScreen() -> Element {
                 button {
                     class: "cursor-pointer w-full p-4 bg-white/30 text-gray-700 flex items-center justify-center gap-2",
                     onclick: move |_evt| {
+                        let list_id = Uuid::new_v4();
                         todos
                             .write()
                             .push(TodoList {
+                                id: list_id,
                                 title: "New Todo List".into(),
                                 items: vec![],
+                                sort: SortMode::Manual,
                                 expanded: true,
                             });
                         state
                             .send_update(TodoCommand::AddList {
+                                list_id,
                                 title: "New Todo List".into(),
                                 metadata: HashMap::new(),
                             });
@@ -4,6 +4,9 @@
 
 mod main_screen;
 
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::{DateTime, Utc};
 use dioxus::prelude::*;
 pub use main_screen::MainScreen;
 
@@ -14,38 +17,103 @@ mod todo_tab;
 
 pub use todo_tab::TodoTab;
 use tokio::sync::mpsc::Sender as TokioSender;
+use uuid::Uuid;
 
 use crate::backends::{
-    multicast::TodoEvent, setup, TodoCommand, TodoItem as McTodoItem, TodoList as McTodoList,
+    connect_or_setup,
+    multicast::TodoEvent,
+    sort::{Priority, SortKey, SortMode},
+    tags::Tag,
+    McastConfig, TodoCommand, TodoItem as McTodoItem,
+    TodoList as McTodoList,
 };
 
 #[derive(Store, Clone)]
 pub struct TodoItem {
+    pub id: Uuid,
     pub text: String,
     pub completed: bool,
+    pub due: Option<DateTime<Utc>>,
+    pub priority: Priority,
+    pub created: Option<DateTime<Utc>>,
+    pub children: Vec<TodoItem>,
+    pub tags: Vec<String>,
+    pub notes: String,
+}
+
+impl TodoItem {
+    /// Whether the item or any of its subtasks has `tag`
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag) || self.children.iter().any(|child| child.has_tag(tag))
+    }
+
+    /// Marks the item and all of its subtasks completed
+    pub fn complete_all(&mut self) {
+        self.completed = true;
+        for child in &mut self.children {
+            child.complete_all();
+        }
+    }
 }
 
 #[derive(Store, Clone)]
 pub struct TodoList {
+    pub id: Uuid,
     pub title: String,
     pub items: Vec<TodoItem>,
+    pub sort: SortMode,
     pub expanded: bool,
 }
 
 impl From<McTodoItem> for TodoItem {
     fn from(item: McTodoItem) -> Self {
         Self {
+            id: item.id,
             text: item.text,
             completed: item.completed,
+            due: item.due,
+            priority: item.priority,
+            created: item.created,
+            children: item.children.into_iter().map(Into::into).collect(),
+            tags: item.tags,
+            notes: item.notes,
+        }
+    }
+}
+
+impl From<&TodoItem> for SortKey {
+    fn from(item: &TodoItem) -> Self {
+        Self {
+            priority: item.priority,
+            due: item.due,
+            created: item.created,
         }
     }
 }
 
+impl TodoList {
+    /// Every item, each followed by its subtasks
+    pub fn all_items(&self) -> Vec<&TodoItem> {
+        fn flatten<'a>(items: &'a [TodoItem], out: &mut Vec<&'a TodoItem>) {
+            for item in items {
+                out.push(item);
+                flatten(&item.children, out);
+            }
+        }
+
+        let mut out = Vec::new();
+        flatten(&self.items, &mut out);
+        out
+    }
+}
+
 impl From<McTodoList> for TodoList {
     fn from(item: McTodoList) -> Self {
         Self {
+            id: item.id,
             title: item.title,
             items: item.items.into_iter().map(Into::into).collect(),
+            sort: item.sort,
             expanded: false,
         }
     }
@@ -70,30 +138,45 @@ impl<Lens> Store<TodoState, Lens> {
 
 pub static TODOS: GlobalStore<Vec<TodoList>> = Global::new(|| Vec::new());
 pub static CONNECTION_STATE: GlobalStore<String> = Global::new(|| String::new());
+/// Something the user should know about, shown until they dismiss it
+pub static NOTICE: GlobalStore<Option<String>> = Global::new(|| None);
+/// The workspace's tags and their colours
+pub static TAGS: GlobalStore<BTreeMap<String, Tag>> = Global::new(|| BTreeMap::new());
+/// When set, only the items with this tag are shown
+pub static TAG_FILTER: GlobalStore<Option<String>> = Global::new(|| None);
+
+/// The colour the workspace has for `tag`
+pub fn tag_color(tag: &str) -> String {
+    TAGS.read()
+        .get(tag)
+        .cloned()
+        .unwrap_or_else(|| Tag::new(tag))
+        .color
+}
 
 impl TodoState {
-    pub fn new() -> Self {
+    pub fn new(config: McastConfig) -> Self {
         let site_id = rand::random();
 
-        let (sender, mut recv) = setup(site_id);
+        let (sender, mut recv) = connect_or_setup(site_id, config);
 
         spawn(async move {
             while let Some(update) = recv.recv().await {
                 match update {
                     TodoEvent::StateUpdate(update) => {
+                        *TAGS.write() = update.tags;
                         let mut todos = TODOS.write();
-                        let prev = std::mem::take(&mut *todos);
+                        let expanded: HashSet<Uuid> = std::mem::take(&mut *todos)
+                            .into_iter()
+                            .filter(|t| t.expanded)
+                            .map(|t| t.id)
+                            .collect();
 
                         *todos = update
                             .lists
                             .into_iter()
-                            .zip(
-                                prev.into_iter()
-                                    .map(|t| t.expanded)
-                                    .chain(std::iter::repeat(false)),
-                            )
-                            .map(|(list, expanded)| TodoList {
-                                expanded,
+                            .map(|list| TodoList {
+                                expanded: expanded.contains(&list.id),
                                 ..list.into()
                             })
                             .collect();
@@ -102,6 +185,10 @@ impl TodoState {
                         let mut alive_connections = CONNECTION_STATE.write();
                         *alive_connections = count;
                     }
+                    TodoEvent::Recovered(recovery) => {
+                        *NOTICE.write() = Some(recovery.to_string());
+                    }
+                    TodoEvent::Document { .. } => {}
                 }
             }
         });
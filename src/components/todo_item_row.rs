@@ -1,8 +1,19 @@
+use std::collections::HashMap;
+
 use dioxus::prelude::*;
+use uuid::Uuid;
+
+use chrono::{Local, Utc};
 
 use crate::{
-    backends::TodoCommand,
-    components::{TodoItem, TodoItemStoreExt, TodoState, TodoStateStoreImplExt, },
+    backends::{
+        due,
+        sort::{Priority, SortMode},
+        tags, TodoCommand,
+    },
+    components::{
+        tag_color, TodoItem, TodoItemStoreExt, TodoState, TodoStateStoreImplExt, TAG_FILTER,
+    },
 };
 
 fn resize_textarea(id: &str) {
@@ -19,13 +30,14 @@ fn resize_textarea(id: &str) {
 
 #[derive(Props, Clone, PartialEq)]
 pub struct TodoItemRowProps {
-    list_idx: usize,
-    item_idx: usize,
+    list_id: Uuid,
     todo: Store<TodoItem>,
+    /// How the list sorts its items, which its subtasks are sorted by too
+    sort: SortMode,
     state: Store<TodoState>,
     #[props(default)]
     autofocus: bool,
-    on_remove: Callback<usize>,
+    on_remove: Callback<Uuid>,
     #[props(default)]
     on_focused: Callback<()>,
 }
@@ -33,9 +45,9 @@ pub struct TodoItemRowProps {
 #[component]
 pub fn TodoItemRow(
     TodoItemRowProps {
-        list_idx,
-        item_idx,
+        list_id,
         todo,
+        sort,
         state,
         autofocus,
         on_remove,
@@ -43,11 +55,47 @@ pub fn TodoItemRow(
     }: TodoItemRowProps,
 ) -> Element {
     let mut todo = todo;
+    let item_id = todo.read().id;
+    let due_date = todo
+        .read()
+        .due
+        .map(|at| at.with_timezone(&Local).format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    let due_title = todo.read().due.map(due::format).unwrap_or_default();
+    let overdue = !todo.read().completed && todo.read().due.is_some_and(|at| at < Utc::now());
+
+    let mut children = todo.children();
+    let mut folded = use_signal(|| false);
+    let mut show_notes = use_signal(|| false);
+    let has_notes = !todo.read().notes.is_empty();
+    let mut focus_new_child = use_signal(|| None::<Uuid>);
+    let (done, total) = {
+        let item = todo.read();
+        (item.children.iter().filter(|c| c.completed).count(), item.children.len())
+    };
+    let mut order = sort.order(&todo.read().children, |item| item.into());
+    if let Some(tag) = TAG_FILTER.read().as_deref() {
+        order.retain(|idx| todo.read().children[*idx].has_tag(tag));
+    }
+    let item_tags = todo.read().tags.clone();
+    let rows: Vec<_> = children.iter().collect();
 
     rsx! {
+        div { class: "space-y-2",
         div {
             class: "flex items-center gap-2 p-2 bg-white/40 rounded-2xl cursor-pointer hover:bg-white/50 transition-colors duration-200",
 
+            // Shows or hides the subtasks
+            if total > 0 {
+                button {
+                    class: "cursor-pointer text-sm text-gray-700 w-4",
+                    onclick: move |evt| {
+                        evt.stop_propagation();
+                        folded.toggle();
+                    },
+                    if folded() { "▸" } else { "▾" }
+                }
+            }
 
             // Checkbox
             button {
@@ -59,12 +107,24 @@ pub fn TodoItemRow(
                 },
                 onclick: move |evt| {
                     evt.stop_propagation();
-                    let is_completed = todo.read().completed;
-                    todo.write().completed = !is_completed;
-                    state.send_update(TodoCommand::ToggleTodo {
-                        list_index: list_idx,
-                        item_index: item_idx,
-                    });
+                    let (is_completed, has_children) = {
+                        let item = todo.read();
+                        (item.completed, !item.children.is_empty())
+                    };
+                    // completing a parent completes its subtasks too
+                    if !is_completed && has_children {
+                        todo.write().complete_all();
+                        state.send_update(TodoCommand::CompleteTodo {
+                            list_id,
+                            item_id,
+                        });
+                    } else {
+                        todo.write().completed = !is_completed;
+                        state.send_update(TodoCommand::ToggleTodo {
+                            list_id,
+                            item_id,
+                        });
+                    }
                 },
                 svg {
                     class: "w-5 h-5 text-white",
@@ -82,7 +142,7 @@ pub fn TodoItemRow(
             }
 
             textarea {
-                id: "todo-textarea-{list_idx}-{item_idx}",
+                id: "todo-textarea-{item_id}",
                 class: if todo.read().completed {
                     "w-full text-gray-500 line-through resize-none overflow-hidden bg-transparent"
                 } else {
@@ -91,15 +151,41 @@ pub fn TodoItemRow(
                 rows: "1",
                 value: todo.text(),
                 onclick: move |evt| evt.stop_propagation(),
+                onkeydown: move |evt| evt.stop_propagation(),
                 oninput: move |evt| {
                     evt.stop_propagation();
                     todo.text().set(evt.value());
                     state.send_update(TodoCommand::RenameTodo {
-                        list_index: list_idx,
+                        list_id,
+                        item_id,
                         text: evt.value(),
-                        item_index: item_idx,
                     });
-                    resize_textarea(&format!("todo-textarea-{list_idx}-{item_idx}"));
+                    resize_textarea(&format!("todo-textarea-{item_id}"));
+                },
+                // #tags typed into the text become the item's tags
+                onblur: move |_| {
+                    let (text, found) = tags::extract(&todo.read().text);
+                    if found.is_empty() {
+                        return;
+                    }
+                    let mut item_tags = todo.read().tags.clone();
+                    for tag in found {
+                        if !item_tags.contains(&tag) {
+                            item_tags.push(tag);
+                        }
+                    }
+                    todo.text().set(text.clone());
+                    todo.tags().set(item_tags.clone());
+                    state.send_update(TodoCommand::RenameTodo {
+                        list_id,
+                        item_id,
+                        text,
+                    });
+                    state.send_update(TodoCommand::SetTags {
+                        list_id,
+                        item_id,
+                        tags: item_tags,
+                    });
                 },
                 onmounted: move |evt| {
                     if autofocus {
@@ -108,15 +194,181 @@ pub fn TodoItemRow(
                         });
                         on_focused(());
                     }
-                    resize_textarea(&format!("todo-textarea-{list_idx}-{item_idx}"));
+                    resize_textarea(&format!("todo-textarea-{item_id}"));
+                },
+            }
+
+            if total > 0 {
+                span { class: "text-sm text-gray-700 whitespace-nowrap", "{done}/{total}" }
+            }
+
+            // Tags, clicking one shows only the items with it
+            for tag in item_tags {
+                span {
+                    class: "px-2 rounded-full text-sm text-white whitespace-nowrap flex items-center gap-2",
+                    style: "background-color: {tag_color(&tag)}",
+                    onclick: {
+                        let tag = tag.clone();
+                        move |evt: MouseEvent| {
+                            evt.stop_propagation();
+                            *TAG_FILTER.write() = Some(tag.clone());
+                        }
+                    },
+                    "#{tag}"
+                    button {
+                        class: "cursor-pointer",
+                        title: "Remove tag",
+                        onclick: {
+                            let tag = tag.clone();
+                            move |evt: MouseEvent| {
+                                evt.stop_propagation();
+                                let item_tags: Vec<String> = todo
+                                    .read()
+                                    .tags
+                                    .iter()
+                                    .filter(|t| **t != tag)
+                                    .cloned()
+                                    .collect();
+                                todo.tags().set(item_tags.clone());
+                                state.send_update(TodoCommand::SetTags {
+                                    list_id,
+                                    item_id,
+                                    tags: item_tags,
+                                });
+                            }
+                        },
+                        "×"
+                    }
+                }
+            }
+
+            select {
+                class: "text-sm bg-transparent text-gray-700 cursor-pointer",
+                style: match todo.read().priority {
+                    Priority::Urgent => "color: #dc2626; font-weight: 600",
+                    Priority::High => "color: #ea580c; font-weight: 600",
+                    _ => "",
+                },
+                onclick: move |evt| evt.stop_propagation(),
+                onkeydown: move |evt| evt.stop_propagation(),
+                onchange: move |evt| {
+                    let Some(priority) = Priority::parse(&evt.value()) else {
+                        return;
+                    };
+                    todo.priority().set(priority);
+                    state.send_update(TodoCommand::SetPriority {
+                        list_id,
+                        item_id,
+                        priority,
+                    });
+                },
+                for priority in Priority::ALL {
+                    option {
+                        value: priority.label(),
+                        selected: priority == todo.read().priority,
+                        if priority == Priority::None { "-" } else { "{priority.label()}" }
+                    }
+                }
+            }
+
+            // Due date, red once it's passed
+            input {
+                r#type: "date",
+                class: "text-sm bg-transparent text-gray-700",
+                style: if overdue { "color: #dc2626; font-weight: 600" } else { "" },
+                title: if overdue { "Overdue since {due_title}" } else { "{due_title}" },
+                value: "{due_date}",
+                onclick: move |evt| evt.stop_propagation(),
+                onkeydown: move |evt| evt.stop_propagation(),
+                onchange: move |evt| {
+                    let Ok(due) = due::parse_optional(&evt.value(), Local::now()) else {
+                        return;
+                    };
+                    todo.due().set(due);
+                    state.send_update(TodoCommand::SetDue {
+                        list_id,
+                        item_id,
+                        due,
+                    });
+                },
+            }
+
+            // Shows or hides the notes, filled in when there are some
+            button {
+                class: if has_notes {
+                    "p-2 cursor-pointer rounded-full bg-gray-700 transition-colors duration-200"
+                } else {
+                    "p-2 cursor-pointer rounded-full bg-white/80 transition-colors duration-200"
+                },
+                title: "Notes",
+                onclick: move |evt| {
+                    evt.stop_propagation();
+                    show_notes.toggle();
+                },
+                svg {
+                    class: if has_notes { "w-4 h-4 text-white" } else { "w-4 h-4 text-gray-900" },
+                    fill: "none",
+                    stroke: "currentColor",
+                    stroke_width: "2",
+                    view_box: "0 0 24 24",
+                    path {
+                        stroke_linecap: "round",
+                        stroke_linejoin: "round",
+                        d: "M4 6h16M4 12h16M4 18h10",
+                    }
+                }
+            }
+
+            // Add a subtask
+            button {
+                class: "p-2 cursor-pointer rounded-full bg-white/80 transition-colors duration-200",
+                title: "Add subtask",
+                onclick: move |evt| {
+                    evt.stop_propagation();
+                    let child_id = Uuid::new_v4();
+                    children.write().push(TodoItem {
+                        id: child_id,
+                        text: "".into(),
+                        completed: false,
+                        due: None,
+                        priority: Priority::None,
+                        created: Some(Utc::now()),
+                        children: vec![],
+                        tags: vec![],
+                        notes: String::new(),
+                    });
+                    state.send_update(TodoCommand::AddTodo {
+                        list_id,
+                        item_id: child_id,
+                        text: "".into(),
+                        metadata: HashMap::new(),
+                        due: None,
+                        priority: Priority::None,
+                        parent_id: Some(item_id),
+                        tags: vec![],
+                    });
+                    folded.set(false);
+                    focus_new_child.set(Some(child_id));
                 },
+                svg {
+                    class: "w-4 h-4 text-gray-900",
+                    fill: "none",
+                    stroke: "currentColor",
+                    stroke_width: "2",
+                    view_box: "0 0 24 24",
+                    path {
+                        stroke_linecap: "round",
+                        stroke_linejoin: "round",
+                        d: "M12 4v16m8-8H4",
+                    }
+                }
             }
 
             button {
                 class: "p-2 cursor-pointer rounded-full bg-white/80 transition-colors duration-200",
                 onclick: move |evt| {
                     evt.stop_propagation();
-                    on_remove(item_idx);
+                    on_remove(item_id);
                 },
                 svg {
                     class: "w-4 h-4 text-gray-900",
@@ -132,5 +384,62 @@ pub fn TodoItemRow(
                 }
             }
         }
+
+        // Notes, sent as they're typed like the text so they merge with edits made elsewhere
+        if show_notes() {
+            div { style: "margin-left: 1.5rem",
+                textarea {
+                    class: "w-full p-2 text-sm text-gray-900 bg-white/40 rounded-2xl",
+                    style: "resize: vertical; min-height: 4rem",
+                    rows: "3",
+                    placeholder: "Notes",
+                    value: todo.notes(),
+                    onclick: move |evt| evt.stop_propagation(),
+                    onkeydown: move |evt| evt.stop_propagation(),
+                    oninput: move |evt| {
+                        evt.stop_propagation();
+                        todo.notes().set(evt.value());
+                        state.send_update(TodoCommand::SetNotes {
+                            list_id,
+                            item_id,
+                            notes: evt.value(),
+                        });
+                    },
+                }
+            }
+        }
+
+        // Subtasks, indented under their item
+        if total > 0 && !folded() {
+            div { class: "space-y-2", style: "margin-left: 1.5rem",
+                for child_idx in order {
+                    {
+                        let child = rows[child_idx];
+                        let child_id = child.read().id;
+                        rsx! {
+                            TodoItemRow {
+                                key: "{child_id}",
+                                list_id,
+                                todo: child,
+                                sort,
+                                state,
+                                autofocus: focus_new_child() == Some(child_id),
+                                on_remove: move |child_id| {
+                                    children.write().retain(|item: &TodoItem| item.id != child_id);
+                                    state.send_update(TodoCommand::RemoveTodo {
+                                        list_id,
+                                        item_id: child_id,
+                                    });
+                                },
+                                on_focused: move |_| {
+                                    focus_new_child.set(None);
+                                },
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        }
     }
 }
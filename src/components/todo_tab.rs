@@ -1,6 +1,7 @@
 use std::hash::{DefaultHasher, Hash, Hasher};
 
 use dioxus::prelude::*;
+use uuid::Uuid;
 
 use crate::components::{TodoList, TodoListStoreExt, TodoState};
 
@@ -11,7 +12,7 @@ pub struct TodoTabProps {
     idx: usize,
     todo: Store<TodoList>,
     state: Store<TodoState>,
-    on_remove: Callback<usize>,
+    on_remove: Callback<Uuid>,
 }
 
 pub fn todo_color(text: &str, idx: usize, lightness_pct: usize) -> String {
@@ -47,11 +47,17 @@ const TAILWIND_CSS: &str = include_str!("../assets/tailwind.css");
 mod cli {
     use clap::{Parser, Subcommand};
 
+    use crate::backends::{
+        backup::BackupCommand, history::HistoryArgs, relay::RelayArgs, McastConfig,
+    };
+
     #[derive(Parser)]
     #[command(version, about, long_about = None)]
     pub struct Cli {
         #[command(subcommand)]
         pub command: Option<Commands>,
+        #[command(flatten)]
+        pub mcast: McastConfig,
     }
 
     #[derive(Subcommand)]
@@ -63,6 +69,24 @@ mod cli {
         /// Run the terminal user interface
         #[cfg(feature = "tui")]
         Tui,
+        /// List the workspaces saved on this machine
+        Workspaces,
+        /// Show who changed what in the workspace, or its lists as of a change
+        History(HistoryArgs),
+        /// List or restore snapshots of the workspace's save file
+        Backup {
+            #[command(subcommand)]
+            command: BackupCommand,
+        },
+        /// Rewrite the workspace's document as its current state, dropping its history
+        Compact,
+        /// Show how big the workspace's save file and history have grown
+        Diagnostics,
+        /// Pass sync traffic between clients on different networks
+        Relay(RelayArgs),
+        /// Run headless, owning the workspace for other instances to connect to
+        #[cfg(unix)]
+        Daemon,
     }
 }
 
@@ -71,17 +95,41 @@ mod cli {
 mod cli {
     use clap::{Parser, Subcommand};
 
+    use crate::backends::{
+        backup::BackupCommand, history::HistoryArgs, relay::RelayArgs, McastConfig,
+    };
+
     #[derive(Parser)]
     #[command(version, about, long_about = None)]
     pub struct Cli {
         #[command(subcommand)]
         pub command: Option<Commands>,
+        #[command(flatten)]
+        pub mcast: McastConfig,
     }
 
     #[derive(Subcommand)]
     pub enum Commands {
         /// Run the terminal user interface
         Tui,
+        /// List the workspaces saved on this machine
+        Workspaces,
+        /// Show who changed what in the workspace, or its lists as of a change
+        History(HistoryArgs),
+        /// List or restore snapshots of the workspace's save file
+        Backup {
+            #[command(subcommand)]
+            command: BackupCommand,
+        },
+        /// Rewrite the workspace's document as its current state, dropping its history
+        Compact,
+        /// Show how big the workspace's save file and history have grown
+        Diagnostics,
+        /// Pass sync traffic between clients on different networks
+        Relay(RelayArgs),
+        /// Run headless, owning the workspace for other instances to connect to
+        #[cfg(unix)]
+        Daemon,
     }
 }
 
@@ -164,16 +212,36 @@ fn desktop_main() {
                             .with_title("Todo MCP"),
                     ),
                 )
+                .with_context(cli.mcast)
                 .launch(App);
         }
         Some(command) => {
             let rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
             rt.block_on(async {
                 match command {
-                    Commands::Mcp => mcp::run_mcp().await.expect("MCP server failed"),
-                    Commands::Hook => hook::run_hook().await.expect("Hook failed"),
+                    Commands::Mcp => mcp::run_mcp(cli.mcast).await.expect("MCP server failed"),
+                    Commands::Hook => hook::run_hook(cli.mcast).await.expect("Hook failed"),
                     #[cfg(feature = "tui")]
-                    Commands::Tui => tui::run_tui().await.expect("TUI failed"),
+                    Commands::Tui => tui::run_tui(cli.mcast).await.expect("TUI failed"),
+                    Commands::Workspaces => print_workspaces(&cli.mcast),
+                    Commands::History(args) => backends::history::run_history(args, cli.mcast)
+                        .await
+                        .expect("History failed"),
+                    Commands::Backup { command } => backends::backup::run_backup(command, cli.mcast)
+                        .await
+                        .expect("Backup failed"),
+                    Commands::Compact => backends::compaction::run_compact(cli.mcast)
+                        .await
+                        .expect("Compaction failed"),
+                    Commands::Diagnostics => backends::compaction::run_diagnostics(&cli.mcast)
+                        .expect("Diagnostics failed"),
+                    Commands::Relay(args) => backends::relay::run_relay(args, cli.mcast)
+                        .await
+                        .expect("Relay failed"),
+                    #[cfg(unix)]
+                    Commands::Daemon => backends::daemon::run_daemon(cli.mcast)
+                        .await
+                        .expect("Daemon failed"),
                 }
             });
         }
@@ -213,12 +281,45 @@ fn tui_main() {
     rt.block_on(async {
         match cli.command {
             Some(Commands::Tui) | None => {
-                tui::run_tui().await.expect("TUI failed");
+                tui::run_tui(cli.mcast).await.expect("TUI failed");
             }
+            Some(Commands::Workspaces) => print_workspaces(&cli.mcast),
+            Some(Commands::History(args)) => backends::history::run_history(args, cli.mcast)
+                .await
+                .expect("History failed"),
+            Some(Commands::Backup { command }) => backends::backup::run_backup(command, cli.mcast)
+                .await
+                .expect("Backup failed"),
+            Some(Commands::Compact) => backends::compaction::run_compact(cli.mcast)
+                .await
+                .expect("Compaction failed"),
+            Some(Commands::Diagnostics) => backends::compaction::run_diagnostics(&cli.mcast)
+                .expect("Diagnostics failed"),
+            Some(Commands::Relay(args)) => backends::relay::run_relay(args, cli.mcast)
+                .await
+                .expect("Relay failed"),
+            #[cfg(unix)]
+            Some(Commands::Daemon) => backends::daemon::run_daemon(cli.mcast)
+                .await
+                .expect("Daemon failed"),
         }
     });
 }
 
+/// Prints every saved workspace, marking the one `--workspace` selects
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn print_workspaces(config: &backends::McastConfig) {
+    let mut workspaces = backends::workspace::list(&config.autosave_path);
+    if !workspaces.contains(&config.workspace) {
+        workspaces.push(config.workspace.clone());
+    }
+
+    for name in workspaces {
+        let marker = if name == config.workspace { "*" } else { " " };
+        println!("{marker} {name}");
+    }
+}
+
 #[cfg(any(feature = "desktop", feature = "web", feature = "mobile"))]
 #[component]
 fn App() -> Element {
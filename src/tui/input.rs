@@ -1,18 +1,41 @@
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
+use crate::backends::TodoCommand;
+
 use super::state::{EditTarget, Focus, TuiState};
 
 pub fn handle_key(state: &mut TuiState, key: KeyEvent) {
+    // Any key dismisses a notice
+    state.notice = None;
+
     // Global quit
     if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
         state.should_quit = true;
         return;
     }
 
+    // Undo and redo this site's edits, anywhere but while typing
+    if matches!(
+        state.focus,
+        Focus::ListSelector | Focus::ItemList | Focus::Upcoming
+    ) {
+        if key.code == KeyCode::Char('u') {
+            state.send_command(TodoCommand::Undo);
+            return;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('r') {
+            state.send_command(TodoCommand::Redo);
+            return;
+        }
+    }
+
     match state.focus {
         Focus::ListSelector => handle_list_selector(state, key),
         Focus::ItemList => handle_item_list(state, key),
         Focus::Editing => handle_editing(state, key),
+        Focus::History => handle_history(state, key),
+        Focus::Upcoming => handle_upcoming(state, key),
+        Focus::Notes => handle_notes(state, key),
     }
 }
 
@@ -37,17 +60,28 @@ fn handle_list_selector(state: &mut TuiState, key: KeyEvent) {
         KeyCode::Char('a') => {
             state.start_edit(EditTarget::NewList, "");
         }
+        KeyCode::Char('w') => {
+            state.start_workspace_switch();
+        }
+        KeyCode::Char('H') => {
+            state.open_history();
+        }
+        KeyCode::Char('U') => {
+            state.open_upcoming();
+        }
+        KeyCode::Char('f') => {
+            let current = state.filter.clone().unwrap_or_default();
+            state.start_edit(EditTarget::Filter, &current);
+        }
         KeyCode::Char('d') => {
-            if !state.on_add_list_row() && state.list_count() > 0 {
-                let idx = state.selected_list;
-                state.remove_list(idx);
+            if let Some(list_id) = state.selected_list_id() {
+                state.remove_list(list_id);
             }
         }
         KeyCode::Char('r') => {
-            if !state.on_add_list_row() && state.list_count() > 0 {
-                let idx = state.selected_list;
-                let title = state.todo_state.lists[idx].title.clone();
-                state.start_edit(EditTarget::RenameList { list_index: idx }, &title);
+            if let Some(list_id) = state.selected_list_id() {
+                let title = state.todo_state.lists[state.selected_list].title.clone();
+                state.start_edit(EditTarget::RenameList { list_id }, &title);
             }
         }
         _ => {}
@@ -66,45 +100,113 @@ fn handle_item_list(state: &mut TuiState, key: KeyEvent) {
             state.move_item_up();
         }
         KeyCode::Char(' ') | KeyCode::Enter => {
-            let list_idx = state.selected_list;
-            let item_idx = state.selected_item_index();
+            let Some(list_id) = state.selected_list_id() else {
+                return;
+            };
 
             // If on the "Add new item" row
-            if item_idx == state.items_in_selected_list() {
-                state.start_edit(EditTarget::NewItem { list_index: list_idx }, "");
-            } else {
-                state.toggle_item(list_idx, item_idx);
+            match state.selected_item_id() {
+                Some(item_id) => state.toggle_item(list_id, item_id),
+                None => state.start_edit(EditTarget::NewItem { list_id }, ""),
             }
         }
         KeyCode::Char('a') => {
-            let list_idx = state.selected_list;
-            state.start_edit(EditTarget::NewItem { list_index: list_idx }, "");
+            if let Some(list_id) = state.selected_list_id() {
+                state.start_edit(EditTarget::NewItem { list_id }, "");
+            }
+        }
+        KeyCode::Char('A') => {
+            if let (Some(list_id), Some(parent_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                state.start_edit(EditTarget::NewSubtask { list_id, parent_id }, "");
+            }
+        }
+        KeyCode::Char('z') => {
+            if let Some(item_id) = state.selected_item_id() {
+                state.toggle_fold(item_id);
+            }
         }
         KeyCode::Char('d') => {
-            let list_idx = state.selected_list;
-            let item_idx = state.selected_item_index();
-            if item_idx < state.items_in_selected_list() {
-                state.remove_item(list_idx, item_idx);
+            if let (Some(list_id), Some(item_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                state.remove_item(list_id, item_id);
             }
         }
         KeyCode::Char('e') => {
-            let list_idx = state.selected_list;
-            let item_idx = state.selected_item_index();
-            if item_idx < state.items_in_selected_list() {
-                let text = state.todo_state.lists[list_idx].items[item_idx].text.clone();
-                state.start_edit(
-                    EditTarget::EditItem {
-                        list_index: list_idx,
-                        item_index: item_idx,
-                    },
-                    &text,
-                );
+            if let (Some(list_id), Some(item_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                let text = state
+                    .todo_state
+                    .list(list_id)
+                    .and_then(|l| l.item(item_id))
+                    .map(|item| item.text.clone())
+                    .unwrap_or_default();
+                state.start_edit(EditTarget::EditItem { list_id, item_id }, &text);
+            }
+        }
+        KeyCode::Char('D') => {
+            if let (Some(list_id), Some(item_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                state.start_due_edit(list_id, item_id);
+            }
+        }
+        KeyCode::Char('t') => {
+            if let (Some(list_id), Some(item_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                let current = state
+                    .todo_state
+                    .list(list_id)
+                    .and_then(|l| l.item(item_id))
+                    .map(|item| {
+                        item.tags
+                            .iter()
+                            .map(|tag| format!("#{tag}"))
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default()
+                    .join(" ");
+                state.start_edit(EditTarget::Tags { list_id, item_id }, &current);
+            }
+        }
+        KeyCode::Char('n') => {
+            if let (Some(list_id), Some(item_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                state.start_notes_edit(list_id, item_id);
+            }
+        }
+        KeyCode::Char('f') => {
+            let current = state.filter.clone().unwrap_or_default();
+            state.start_edit(EditTarget::Filter, &current);
+        }
+        KeyCode::Char('p') => {
+            if let (Some(list_id), Some(item_id)) =
+                (state.selected_list_id(), state.selected_item_id())
+            {
+                state.cycle_priority(list_id, item_id);
+            }
+        }
+        KeyCode::Char('s') => {
+            if let Some(list_id) = state.selected_list_id() {
+                state.cycle_sort(list_id);
             }
         }
         KeyCode::Char('r') => {
-            let list_idx = state.selected_list;
-            let title = state.todo_state.lists[list_idx].title.clone();
-            state.start_edit(EditTarget::RenameList { list_index: list_idx }, &title);
+            if let Some(list_id) = state.selected_list_id() {
+                let title = state.todo_state.lists[state.selected_list].title.clone();
+                state.start_edit(EditTarget::RenameList { list_id }, &title);
+            }
+        }
+        KeyCode::Char('H') => {
+            state.open_history();
+        }
+        KeyCode::Char('U') => {
+            state.open_upcoming();
         }
         KeyCode::Char('q') => {
             state.should_quit = true;
@@ -113,6 +215,39 @@ fn handle_item_list(state: &mut TuiState, key: KeyEvent) {
     }
 }
 
+fn handle_history(state: &mut TuiState, key: KeyEvent) {
+    match key.code {
+        KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('H') => {
+            state.close_history();
+        }
+        KeyCode::Char('j') | KeyCode::Down => {
+            state.move_history(true);
+        }
+        KeyCode::Char('k') | KeyCode::Up => {
+            state.move_history(false);
+        }
+        _ => {}
+    }
+}
+
+fn handle_upcoming(state: &mut TuiState, key: KeyEvent) {
+    match key.code {
+        KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('U') => {
+            state.close_upcoming();
+        }
+        KeyCode::Char('j') | KeyCode::Down => {
+            state.move_upcoming(true);
+        }
+        KeyCode::Char('k') | KeyCode::Up => {
+            state.move_upcoming(false);
+        }
+        KeyCode::Char(' ') | KeyCode::Enter => {
+            state.toggle_upcoming();
+        }
+        _ => {}
+    }
+}
+
 fn handle_editing(state: &mut TuiState, key: KeyEvent) {
     match key.code {
         KeyCode::Esc => {
@@ -149,3 +284,28 @@ fn handle_editing(state: &mut TuiState, key: KeyEvent) {
         _ => {}
     }
 }
+
+/// Like editing a line, but Enter starts a new one and Esc saves the notes
+fn handle_notes(state: &mut TuiState, key: KeyEvent) {
+    match key.code {
+        KeyCode::Esc => {
+            state.confirm_edit();
+        }
+        KeyCode::Enter => {
+            if let Some(edit) = &mut state.edit {
+                edit.insert_char('\n');
+            }
+        }
+        KeyCode::Up => {
+            if let Some(edit) = &mut state.edit {
+                edit.move_line(true);
+            }
+        }
+        KeyCode::Down => {
+            if let Some(edit) = &mut state.edit {
+                edit.move_line(false);
+            }
+        }
+        _ => handle_editing(state, key),
+    }
+}
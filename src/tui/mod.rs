@@ -16,16 +16,13 @@ use ratatui::{Terminal, backend::CrosstermBackend};
 use tokio::sync::mpsc::Receiver as TokioReceiver;
 
 use crate::backends::{
+    McastConfig, connect_or_setup,
     multicast::{TodoCommand, TodoEvent},
-    setup,
 };
 
 use self::state::TuiState;
 
-pub async fn run_tui() -> Result<()> {
-    let site_id: u32 = rand::random();
-    let (command_tx, mut event_rx) = setup(site_id);
-
+pub async fn run_tui(mut config: McastConfig) -> Result<()> {
     // Terminal setup
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -33,30 +30,47 @@ pub async fn run_tui() -> Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let result = event_loop(&mut terminal, command_tx.clone(), &mut event_rx).await;
+    let result = loop {
+        let site_id: u32 = rand::random();
+        let (command_tx, mut event_rx) = connect_or_setup(site_id, config.clone());
+
+        let state = TuiState::new(
+            command_tx.clone(),
+            config.workspace.clone(),
+            config.autosave_path.clone(),
+        );
+        let result = event_loop(&mut terminal, state, &mut event_rx).await;
+
+        // Send shutdown, which also saves the workspace before we leave it
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        command_tx
+            .send(TodoCommand::Shutdown { sender: shutdown_tx })
+            .await
+            .ok();
+        shutdown_rx.await.ok();
+
+        match result {
+            Ok(Some(workspace)) => config.workspace = workspace,
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
 
     // Terminal teardown (always runs)
     disable_raw_mode()?;
     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
     terminal.show_cursor()?;
 
-    // Send shutdown
-    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
-    command_tx
-        .send(TodoCommand::Shutdown { sender: shutdown_tx })
-        .await
-        .ok();
-    shutdown_rx.await.ok();
-
     result
 }
 
+/// Runs until the user quits, or picks another workspace or loses the site, returning the
+/// workspace to open next
 async fn event_loop(
     terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
-    command_tx: tokio::sync::mpsc::Sender<TodoCommand>,
+    mut state: TuiState,
     event_rx: &mut TokioReceiver<TodoEvent>,
-) -> Result<()> {
-    let mut state = TuiState::new(command_tx);
+) -> Result<Option<String>> {
     let mut reader = EventStream::new();
 
     // Initial draw
@@ -70,7 +84,10 @@ async fn event_loop(
                     Some(Ok(Event::Key(key))) => {
                         input::handle_key(&mut state, key);
                         if state.should_quit {
-                            return Ok(());
+                            return Ok(None);
+                        }
+                        if let Some(workspace) = state.switch_to.take() {
+                            return Ok(Some(workspace));
                         }
                     }
                     Some(Ok(Event::Resize(_, _))) => {
@@ -80,7 +97,7 @@ async fn event_loop(
                         return Err(e.into());
                     }
                     None => {
-                        return Ok(());
+                        return Ok(None);
                     }
                     _ => {}
                 }
@@ -92,8 +109,9 @@ async fn event_loop(
                         state.handle_event(event);
                     }
                     None => {
-                        // Backend channel closed
-                        return Ok(());
+                        // Backend channel closed, open the workspace again rather than quit
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        return Ok(Some(state.workspace));
                     }
                 }
             }
@@ -1,8 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
+use anyhow::Result;
+use automerge::{AutoCommit, Change};
+use chrono::{Local, Utc};
 use tokio::sync::mpsc::Sender as TokioSender;
+use uuid::Uuid;
 
-use crate::backends::multicast::{TodoCommand, TodoEvent, TodoItem, TodoList, TodoState};
+use crate::backends::{
+    due::{self, DueItem},
+    history::{self, HistoryEntry},
+    multicast::{TodoCommand, TodoEvent, TodoItem, TodoList, TodoState},
+    sort::{self, Priority, SortMode},
+    tags, workspace,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Focus {
@@ -12,14 +23,46 @@ pub enum Focus {
     ItemList,
     /// Editing text inline (new list, rename list, new item, edit item)
     Editing,
+    /// Browsing the workspace's change history
+    History,
+    /// Browsing what's due soon across every list
+    Upcoming,
+    /// Editing an item's notes in the detail pane
+    Notes,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EditTarget {
     NewList,
-    RenameList { list_index: usize },
-    NewItem { list_index: usize },
-    EditItem { list_index: usize, item_index: usize },
+    RenameList {
+        list_id: Uuid,
+    },
+    NewItem {
+        list_id: Uuid,
+    },
+    NewSubtask {
+        list_id: Uuid,
+        parent_id: Uuid,
+    },
+    EditItem {
+        list_id: Uuid,
+        item_id: Uuid,
+    },
+    Due {
+        list_id: Uuid,
+        item_id: Uuid,
+    },
+    Tags {
+        list_id: Uuid,
+        item_id: Uuid,
+    },
+    /// The tag to show only the items with
+    Filter,
+    Notes {
+        list_id: Uuid,
+        item_id: Uuid,
+    },
+    Workspace,
 }
 
 #[derive(Debug, Clone)]
@@ -83,21 +126,111 @@ impl EditState {
         }
     }
 
-    pub fn is_rename_list(&self, list_idx: usize) -> bool {
-        matches!(self.target, EditTarget::RenameList { list_index } if list_index == list_idx)
+    /// Moves to the same column on the line above or below, for the notes
+    pub fn move_line(&mut self, up: bool) {
+        let start = self.buffer[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+        let column = self.buffer[start..self.cursor].chars().count();
+        let target = if up {
+            if start == 0 {
+                return;
+            }
+            self.buffer[..start - 1].rfind('\n').map_or(0, |i| i + 1)
+        } else {
+            match self.buffer[self.cursor..].find('\n') {
+                Some(i) => self.cursor + i + 1,
+                None => return,
+            }
+        };
+        let line = self.buffer[target..].split('\n').next().unwrap_or("");
+        self.cursor = target + line.chars().take(column).map(char::len_utf8).sum::<usize>();
+    }
+
+    /// The line the cursor is on and how many characters into it, for the notes
+    pub fn line_and_column(&self) -> (usize, usize) {
+        let before = &self.buffer[..self.cursor];
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count();
+        (before.matches('\n').count(), column)
+    }
+
+    pub fn is_rename_list(&self, id: Uuid) -> bool {
+        matches!(self.target, EditTarget::RenameList { list_id } if list_id == id)
+    }
+
+    pub fn is_edit_item(&self, list: Uuid, item: Uuid) -> bool {
+        matches!(self.target, EditTarget::EditItem { list_id, item_id } if list_id == list && item_id == item)
+    }
+
+    pub fn is_due(&self, list: Uuid, item: Uuid) -> bool {
+        matches!(self.target, EditTarget::Due { list_id, item_id } if list_id == list && item_id == item)
+    }
+
+    pub fn is_tags(&self, list: Uuid, item: Uuid) -> bool {
+        matches!(self.target, EditTarget::Tags { list_id, item_id } if list_id == list && item_id == item)
     }
 
-    pub fn is_edit_item(&self, list_idx: usize, item_idx: usize) -> bool {
-        matches!(self.target, EditTarget::EditItem { list_index, item_index } if list_index == list_idx && item_index == item_idx)
+    pub fn is_new_item(&self, id: Uuid) -> bool {
+        matches!(self.target, EditTarget::NewItem { list_id } if list_id == id)
     }
 
-    pub fn is_new_item(&self, list_idx: usize) -> bool {
-        matches!(self.target, EditTarget::NewItem { list_index } if list_index == list_idx)
+    pub fn is_new_subtask(&self, id: Uuid) -> bool {
+        matches!(self.target, EditTarget::NewSubtask { list_id, .. } if list_id == id)
     }
 
     pub fn is_new_list(&self) -> bool {
         matches!(self.target, EditTarget::NewList)
     }
+
+    pub fn is_filter(&self) -> bool {
+        matches!(self.target, EditTarget::Filter)
+    }
+
+    pub fn is_workspace(&self) -> bool {
+        matches!(self.target, EditTarget::Workspace)
+    }
+}
+
+/// The change history as of opening the history pane
+pub struct HistoryView {
+    doc: AutoCommit,
+    /// Every change, newest first, summarised into `entries` a page at a time as they're browsed
+    changes: Vec<Change>,
+    /// Newest first
+    pub entries: Vec<HistoryEntry>,
+    pub selected: usize,
+    /// The lists as of the selected change
+    pub preview: TodoState,
+}
+
+impl HistoryView {
+    fn load(document: &[u8]) -> Result<Self> {
+        let mut doc = AutoCommit::load(document)?;
+
+        let mut view = Self {
+            changes: history::changes(&mut doc),
+            doc,
+            entries: Vec::new(),
+            selected: 0,
+            preview: TodoState::default(),
+        };
+        view.select(0)?;
+        Ok(view)
+    }
+
+    fn select(&mut self, idx: usize) -> Result<()> {
+        if idx >= self.entries.len() {
+            let end = (idx + history::DEFAULT_HISTORY_LIMIT).min(self.changes.len());
+            for change in &self.changes[self.entries.len()..end] {
+                self.entries.push(history::entry(&mut self.doc, change)?);
+            }
+        }
+
+        let Some(entry) = self.entries.get(idx) else {
+            return Ok(());
+        };
+        self.preview = history::state_at(&mut self.doc, &[entry.hash])?;
+        self.selected = idx;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -113,13 +246,33 @@ pub struct TuiState {
     pub focus: Focus,
     pub edit: Option<EditState>,
     pub connection_status: String,
+    /// Shown in place of the status bar until the next key press
+    pub notice: Option<String>,
     pub command_tx: TokioSender<TodoCommand>,
     pub scroll_offset: u16,
     pub should_quit: bool,
+    pub workspace: String,
+    pub autosave_path: PathBuf,
+    /// Saved workspaces, shown while picking one to switch to
+    pub workspaces: Vec<String>,
+    /// Set once a workspace has been picked, the event loop then reconnects to it
+    pub switch_to: Option<String>,
+    /// Open while browsing history
+    pub history: Option<HistoryView>,
+    /// The selected row of the upcoming pane
+    pub upcoming_selected: usize,
+    /// Items whose subtasks are hidden
+    pub folded: HashSet<Uuid>,
+    /// When set, only the items with this tag are shown, along with the ones they're subtasks of
+    pub filter: Option<String>,
 }
 
 impl TuiState {
-    pub fn new(command_tx: TokioSender<TodoCommand>) -> Self {
+    pub fn new(
+        command_tx: TokioSender<TodoCommand>,
+        workspace: String,
+        autosave_path: PathBuf,
+    ) -> Self {
         Self {
             todo_state: TodoState::default(),
             selected_list: 0,
@@ -127,24 +280,38 @@ impl TuiState {
             focus: Focus::ListSelector,
             edit: None,
             connection_status: String::new(),
+            notice: None,
             command_tx,
             scroll_offset: 0,
             should_quit: false,
+            workspace,
+            autosave_path,
+            workspaces: Vec::new(),
+            switch_to: None,
+            history: None,
+            upcoming_selected: 0,
+            folded: HashSet::new(),
+            filter: None,
         }
     }
 
     pub fn handle_event(&mut self, event: TodoEvent) {
         match event {
             TodoEvent::StateUpdate(state) => {
-                // Preserve UI state (expanded, selected_item) across syncs,
-                // matching the pattern in components/mod.rs:83-99
-                let prev_ui: Vec<ListUiState> = std::mem::take(&mut self.list_ui);
+                // Preserve UI state (expanded, selected_item) across syncs by list id,
+                // matching the pattern in components/mod.rs
+                let mut prev_ui: HashMap<Uuid, ListUiState> = self
+                    .todo_state
+                    .lists
+                    .iter()
+                    .map(|list| list.id)
+                    .zip(std::mem::take(&mut self.list_ui))
+                    .collect();
                 self.list_ui = state
                     .lists
                     .iter()
-                    .enumerate()
-                    .map(|(i, _)| {
-                        prev_ui.get(i).cloned().unwrap_or(ListUiState {
+                    .map(|list| {
+                        prev_ui.remove(&list.id).unwrap_or(ListUiState {
                             expanded: false,
                             selected_item: 0,
                         })
@@ -162,6 +329,16 @@ impl TuiState {
             TodoEvent::ConnectionStatus(status) => {
                 self.connection_status = status;
             }
+            TodoEvent::Recovered(recovery) => {
+                self.notice = Some(recovery.to_string());
+            }
+            TodoEvent::Document { data, .. } => match HistoryView::load(&data) {
+                Ok(view) => {
+                    self.history = Some(view);
+                    self.focus = Focus::History;
+                }
+                Err(err) => self.connection_status = format!("Could not load history: {err}"),
+            },
         }
     }
 
@@ -184,6 +361,69 @@ impl TuiState {
             .is_some_and(|ui| ui.expanded)
     }
 
+    pub fn selected_list_id(&self) -> Option<Uuid> {
+        self.todo_state
+            .lists
+            .get(self.selected_list)
+            .map(|list| list.id)
+    }
+
+    /// The list's rows as shown: each item followed by its subtasks unless it's folded, every
+    /// level sorted by the list's sort mode, with how deep each is nested. Only the items
+    /// matching the tag filter are shown, if there is one.
+    pub fn visible_items<'a>(&self, list: &'a TodoList) -> Vec<(usize, &'a TodoItem)> {
+        fn visit<'a>(
+            state: &TuiState,
+            items: &'a [TodoItem],
+            sort: SortMode,
+            depth: usize,
+            out: &mut Vec<(usize, &'a TodoItem)>,
+        ) {
+            for item in sort::sorted_items(items, sort) {
+                if state
+                    .filter
+                    .as_ref()
+                    .is_some_and(|tag| !tags::matches(item, tag))
+                {
+                    continue;
+                }
+                out.push((depth, item));
+                if !state.folded.contains(&item.id) {
+                    visit(state, &item.children, sort, depth + 1, out);
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        visit(self, &list.items, list.sort, 0, &mut out);
+        out
+    }
+
+    /// The id of the selected item, or `None` when on the "Add new item" row
+    pub fn selected_item_id(&self) -> Option<Uuid> {
+        let list = self.todo_state.lists.get(self.selected_list)?;
+        self.visible_items(list)
+            .get(self.selected_item_index())
+            .map(|(_, item)| item.id)
+    }
+
+    /// Moves the selection onto an item, wherever its list's sort mode now shows it
+    fn follow_item(&mut self, list_id: Uuid, item_id: Uuid) {
+        let Some(list_idx) = self.todo_state.lists.iter().position(|l| l.id == list_id) else {
+            return;
+        };
+        let Some(idx) = self
+            .visible_items(&self.todo_state.lists[list_idx])
+            .iter()
+            .position(|(_, item)| item.id == item_id)
+        else {
+            return;
+        };
+        if let Some(ui) = self.list_ui.get_mut(list_idx) {
+            ui.selected_item = idx;
+        }
+    }
+
     pub fn selected_item_index(&self) -> usize {
         self.list_ui
             .get(self.selected_list)
@@ -195,7 +435,7 @@ impl TuiState {
         self.todo_state
             .lists
             .get(self.selected_list)
-            .map(|l| l.items.len())
+            .map(|l| self.visible_items(l).len())
             .unwrap_or(0)
     }
 
@@ -227,7 +467,7 @@ impl TuiState {
 
     pub fn move_list_down(&mut self) {
         // +1 to allow navigating to the "Add New Todo List" row
-        if self.selected_list + 1 <= self.list_count() {
+        if self.selected_list < self.list_count() {
             self.selected_list += 1;
         }
     }
@@ -238,50 +478,61 @@ impl TuiState {
     }
 
     pub fn move_item_up(&mut self) {
-        if let Some(ui) = self.list_ui.get_mut(self.selected_list) {
-            if ui.selected_item > 0 {
-                ui.selected_item -= 1;
-            }
+        if let Some(ui) = self.list_ui.get_mut(self.selected_list)
+            && ui.selected_item > 0
+        {
+            ui.selected_item -= 1;
         }
     }
 
     pub fn move_item_down(&mut self) {
         let max = self.selectable_rows_in_list();
-        if let Some(ui) = self.list_ui.get_mut(self.selected_list) {
-            if ui.selected_item + 1 < max {
-                ui.selected_item += 1;
-            }
+        if let Some(ui) = self.list_ui.get_mut(self.selected_list)
+            && ui.selected_item + 1 < max
+        {
+            ui.selected_item += 1;
         }
     }
 
-    pub fn toggle_item(&mut self, list_index: usize, item_index: usize) {
-        if let Some(item) = self
+    /// Completes or reopens the item, completing an open one's subtasks along with it
+    pub fn toggle_item(&mut self, list_id: Uuid, item_id: Uuid) {
+        let Some(item) = self
             .todo_state
-            .lists
-            .get_mut(list_index)
-            .and_then(|l| l.items.get_mut(item_index))
-        {
+            .list_mut(list_id)
+            .and_then(|l| l.item_mut(item_id))
+        else {
+            self.send_command(TodoCommand::ToggleTodo { list_id, item_id });
+            return;
+        };
+        // Optimistic local update
+        if !item.completed && !item.children.is_empty() {
+            item.complete_all();
+            self.send_command(TodoCommand::CompleteTodo { list_id, item_id });
+        } else {
             item.completed = !item.completed;
+            self.send_command(TodoCommand::ToggleTodo { list_id, item_id });
         }
-        self.send_command(TodoCommand::ToggleTodo {
-            list_index,
-            item_index,
-        });
     }
 
-    pub fn remove_item(&mut self, list_index: usize, item_index: usize) {
-        if let Some(list) = self.todo_state.lists.get_mut(list_index) {
-            if item_index < list.items.len() {
-                list.items.remove(item_index);
-            }
+    /// Hides or shows the item's subtasks
+    pub fn toggle_fold(&mut self, item_id: Uuid) {
+        if !self.folded.remove(&item_id) {
+            self.folded.insert(item_id);
         }
-        self.send_command(TodoCommand::RemoveTodo {
-            list_index,
-            item_index,
-        });
+    }
+
+    pub fn remove_item(&mut self, list_id: Uuid, item_id: Uuid) {
+        let list_index = self.todo_state.lists.iter().position(|l| l.id == list_id);
+        if let Some(list) = self.todo_state.list_mut(list_id) {
+            list.remove_item(item_id);
+        }
+        self.send_command(TodoCommand::RemoveTodo { list_id, item_id });
         // Clamp selection
-        let new_count = self.items_in_selected_list();
-        if let Some(ui) = self.list_ui.get_mut(list_index) {
+        let new_count = list_index
+            .and_then(|idx| self.todo_state.lists.get(idx))
+            .map(|l| self.visible_items(l).len())
+            .unwrap_or(0);
+        if let Some(ui) = list_index.and_then(|idx| self.list_ui.get_mut(idx)) {
             if new_count == 0 {
                 ui.selected_item = 0;
             } else if ui.selected_item >= new_count {
@@ -290,12 +541,169 @@ impl TuiState {
         }
     }
 
-    pub fn remove_list(&mut self, list_index: usize) {
-        if list_index < self.todo_state.lists.len() {
+    /// Raises the item's priority a step, wrapping from urgent back to none
+    pub fn cycle_priority(&mut self, list_id: Uuid, item_id: Uuid) {
+        let Some(item) = self
+            .todo_state
+            .list_mut(list_id)
+            .and_then(|l| l.item_mut(item_id))
+        else {
+            return;
+        };
+        // Optimistic local update
+        item.priority = item.priority.next();
+        let priority = item.priority;
+        self.send_command(TodoCommand::SetPriority {
+            list_id,
+            item_id,
+            priority,
+        });
+        self.follow_item(list_id, item_id);
+    }
+
+    /// Shows the list in the next sort mode, keeping the selected item selected
+    pub fn cycle_sort(&mut self, list_id: Uuid) {
+        let selected = self.selected_item_id();
+        let Some(list) = self.todo_state.list_mut(list_id) else {
+            return;
+        };
+        // Optimistic local update
+        list.sort = list.sort.next();
+        let sort = list.sort;
+        self.send_command(TodoCommand::SetSort { list_id, sort });
+        if let Some(item_id) = selected {
+            self.follow_item(list_id, item_id);
+        }
+    }
+
+    /// Adds an item, or a subtask of `parent_id`, taking its `#tags` out of `input`
+    fn add_item(&mut self, list_id: Uuid, parent_id: Option<Uuid>, input: &str) {
+        let (text, tags) = tags::extract(input);
+        // Optimistic local update
+        let item = TodoItem {
+            tags: tags.clone(),
+            ..TodoItem::new(text.clone(), HashMap::new())
+        };
+        let item_id = item.id;
+        let siblings = match parent_id {
+            Some(parent_id) => self
+                .todo_state
+                .list_mut(list_id)
+                .and_then(|l| l.item_mut(parent_id))
+                .map(|parent| &mut parent.children),
+            None => self.todo_state.list_mut(list_id).map(|l| &mut l.items),
+        };
+        if let Some(siblings) = siblings {
+            siblings.push(item);
+        }
+        self.send_command(TodoCommand::AddTodo {
+            list_id,
+            item_id,
+            text,
+            metadata: HashMap::new(),
+            due: None,
+            priority: Priority::None,
+            parent_id,
+            tags,
+        });
+        if parent_id.is_some() {
+            self.follow_item(list_id, item_id);
+        }
+    }
+
+    fn set_tags(&mut self, list_id: Uuid, item_id: Uuid, input: &str) {
+        let tags = match tags::parse_list(input) {
+            Ok(tags) => tags,
+            Err(err) => {
+                self.connection_status = err.to_string();
+                return;
+            }
+        };
+        // Optimistic local update
+        if let Some(item) = self
+            .todo_state
+            .list_mut(list_id)
+            .and_then(|l| l.item_mut(item_id))
+        {
+            item.tags = tags.clone();
+        }
+        self.send_command(TodoCommand::SetTags {
+            list_id,
+            item_id,
+            tags,
+        });
+    }
+
+    fn set_notes(&mut self, list_id: Uuid, item_id: Uuid, input: &str) {
+        let notes = input.trim_end().to_string();
+        let Some(item) = self
+            .todo_state
+            .list_mut(list_id)
+            .and_then(|l| l.item_mut(item_id))
+        else {
+            return;
+        };
+        if item.notes == notes {
+            return;
+        }
+        // Optimistic local update
+        item.notes = notes.clone();
+        self.send_command(TodoCommand::SetNotes {
+            list_id,
+            item_id,
+            notes,
+        });
+    }
+
+    /// Shows only the items tagged `input`, or every item if it's empty
+    fn set_filter(&mut self, input: &str) {
+        let filter = if input.trim().is_empty() {
+            None
+        } else {
+            match tags::parse(input) {
+                Ok(tag) => Some(tag),
+                Err(err) => {
+                    self.connection_status = err.to_string();
+                    return;
+                }
+            }
+        };
+        self.filter = filter;
+        // the rows just changed under every list's selection
+        for ui in &mut self.list_ui {
+            ui.selected_item = 0;
+        }
+    }
+
+    fn set_due(&mut self, list_id: Uuid, item_id: Uuid, input: &str) {
+        let due = match due::parse_optional(input, Local::now()) {
+            Ok(due) => due,
+            Err(err) => {
+                self.connection_status = err.to_string();
+                return;
+            }
+        };
+        // Optimistic local update
+        if let Some(item) = self
+            .todo_state
+            .list_mut(list_id)
+            .and_then(|l| l.item_mut(item_id))
+        {
+            item.due = due;
+        }
+        self.send_command(TodoCommand::SetDue {
+            list_id,
+            item_id,
+            due,
+        });
+    }
+
+    pub fn remove_list(&mut self, list_id: Uuid) {
+        if let Some(list_index) = self.todo_state.lists.iter().position(|l| l.id == list_id) {
             self.todo_state.lists.remove(list_index);
             self.list_ui.remove(list_index);
         }
-        self.send_command(TodoCommand::RemoveList { list_index });
+        self.send_command(TodoCommand::RemoveList { list_id });
         // Clamp selected_list
         if !self.todo_state.lists.is_empty() {
             self.selected_list = self.selected_list.min(self.todo_state.lists.len() - 1);
@@ -309,6 +717,116 @@ impl TuiState {
         self.focus = Focus::Editing;
     }
 
+    pub fn start_workspace_switch(&mut self) {
+        self.workspaces = workspace::list(&self.autosave_path);
+        self.start_edit(EditTarget::Workspace, "");
+    }
+
+    /// Asks the site for its document, the history opens once it's sent
+    pub fn open_history(&mut self) {
+        self.send_command(TodoCommand::GetDocument);
+    }
+
+    pub fn close_history(&mut self) {
+        self.history = None;
+        self.focus = if self.selected_list_expanded() {
+            Focus::ItemList
+        } else {
+            Focus::ListSelector
+        };
+    }
+
+    pub fn move_history(&mut self, down: bool) {
+        let Some(view) = &mut self.history else {
+            return;
+        };
+        let idx = if down {
+            view.selected + 1
+        } else {
+            view.selected.saturating_sub(1)
+        };
+        if let Err(err) = view.select(idx) {
+            self.connection_status = format!("Could not load history: {err}");
+        }
+    }
+
+    /// Open items due within the week or overdue, soonest first
+    pub fn upcoming(&self) -> Vec<DueItem> {
+        due::upcoming(
+            &self.todo_state,
+            Utc::now(),
+            due::DEFAULT_UPCOMING_DAYS,
+            true,
+        )
+    }
+
+    pub fn open_upcoming(&mut self) {
+        self.upcoming_selected = 0;
+        self.focus = Focus::Upcoming;
+    }
+
+    pub fn close_upcoming(&mut self) {
+        self.focus = if self.selected_list_expanded() {
+            Focus::ItemList
+        } else {
+            Focus::ListSelector
+        };
+    }
+
+    pub fn move_upcoming(&mut self, down: bool) {
+        let last = self.upcoming().len().saturating_sub(1);
+        self.upcoming_selected = if down {
+            (self.upcoming_selected + 1).min(last)
+        } else {
+            self.upcoming_selected.saturating_sub(1).min(last)
+        };
+    }
+
+    /// Completes the selected upcoming item, which takes it off the pane
+    pub fn toggle_upcoming(&mut self) {
+        let Some(selected) = self.upcoming().into_iter().nth(self.upcoming_selected) else {
+            return;
+        };
+        self.toggle_item(selected.list_id, selected.item.id);
+        self.upcoming_selected = self
+            .upcoming_selected
+            .min(self.upcoming().len().saturating_sub(1));
+    }
+
+    pub fn start_due_edit(&mut self, list_id: Uuid, item_id: Uuid) {
+        let current = self
+            .todo_state
+            .list(list_id)
+            .and_then(|l| l.item(item_id))
+            .and_then(|item| item.due)
+            .map(due::format)
+            .unwrap_or_default();
+        self.start_edit(EditTarget::Due { list_id, item_id }, &current);
+    }
+
+    /// Opens the selected item's notes in the detail pane
+    pub fn start_notes_edit(&mut self, list_id: Uuid, item_id: Uuid) {
+        let current = self
+            .todo_state
+            .list(list_id)
+            .and_then(|l| l.item(item_id))
+            .map(|item| item.notes.clone())
+            .unwrap_or_default();
+        self.edit = Some(EditState::new(
+            EditTarget::Notes { list_id, item_id },
+            &current,
+        ));
+        self.focus = Focus::Notes;
+    }
+
+    /// The item whose notes are open in the detail pane
+    pub fn notes_item(&self) -> Option<&TodoItem> {
+        match self.edit.as_ref()?.target {
+            EditTarget::Notes { list_id, item_id } => self.todo_state.list(list_id)?.item(item_id),
+            _ => None,
+        }
+    }
+
     pub fn cancel_edit(&mut self) {
         self.edit.take();
         self.focus = if self.selected_list_expanded() {
@@ -320,6 +838,35 @@ impl TuiState {
 
     pub fn confirm_edit(&mut self) {
         if let Some(edit) = self.edit.take() {
+            // an empty due date, tag list, filter or notes clears it rather than being ignored
+            match edit.target {
+                EditTarget::Due { list_id, item_id } => {
+                    self.set_due(list_id, item_id, &edit.buffer);
+                    self.focus = Focus::ItemList;
+                    return;
+                }
+                EditTarget::Tags { list_id, item_id } => {
+                    self.set_tags(list_id, item_id, &edit.buffer);
+                    self.focus = Focus::ItemList;
+                    return;
+                }
+                EditTarget::Notes { list_id, item_id } => {
+                    self.set_notes(list_id, item_id, &edit.buffer);
+                    self.focus = Focus::ItemList;
+                    return;
+                }
+                EditTarget::Filter => {
+                    self.set_filter(&edit.buffer);
+                    self.focus = if self.selected_list_expanded() {
+                        Focus::ItemList
+                    } else {
+                        Focus::ListSelector
+                    };
+                    return;
+                }
+                _ => {}
+            }
+
             let text = edit.buffer.trim().to_string();
             if text.is_empty() {
                 self.focus = if self.selected_list_expanded() {
@@ -333,24 +880,27 @@ impl TuiState {
             match edit.target {
                 EditTarget::NewList => {
                     // Optimistic local update
-                    self.todo_state.lists.push(TodoList::new(text.clone()));
+                    let list = TodoList::new(text.clone());
+                    let list_id = list.id;
+                    self.todo_state.lists.push(list);
                     self.list_ui.push(ListUiState {
                         expanded: false,
                         selected_item: 0,
                     });
                     self.send_command(TodoCommand::AddList {
+                        list_id,
                         title: text,
                         metadata: HashMap::new(),
                     });
                     self.focus = Focus::ListSelector;
                 }
-                EditTarget::RenameList { list_index } => {
+                EditTarget::RenameList { list_id } => {
                     // Optimistic local update
-                    if let Some(list) = self.todo_state.lists.get_mut(list_index) {
+                    if let Some(list) = self.todo_state.list_mut(list_id) {
                         list.title = text.clone();
                     }
                     self.send_command(TodoCommand::RenameList {
-                        list_index,
+                        list_id,
                         title: text,
                     });
                     self.focus = if self.selected_list_expanded() {
@@ -359,42 +909,44 @@ impl TuiState {
                         Focus::ListSelector
                     };
                 }
-                EditTarget::NewItem { list_index } => {
-                    // Optimistic local update
-                    if let Some(list) = self.todo_state.lists.get_mut(list_index) {
-                        list.items.push(TodoItem {
-                            text: text.clone(),
-                            completed: false,
-                            metadata: HashMap::new(),
-                        });
-                    }
-                    self.send_command(TodoCommand::AddTodo {
-                        list_index,
-                        text,
-                        metadata: HashMap::new(),
-                    });
+                EditTarget::NewItem { list_id } => {
+                    self.add_item(list_id, None, &text);
+                    self.focus = Focus::ItemList;
+                }
+                EditTarget::NewSubtask { list_id, parent_id } => {
+                    self.add_item(list_id, Some(parent_id), &text);
+                    self.folded.remove(&parent_id);
                     self.focus = Focus::ItemList;
                 }
-                EditTarget::EditItem {
-                    list_index,
-                    item_index,
-                } => {
+                EditTarget::EditItem { list_id, item_id } => {
                     // Optimistic local update
                     if let Some(item) = self
                         .todo_state
-                        .lists
-                        .get_mut(list_index)
-                        .and_then(|l| l.items.get_mut(item_index))
+                        .list_mut(list_id)
+                        .and_then(|l| l.item_mut(item_id))
                     {
                         item.text = text.clone();
                     }
                     self.send_command(TodoCommand::RenameTodo {
-                        list_index,
-                        item_index,
+                        list_id,
+                        item_id,
                         text,
                     });
                     self.focus = Focus::ItemList;
                 }
+                // handled above
+                EditTarget::Due { .. }
+                | EditTarget::Tags { .. }
+                | EditTarget::Filter
+                | EditTarget::Notes { .. } => {}
+                EditTarget::Workspace => {
+                    match workspace::parse_name(&text) {
+                        Ok(name) if name != self.workspace => self.switch_to = Some(name),
+                        Ok(_) => {}
+                        Err(err) => self.connection_status = err,
+                    }
+                    self.focus = Focus::ListSelector;
+                }
             }
         }
     }
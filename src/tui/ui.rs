@@ -6,8 +6,16 @@ use ratatui::{
     widgets::{Block, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState},
 };
 
+use chrono::Utc;
+
+use crate::backends::{
+    due, history, notes,
+    sort::{Priority, SortMode},
+    tags,
+};
+
 use super::colors::{todo_color, todo_fg};
-use super::state::{EditState, Focus, TuiState};
+use super::state::{EditState, EditTarget, Focus, TuiState};
 
 const COLLAPSED_HEIGHT: u16 = 4; // blank + title + counts + blank
 const ITEM_HEIGHT: u16 = 1;
@@ -28,7 +36,7 @@ fn content_height(state: &TuiState) -> u16 {
     let mut h: u16 = 0;
     for (i, list) in state.todo_state.lists.iter().enumerate() {
         let expanded = state.list_ui.get(i).is_some_and(|u| u.expanded);
-        h += list_block_height(list.items.len() as u16, expanded);
+        h += list_block_height(state.visible_items(list).len() as u16, expanded);
     }
     h += ADD_LIST_ROW_HEIGHT;
     h
@@ -44,31 +52,97 @@ pub fn draw(f: &mut Frame, state: &mut TuiState) {
     ])
     .split(size);
 
-    draw_title_bar(f, outer[0]);
-    draw_content(f, outer[1], state);
+    draw_title_bar(f, outer[0], state);
+    match state.focus {
+        Focus::History => draw_history(f, outer[1], state),
+        Focus::Upcoming => draw_upcoming(f, outer[1], state),
+        Focus::Notes => {
+            let columns = Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).split(outer[1]);
+            draw_content(f, columns[0], state);
+            draw_notes(f, columns[1], state);
+        }
+        _ => draw_content(f, outer[1], state),
+    }
     draw_status_bar(f, outer[2], state);
 }
 
-fn draw_title_bar(f: &mut Frame, area: Rect) {
-    let bar = Paragraph::new(Line::from(vec![Span::styled(
-        " Todo MCP",
-        Style::default()
-            .fg(Color::White)
-            .add_modifier(Modifier::BOLD),
-    )]))
-    .style(Style::default().bg(Color::Rgb(60, 60, 80)));
+fn draw_title_bar(f: &mut Frame, area: Rect, state: &TuiState) {
+    let bar_style = Style::default().bg(Color::Rgb(60, 60, 80));
+    let title_style = Style::default()
+        .fg(Color::White)
+        .add_modifier(Modifier::BOLD);
+
+    if let Some(edit) = state.edit.as_ref().filter(|e| e.is_workspace()) {
+        render_edit_line(
+            f, area, area,
+            vec![Span::styled(" Workspace: ", title_style)],
+            edit,
+            Style::default().fg(Color::White),
+            bar_style,
+        );
+        return;
+    }
+    if let Some(edit) = state.edit.as_ref().filter(|e| e.is_filter()) {
+        render_edit_line(
+            f, area, area,
+            vec![Span::styled(" Show only #", title_style)],
+            edit,
+            Style::default().fg(Color::White),
+            bar_style,
+        );
+        return;
+    }
+
+    let mut spans = vec![
+        Span::styled(" Todo MCP", title_style),
+        Span::styled(
+            format!(" [{}]", state.workspace),
+            Style::default().fg(Color::Rgb(180, 180, 200)),
+        ),
+    ];
+    if let Some(tag) = &state.filter {
+        spans.push(Span::styled("  showing ", Style::default().fg(Color::Rgb(180, 180, 200))));
+        spans.push(tag_span(state, tag).patch_style(Modifier::BOLD));
+    }
+    let bar = Paragraph::new(Line::from(spans)).style(bar_style);
     f.render_widget(bar, area);
 }
 
 fn draw_status_bar(f: &mut Frame, area: Rect, state: &TuiState) {
+    if let Some(notice) = &state.notice {
+        let bar = Paragraph::new(Line::from(vec![Span::styled(
+            format!(" {notice}  (any key to dismiss)"),
+            Style::default().fg(Color::Black),
+        )]))
+        .style(Style::default().bg(Color::Yellow));
+        f.render_widget(bar, area);
+        return;
+    }
+
     let mode_hint = match state.focus {
-        Focus::ListSelector => "Enter:expand  a:add  d:del  r:rename  q:quit",
-        Focus::ItemList => "Space:toggle  a:add  d:del  e:edit  r:rename list  q:quit  Esc:back",
-        Focus::Editing => "Enter:confirm  Esc:cancel",
+        Focus::ListSelector => "Enter:expand  a:add  d:del  r:rename  f:filter  u:undo  ^R:redo  w:workspace  U:upcoming  H:history  q:quit".to_string(),
+        Focus::ItemList => "Space:toggle  a:add  A:add subtask  z:fold  d:del  e:edit  n:notes  D:due date  t:tags  p:priority  s:sort  f:filter  r:rename list  u:undo  ^R:redo  U:upcoming  H:history  q:quit  Esc:back".to_string(),
+        Focus::History => "j/k:browse changes  Esc:close".to_string(),
+        Focus::Upcoming => "j/k:browse  Space:complete  u:undo  Esc:close".to_string(),
+        Focus::Notes => "Enter:new line  arrows:move  Esc:save and close".to_string(),
+        Focus::Editing if state.edit.as_ref().is_some_and(|e| matches!(e.target, EditTarget::Due { .. })) => {
+            "YYYY-MM-DD [HH:MM], today or tomorrow, empty to clear  Enter:confirm  Esc:cancel".to_string()
+        }
+        Focus::Editing if state.edit.as_ref().is_some_and(|e| matches!(e.target, EditTarget::Tags { .. })) => {
+            "#tags separated by spaces, empty to remove them all  Enter:confirm  Esc:cancel".to_string()
+        }
+        Focus::Editing if state.edit.as_ref().is_some_and(|e| e.is_filter()) => {
+            let tags: Vec<String> = tags::all(&state.todo_state).into_iter().collect();
+            format!("Tags: {}  empty to show everything  Enter:filter  Esc:cancel", tags.join(", "))
+        }
+        Focus::Editing if state.edit.as_ref().is_some_and(|e| e.is_workspace()) => {
+            format!("Workspaces: {}  Enter:switch  Esc:cancel", state.workspaces.join(", "))
+        }
+        Focus::Editing => "Enter:confirm  Esc:cancel".to_string(),
     };
 
     let status = if state.connection_status.is_empty() {
-        mode_hint.to_string()
+        mode_hint
     } else {
         format!("{} | {}", state.connection_status, mode_hint)
     };
@@ -81,6 +155,124 @@ fn draw_status_bar(f: &mut Frame, area: Rect, state: &TuiState) {
     f.render_widget(bar, area);
 }
 
+/// Changes on the left, newest first, and the lists as of the selected one on the right
+fn draw_history(f: &mut Frame, area: Rect, state: &TuiState) {
+    let Some(view) = &state.history else {
+        return;
+    };
+    let columns = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).split(area);
+
+    // Keep the selected change on screen
+    let height = columns[0].height as usize;
+    let offset = view.selected.saturating_sub(height.saturating_sub(1));
+
+    let changes: Vec<Line> = if view.entries.is_empty() {
+        vec![Line::from(Span::styled(" No history yet", Style::default().fg(Color::Gray)))]
+    } else {
+        view.entries
+            .iter()
+            .enumerate()
+            .skip(offset)
+            .take(height)
+            .map(|(idx, entry)| {
+                let text = format!(" {}", history::format_entry(entry));
+                if idx == view.selected {
+                    Line::from(Span::styled(text, Style::default().fg(Color::Black).bg(Color::Rgb(180, 180, 200))))
+                } else {
+                    Line::from(Span::styled(text, Style::default().fg(Color::White)))
+                }
+            })
+            .collect()
+    };
+    f.render_widget(Paragraph::new(changes).style(Style::default().bg(Color::Rgb(30, 30, 40))), columns[0]);
+
+    let mut preview = vec![Line::from(Span::styled(
+        " As of this change",
+        Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC),
+    ))];
+    for list in &view.preview.lists {
+        preview.push(Line::from(Span::styled(
+            format!(" {}", list.title),
+            Style::default().fg(Color::White).add_modifier(Modifier::BOLD),
+        )));
+        for (depth, item) in list.all_items() {
+            let check = if item.completed { "x" } else { " " };
+            preview.push(Line::from(Span::styled(
+                format!("   {}[{check}] {}", "  ".repeat(depth), item.text),
+                Style::default().fg(Color::Rgb(200, 200, 210)),
+            )));
+        }
+    }
+    f.render_widget(Paragraph::new(preview).style(Style::default().bg(Color::Rgb(40, 40, 52))), columns[1]);
+}
+
+/// The notes of the selected item, being edited, beside the lists
+fn draw_notes(f: &mut Frame, area: Rect, state: &TuiState) {
+    let (Some(edit), Some(item)) = (&state.edit, state.notes_item()) else {
+        return;
+    };
+    let header = 2;
+    let height = area.height.saturating_sub(header) as usize;
+    let (line, column) = edit.line_and_column();
+    // Keep the cursor's line on screen
+    let offset = line.saturating_sub(height.saturating_sub(1));
+
+    let mut lines = vec![
+        Line::from(Span::styled(
+            format!(" {}", item.text),
+            Style::default().fg(Color::White).add_modifier(Modifier::BOLD),
+        )),
+        Line::from(Span::styled(" Notes", Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC))),
+    ];
+    for text in edit.buffer.split('\n').skip(offset).take(height) {
+        lines.push(Line::from(Span::styled(format!(" {text}"), Style::default().fg(Color::Rgb(200, 200, 210)))));
+    }
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(Color::Rgb(40, 40, 52))), area);
+
+    let cx = area.x + 1 + column as u16;
+    let cy = area.y + header + (line - offset) as u16;
+    if cx < area.x + area.width && cy < area.y + area.height {
+        f.set_cursor_position(Position::new(cx, cy));
+    }
+}
+
+/// Open items due within the week or overdue, soonest first, across every list
+fn draw_upcoming(f: &mut Frame, area: Rect, state: &TuiState) {
+    let upcoming = state.upcoming();
+    let now = Utc::now();
+
+    let height = area.height.saturating_sub(1) as usize;
+    let offset = state.upcoming_selected.saturating_sub(height.saturating_sub(1));
+
+    let mut lines = vec![Line::from(Span::styled(
+        format!(" Due in the next {} days or overdue", due::DEFAULT_UPCOMING_DAYS),
+        Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC),
+    ))];
+    if upcoming.is_empty() {
+        lines.push(Line::from(Span::styled(" Nothing due", Style::default().fg(Color::Gray))));
+    }
+    for (idx, entry) in upcoming.iter().enumerate().skip(offset).take(height) {
+        let overdue = due::is_overdue(&entry.item, now);
+        let date_style = if overdue {
+            Style::default().fg(Color::LightRed).add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(Color::Rgb(180, 180, 200))
+        };
+        let mut spans = vec![
+            Span::styled(format!(" {:<16}", entry.item.due.map(due::format).unwrap_or_default()), date_style),
+            Span::styled(format!(" {}", entry.item.text), Style::default().fg(Color::White)),
+            Span::styled(format!("  ({})", entry.list_title), Style::default().fg(Color::Gray)),
+        ];
+        if idx == state.upcoming_selected {
+            for span in &mut spans {
+                span.style = span.style.bg(Color::Rgb(70, 70, 90)).add_modifier(Modifier::BOLD);
+            }
+        }
+        lines.push(Line::from(spans));
+    }
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(Color::Rgb(30, 30, 40))), area);
+}
+
 fn draw_content(f: &mut Frame, area: Rect, state: &mut TuiState) {
     let total = content_height(state);
 
@@ -93,7 +285,7 @@ fn draw_content(f: &mut Frame, area: Rect, state: &mut TuiState) {
         let expanded = state.list_ui.get(i).is_some_and(|u| u.expanded);
         let is_selected = i == state.selected_list;
 
-        let block_h = list_block_height(list.items.len() as u16, expanded);
+        let block_h = list_block_height(state.visible_items(list).len() as u16, expanded);
         let r = Rect::new(area.x, area.y.saturating_add_signed(y), area.width, block_h);
         if expanded {
             draw_expanded_list(f, area, r, state, i, is_selected);
@@ -171,8 +363,11 @@ fn draw_collapsed_list(
     };
 
     let list = &state.todo_state.lists[list_idx];
-    let completed = list.items.iter().filter(|i| i.completed).count();
-    let total = list.items.len();
+    let items = list.all_items();
+    let completed = items.iter().filter(|(_, i)| i.completed).count();
+    let total = items.len();
+    let now = Utc::now();
+    let overdue = items.iter().filter(|(_, i)| due::is_overdue(i, now)).count();
     let bg = todo_color(&list.title, list_idx, 93);
     let fg = todo_fg(&list.title, list_idx);
     let active = is_selected && state.focus == Focus::ListSelector;
@@ -187,7 +382,7 @@ fn draw_collapsed_list(
 
     // Title at y+1
     if let Some(vis) = clip(clip_area, Rect::new(rect.x, rect.y + 1, rect.width, 1)) {
-        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_rename_list(list_idx)) {
+        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_rename_list(list.id)) {
             render_edit_line(
                 f, vis, clip_area,
                 vec![Span::styled("   ", Style::default().fg(fg).bg(bg))],
@@ -207,10 +402,22 @@ fn draw_collapsed_list(
 
     // Counts at y+2
     if let Some(vis) = clip(clip_area, Rect::new(rect.x, rect.y + 2, rect.width, 1)) {
-        let line = Line::from(Span::styled(
+        let mut spans = vec![Span::styled(
             format!("     {completed}/{total} completed"),
             Style::default().fg(Color::Rgb(80, 80, 80)).bg(bg),
-        ));
+        )];
+        if overdue > 0 {
+            spans.push(Span::styled(
+                format!("  {overdue} overdue"),
+                Style::default().fg(Color::Red).bg(bg).add_modifier(Modifier::BOLD),
+            ));
+        }
+        if let Some(tag) = &state.filter {
+            let tagged = items.iter().filter(|(_, i)| i.tags.contains(tag)).count();
+            spans.push(Span::styled(format!("  {tagged} "), Style::default().fg(Color::Rgb(80, 80, 80)).bg(bg)));
+            spans.push(tag_span(state, tag).patch_style(Style::default().bg(bg)));
+        }
+        let line = Line::from(spans);
         f.render_widget(Paragraph::new(line).style(Style::default().bg(bg)), vis);
     }
 }
@@ -227,6 +434,7 @@ fn draw_expanded_list(
     let bg = todo_color(&list.title, list_idx, 93);
     let fg = todo_fg(&list.title, list_idx);
     let selected_item = state.list_ui.get(list_idx).map(|u| u.selected_item).unwrap_or(0);
+    let now = Utc::now();
 
     // Render background fill
     if let Some(visible) = clip(clip_area, rect) {
@@ -237,7 +445,7 @@ fn draw_expanded_list(
 
     // Title line (offset by 1 for spacing above)
     if let Some(vis) = clip(clip_area, Rect::new(rect.x, rect.y + 1, rect.width, 1)) {
-        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_rename_list(list_idx)) {
+        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_rename_list(list.id)) {
             render_edit_line(
                 f, vis, clip_area,
                 vec![Span::styled("   ", Style::default().fg(fg).bg(bg))],
@@ -247,36 +455,81 @@ fn draw_expanded_list(
             );
         } else {
             let marker = if is_selected { " < " } else { "   " };
-            let line = Line::from(vec![
+            let mut spans = vec![
                 Span::styled(marker, Style::default().fg(fg).bg(bg)),
                 Span::styled(
                     &list.title,
                     Style::default().fg(fg).bg(bg).add_modifier(Modifier::BOLD),
                 ),
-            ]);
+            ];
+            if list.sort != SortMode::Manual {
+                spans.push(Span::styled(
+                    format!("  sorted by {}", list.sort.label()),
+                    Style::default().fg(Color::Rgb(100, 100, 100)).bg(bg),
+                ));
+            }
+            let line = Line::from(spans);
             f.render_widget(Paragraph::new(line).style(Style::default().bg(bg)), vis);
         }
     }
 
-    // Items
-    for (item_idx, item) in list.items.iter().enumerate() {
+    // Items, subtasks indented under theirs
+    let rows = state.visible_items(list);
+    for (item_idx, (depth, item)) in rows.iter().copied().enumerate() {
         let item_y = rect.y + LIST_HEADER_HEIGHT + item_idx as u16;
         let Some(vis) = clip(clip_area, Rect::new(rect.x, item_y, rect.width, 1)) else {
             continue;
         };
 
         let is_item_selected = is_selected
-            && state.focus == Focus::ItemList
+            && matches!(state.focus, Focus::ItemList | Focus::Notes)
             && selected_item == item_idx;
 
         let checkbox = if item.completed { "[x] " } else { "[ ] " };
+        let fold = if item.children.is_empty() {
+            "  "
+        } else if state.folded.contains(&item.id) {
+            "▸ "
+        } else {
+            "▾ "
+        };
         let sel_marker = if is_item_selected { " > " } else { "   " };
+        let indent = format!("{}{fold}", "  ".repeat(depth));
 
-        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_edit_item(list_idx, item_idx)) {
+        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_due(list.id, item.id)) {
+            render_edit_line(
+                f, vis, clip_area,
+                vec![
+                    Span::styled(sel_marker, Style::default().fg(fg).bg(bg)),
+                    Span::styled(indent.clone(), Style::default().fg(fg).bg(bg)),
+                    Span::styled(checkbox, Style::default().fg(fg).bg(bg)),
+                    Span::styled(item.text.as_str(), Style::default().fg(fg).bg(bg)),
+                    Span::styled("  due: ", Style::default().fg(fg).bg(bg)),
+                ],
+                edit,
+                Style::default().fg(fg).bg(bg),
+                Style::default().bg(bg),
+            );
+        } else if let Some(edit) = state.edit.as_ref().filter(|e| e.is_tags(list.id, item.id)) {
+            render_edit_line(
+                f, vis, clip_area,
+                vec![
+                    Span::styled(sel_marker, Style::default().fg(fg).bg(bg)),
+                    Span::styled(indent.clone(), Style::default().fg(fg).bg(bg)),
+                    Span::styled(checkbox, Style::default().fg(fg).bg(bg)),
+                    Span::styled(item.text.as_str(), Style::default().fg(fg).bg(bg)),
+                    Span::styled("  tags: ", Style::default().fg(fg).bg(bg)),
+                ],
+                edit,
+                Style::default().fg(fg).bg(bg),
+                Style::default().bg(bg),
+            );
+        } else if let Some(edit) = state.edit.as_ref().filter(|e| e.is_edit_item(list.id, item.id)) {
             render_edit_line(
                 f, vis, clip_area,
                 vec![
                     Span::styled(sel_marker, Style::default().fg(fg).bg(bg)),
+                    Span::styled(indent.clone(), Style::default().fg(fg).bg(bg)),
                     Span::styled(checkbox, Style::default().fg(fg).bg(bg)),
                 ],
                 edit,
@@ -295,9 +548,39 @@ fn draw_expanded_list(
 
             let mut spans = vec![
                 Span::styled(sel_marker, Style::default().fg(fg).bg(bg)),
+                Span::styled(indent, Style::default().fg(fg).bg(bg)),
                 Span::styled(checkbox, Style::default().fg(fg).bg(bg)),
-                Span::styled(&item.text, text_style),
             ];
+            if let Some(style) = priority_style(item.priority) {
+                spans.push(Span::styled(format!("{} ", item.priority.label()), style.bg(bg)));
+            }
+            spans.push(Span::styled(&item.text, text_style));
+            if !item.children.is_empty() {
+                let (done, total) = item.progress();
+                spans.push(Span::styled(
+                    format!("  {done}/{total}"),
+                    Style::default().fg(Color::Rgb(100, 100, 100)).bg(bg),
+                ));
+            }
+            for tag in &item.tags {
+                spans.push(Span::styled(" ", Style::default().bg(bg)));
+                spans.push(tag_span(state, tag).patch_style(Style::default().bg(bg)));
+            }
+            if let Some(at) = item.due {
+                let due_style = if due::is_overdue(item, now) {
+                    Style::default().fg(Color::Red).bg(bg).add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default().fg(Color::Rgb(100, 100, 100)).bg(bg)
+                };
+                let label = if due::is_overdue(item, now) { "overdue" } else { "due" };
+                spans.push(Span::styled(format!("  {label} {}", due::format(at)), due_style));
+            }
+            if let Some(first) = notes::preview(&item.notes) {
+                spans.push(Span::styled(
+                    format!("  ✎ {first}"),
+                    Style::default().fg(Color::Rgb(100, 100, 100)).bg(bg).add_modifier(Modifier::ITALIC),
+                ));
+            }
 
             if is_item_selected {
                 // Highlight the whole line
@@ -312,13 +595,13 @@ fn draw_expanded_list(
     }
 
     // "Add new item" row
-    let add_y = rect.y + LIST_HEADER_HEIGHT + list.items.len() as u16;
+    let add_y = rect.y + LIST_HEADER_HEIGHT + rows.len() as u16;
     if let Some(vis) = clip(clip_area, Rect::new(rect.x, add_y, rect.width, 1)) {
         let is_add_selected = is_selected
             && state.focus == Focus::ItemList
-            && selected_item == list.items.len();
+            && selected_item == rows.len();
 
-        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_new_item(list_idx)) {
+        if let Some(edit) = state.edit.as_ref().filter(|e| e.is_new_item(list.id)) {
             render_edit_line(
                 f, vis, clip_area,
                 vec![Span::styled("    +  ", Style::default().fg(fg).bg(bg))],
@@ -326,6 +609,21 @@ fn draw_expanded_list(
                 Style::default().fg(fg).bg(bg),
                 Style::default().bg(bg),
             );
+        } else if let Some(edit) = state.edit.as_ref().filter(|e| e.is_new_subtask(list.id)) {
+            let parent = match edit.target {
+                EditTarget::NewSubtask { parent_id, .. } => list.item(parent_id),
+                _ => None,
+            };
+            render_edit_line(
+                f, vis, clip_area,
+                vec![Span::styled(
+                    format!("    +  under \"{}\": ", parent.map(|p| p.text.as_str()).unwrap_or("")),
+                    Style::default().fg(fg).bg(bg),
+                )],
+                edit,
+                Style::default().fg(fg).bg(bg),
+                Style::default().bg(bg),
+            );
         } else {
             let style = if is_add_selected {
                 Style::default()
@@ -345,6 +643,27 @@ fn draw_expanded_list(
     }
 }
 
+/// How a priority is marked in front of an item, if it's marked at all
+fn priority_style(priority: Priority) -> Option<Style> {
+    let style = Style::default().add_modifier(Modifier::BOLD);
+    match priority {
+        Priority::None => None,
+        Priority::Low => Some(style.fg(Color::Rgb(90, 110, 140))),
+        Priority::Medium => Some(style.fg(Color::Rgb(150, 110, 0))),
+        Priority::High => Some(style.fg(Color::Rgb(200, 80, 0))),
+        Priority::Urgent => Some(style.fg(Color::Red).add_modifier(Modifier::REVERSED)),
+    }
+}
+
+/// `#tag` in the colour the workspace has for it
+fn tag_span<'a>(state: &TuiState, tag: &str) -> Span<'a> {
+    let style = match tags::color(&state.todo_state, tag).rgb() {
+        Some((r, g, b)) => Style::default().fg(Color::Rgb(r, g, b)),
+        None => Style::default(),
+    };
+    Span::styled(format!("#{tag}"), style)
+}
+
 fn draw_add_list_row(f: &mut Frame, clip_area: Rect, rect: Rect, state: &TuiState) {
     let Some(visible) = clip(clip_area, rect) else {
         return;
@@ -400,10 +719,10 @@ fn selected_y_range(state: &TuiState) -> (u16, u16) {
 
     for (i, list) in state.todo_state.lists.iter().enumerate() {
         let expanded = state.list_ui.get(i).is_some_and(|u| u.expanded);
-        let block_h = list_block_height(list.items.len() as u16, expanded);
+        let block_h = list_block_height(state.visible_items(list).len() as u16, expanded);
 
         if i == state.selected_list {
-            if expanded && state.focus == Focus::ItemList {
+            if expanded && matches!(state.focus, Focus::ItemList | Focus::Notes) {
                 let sel = state.list_ui.get(i).map(|u| u.selected_item).unwrap_or(0) as u16;
                 let item_y = y + LIST_HEADER_HEIGHT + sel;
                 return (item_y, item_y + 1);